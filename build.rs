@@ -0,0 +1,38 @@
+//! Records build information for `build_info`: git commit and dirty flag,
+//! build time and rustc version. Each falls back to "unknown" when the tool
+//! is unavailable, e.g. when building from a source archive.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Trimmed stdout of a successful command
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(program).args(args).output().ok()?;
+    out.status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn main() {
+    let commit = output("git", &["rev-parse", "--short=10", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let dirty = match output("git", &["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) => (!status.is_empty()).to_string(),
+        None => "unknown".to_string(),
+    };
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let built_at = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=CORKY_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=CORKY_GIT_DIRTY={}", dirty);
+    println!("cargo:rustc-env=CORKY_BUILT_AT={}", built_at);
+    println!("cargo:rustc-env=CORKY_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
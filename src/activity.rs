@@ -0,0 +1,203 @@
+//! Recent significant events, kept in memory for the owner's `/tail`.
+//!
+//! The same code paths that update the stats counters add an entry here:
+//! deliveries, failed attempts with their category, ZMQ link changes and
+//! ZMQ messages that could not be parsed. Only the newest `CAPACITY`
+//! entries are kept, and texts are stored as short previews, so the buffer
+//! stays small however busy the bot is. Like the counters, the buffer
+//! belongs to the `BotState`.
+
+use crate::errors::ErrorCategory;
+use crate::sender::preview;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Entries kept; older ones are dropped
+pub const CAPACITY: usize = 500;
+
+/// Characters of a message or error kept in an entry
+const PREVIEW_CHARS: usize = 60;
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Delivered,
+    Failed(ErrorCategory),
+    Zmq,
+    ParseError,
+}
+
+/// One recorded event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    pub kind: Kind,
+    pub chat_id: Option<i64>,
+    /// Preview of the message text or the event's description
+    pub detail: String,
+}
+
+impl Entry {
+    /// The entry without its time, e.g. `✗ 123 (timeout): disk full`
+    pub fn line(&self) -> String {
+        let mut line = match self.kind {
+            Kind::Delivered => "✓".to_string(),
+            Kind::Failed(_) => "✗".to_string(),
+            Kind::Zmq => "zmq".to_string(),
+            Kind::ParseError => "parse error".to_string(),
+        };
+        if let Some(chat_id) = self.chat_id {
+            line.push_str(&format!(" {}", chat_id));
+        }
+        if let Kind::Failed(category) = self.kind {
+            line.push_str(&format!(" ({})", category));
+        }
+        line.push_str(": ");
+        line.push_str(&self.detail);
+        line
+    }
+}
+
+/// Ring buffer of the most recent entries
+pub struct Activity {
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity::new()
+    }
+}
+
+impl Activity {
+    pub const fn new() -> Self {
+        Activity { entries: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Record a message accepted by Telegram
+    pub fn record_delivered(&self, chat_id: i64, text: &str) {
+        self.record(Kind::Delivered, Some(chat_id), text);
+    }
+
+    /// Record a failed send attempt
+    pub fn record_failure(&self, chat_id: i64, category: ErrorCategory, text: &str) {
+        self.record(Kind::Failed(category), Some(chat_id), text);
+    }
+
+    /// Record a change of the ZMQ link
+    pub fn record_zmq(&self, description: &str) {
+        self.record(Kind::Zmq, None, description);
+    }
+
+    /// Record a ZMQ message that could not be parsed or was invalid
+    pub fn record_parse_error(&self, error: &str) {
+        self.record(Kind::ParseError, None, error);
+    }
+
+    fn record(&self, kind: Kind, chat_id: Option<i64>, detail: &str) {
+        self.record_at(Utc::now(), kind, chat_id, detail);
+    }
+
+    pub fn record_at(&self, at: DateTime<Utc>, kind: Kind, chat_id: Option<i64>, detail: &str) {
+        let entry = Entry { at, kind, chat_id, detail: preview(detail, PREVIEW_CHARS) };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The newest `limit` entries whose line contains `filter`, ignoring
+    /// case, oldest first
+    pub fn recent(&self, limit: usize, filter: Option<&str>) -> Vec<Entry> {
+        let filter = filter.map(str::to_lowercase);
+        let entries = self.entries.lock().unwrap();
+        let mut matching: Vec<Entry> = entries
+            .iter()
+            .rev()
+            .filter(|entry| filter.as_ref().is_none_or(|f| entry.line().to_lowercase().contains(f)))
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+/// How long before `now` something happened, e.g. `3m ago`
+pub fn ago(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - at).num_seconds().max(0);
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// One line per entry, prefixed with its age at `now`
+pub fn format_entries(entries: &[Entry], now: DateTime<Utc>) -> String {
+    entries
+        .iter()
+        .map(|entry| format!("{} {}", ago(entry.at, now), entry.line()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn only_the_newest_entries_are_kept() {
+        let activity = Activity::new();
+        let now = Utc::now();
+        for i in 0..CAPACITY + 5 {
+            activity.record_at(now, Kind::Delivered, Some(i as i64), "hi");
+        }
+        let all = activity.recent(usize::MAX, None);
+        assert_eq!(all.len(), CAPACITY);
+        assert_eq!(all[0].chat_id, Some(5));
+        assert_eq!(all.last().unwrap().chat_id, Some(CAPACITY as i64 + 4));
+    }
+
+    #[test]
+    fn details_are_stored_as_previews() {
+        let activity = Activity::new();
+        activity.record_failure(7, ErrorCategory::Timeout, &format!("line one\n{}", "x".repeat(1000)));
+        let entry = &activity.recent(1, None)[0];
+        assert_eq!(entry.detail.chars().count(), PREVIEW_CHARS + 1);
+        assert!(!entry.detail.contains('\n'));
+        assert!(entry.line().starts_with("✗ 7 (timeout): line one x"));
+    }
+
+    #[test]
+    fn recent_filters_and_keeps_order() {
+        let activity = Activity::new();
+        let now = Utc::now();
+        activity.record_at(now, Kind::Delivered, Some(1), "Disk full on db1");
+        activity.record_at(now, Kind::Zmq, None, "link down");
+        activity.record_at(now, Kind::Delivered, Some(2), "disk ok");
+        activity.record_at(now, Kind::ParseError, None, "missing text");
+        let disk: Vec<_> = activity.recent(10, Some("DISK")).iter().map(|e| e.chat_id).collect();
+        assert_eq!(disk, vec![Some(1), Some(2)]);
+        let last_two: Vec<_> = activity.recent(2, None).iter().map(|e| e.kind).collect();
+        assert_eq!(last_two, vec![Kind::Delivered, Kind::ParseError]);
+        assert_eq!(activity.recent(10, Some("zmq")).len(), 1);
+    }
+
+    #[test]
+    fn ages_are_relative() {
+        let now = Utc::now();
+        assert_eq!(ago(now, now), "0s ago");
+        assert_eq!(ago(now - Duration::seconds(59), now), "59s ago");
+        assert_eq!(ago(now - Duration::minutes(3), now), "3m ago");
+        assert_eq!(ago(now - Duration::hours(5), now), "5h ago");
+        assert_eq!(ago(now - Duration::days(2), now), "2d ago");
+        assert_eq!(ago(now + Duration::seconds(5), now), "0s ago");
+        let entry = Entry { at: now - Duration::minutes(3), kind: Kind::Delivered, chat_id: Some(9), detail: "hi".into() };
+        assert_eq!(format_entries(&[entry], now), "3m ago ✓ 9: hi");
+    }
+}
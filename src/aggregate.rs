@@ -0,0 +1,280 @@
+//! Coalescing of rapid-fire messages to the same destination.
+//!
+//! The first message to a destination opens a window; texts arriving for the
+//! same destination before it closes are appended, and the whole batch goes
+//! to the outbox as one message when the window closes. The event loop owns
+//! the aggregator and its timer, so batches reach the outbox in the same
+//! order as the traffic that bypasses aggregation.
+
+use crate::sender::TELEGRAM_MAX_MESSAGE_CHARS;
+use crate::zmq_listener::{Priority, ZmqMessage};
+use log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Room kept at the end of a merged text for the "(+N more)" suffix
+const SUFFIX_ROOM: usize = 24;
+
+/// Where a message is addressed, following the same precedence as delivery
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// Deduplicated `chat_ids`, in order
+    Chats(Vec<i64>),
+    Chat(i64),
+    List(String),
+    Owner,
+}
+
+impl Target {
+    pub fn of(message: &ZmqMessage) -> Self {
+        if !message.chat_ids.is_empty() {
+            return Target::Chats(message.recipients());
+        }
+        match (message.chat_id, &message.subscriber_list) {
+            (Some(chat_id), _) => Target::Chat(chat_id),
+            (None, Some(list)) => Target::List(list.clone()),
+            (None, None) => Target::Owner,
+        }
+    }
+}
+
+struct Batch {
+    messages: Vec<ZmqMessage>,
+    closes_at: Instant,
+}
+
+/// Open batches keyed by destination
+pub struct Aggregator {
+    batches: HashMap<Target, Batch>,
+    max_chars: usize,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new(TELEGRAM_MAX_MESSAGE_CHARS)
+    }
+}
+
+impl Aggregator {
+    /// Merged texts are kept within `max_chars` characters
+    pub fn new(max_chars: usize) -> Self {
+        Aggregator { batches: HashMap::new(), max_chars: max_chars.max(SUFFIX_ROOM * 2) }
+    }
+
+    /// Offer a message with its aggregation window (`None` disables it).
+    /// Returns what should go to the outbox now, in order: a batch for the
+    /// same destination that must be flushed first, then the message itself
+    /// unless it was held.
+    pub fn offer(&mut self, message: ZmqMessage, window: Option<Duration>, now: Instant) -> Vec<ZmqMessage> {
+        let target = Target::of(&message);
+        let mut ready = Vec::new();
+        if self.batches.get(&target).is_some_and(|batch| batch.closes_at <= now) {
+            ready.extend(self.flush(&target));
+        }
+        let window = match window {
+            Some(window) if self.can_hold(&message) => window,
+            _ => {
+                // Keep earlier texts ahead of this one
+                ready.extend(self.flush(&target));
+                ready.push(message);
+                return ready;
+            }
+        };
+        self.batches
+            .entry(target)
+            .or_insert_with(|| Batch { messages: Vec::new(), closes_at: now + window })
+            .messages
+            .push(message);
+        ready
+    }
+
+    /// Attachments, high priority, texts that are already long, HTML,
+    /// messages with their own `protect_content` and messages with several
+    /// kinds of target are sent as they come
+    fn can_hold(&self, message: &ZmqMessage) -> bool {
+        !message.has_image()
+            && message.priority == Priority::Normal
+            && message.protect_content.is_none()
+            && message.parse_mode.is_none()
+            && message.target_count() <= 1
+            && message.text.chars().count() <= self.max_chars - SUFFIX_ROOM
+    }
+
+    /// When the earliest open window closes
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.batches.values().map(|batch| batch.closes_at).min()
+    }
+
+    /// Merged messages whose windows have closed, oldest window first
+    pub fn take_due(&mut self, now: Instant) -> Vec<ZmqMessage> {
+        let mut due: Vec<(Instant, Target)> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| batch.closes_at <= now)
+            .map(|(target, batch)| (batch.closes_at, target.clone()))
+            .collect();
+        due.sort_by_key(|(closes_at, _)| *closes_at);
+        due.into_iter().filter_map(|(_, target)| self.flush(&target)).collect()
+    }
+
+    /// Every open batch, merged, regardless of its window (used on shutdown)
+    pub fn drain(&mut self) -> Vec<ZmqMessage> {
+        self.take_due(Instant::now() + Duration::from_secs(365 * 86_400))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    fn flush(&mut self, target: &Target) -> Option<ZmqMessage> {
+        self.batches.remove(target).map(|batch| self.merge(batch.messages))
+    }
+
+    /// Join texts with newlines, summarising whatever does not fit as "(+N more)".
+    /// Other fields, the trace ID among them, come from the first message,
+    /// except that previews are disabled if any message disabled them.
+    fn merge(&self, messages: Vec<ZmqMessage>) -> ZmqMessage {
+        let mut messages = messages.into_iter();
+        let mut merged = messages.next().expect("batches are never empty");
+        let mut len = merged.text.chars().count();
+        let mut omitted = 0;
+        let mut traces = Vec::new();
+        for message in messages {
+            traces.extend(message.trace_id);
+            let extra = message.text.chars().count() + 1;
+            // One message asking for no previews is enough to disable them for the batch
+            if message.disable_link_preview == Some(true) {
+                merged.disable_link_preview = Some(true);
+            }
+            if omitted == 0 && len + extra <= self.max_chars - SUFFIX_ROOM {
+                merged.text.push('\n');
+                merged.text.push_str(&message.text);
+                len += extra;
+            } else {
+                omitted += 1;
+            }
+        }
+        if omitted > 0 {
+            merged.text.push_str(&format!("\n(+{} more)", omitted));
+        }
+        if !traces.is_empty() {
+            info!(
+                "Aggregated trace_ids [{}] into trace_id={}",
+                traces.join(", "),
+                merged.trace_id.as_deref().unwrap_or("-")
+            );
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Option<Duration> = Some(Duration::from_millis(1000));
+
+    fn message(text: &str, chat_id: i64) -> ZmqMessage {
+        serde_json::from_value(serde_json::json!({ "text": text, "chat_id": chat_id })).unwrap()
+    }
+
+    fn texts(messages: Vec<ZmqMessage>) -> Vec<String> {
+        messages.into_iter().map(|m| m.text).collect()
+    }
+
+    #[test]
+    fn texts_within_window_are_merged() {
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        assert!(agg.offer(message("a", 1), WINDOW, t0).is_empty());
+        assert!(agg.offer(message("b", 1), WINDOW, t0 + Duration::from_millis(900)).is_empty());
+        assert_eq!(agg.next_deadline(), Some(t0 + Duration::from_millis(1000)));
+        assert!(agg.take_due(t0 + Duration::from_millis(999)).is_empty());
+        assert_eq!(texts(agg.take_due(t0 + Duration::from_millis(1000))), vec!["a\nb"]);
+        assert!(agg.is_empty());
+    }
+
+    #[test]
+    fn message_after_window_closes_starts_new_batch() {
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        agg.offer(message("a", 1), WINDOW, t0);
+        agg.offer(message("b", 1), WINDOW, t0 + Duration::from_millis(999));
+        // The loop has not fired the timer yet: the old batch is flushed first
+        let ready = agg.offer(message("c", 1), WINDOW, t0 + Duration::from_millis(1000));
+        assert_eq!(texts(ready), vec!["a\nb"]);
+        assert_eq!(agg.next_deadline(), Some(t0 + Duration::from_millis(2000)));
+        assert_eq!(texts(agg.drain()), vec!["c"]);
+    }
+
+    #[test]
+    fn destinations_are_batched_separately() {
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        agg.offer(message("a", 1), WINDOW, t0);
+        agg.offer(message("x", 2), WINDOW, t0 + Duration::from_millis(100));
+        agg.offer(message("b", 1), WINDOW, t0 + Duration::from_millis(200));
+        assert_eq!(texts(agg.take_due(t0 + Duration::from_millis(1100))), vec!["a\nb", "x"]);
+    }
+
+    #[test]
+    fn bypassing_message_flushes_its_destination_first() {
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        agg.offer(message("a", 1), WINDOW, t0);
+        agg.offer(message("x", 2), WINDOW, t0);
+        let mut photo = message("photo", 1);
+        photo.image_path = Some("/tmp/p.png".to_string());
+        assert_eq!(texts(agg.offer(photo, WINDOW, t0)), vec!["a", "photo"]);
+        let mut alert = message("alert", 2);
+        alert.priority = Priority::High;
+        assert_eq!(texts(agg.offer(alert, WINDOW, t0)), vec!["x", "alert"]);
+        assert!(agg.is_empty());
+    }
+
+    #[test]
+    fn chat_id_groups_batch_by_their_recipients() {
+        let group = |ids: &[i64]| -> ZmqMessage {
+            serde_json::from_value(serde_json::json!({ "text": "t", "chat_ids": ids })).unwrap()
+        };
+        assert_eq!(Target::of(&group(&[2, 1, 2])), Target::Chats(vec![2, 1]));
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        agg.offer(group(&[1, 2]), WINDOW, t0);
+        agg.offer(group(&[1, 2, 1]), WINDOW, t0);
+        agg.offer(message("solo", 1), WINDOW, t0);
+        assert_eq!(agg.drain().len(), 2);
+    }
+
+    #[test]
+    fn disabled_window_passes_through() {
+        let mut agg = Aggregator::default();
+        assert_eq!(texts(agg.offer(message("a", 1), None, Instant::now())), vec!["a"]);
+        assert!(agg.is_empty());
+    }
+
+    #[test]
+    fn batch_keeps_disabled_previews() {
+        let mut agg = Aggregator::default();
+        let t0 = Instant::now();
+        agg.offer(message("a", 1), WINDOW, t0);
+        let mut quiet = message("https://example.com", 1);
+        quiet.disable_link_preview = Some(true);
+        agg.offer(quiet, WINDOW, t0);
+        assert_eq!(agg.drain().pop().unwrap().disable_link_preview, Some(true));
+    }
+
+    #[test]
+    fn overflow_is_summarised() {
+        let mut agg = Aggregator::new(100);
+        let t0 = Instant::now();
+        for i in 0..20 {
+            agg.offer(message(&format!("line {:02}", i), 1), WINDOW, t0);
+        }
+        let merged = agg.drain().pop().unwrap().text;
+        assert!(merged.chars().count() <= 100);
+        assert!(merged.starts_with("line 00\nline 01\n"));
+        let kept = merged.lines().filter(|l| l.starts_with("line")).count();
+        assert!(merged.ends_with(&format!("(+{} more)", 20 - kept)));
+    }
+}
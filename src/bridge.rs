@@ -0,0 +1,530 @@
+//! The bridge behind one handle, for services that embed it instead of
+//! running the binary and talking to it over ZMQ.
+
+use crate::aggregate::Aggregator;
+use crate::config::{AppConfig, ChatRef, SocketType, TelegramSettings};
+use crate::error_replies::ErrorReplies;
+use crate::errors::ErrorCategory;
+use crate::outbox::Serve;
+use crate::routes;
+use crate::runtime;
+use crate::sender::{self, Delivery};
+use crate::sink::{self, MessageSink, TelegramSink};
+use crate::state::BotState;
+use crate::shared_settings::SharedSettings;
+use crate::zmq_listener::{
+    self, EnvelopeLayout, ImageBytes, MessageLimits, ParseError, ParseMode, Priority, ValidationError, ZmqCommand,
+    ZmqMessage,
+};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Why a `CorkyBot` call stopped before anything was sent
+#[derive(Debug)]
+pub enum Error {
+    /// The config file is missing or unreadable
+    Config(String),
+    /// The settings fail `TelegramSettings::validate`, one entry per problem
+    InvalidSettings(Vec<String>),
+    /// A ZMQ payload could not be parsed or was refused
+    Parse(ParseError),
+    /// A message is outside the configured limits
+    Invalid(ValidationError),
+    /// A broadcast names a list that is not configured
+    UnknownList(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(err) => write!(f, "{}", err),
+            Error::InvalidSettings(problems) => write!(f, "Invalid config: {}", problems.join("; ")),
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::Invalid(err) => write!(f, "{}", err),
+            Error::UnknownList(list) => write!(f, "Unknown subscriber list '{}'", list),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::Invalid(err) => Error::Invalid(err),
+            err => Error::Parse(err),
+        }
+    }
+}
+
+/// Where a message goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Chat(i64),
+    Chats(Vec<i64>),
+    /// A public chat as `"@username"`, resolved before sending
+    Username(String),
+    /// A configured subscriber list
+    List(String),
+}
+
+impl From<i64> for Target {
+    fn from(chat: i64) -> Self {
+        Target::Chat(chat)
+    }
+}
+
+impl Target {
+    fn apply(self, msg: &mut ZmqMessage) {
+        match self {
+            Target::Chat(chat) => msg.chat_id = Some(chat),
+            Target::Chats(chats) => msg.chat_ids = chats,
+            Target::Username(username) => msg.chat = Some(ChatRef::Username(username)),
+            Target::List(list) => msg.subscriber_list = Some(list),
+        }
+    }
+}
+
+/// Per-message choices a producer would otherwise put in the JSON payload.
+/// `None` keeps the configured default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageOptions {
+    pub priority: Priority,
+    pub parse_mode: Option<ParseMode>,
+    pub protect_content: Option<bool>,
+    pub spoiler: Option<bool>,
+    pub disable_link_preview: Option<bool>,
+    pub max_retries: Option<u32>,
+    pub retry_base_delay_ms: Option<u64>,
+    /// Identifier shown in logs and delivery history
+    pub id: Option<String>,
+    /// Correlation ID for logs, events and history; generated when unset
+    pub trace_id: Option<String>,
+}
+
+impl MessageOptions {
+    fn message(self, target: Target, text: &str) -> ZmqMessage {
+        let mut msg = ZmqMessage {
+            text: text.to_string(),
+            priority: self.priority,
+            parse_mode: self.parse_mode,
+            protect_content: self.protect_content,
+            spoiler: self.spoiler,
+            disable_link_preview: self.disable_link_preview,
+            max_retries: self.max_retries,
+            retry_base_delay_ms: self.retry_base_delay_ms,
+            id: self.id,
+            trace_id: self.trace_id,
+            ..ZmqMessage::default()
+        };
+        target.apply(&mut msg);
+        msg
+    }
+}
+
+/// An image for `send_photo`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Photo {
+    /// A file readable by this process
+    Path(PathBuf),
+    /// Image data in memory; not kept if the message is deferred across a restart
+    Bytes(Vec<u8>),
+}
+
+/// Outcome of one message, per chat a send was attempted to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub outcomes: Vec<(i64, Delivery)>,
+}
+
+impl Report {
+    /// Chats that got the message
+    pub fn delivered(&self) -> impl Iterator<Item = i64> + '_ {
+        self.outcomes.iter().filter(|(_, outcome)| outcome.is_ok()).map(|(chat, _)| *chat)
+    }
+
+    /// Chats that did not, with the reason
+    pub fn failed(&self) -> impl Iterator<Item = (i64, ErrorCategory)> + '_ {
+        self.outcomes.iter().filter_map(|(chat, outcome)| outcome.as_ref().err().map(|category| (*chat, *category)))
+    }
+
+    /// Whether every chat got the message. False when nothing was sent, for
+    /// example because it was held for quiet hours or spooled.
+    pub fn is_complete(&self) -> bool {
+        !self.outcomes.is_empty() && self.outcomes.iter().all(|(_, outcome)| outcome.is_ok())
+    }
+}
+
+/// What `handle_zmq_payload` did with a payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handled {
+    /// A message, delivered as reported
+    Sent(Report),
+    /// A control action, applied
+    Control,
+    /// Dropped for its signature or by a route rule
+    Dropped,
+}
+
+/// The Telegram bot with its settings and runtime state: mutes, quarantine,
+/// spool, flood handling, retries and the rest of what the binary applies
+/// to a ZMQ message. Cheap to clone; clones share the state.
+///
+/// `S` is the Telegram connection, a `TelegramSink` outside tests.
+#[derive(Clone)]
+pub struct CorkyBot<S: MessageSink = TelegramSink> {
+    sink: S,
+    settings: SharedSettings,
+    state: Arc<BotState>,
+}
+
+impl CorkyBot<TelegramSink> {
+    /// Bot for `settings`, with state persisted in its data directory.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::CorkyBot;
+    /// use corky_telegram::config::AppConfig;
+    ///
+    /// # fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let config = AppConfig::load_from("/etc/corky/config.toml".as_ref())
+    ///     .map_err(corky_telegram::bridge::Error::Config)?;
+    /// let corky = CorkyBot::new(config.telegram)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new(settings: TelegramSettings) -> Result<Self, Error> {
+        let shared = SharedSettings::load(settings);
+        let settings = shared.snapshot();
+        let problems = settings.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidSettings(problems));
+        }
+        let state = Arc::new(BotState::load(&settings));
+        Ok(CorkyBot::with_sink(state.sink(sink::bot_for(&settings)), shared, state))
+    }
+
+    /// Bot for `CORKY_CONFIG` or ~/.corky/config.toml, with the environment overrides the binary applies
+    pub fn from_config() -> Result<Self, Error> {
+        let config = AppConfig::load().map_err(Error::Config)?;
+        CorkyBot::new(config.telegram)
+    }
+
+    /// Run the bot as the binary does: listen on ZMQ, handle Telegram
+    /// commands and send until CTRL+C or SIGTERM. Returns the process exit
+    /// code.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::CorkyBot;
+    ///
+    /// # async fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let corky = CorkyBot::from_config()?;
+    /// std::process::exit(corky.run().await);
+    /// # }
+    /// ```
+    pub async fn run(self) -> i32 {
+        runtime::run(self).await
+    }
+}
+
+impl<S: MessageSink> CorkyBot<S> {
+    /// Bot sending through `sink`. The settings are not validated.
+    pub fn with_sink(sink: S, settings: impl Into<SharedSettings>, state: Arc<BotState>) -> Self {
+        CorkyBot { sink, settings: settings.into(), state }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    /// The handle to the settings, shared with every clone of the bot
+    pub fn settings(&self) -> &SharedSettings {
+        &self.settings
+    }
+
+    pub fn state(&self) -> &Arc<BotState> {
+        &self.state
+    }
+
+    /// Deliver `msg` now, as if a producer had sent it
+    pub async fn send(&self, msg: ZmqMessage) -> Result<Report, Error> {
+        let settings = self.settings.snapshot();
+        msg.validate(&MessageLimits::from_settings(&settings)).map_err(Error::Invalid)?;
+        let outcomes = sender::process_zmq_message(&self.sink, &settings, &self.state, msg).await;
+        Ok(Report { outcomes })
+    }
+
+    /// Send `text` to `target`.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::{CorkyBot, MessageOptions};
+    ///
+    /// # async fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let corky = CorkyBot::from_config()?;
+    /// let report = corky.send_text(123456789, "Backup finished", MessageOptions::default()).await?;
+    /// for (chat, category) in report.failed() {
+    ///     eprintln!("not sent to {}: {}", chat, category);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_text(&self, target: impl Into<Target>, text: &str, opts: MessageOptions) -> Result<Report, Error> {
+        self.send(opts.message(target.into(), text)).await
+    }
+
+    /// Send `photo` with `caption` to `target`.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::{CorkyBot, MessageOptions, Photo, Target};
+    ///
+    /// # async fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let corky = CorkyBot::from_config()?;
+    /// let photo = Photo::Path("/var/lib/grafana/cpu.png".into());
+    /// let target = Target::Username("@ops_room".to_string());
+    /// corky.send_photo(target, photo, "CPU, last hour", MessageOptions::default()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_photo(
+        &self,
+        target: impl Into<Target>,
+        photo: Photo,
+        caption: &str,
+        opts: MessageOptions,
+    ) -> Result<Report, Error> {
+        let mut msg = opts.message(target.into(), caption);
+        match photo {
+            Photo::Path(path) => msg.image_path = Some(path.to_string_lossy().into_owned()),
+            Photo::Bytes(bytes) => {
+                msg.image_frame = true;
+                msg.image_bytes = Some(ImageBytes(Arc::new(bytes)));
+            }
+        }
+        self.send(msg).await
+    }
+
+    /// Send `text` to every chat of subscriber list `list`, honoring its
+    /// mutes and quiet hours.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::{CorkyBot, MessageOptions};
+    ///
+    /// # async fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let corky = CorkyBot::from_config()?;
+    /// let report = corky.broadcast("team", "Deploy started", MessageOptions::default()).await?;
+    /// println!("reached {} chat(s)", report.delivered().count());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn broadcast(&self, list: &str, text: &str, opts: MessageOptions) -> Result<Report, Error> {
+        if !self.settings.snapshot().subscriber_lists.contains_key(list) {
+            return Err(Error::UnknownList(list.to_string()));
+        }
+        self.send(opts.message(Target::List(list.to_string()), text)).await
+    }
+
+    /// Handle one payload as the listener would have received it, in the
+    /// configured envelope, and deliver it before returning. Route rules
+    /// and signatures apply; aggregation windows and digests, which belong
+    /// to the event loop, do not. Image frames need `handle_zmq_frames`.
+    ///
+    /// ```no_run
+    /// use corky_telegram::bridge::{CorkyBot, Handled};
+    ///
+    /// # async fn example() -> Result<(), corky_telegram::bridge::Error> {
+    /// let corky = CorkyBot::from_config()?;
+    /// let payload = br#"[200, "send", {"chat_id": 123456789, "text": "hello"}]"#;
+    /// if let Handled::Sent(report) = corky.handle_zmq_payload(payload).await? {
+    ///     assert!(report.is_complete());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn handle_zmq_payload(&self, payload: &[u8]) -> Result<Handled, Error> {
+        let settings = self.settings.snapshot();
+        let layout = EnvelopeLayout {
+            socket_type: SocketType::Dealer,
+            payload_frame: 0,
+            ..EnvelopeLayout::from_settings(&settings)
+        };
+        let command = zmq_listener::accept_frames(&settings, &self.state, &[payload.to_vec()], &layout)
+            .inspect_err(|err| {
+                if let ParseError::Invalid(_) = err {
+                    self.state.stats.record_invalid_message();
+                }
+            })?;
+        match command {
+            None => Ok(Handled::Dropped),
+            Some(ZmqCommand::Send(msg)) => match routes::apply(&settings.routes, *msg) {
+                Some(msg) => {
+                    let outcomes = sender::process_zmq_message(&self.sink, &settings, &self.state, msg).await;
+                    Ok(Handled::Sent(Report { outcomes }))
+                }
+                None => Ok(Handled::Dropped),
+            },
+            Some(ZmqCommand::Control(action)) => {
+                sender::process_control(&self.settings, &self.state, action);
+                Ok(Handled::Control)
+            }
+        }
+    }
+
+    /// Queue the command in `frames` for the outbox workers, as the binary's
+    /// event loop does: messages pass through `aggregator` and digests, and
+    /// payloads that fail to parse are answered through `replies`
+    pub fn handle_zmq_frames(&self, aggregator: &mut Aggregator, replies: &mut ErrorReplies, frames: Vec<Vec<u8>>) {
+        zmq_listener::handle_zmq_frames(&self.settings, &self.state, aggregator, replies, frames)
+    }
+
+    /// Send messages from the outbox until it is closed and drained
+    pub async fn run_outbox_worker(self, serve: Serve) {
+        sender::run_outbox_worker(self.sink, self.settings, self.state, serve).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Activity;
+    use crate::flood;
+    use crate::migrations::Migrations;
+    use crate::stats::Stats;
+    use crate::sink::{SendOptions, SentPhoto};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use teloxide::types::{ChatAction, ChatId, MessageId};
+
+    /// Records (chat, text) for every send and always succeeds
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        calls: Arc<Mutex<Vec<(i64, String)>>>,
+        breaker: Arc<flood::FloodBreaker>,
+        migrations: Arc<Migrations>,
+        stats: Arc<Stats>,
+        activity: Arc<Activity>,
+    }
+
+    impl RecordingSink {
+        fn calls(&self) -> Vec<(i64, String)> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn record(&self, chat: ChatId, text: &str) -> Result<MessageId, ErrorCategory> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((chat.0, text.to_string()));
+            Ok(MessageId(calls.len() as i32))
+        }
+    }
+
+    impl MessageSink for RecordingSink {
+        type Error = ErrorCategory;
+
+        async fn send_text(&self, chat: ChatId, text: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+            self.record(chat, text)
+        }
+
+        async fn send_photo(&self, chat: ChatId, _path: &Path, caption: &str, _opts: SendOptions) -> Result<SentPhoto, ErrorCategory> {
+            self.record(chat, caption).map(|id| SentPhoto { id, file_id: None })
+        }
+
+        async fn send_photo_bytes(&self, chat: ChatId, _image: &ImageBytes, caption: &str, _opts: SendOptions) -> Result<SentPhoto, ErrorCategory> {
+            self.record(chat, caption).map(|id| SentPhoto { id, file_id: None })
+        }
+
+        async fn send_photo_by_id(&self, chat: ChatId, _file_id: &str, caption: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+            self.record(chat, caption)
+        }
+
+        async fn send_document(&self, chat: ChatId, _path: &Path, caption: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+            self.record(chat, caption)
+        }
+
+        async fn send_chat_action(&self, _chat: ChatId, _action: ChatAction) -> Result<(), ErrorCategory> {
+            Ok(())
+        }
+
+        async fn resolve_username(&self, _username: &str) -> Result<ChatId, ErrorCategory> {
+            Err(ErrorCategory::ChatNotFound)
+        }
+
+        fn flood_breaker(&self) -> &flood::FloodBreaker {
+            &self.breaker
+        }
+
+        fn migrations(&self) -> &Migrations {
+            &self.migrations
+        }
+
+        fn stats(&self) -> &Stats {
+            &self.stats
+        }
+
+        fn activity(&self) -> &Activity {
+            &self.activity
+        }
+    }
+
+    fn corky() -> CorkyBot<RecordingSink> {
+        let settings = toml::from_str::<AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 99\n\
+             [telegram.subscriber_lists]\nteam = [1, 2]\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = Arc::new(BotState::in_memory(&settings));
+        CorkyBot::with_sink(RecordingSink::default(), settings, state)
+    }
+
+    #[test]
+    fn handles_can_be_shared_between_tasks() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CorkyBot>();
+        assert_send_sync::<Error>();
+    }
+
+    #[tokio::test]
+    async fn texts_and_broadcasts_report_each_chat() {
+        let corky = corky();
+        let report = corky.send_text(7, "hello", MessageOptions::default()).await.unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.delivered().collect::<Vec<_>>(), vec![7]);
+
+        let report = corky.broadcast("team", "to all", MessageOptions::default()).await.unwrap();
+        assert_eq!(report.delivered().collect::<Vec<_>>(), vec![1, 2]);
+        assert!(matches!(corky.broadcast("nobody", "hi", MessageOptions::default()).await, Err(Error::UnknownList(_))));
+
+        let report = corky.send_photo(8, Photo::Bytes(b"png".to_vec()), "chart", MessageOptions::default()).await.unwrap();
+        assert!(report.is_complete());
+        assert_eq!(corky.sink().calls().last(), Some(&(8, "chart".to_string())));
+    }
+
+    #[tokio::test]
+    async fn messages_outside_the_limits_are_refused() {
+        let corky = corky();
+        let text = "x".repeat(corky.settings().snapshot().max_text_bytes + 1);
+        let err = corky.send_text(7, &text, MessageOptions::default()).await.unwrap_err();
+        assert!(matches!(err, Error::Invalid(ValidationError::TextTooLong { .. })));
+        assert!(corky.sink().calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn payloads_go_through_the_envelope() {
+        let corky = corky();
+        let handled = corky.handle_zmq_payload(br#"[200, "send", {"chat_id": 5, "text": "hi"}]"#).await.unwrap();
+        assert!(matches!(handled, Handled::Sent(report) if report.is_complete()));
+        assert_eq!(corky.sink().calls(), vec![(5, "hi".to_string())]);
+
+        let handled = corky.handle_zmq_payload(br#"[200, "send", {"action": "unquarantine", "chat_id": 5}]"#).await.unwrap();
+        assert_eq!(handled, Handled::Control);
+
+        let err = corky.handle_zmq_payload(b"not json").await.unwrap_err();
+        assert!(matches!(err, Error::Parse(ParseError::InvalidJson(_))));
+    }
+}
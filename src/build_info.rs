@@ -0,0 +1,52 @@
+//! Build information recorded by `build.rs`, for `/version`, the startup
+//! log and the startup notice.
+
+use chrono::DateTime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short commit hash, or "unknown" outside a git checkout
+pub const GIT_COMMIT: &str = env!("CORKY_GIT_COMMIT");
+/// "true", "false" or "unknown"
+pub const GIT_DIRTY: &str = env!("CORKY_GIT_DIRTY");
+/// Unix seconds
+pub const BUILT_AT: &str = env!("CORKY_BUILT_AT");
+pub const RUSTC_VERSION: &str = env!("CORKY_RUSTC_VERSION");
+
+/// One line such as `corky-telegram 0.1.0 (3f2a9c1d0b-dirty, built
+/// 2024-05-01 12:00 UTC, rustc 1.78.0 (9b00956e5 2024-04-29))`
+pub fn summary() -> String {
+    format_summary(VERSION, GIT_COMMIT, GIT_DIRTY, BUILT_AT, RUSTC_VERSION)
+}
+
+fn format_summary(version: &str, commit: &str, dirty: &str, built_at: &str, rustc: &str) -> String {
+    let built = built_at
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let dirty = if dirty == "true" { "-dirty" } else { "" };
+    format!("corky-telegram {} ({}{}, built {}, {})", version, commit, dirty, built, rustc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_marks_dirty_builds() {
+        assert_eq!(
+            format_summary("0.1.0", "3f2a9c1d0b", "true", "1714564800", "rustc 1.78.0"),
+            "corky-telegram 0.1.0 (3f2a9c1d0b-dirty, built 2024-05-01 12:00 UTC, rustc 1.78.0)"
+        );
+        assert_eq!(
+            format_summary("0.1.0", "unknown", "unknown", "x", "unknown"),
+            "corky-telegram 0.1.0 (unknown, built unknown, unknown)"
+        );
+    }
+
+    #[test]
+    fn summary_uses_recorded_values() {
+        assert!(summary().starts_with(&format!("corky-telegram {} (", VERSION)));
+    }
+}
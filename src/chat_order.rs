@@ -0,0 +1,132 @@
+//! First-in, first-out delivery per chat.
+//!
+//! Several send workers and broadcast tasks run at once, so a message still
+//! retrying could otherwise be overtaken by the next one to the same chat.
+//! Each message takes a ticket for every chat it targets as soon as they are
+//! known, and sends to a chat only once the tickets ahead of it are done.
+//! Different chats never wait for each other, and a chat with no tickets
+//! left takes no memory.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Line {
+    /// Tickets not yet dropped, in the order they were taken
+    waiting: VecDeque<u64>,
+    next: u64,
+    turn: Arc<Notify>,
+}
+
+/// Ticket lines of the chats with sends in flight
+#[derive(Clone, Default)]
+pub struct ChatOrder {
+    lines: Arc<Mutex<HashMap<i64, Line>>>,
+}
+
+impl ChatOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue behind every ticket already taken for `chat`
+    pub fn ticket(&self, chat: i64) -> Ticket {
+        let mut lines = self.lines.lock().unwrap();
+        let line = lines
+            .entry(chat)
+            .or_insert_with(|| Line { waiting: VecDeque::new(), next: 0, turn: Arc::new(Notify::new()) });
+        let number = line.next;
+        line.next += 1;
+        line.waiting.push_back(number);
+        Ticket { order: self.clone(), chat, number, turn: line.turn.clone() }
+    }
+
+    /// Chats with at least one ticket outstanding
+    pub fn len(&self) -> usize {
+        self.lines.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A place in a chat's line, given up when dropped
+pub struct Ticket {
+    order: ChatOrder,
+    chat: i64,
+    number: u64,
+    turn: Arc<Notify>,
+}
+
+impl Ticket {
+    /// Wait until every earlier ticket for this chat has been dropped
+    pub async fn turn(&self) {
+        loop {
+            let notified = self.turn.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_first() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn is_first(&self) -> bool {
+        let lines = self.order.lines.lock().unwrap();
+        lines.get(&self.chat).and_then(|line| line.waiting.front()) == Some(&self.number)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut lines = self.order.lines.lock().unwrap();
+        if let Some(line) = lines.get_mut(&self.chat) {
+            line.waiting.retain(|&number| number != self.number);
+            if line.waiting.is_empty() {
+                lines.remove(&self.chat);
+            }
+        }
+        self.turn.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test(start_paused = true)]
+    async fn tickets_are_served_in_order_per_chat() {
+        let order = ChatOrder::new();
+        let first = order.ticket(1);
+        let second = order.ticket(1);
+        let other = order.ticket(2);
+        // Another chat is not held up
+        other.turn().await;
+        assert!(time::timeout(Duration::from_secs(1), second.turn()).await.is_err());
+
+        let waiter = tokio::spawn(async move {
+            second.turn().await;
+        });
+        first.turn().await;
+        drop(first);
+        waiter.await.unwrap();
+        drop(other);
+        assert!(order.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_a_waiting_ticket_lets_later_ones_through() {
+        let order = ChatOrder::new();
+        let first = order.ticket(1);
+        let abandoned = order.ticket(1);
+        let third = order.ticket(1);
+        drop(abandoned);
+        drop(first);
+        third.turn().await;
+        assert_eq!(order.len(), 1);
+    }
+}
@@ -0,0 +1,333 @@
+//! `--check-config` mode: validate configuration and connectivity, then exit.
+
+use crate::config::SocketType;
+use crate::data_dir::DataDir;
+use crate::{config, sink};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use tokio::time;
+
+/// Run `--check-config`: validate the config and connectivity, print a
+/// report, and return the process exit code. Never sends Telegram messages.
+pub async fn run(path: Option<PathBuf>, offline: bool) -> i32 {
+    let mut errors = Vec::new();
+
+    let config_path = match config::AppConfig::path(path) {
+        Ok(p) => p,
+        Err(err) => {
+            println!("FAIL {}", err);
+            return 1;
+        }
+    };
+    println!("Checking {}", config_path.display());
+
+    let app_config = match config::AppConfig::load_from(&config_path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("FAIL {}", err);
+            return 1;
+        }
+    };
+    let settings = &app_config.telegram;
+    errors.extend(settings.validate());
+
+    println!();
+    println!("  bot_token:              {} (from {})", settings.redacted_token(), settings.sources.bot_token);
+    println!("  owner_chat_ids:         {:?} (from {})", settings.owner_chat_ids, settings.sources.owner_chat_ids);
+    println!("  zmq_endpoint:           {} (from {})", settings.zmq_endpoint, settings.sources.zmq_endpoint);
+    println!("  api_url:                {}", settings.api_url.as_deref().unwrap_or("(api.telegram.org)"));
+    println!("  max_photo_bytes:        {}", settings.max_photo_bytes);
+    println!("  max_document_bytes:     {}", settings.max_document_bytes);
+    println!("  max_image_frame_bytes:  {}", settings.max_image_frame_bytes);
+    match settings.convert_unsupported_images {
+        true => println!("  unsupported images:     converted to JPEG"),
+        false => println!("  unsupported images:     (sent as given)"),
+    }
+    match settings.zmq_accept_compressed {
+        true => println!("  compressed payloads:    gzip or zstd, up to {} bytes inflated", settings.zmq_max_inflated_bytes),
+        false => println!("  compressed payloads:    (rejected)"),
+    }
+    match settings.translations.languages() {
+        languages if languages.is_empty() => println!("  language:               {}", settings.language),
+        languages => println!("  language:               {} (translated: {})", settings.language, languages.join(", ")),
+    }
+    println!(
+        "  message limits:         {} text bytes, {} targets, {} image_path chars",
+        settings.max_text_bytes, settings.max_targets_per_message, settings.max_image_path_chars
+    );
+    println!("  send_chat_actions:      {} (files from {} bytes)", settings.send_chat_actions, settings.chat_action_min_bytes);
+    println!("  long_text_as_file_over: {}", settings.long_text_as_file_over);
+    println!("  username_cache_secs:    {}", settings.username_cache_secs);
+    println!("  file_id_cache:          {} entries for {}s", settings.file_id_cache_size, settings.file_id_cache_ttl_secs);
+    println!("  disable_link_preview:   {}", settings.disable_link_preview);
+    println!("  delete_after_send:      {}", settings.delete_after_send);
+    println!("  dispatcher_exit_fatal:  {}", settings.dispatcher_exit_fatal);
+    match settings.suppress_duplicates_secs {
+        0 => println!("  suppress_duplicates:    (disabled)"),
+        secs => println!("  suppress_duplicates:    {}s (summaries: {})", secs, settings.duplicate_summaries),
+    }
+    match &settings.media_dir {
+        Some(dir) => println!("  media_dir:              {} (warn owners: {})", dir.display(), settings.media_dir_warn_owners),
+        None => println!("  media_dir:              (any path)"),
+    }
+    println!("  html_mode:              {:?}", settings.html_mode);
+    println!("  combine_targets:        {}", settings.combine_targets);
+    match &settings.history_db {
+        Some(path) => println!("  history_db:             {} (keeping {} days)", path.display(), settings.history_keep_days),
+        None => println!("  history_db:             (disabled)"),
+    }
+    let data_dir = DataDir::for_settings(settings);
+    match (data_dir.path(), data_dir.problem()) {
+        (Some(dir), _) => println!("  data_dir:               {}", dir.display()),
+        (None, problem) => println!("  data_dir:               (none, state kept in memory: {})", problem.unwrap_or_default()),
+    }
+    match &settings.heartbeat_file {
+        Some(path) => println!("  heartbeat_file:         {}", path.display()),
+        None => println!("  heartbeat_file:         (disabled)"),
+    }
+    match settings.keepalive_secs {
+        0 => println!("  keepalive:              (disabled)"),
+        secs => println!("  keepalive:              after {}s idle", secs),
+    }
+    println!("  zmq_socket_type:        {:?}", settings.zmq_socket_type);
+    match &settings.zmq_events_endpoint {
+        Some(endpoint) => println!(
+            "  zmq_events_endpoint:    {} ({:?}: {})",
+            endpoint,
+            settings.zmq_events_socket,
+            settings.zmq_events.join(", ")
+        ),
+        None => println!("  zmq_events_endpoint:    (disabled)"),
+    }
+    println!("  zmq_payload_frame:      {}", settings.payload_frame());
+    match settings.zmq_envelope {
+        config::Envelope::Array => println!("  zmq_envelope:           array (command at index {})", settings.zmq_envelope_index),
+        config::Envelope::None => println!("  zmq_envelope:           none"),
+    }
+    println!("  zmq_down_alert_after:   {}s", settings.zmq_down_alert_after_secs);
+    println!(
+        "  zmq_reconnect:          {}-{}ms, after {} errors",
+        settings.zmq_reconnect_min_ms, settings.zmq_reconnect_max_ms, settings.zmq_max_consecutive_errors
+    );
+    println!("  zmq_poll_timeout:       {}ms", settings.zmq_poll_timeout_ms);
+    println!("  event_queue_size:       {}", settings.event_queue_size);
+    println!("  event_queue_overflow:   {:?}", settings.event_queue_overflow);
+    println!("  outbox_workers:         {}", settings.outbox_workers);
+    match settings.flood_breaker_threshold {
+        0 => println!("  flood_breaker:          (disabled)"),
+        n => println!("  flood_breaker:          {} flood waits within {}s", n, settings.flood_breaker_window_secs),
+    }
+    println!("  broadcast_concurrency:  {}", settings.broadcast_concurrency);
+    println!("  quarantine_after:       {}", settings.quarantine_after);
+    println!("  mutes_apply_to_direct:  {}", settings.mutes_apply_to_direct);
+    println!("  relay_replies_to:       {}", settings.relay_replies_to.as_deref().unwrap_or("(disabled)"));
+    println!("  relay_callbacks_to:     {}", settings.relay_callbacks_to.as_deref().unwrap_or("(disabled)"));
+    if settings.zmq_error_replies {
+        println!("  zmq_error_replies:      every {}s per producer", settings.zmq_error_reply_interval_secs);
+    } else {
+        println!("  zmq_error_replies:      (disabled)");
+    }
+    match settings.zmq_hmac_secret {
+        Some(_) => println!("  zmq_hmac_secret:        (set, ts within {}s)", settings.zmq_hmac_window_secs),
+        None => println!("  zmq_hmac_secret:        (unsigned commands accepted)"),
+    }
+    if settings.allowed_callback_users.is_empty() {
+        println!("  allowed_callback_users: (everyone)");
+    } else {
+        println!("  allowed_callback_users: {:?}", settings.allowed_callback_users);
+    }
+    println!("  callback_edit_message:  {}", settings.callback_edit_message);
+    for (name, command) in &settings.commands {
+        match command.allowed_chats.is_empty() {
+            true => println!("  command /{}: -> '{}' (owner only)", name, command.destination),
+            false => println!("  command /{}: -> '{}' (chats {:?})", name, command.destination, command.allowed_chats),
+        }
+    }
+    for (index, route) in settings.routes.iter().enumerate() {
+        match route.target() {
+            Some(target) => println!("  {}: -> {}", route.label(index), target),
+            None => println!("  {}: (no valid target)", route.label(index)),
+        }
+    }
+    println!("  aggregate_window:       {}ms", settings.aggregate_window_ms);
+    match settings.log_filters.is_empty() {
+        true => println!("  log_level:              {}", settings.log_level),
+        false => println!("  log_level:              {} ({})", settings.log_level, settings.log_filters),
+    }
+    println!("  notify_owner_on_startup:  {}", settings.notify_owner_on_startup);
+    println!("  notify_owner_on_shutdown: {}", settings.notify_owner_on_shutdown);
+    match &settings.webhook {
+        Some(webhook) => println!("  webhook:                {} (listening on {})", webhook.url, webhook.listen),
+        None => println!("  webhook:                (disabled, long polling)"),
+    }
+    let mut names: Vec<_> = settings.subscriber_lists.keys().collect();
+    names.sort();
+    if names.is_empty() {
+        println!("  subscriber_lists:       (none)");
+    } else {
+        println!("  subscriber_lists:");
+        for name in names {
+            let list = &settings.subscriber_lists[name];
+            let chats: Vec<String> = list.chats.iter().map(ToString::to_string).collect();
+            println!("    {} ({} chats): [{}]", name, list.chats.len(), chats.join(", "));
+            if let Some(interval) = list.digest_interval {
+                println!("      digest_interval: {}m", interval.num_minutes());
+            }
+            if let Some(language) = &list.language {
+                println!("      language: {}", language);
+            }
+            if let Some(every) = list.expect_message_every {
+                println!("      expect_message_every: {}m", every.num_minutes());
+            }
+            if let Some(window) = list.aggregate_window_ms {
+                println!("      aggregate_window: {}ms", window);
+            }
+            if let Some(disabled) = list.disable_link_preview {
+                println!("      disable_link_preview: {}", disabled);
+            }
+            if list.protect_content || list.spoiler {
+                println!("      protect_content: {}, spoiler: {}", list.protect_content, list.spoiler);
+            }
+            if let Some(quiet) = &list.quiet_hours {
+                println!(
+                    "      quiet_hours: {}-{} {} ({:?})",
+                    quiet.start.format("%H:%M"),
+                    quiet.end.format("%H:%M"),
+                    quiet.tz,
+                    quiet.mode
+                );
+            }
+        }
+    }
+    println!();
+
+    if offline {
+        println!("Skipping Telegram and ZMQ connectivity checks (--offline)");
+    } else {
+        match check_token(settings).await {
+            Ok(username) => println!("OK   Telegram token valid for @{}", username),
+            Err(err) => errors.push(err),
+        }
+        match check_zmq(&settings.zmq_endpoint, settings.zmq_socket_type) {
+            Ok(()) => match settings.zmq_socket_type {
+                SocketType::Dealer => println!("OK   ZMQ endpoint {} accepted a connection", settings.zmq_endpoint),
+                SocketType::Router => println!("OK   ZMQ endpoint {} can be bound", settings.zmq_endpoint),
+            },
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        println!("Config OK");
+        0
+    } else {
+        for err in &errors {
+            println!("FAIL {}", err);
+        }
+        println!("{} problem(s) found", errors.len());
+        1
+    }
+}
+
+/// Call `get_me` to verify the bot token
+async fn check_token(settings: &config::TelegramSettings) -> Result<String, String> {
+    let bot = sink::bot_for(settings);
+    match time::timeout(time::Duration::from_secs(15), bot.get_me()).await {
+        Ok(Ok(me)) => Ok(me.username().to_string()),
+        Ok(Err(err)) => Err(format!("Telegram get_me failed: {}", err)),
+        Err(_elapsed) => Err("Telegram get_me timed out".to_string()),
+    }
+}
+
+/// How long a DEALER check waits for the endpoint to take the connection
+const ZMQ_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connect a DEALER socket to the endpoint and wait for the connection to
+/// be made, or in ROUTER mode check that the endpoint can be bound
+fn check_zmq(endpoint: &str, socket_type: SocketType) -> Result<(), String> {
+    let context = zmq::Context::new();
+    if socket_type == SocketType::Router {
+        let socket = context
+            .socket(zmq::ROUTER)
+            .map_err(|e| format!("Failed to create ZMQ socket: {}", e))?;
+        let _ = socket.set_linger(0);
+        return socket
+            .bind(endpoint)
+            .map_err(|e| format!("Failed to bind ZMQ endpoint {}: {}", endpoint, e));
+    }
+    let socket = context
+        .socket(zmq::DEALER)
+        .map_err(|e| format!("Failed to create ZMQ socket: {}", e))?;
+    let _ = socket.set_linger(0);
+    // connect() only parses the address; the monitor tells whether a peer
+    // actually took the connection
+    let events = zmq::SocketEvent::CONNECTED.to_raw() | zmq::SocketEvent::CONNECT_RETRIED.to_raw();
+    socket
+        .monitor("inproc://check-monitor", events as i32)
+        .map_err(|e| format!("Failed to monitor ZMQ socket: {}", e))?;
+    let monitor = context
+        .socket(zmq::PAIR)
+        .map_err(|e| format!("Failed to create ZMQ socket: {}", e))?;
+    monitor
+        .connect("inproc://check-monitor")
+        .map_err(|e| format!("Failed to monitor ZMQ socket: {}", e))?;
+    socket
+        .connect(endpoint)
+        .map_err(|e| format!("Invalid ZMQ endpoint {}: {}", endpoint, e))?;
+
+    let deadline = Instant::now() + ZMQ_CONNECT_TIMEOUT;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match monitor.poll(zmq::POLLIN, left.as_millis() as i64) {
+            Ok(0) => {
+                return Err(format!(
+                    "Nothing accepted a connection to ZMQ endpoint {} within {}s",
+                    endpoint,
+                    ZMQ_CONNECT_TIMEOUT.as_secs()
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => return Err(format!("Failed to monitor ZMQ socket: {}", e)),
+        }
+        let frames = monitor
+            .recv_multipart(0)
+            .map_err(|e| format!("Failed to monitor ZMQ socket: {}", e))?;
+        let event = match frames.first() {
+            Some(frame) if frame.len() >= 2 => u16::from_ne_bytes([frame[0], frame[1]]),
+            _ => continue,
+        };
+        match zmq::SocketEvent::from_raw(event) {
+            zmq::SocketEvent::CONNECTED => return Ok(()),
+            zmq::SocketEvent::CONNECT_RETRIED => {
+                return Err(format!("ZMQ endpoint {} refused the connection; is the producer running?", endpoint))
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dealer_check_needs_a_listener() {
+        let context = zmq::Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_linger(0).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = router.get_last_endpoint().unwrap().unwrap();
+
+        assert_eq!(check_zmq(&endpoint, SocketType::Dealer), Ok(()));
+        drop(router);
+        let err = check_zmq(&endpoint, SocketType::Dealer).unwrap_err();
+        assert!(err.contains("refused"), "{}", err);
+    }
+
+    #[test]
+    fn dealer_check_rejects_a_malformed_endpoint() {
+        let err = check_zmq("tcp://", SocketType::Dealer).unwrap_err();
+        assert!(err.starts_with("Invalid ZMQ endpoint"), "{}", err);
+    }
+}
@@ -0,0 +1,1109 @@
+//! Telegram bot commands.
+
+use crate::activity;
+use crate::build_info;
+use crate::config::TelegramSettings;
+use crate::events::BotEvent;
+use crate::history;
+use crate::html;
+use crate::i18n::Texts;
+use crate::mutes;
+use crate::routes;
+use crate::sender::{self, split_text, Delivery, TELEGRAM_MAX_MESSAGE_CHARS};
+use crate::shared_settings::{ListError, SharedSettings};
+use crate::state::BotState;
+use crate::traffic;
+use crate::zmq_listener::{MessageLimits, ZmqMessage};
+use chrono::{Duration, Local, Utc};
+use log::info;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, Chat, ChatKind, MessageOrigin, ParseMode, PublicChatKind};
+use teloxide::utils::command::BotCommands;
+
+/// Supported bot commands
+#[derive(BotCommands, Clone, Debug)]
+#[command(rename_rule = "lowercase", description = "These commands are supported:")]
+pub enum Command {
+    #[command(description = "Display this chat's ID, type and topic, or a replied-to forward's origin.")]
+    Id,
+    #[command(description = "Show the welcome message.")]
+    Start,
+    #[command(description = "Show this help text.")]
+    Help,
+    #[command(description = "Show the bot's version and build.")]
+    Version,
+    #[command(description = "Owner only: show quarantined chats and delivery stats.")]
+    Status,
+    #[command(description = "Owner only: resume deliveries to a quarantined chat id.")]
+    Unquarantine(i64),
+    #[command(description = "Pause broadcasts to this chat, optionally for a duration (30m, 12h, 7d, 2w).")]
+    Mute(String),
+    #[command(description = "Resume broadcasts to this chat.")]
+    Unmute(String),
+    #[command(description = "Owner only: send a digest list's buffered messages now.")]
+    Flush(String),
+    #[command(description = "Owner only: recent deliveries, optionally for one chat: /history [chat_id|all] [n].")]
+    History(String),
+    #[command(description = "Owner only: send a message as the bot: /send [--force] <chat_id> <text>, or /send <text> in reply to a forward.")]
+    Send(String),
+    #[command(description = "Owner only: show where routing rules send a message with this text: /routetest <text>.")]
+    RouteTest(String),
+    #[command(description = "Owner only: send the delivery report for the period so far: /report now.")]
+    Report(String),
+    #[command(description = "Owner only: recent deliveries, failures and ZMQ events: /tail [n] [filter].")]
+    Tail(String),
+    #[command(description = "Owner only: add a chat to a subscriber list: /listadd <list> <chat_id> [--create].")]
+    ListAdd(String),
+    #[command(description = "Owner only: remove a chat from a subscriber list: /listdel <list> <chat_id>.")]
+    ListDel(String),
+}
+
+impl Command {
+    /// The command's name, without the `/`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Id => "id",
+            Command::Start => "start",
+            Command::Help => "help",
+            Command::Version => "version",
+            Command::Status => "status",
+            Command::Unquarantine(_) => "unquarantine",
+            Command::Mute(_) => "mute",
+            Command::Unmute(_) => "unmute",
+            Command::Flush(_) => "flush",
+            Command::History(_) => "history",
+            Command::Send(_) => "send",
+            Command::RouteTest(_) => "routetest",
+            Command::Report(_) => "report",
+            Command::Tail(_) => "tail",
+            Command::ListAdd(_) => "listadd",
+            Command::ListDel(_) => "listdel",
+        }
+    }
+}
+
+/// Built-in commands that answer only in the owner chats
+pub const OWNER_COMMANDS: &[&str] = &["status", "unquarantine", "flush", "history", "send", "routetest", "report", "tail", "listadd", "listdel"];
+
+/// Entries shown by `/history` without a count, and the most it will show
+const HISTORY_DEFAULT_ENTRIES: usize = 10;
+const HISTORY_MAX_ENTRIES: usize = 100;
+
+/// Entries shown by `/tail` without a count
+const TAIL_DEFAULT_ENTRIES: usize = 20;
+
+/// Directly targeted chats listed by `/status`, most recent first
+const STATUS_MAX_CHATS: usize = 10;
+
+/// Handle incoming Telegram commands
+pub async fn handle(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+) -> ResponseResult<()> {
+    let settings = shared.snapshot();
+    let (display_name, username, user_id) = extract_user_info(&msg);
+    let is_owner = settings.is_owner(msg.chat.id.0);
+    let language = settings.language_for(msg.chat.id.0, msg.from.as_ref().and_then(|user| user.language_code.as_deref()));
+    let texts = settings.translations.texts(&language);
+    state.events.publish(BotEvent::CommandInvoked {
+        command: cmd.name().to_string(),
+        chat_id: msg.chat.id.0,
+        user_id: user_id.clone(),
+    });
+    let response = match &cmd {
+        Command::Id => {
+            let text = id_text(&msg);
+            bot.send_message(msg.chat.id, text.clone()).parse_mode(ParseMode::Html).await?;
+            format!("Id: {}", text.replace('\n', " | "))
+        }
+        Command::Start => {
+            let text = settings.welcome_text.clone().unwrap_or_else(|| texts.text("welcome", &[]));
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            format!("Start: {}", text)
+        }
+        Command::Help => {
+            let help_text = help_text(&settings, &texts);
+            bot.send_message(msg.chat.id, help_text.clone()).await?;
+            format!("Help: {}", help_text)
+        }
+        Command::Version => {
+            let text = build_info::summary();
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::Status
+        | Command::Unquarantine(_)
+        | Command::Flush(_)
+        | Command::History(_)
+        | Command::Send(_)
+        | Command::RouteTest(_)
+        | Command::Report(_)
+        | Command::Tail(_)
+        | Command::ListAdd(_)
+        | Command::ListDel(_)
+            if !is_owner =>
+        {
+            bot.send_message(msg.chat.id, texts.text("owner_only", &[])).await?;
+            "Refused: not owner".to_string()
+        }
+        Command::Status => {
+            let text = status_text(&settings, &state);
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            format!("Status: {}", text)
+        }
+        Command::Unquarantine(chat_id) => {
+            let text = if state.quarantine.release(*chat_id) {
+                format!("Chat {} released from quarantine.", chat_id)
+            } else {
+                format!("Chat {} is not quarantined.", chat_id)
+            };
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::Flush(list) => {
+            let text = flush_digest(&settings, &state, list.trim(), &texts);
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::History(args) => {
+            let text = match parse_history_args(args) {
+                Ok((chat_id, limit)) => match state.history.recent(chat_id, limit).await {
+                    Ok(entries) if entries.is_empty() => "No deliveries recorded.".to_string(),
+                    Ok(entries) => history::format_entries(&entries),
+                    Err(err) => err,
+                },
+                Err(err) => err,
+            };
+            for chunk in split_text(&text, TELEGRAM_MAX_MESSAGE_CHARS) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+            format!("History: {} line(s)", text.lines().count())
+        }
+        Command::Send(args) => {
+            let text = match forward_origin_chat(&msg).and_then(|origin| parse_send_args(args, origin)) {
+                Err(err) => err,
+                Ok(send) if !send.force && settings.lists_containing(send.chat_id).is_empty() => format!(
+                    "Chat {} is not on any subscriber list; use /send --force to send anyway.",
+                    send.chat_id
+                ),
+                Ok(send) => match send_message(&settings, &send) {
+                    Err(err) => err,
+                    Ok(message) => {
+                        info!("Owner {} sends to chat {} as the bot: {}", user_id, send.chat_id, send.text);
+                        let outcomes = sender::process_zmq_message(&state.sink(bot.clone()), &settings, &state, message).await;
+                        send_result_text(send.chat_id, &outcomes)
+                    }
+                },
+            };
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::RouteTest(text) => {
+            let text = route_test_text(&settings, text);
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::Report(args) if args.trim() != "now" => {
+            let text = "Usage: /report now".to_string();
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::Report(_) => {
+            let period = state.reports.current(Utc::now());
+            let failed = sender::send_report(&state.sink(bot.clone()), &settings, &state, &[msg.chat.id.0], "Report so far", period).await;
+            match failed.first() {
+                Some((_, category)) => format!("Report: failed ({})", category),
+                None => "Report: sent".to_string(),
+            }
+        }
+        Command::Tail(args) => {
+            let text = match parse_tail_args(args) {
+                Ok((limit, filter)) => match state.activity.recent(limit, filter) {
+                    entries if entries.is_empty() => "No recent activity.".to_string(),
+                    entries => activity::format_entries(&entries, Utc::now()),
+                },
+                Err(err) => err,
+            };
+            for chunk in split_text(&text, TELEGRAM_MAX_MESSAGE_CHARS) {
+                bot.send_message(msg.chat.id, chunk).await?;
+            }
+            format!("Tail: {} line(s)", text.lines().count())
+        }
+        Command::ListAdd(args) | Command::ListDel(args) => {
+            let adding = matches!(cmd, Command::ListAdd(_));
+            let text = match parse_list_args(args, adding) {
+                Err(err) => err,
+                Ok(change) => {
+                    let result = if adding {
+                        shared.add_list_chat(&change.list, change.chat_id, change.create)
+                    } else {
+                        shared.remove_list_chat(&change.list, change.chat_id)
+                    };
+                    match result {
+                        Ok(done) => {
+                            let text = done.describe(&change.list, change.chat_id);
+                            info!("Owner {}: {}", user_id, text);
+                            text
+                        }
+                        Err(ListError::UnknownList) if adding => {
+                            format!("{}; use /listadd --create to create it.", ListError::UnknownList.describe(&change.list))
+                        }
+                        Err(err) => err.describe(&change.list),
+                    }
+                }
+            };
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+        Command::Mute(args) | Command::Unmute(args) => {
+            let text = match parse_target(args, msg.chat.id.0, is_owner, &texts) {
+                Err(err) => err,
+                Ok((chat_id, _)) if !is_owner && settings.lists_containing(chat_id).is_empty() => {
+                    texts.text("not_subscribed", &[])
+                }
+                Ok((chat_id, duration)) => {
+                    let chat = chat_id.to_string();
+                    match cmd {
+                        Command::Mute(_) => {
+                            let until = duration.map(|d| Utc::now() + d);
+                            state.mutes.mute(chat_id, until);
+                            state.events.publish(BotEvent::SubscriberMuted { chat_id, until });
+                            match until {
+                                Some(until) => {
+                                    let until = until.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string();
+                                    texts.text("muted_until", &[("chat", &chat), ("until", &until)])
+                                }
+                                None => texts.text("muted", &[("chat", &chat)]),
+                            }
+                        }
+                        _ if state.mutes.unmute(chat_id) => {
+                            state.events.publish(BotEvent::SubscriberUnmuted { chat_id });
+                            texts.text("unmuted", &[("chat", &chat)])
+                        }
+                        _ => texts.text("not_muted", &[("chat", &chat)]),
+                    }
+                }
+            };
+            bot.send_message(msg.chat.id, text.clone()).await?;
+            text
+        }
+    };
+
+    info!(
+        "{} | {} (@{}) id={} invoked {:?}, responded with: {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        display_name,
+        username,
+        user_id,
+        cmd,
+        response
+    );
+
+    Ok(())
+}
+
+/// Names of the built-in commands, without the `/`
+pub fn builtin_names() -> Vec<String> {
+    Command::bot_commands()
+        .into_iter()
+        .map(|command| command.command.trim_start_matches('/').to_string())
+        .collect()
+}
+
+/// Description of built-in `command` in the language of `texts`
+pub fn describe(texts: &Texts, command: &BotCommand) -> String {
+    let name = command.command.trim_start_matches('/');
+    texts.lookup(&format!("command.{}", name)).unwrap_or(&command.description).to_string()
+}
+
+/// The built-in command descriptions followed by the configured commands
+pub fn help_text(settings: &TelegramSettings, texts: &Texts) -> String {
+    let descriptions = Command::descriptions().to_string();
+    let header = texts.lookup("help_header").unwrap_or_else(|| descriptions.split("\n\n").next().unwrap_or_default());
+    let mut text = format!("{}\n", header);
+    for command in Command::bot_commands() {
+        text.push_str(&format!("\n{} — {}", command.command, describe(texts, &command)));
+    }
+    for (name, command) in &settings.commands {
+        text.push_str(&format!("\n/{} — {}", name, command.description));
+    }
+    text
+}
+
+/// Owner-facing summary of the bot's health, quarantined chats and delivery counters
+fn status_text(settings: &TelegramSettings, state: &BotState) -> String {
+    let mut lines = Vec::new();
+    let quarantined = state.quarantine.chats();
+    if quarantined.is_empty() {
+        lines.push("Quarantined chats: none".to_string());
+    } else {
+        lines.push("Quarantined chats:".to_string());
+        for chat_id in quarantined {
+            let lists = settings.lists_containing(chat_id);
+            if lists.is_empty() {
+                lines.push(format!("  {}", chat_id));
+            } else {
+                lines.push(format!("  {} (lists: {})", chat_id, lists.join(", ")));
+            }
+        }
+    }
+    let muted = state.mutes.active(Utc::now());
+    if muted.is_empty() {
+        lines.push("Muted chats: none".to_string());
+    } else {
+        lines.push("Muted chats:".to_string());
+        for (chat_id, until) in muted {
+            match until {
+                Some(until) => lines.push(format!(
+                    "  {} until {}",
+                    chat_id,
+                    until.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+                )),
+                None => lines.push(format!("  {}", chat_id)),
+            }
+        }
+    }
+    let health = state.health.snapshot();
+    let since = health.zmq_since.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+    match &health.zmq_down_reason {
+        None => lines.push(format!("ZMQ: up since {}", since)),
+        Some(reason) => lines.push(format!("ZMQ: down since {} ({})", since, reason)),
+    }
+    match (health.last_sent_at, health.last_sent_chat) {
+        (Some(at), Some(chat)) => {
+            lines.push(format!("Last delivery: {} to {}", at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), chat))
+        }
+        _ => lines.push("Last delivery: none".to_string()),
+    }
+    if let Some(failure) = &health.last_failure {
+        lines.push(format!(
+            "Last failure: {} to {} ({}); {} in a row",
+            failure.at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            failure.chat,
+            failure.category,
+            health.consecutive_failures
+        ));
+    }
+    lines.push(format!("Event queue: {}", health.queue_depth));
+    let now = Utc::now();
+    let mut lists: Vec<_> = state.traffic.lists().into_iter().map(|(list, at)| (list, Some(at))).collect();
+    for (list, _) in settings.expected_traffic() {
+        if !lists.iter().any(|(name, _)| *name == list) {
+            lists.push((list, None));
+        }
+    }
+    let lists: Vec<String> = lists
+        .iter()
+        .map(|(list, at)| {
+            let age = at.map_or("never".to_string(), |at| format!("{} ago", traffic::format_age(now - at)));
+            let warning = if state.traffic.is_quiet(list) { " ⚠" } else { "" };
+            format!("{}: {}{}", list, age, warning)
+        })
+        .collect();
+    if !lists.is_empty() {
+        lines.push(format!("Last delivery per list: {}", lists.join(", ")));
+    }
+    let chats: Vec<String> = state
+        .traffic
+        .chats()
+        .into_iter()
+        .take(STATUS_MAX_CHATS)
+        .map(|(chat, at)| format!("{}: {} ago", chat, traffic::format_age(now - at)))
+        .collect();
+    if !chats.is_empty() {
+        lines.push(format!("Last direct delivery: {}", chats.join(", ")));
+    }
+    let now = tokio::time::Instant::now().into_std();
+    match state.flood.open_until(now) {
+        Some(until) => lines.push(format!("Flood breaker: open, sending resumes in {}s", until.duration_since(now).as_secs())),
+        None => lines.push("Flood breaker: closed".to_string()),
+    }
+    let parked: Vec<String> = state
+        .flood
+        .parked(now)
+        .into_iter()
+        .map(|(chat, left)| format!("{} ({}s left)", chat, left.as_secs().max(1)))
+        .collect();
+    if !parked.is_empty() {
+        lines.push(format!("Flood-waited chats: {}; held messages: {}", parked.join(", "), state.outbox.held()));
+    }
+    if state.spool.is_active() {
+        lines.push(format!("Spooled while the token is refused: {}", state.spool.len()));
+    }
+    let (high, normal) = state.outbox.depths();
+    lines.push(format!("Outbox: high={}, normal={}", high, normal));
+    for (list, entries) in state.digests.pending() {
+        lines.push(format!("Digest '{}': {} buffered", list, entries));
+    }
+    let snapshot = state.stats.snapshot();
+    lines.push(format!("Delivered: {}", snapshot.delivered));
+    lines.push(format!("Dropped events: {}", snapshot.dropped_events));
+    if snapshot.file_id_hits > 0 {
+        lines.push(format!(
+            "Images sent by file ID: {} ({} bytes not uploaded)",
+            snapshot.file_id_hits, snapshot.upload_bytes_saved
+        ));
+    }
+    if snapshot.flood_pauses > 0 {
+        lines.push(format!("Flood limit pauses: {}", snapshot.flood_pauses));
+    }
+    if snapshot.dispatcher_restarts > 0 {
+        lines.push(format!("Dispatcher restarts: {}", snapshot.dispatcher_restarts));
+    }
+    if snapshot.duplicates_suppressed > 0 {
+        lines.push(format!("Duplicates suppressed: {}", snapshot.duplicates_suppressed));
+    }
+    if snapshot.rejected_signatures > 0 {
+        lines.push(format!("Rejected unsigned or badly signed payloads: {}", snapshot.rejected_signatures));
+    }
+    if snapshot.invalid_messages > 0 {
+        lines.push(format!("Rejected invalid messages: {}", snapshot.invalid_messages));
+    }
+    if snapshot.dropped_publications > 0 {
+        lines.push(format!("Dropped published events: {}", snapshot.dropped_publications));
+    }
+    if snapshot.failures.is_empty() {
+        lines.push("Failed send attempts: none".to_string());
+    } else {
+        let failures: Vec<String> = snapshot
+            .failures
+            .iter()
+            .map(|(category, count)| format!("{}={}", category, count))
+            .collect();
+        lines.push(format!("Failed send attempts: {}", failures.join(", ")));
+    }
+    lines.join("\n")
+}
+
+/// `/id` reply in HTML: the bare chat ID first, then whatever else helps
+/// address this chat or the origin of a forwarded message replied to
+fn id_text(msg: &Message) -> String {
+    let mut lines = vec![format!("<code>{}</code>", msg.chat.id)];
+    lines.push(format!("Type: {}", chat_type(&msg.chat)));
+    if let (true, Some(title)) = (msg.chat.is_channel(), msg.chat.title()) {
+        lines.push(format!("Title: {}", html::escape(title)));
+    }
+    if let (true, Some(thread)) = (msg.is_topic_message, msg.thread_id) {
+        lines.push(format!("Topic: <code>{}</code>", thread.0 .0));
+    }
+    match msg.reply_to_message().and_then(|reply| reply.forward_origin()) {
+        Some(MessageOrigin::Channel { chat, message_id, .. }) => lines.push(format!(
+            "Forwarded from channel: <code>{}</code>, message <code>{}</code>",
+            chat.id, message_id.0
+        )),
+        Some(MessageOrigin::Chat { sender_chat, .. }) => {
+            lines.push(format!("Forwarded from chat: <code>{}</code>", sender_chat.id))
+        }
+        Some(MessageOrigin::User { sender_user, .. }) => {
+            lines.push(format!("Forwarded from user: <code>{}</code>", sender_user.id))
+        }
+        Some(MessageOrigin::HiddenUser { .. }) => lines.push("Forwarded from a hidden user".to_string()),
+        None => {}
+    }
+    lines.join("\n")
+}
+
+fn chat_type(chat: &Chat) -> &'static str {
+    match &chat.kind {
+        ChatKind::Private(_) => "private",
+        ChatKind::Public(public) => match &public.kind {
+            PublicChatKind::Group => "group",
+            PublicChatKind::Supergroup(group) if group.is_forum => "supergroup (forum)",
+            PublicChatKind::Supergroup(_) => "supergroup",
+            PublicChatKind::Channel(_) => "channel",
+        },
+    }
+}
+
+/// Queue the buffered digest for `list` and describe what happened
+fn flush_digest(settings: &TelegramSettings, state: &BotState, list: &str, texts: &Texts) -> String {
+    match settings.subscriber_lists.get(list) {
+        _ if list.is_empty() => "Usage: /flush <list>".to_string(),
+        None => texts.text("unknown_list", &[("list", list)]),
+        Some(config) if config.digest_interval.is_none() => format!("List '{}' is not in digest mode.", list),
+        Some(_) => match state.digests.take(list) {
+            Some(digest) => {
+                let text = format!("Sending digest for '{}' now.", list);
+                state.push_digest(digest);
+                text
+            }
+            None => format!("Nothing buffered for '{}'.", list),
+        },
+    }
+}
+
+/// Parse `/mute` and `/unmute` arguments: `[chat_id] [duration]`.
+/// Only the owner may name a chat other than their own.
+fn parse_target(args: &str, own_chat: i64, is_owner: bool, texts: &Texts) -> Result<(i64, Option<Duration>), String> {
+    let mut chat_id = own_chat;
+    let mut duration = None;
+    for arg in args.split_whitespace() {
+        if let Ok(id) = arg.parse::<i64>() {
+            if !is_owner {
+                return Err(texts.text("owner_only", &[]));
+            }
+            chat_id = id;
+        } else if let Some(d) = mutes::parse_duration(arg) {
+            duration = Some(d);
+        } else {
+            return Err(texts.text("bad_duration", &[("arg", arg)]));
+        }
+    }
+    Ok((chat_id, duration))
+}
+
+/// Parse `/history [chat_id|all] [n]` into a chat filter and an entry count
+fn parse_history_args(args: &str) -> Result<(Option<i64>, usize), String> {
+    const USAGE: &str = "Usage: /history [chat_id|all] [n], e.g. /history 123456789 20";
+    let mut parts = args.split_whitespace();
+    let chat_id = match parts.next() {
+        None | Some("all") => None,
+        Some(arg) => Some(arg.parse::<i64>().map_err(|_| USAGE.to_string())?),
+    };
+    let limit = match parts.next() {
+        None => HISTORY_DEFAULT_ENTRIES,
+        Some(arg) => arg.parse::<usize>().ok().filter(|n| *n > 0).ok_or(USAGE)?,
+    };
+    if parts.next().is_some() {
+        return Err(USAGE.to_string());
+    }
+    Ok((chat_id, limit.min(HISTORY_MAX_ENTRIES)))
+}
+
+/// Parse `/tail [n] [filter]`: the number of entries, at most the whole
+/// buffer, and the text they must contain
+fn parse_tail_args(args: &str) -> Result<(usize, Option<&str>), String> {
+    const USAGE: &str = "Usage: /tail [n] [filter], e.g. /tail 50 timeout";
+    let args = args.trim();
+    let (first, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let (limit, filter) = match first.parse::<usize>() {
+        Ok(0) => return Err(USAGE.to_string()),
+        Ok(n) => (n, rest.trim()),
+        Err(_) => (TAIL_DEFAULT_ENTRIES, args),
+    };
+    Ok((limit.min(activity::CAPACITY), (!filter.is_empty()).then_some(filter)))
+}
+
+/// A parsed `/listadd` or `/listdel`
+#[derive(Debug, PartialEq, Eq)]
+struct ListArgs {
+    list: String,
+    chat_id: i64,
+    /// Create the list if it does not exist
+    create: bool,
+}
+
+/// Parse `/listadd <list> <chat_id> [--create]`, or `/listdel <list>
+/// <chat_id>` when `adding` is false
+fn parse_list_args(args: &str, adding: bool) -> Result<ListArgs, String> {
+    let usage = || {
+        if adding {
+            "Usage: /listadd <list> <chat_id> [--create], e.g. /listadd ops -100123".to_string()
+        } else {
+            "Usage: /listdel <list> <chat_id>, e.g. /listdel ops -100123".to_string()
+        }
+    };
+    let mut create = false;
+    let mut words = Vec::new();
+    for arg in args.split_whitespace() {
+        match arg {
+            "--create" if adding => create = true,
+            _ => words.push(arg),
+        }
+    }
+    let [list, chat_id] = words[..] else {
+        return Err(usage());
+    };
+    let chat_id = chat_id.parse::<i64>().map_err(|_| usage())?;
+    Ok(ListArgs { list: list.to_string(), chat_id, create })
+}
+
+/// A parsed `/send`
+#[derive(Debug, PartialEq, Eq)]
+struct SendArgs {
+    chat_id: i64,
+    text: String,
+    /// Send even to a chat outside every subscriber list
+    force: bool,
+}
+
+/// Parse `/send [--force] <chat_id> <text>`. In reply to a forward whose
+/// origin is `origin`, all of the arguments are the text.
+fn parse_send_args(args: &str, origin: Option<i64>) -> Result<SendArgs, String> {
+    const USAGE: &str = "Usage: /send [--force] <chat_id> <text>, or /send <text> in reply to a forwarded message";
+    let args = args.trim_start();
+    let (force, args) = match args.strip_prefix("--force") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => (true, rest.trim_start()),
+        _ => (false, args),
+    };
+    let (chat_id, text) = match origin {
+        Some(chat_id) => (chat_id, args),
+        None => {
+            let (id, text) = args.split_once(char::is_whitespace).ok_or(USAGE)?;
+            (id.parse::<i64>().map_err(|_| USAGE.to_string())?, text.trim_start())
+        }
+    };
+    match text.trim().is_empty() {
+        true => Err(USAGE.to_string()),
+        false => Ok(SendArgs { chat_id, text: text.to_string(), force }),
+    }
+}
+
+/// The message `/send` delivers, held to the same limits as one from a
+/// producer
+fn send_message(settings: &TelegramSettings, send: &SendArgs) -> Result<ZmqMessage, String> {
+    let message = ZmqMessage { chat_id: Some(send.chat_id), text: send.text.clone(), ..Default::default() };
+    match message.validate(&MessageLimits::from_settings(settings)) {
+        Ok(()) => Ok(message),
+        Err(err) => Err(format!("Not sent: {}.", err)),
+    }
+}
+
+/// The chat a replied-to forward came from, if the command replies to one.
+/// Fails for forwards whose sender hid their account.
+fn forward_origin_chat(msg: &Message) -> Result<Option<i64>, String> {
+    match msg.reply_to_message().and_then(|reply| reply.forward_origin()) {
+        Some(MessageOrigin::Channel { chat, .. }) => Ok(Some(chat.id.0)),
+        Some(MessageOrigin::Chat { sender_chat, .. }) => Ok(Some(sender_chat.id.0)),
+        Some(MessageOrigin::User { sender_user, .. }) => Ok(Some(sender_user.id.0 as i64)),
+        Some(MessageOrigin::HiddenUser { .. }) => {
+            Err("The forward's sender is hidden; use /send <chat_id> <text> instead.".to_string())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Owner reply describing how a `/send` to `chat_id` went
+fn send_result_text(chat_id: i64, outcomes: &[(i64, Delivery)]) -> String {
+    match outcomes.iter().find(|(chat, _)| *chat == chat_id).map(|(_, delivery)| delivery) {
+        Some(Ok(ids)) if !ids.is_empty() => {
+            let ids: Vec<String> = ids.iter().map(|id| id.0.to_string()).collect();
+            match ids.len() {
+                1 => format!("Sent to {} as message {}.", chat_id, ids[0]),
+                _ => format!("Sent to {} as messages {}.", chat_id, ids.join(", ")),
+            }
+        }
+        Some(Err(category)) => format!("Failed to send to {}: {}.", chat_id, category),
+        _ => format!("Nothing sent to {}: the chat is muted or quarantined, or sending is on hold.", chat_id),
+    }
+}
+
+/// Owner reply naming the routing rule a message with `text` and no
+/// target would match
+fn route_test_text(settings: &TelegramSettings, text: &str) -> String {
+    if text.trim().is_empty() {
+        return "Usage: /routetest <text>".to_string();
+    }
+    match routes::find(&settings.routes, text, None) {
+        Some((index, route)) => match route.target() {
+            Some(target) => format!("Matches {}: {}.", route.label(index), target),
+            None => format!("Matches {}, which has no valid target.", route.label(index)),
+        },
+        None if settings.routes.is_empty() => "No routes are configured; it would go to the owners.".to_string(),
+        None => format!("Matches none of the {} routes; it would go to the owners.", settings.routes.len()),
+    }
+}
+
+/// Extract the sender's display name, username, and ID from a Message. A
+/// message sent as a chat (a channel post, an anonymous group admin, or a
+/// post on behalf of a channel) is from that chat, not from `from`.
+fn extract_user_info(msg: &Message) -> (String, String, String) {
+    if let Some(chat) = &msg.sender_chat {
+        let name = chat.title().or(chat.first_name()).unwrap_or("unknown").to_string();
+        let uname = chat.username().unwrap_or("unknown").to_string();
+        (name, uname, chat.id.to_string())
+    } else if let Some(user) = &msg.from {
+        let name = user.first_name.clone();
+        let uname = user.username.clone().unwrap_or_else(|| "unknown".into());
+        let uid = user.id.to_string();
+        (name, uname, uid)
+    } else {
+        ("unknown".into(), "unknown".into(), "unknown".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn english() -> Texts<'static> {
+        static ENGLISH: std::sync::OnceLock<crate::i18n::Translations> = std::sync::OnceLock::new();
+        ENGLISH.get_or_init(Default::default).texts("en")
+    }
+
+    fn message(from: Option<serde_json::Value>) -> Message {
+        let mut json = serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+            "text": "/id"
+        });
+        if let Some(from) = from {
+            json["from"] = from;
+        }
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn user_info_from_sender() {
+        let msg = message(Some(serde_json::json!({
+            "id": 7, "is_bot": false, "first_name": "Ann", "username": "ann"
+        })));
+        assert_eq!(
+            extract_user_info(&msg),
+            ("Ann".to_string(), "ann".to_string(), "7".to_string())
+        );
+    }
+
+    #[test]
+    fn user_info_without_username() {
+        let msg = message(Some(serde_json::json!({
+            "id": 7, "is_bot": false, "first_name": "Ann"
+        })));
+        assert_eq!(extract_user_info(&msg).1, "unknown");
+    }
+
+    #[test]
+    fn user_info_without_sender() {
+        let msg = message(None);
+        assert_eq!(
+            extract_user_info(&msg),
+            ("unknown".to_string(), "unknown".to_string(), "unknown".to_string())
+        );
+    }
+
+    #[test]
+    fn status_lists_quarantined_chats_with_lists() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\nquarantine_after = 1\n\
+             [telegram.subscriber_lists]\nfamily = [5]\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        assert!(status_text(&settings, &state).starts_with("Quarantined chats: none"));
+        state.quarantine.record(5, Err(crate::errors::ErrorCategory::Blocked));
+        assert!(status_text(&settings, &state).contains("  5 (lists: family)"));
+    }
+
+    #[test]
+    fn status_reports_health() {
+        let settings = toml::from_str::<crate::config::AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n")
+            .unwrap()
+            .telegram;
+        let state = BotState::in_memory(&settings);
+        let text = status_text(&settings, &state);
+        assert!(text.contains("ZMQ: up since ") && text.contains("Last delivery: none\nEvent queue: 0"));
+
+        let now = Utc::now();
+        let down = crate::health::Transition::ZmqDisconnected { reason: "nothing received for 60s".to_string() };
+        state.health.apply(down, now);
+        state.health.apply(crate::health::Transition::SendFailed { chat: 5, category: crate::errors::ErrorCategory::Blocked }, now);
+        let text = status_text(&settings, &state);
+        assert!(text.contains("(nothing received for 60s)"));
+        assert!(text.contains(" to 5 (blocked); 1 in a row"));
+    }
+
+    #[test]
+    fn status_shows_last_delivery_per_list() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [2]\nheartbeat = { chats = [3], expect_message_every = \"2h\" }\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        assert!(!status_text(&settings, &state).contains("Last direct delivery"));
+        state.traffic.record(Some("ops"), Some(7), Utc::now() - Duration::minutes(4));
+        state.traffic.check(&settings.expected_traffic(), Utc::now() + Duration::hours(2));
+        let text = status_text(&settings, &state);
+        assert!(text.contains("Last delivery per list: ops: 4m ago, heartbeat: never ⚠"));
+        assert!(text.contains("Last direct delivery: 7: 4m ago"));
+    }
+
+    #[test]
+    fn channel_posts_are_from_the_channel() {
+        let channel = serde_json::json!({ "id": -1005, "type": "channel", "title": "Alerts & Co", "username": "alerts" });
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 3, "date": 0, "chat": channel, "sender_chat": channel, "text": "/id"
+        }))
+        .unwrap();
+        assert_eq!(
+            extract_user_info(&msg),
+            ("Alerts & Co".to_string(), "alerts".to_string(), "-1005".to_string())
+        );
+        assert_eq!(id_text(&msg), "<code>-1005</code>\nType: channel\nTitle: Alerts &amp; Co");
+    }
+
+    #[test]
+    fn anonymous_admins_are_their_group() {
+        let group = serde_json::json!({ "id": -1006, "type": "supergroup", "title": "Ops" });
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 4, "date": 0, "chat": group, "sender_chat": group, "text": "/id",
+            "from": { "id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot" }
+        }))
+        .unwrap();
+        assert_eq!(
+            extract_user_info(&msg),
+            ("Ops".to_string(), "unknown".to_string(), "-1006".to_string())
+        );
+        assert_eq!(id_text(&msg), "<code>-1006</code>\nType: supergroup");
+    }
+
+    #[test]
+    fn id_in_private_chat_starts_with_bare_id() {
+        assert_eq!(id_text(&message(None)), "<code>100</code>\nType: private");
+    }
+
+    #[test]
+    fn id_in_forum_topic_includes_thread() {
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 5,
+            "message_thread_id": 42,
+            "is_topic_message": true,
+            "date": 0,
+            "chat": { "id": -1001234, "type": "supergroup", "title": "Ops", "is_forum": true },
+            "text": "/id"
+        }))
+        .unwrap();
+        assert_eq!(id_text(&msg), "<code>-1001234</code>\nType: supergroup (forum)\nTopic: <code>42</code>");
+    }
+
+    #[test]
+    fn id_reply_to_channel_forward_shows_origin() {
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 6,
+            "date": 0,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+            "text": "/id",
+            "reply_to_message": {
+                "message_id": 4,
+                "date": 0,
+                "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+                "text": "news",
+                "forward_origin": {
+                    "type": "channel",
+                    "date": 0,
+                    "chat": { "id": -1009876, "type": "channel", "title": "News" },
+                    "message_id": 77
+                }
+            }
+        }))
+        .unwrap();
+        assert!(id_text(&msg).ends_with("\nForwarded from channel: <code>-1009876</code>, message <code>77</code>"));
+    }
+
+    #[test]
+    fn mute_args_default_to_own_chat() {
+        assert_eq!(parse_target("", 5, false, &english()), Ok((5, None)));
+        assert_eq!(parse_target("12h", 5, false, &english()), Ok((5, Some(Duration::hours(12)))));
+        assert!(parse_target("soon", 5, false, &english()).is_err());
+    }
+
+    #[test]
+    fn only_owner_names_other_chats() {
+        assert_eq!(parse_target("7 2w", 1, true, &english()), Ok((7, Some(Duration::weeks(2)))));
+        assert_eq!(parse_target("7", 5, false, &english()), Err("This command is only available to the bot owner.".to_string()));
+    }
+
+    #[test]
+    fn history_args_pick_chat_and_count() {
+        assert_eq!(parse_history_args(""), Ok((None, HISTORY_DEFAULT_ENTRIES)));
+        assert_eq!(parse_history_args("-100123"), Ok((Some(-100123), HISTORY_DEFAULT_ENTRIES)));
+        assert_eq!(parse_history_args("all 5"), Ok((None, 5)));
+        assert_eq!(parse_history_args("7 1000"), Ok((Some(7), HISTORY_MAX_ENTRIES)));
+        assert!(parse_history_args("ops").is_err());
+        assert!(parse_history_args("7 0").is_err());
+        assert!(parse_history_args("7 5 extra").is_err());
+    }
+
+    #[test]
+    fn tail_args() {
+        assert_eq!(parse_tail_args(""), Ok((TAIL_DEFAULT_ENTRIES, None)));
+        assert_eq!(parse_tail_args("50"), Ok((50, None)));
+        assert_eq!(parse_tail_args("5 disk full"), Ok((5, Some("disk full"))));
+        assert_eq!(parse_tail_args(" timeout "), Ok((TAIL_DEFAULT_ENTRIES, Some("timeout"))));
+        assert_eq!(parse_tail_args("100000"), Ok((activity::CAPACITY, None)));
+        assert!(parse_tail_args("0").is_err());
+    }
+
+    #[test]
+    fn list_args() {
+        let args = |list: &str, chat_id, create| ListArgs { list: list.to_string(), chat_id, create };
+        assert_eq!(parse_list_args("ops -100123", true), Ok(args("ops", -100123, false)));
+        assert_eq!(parse_list_args(" --create new 5 ", true), Ok(args("new", 5, true)));
+        assert_eq!(parse_list_args("new 5 --create", true), Ok(args("new", 5, true)));
+        assert_eq!(parse_list_args("ops 5", false), Ok(args("ops", 5, false)));
+        assert!(parse_list_args("ops 5 --create", false).unwrap_err().starts_with("Usage: /listdel"));
+        assert!(parse_list_args("ops", true).unwrap_err().starts_with("Usage: /listadd"));
+        assert!(parse_list_args("ops abc", true).is_err());
+        assert!(parse_list_args("ops 5 6", true).is_err());
+    }
+
+    #[test]
+    fn send_args_name_a_chat_or_use_the_forward() {
+        let args = |chat_id, text: &str, force| Ok(SendArgs { chat_id, text: text.to_string(), force });
+        assert_eq!(parse_send_args("-100123 Sorry for the noise", None), args(-100123, "Sorry for the noise", false));
+        assert_eq!(parse_send_args("--force 7  two  spaces", None), args(7, "two  spaces", true));
+        assert!(parse_send_args("7", None).is_err());
+        assert!(parse_send_args("ops hello", None).is_err());
+        assert!(parse_send_args("--forced 7 hi", None).is_err());
+        // Replying to a forward, a leading number is part of the text
+        assert_eq!(parse_send_args("5 minutes late, sorry", Some(-100)), args(-100, "5 minutes late, sorry", false));
+        assert_eq!(parse_send_args("--force hi", Some(-100)), args(-100, "hi", true));
+        assert!(parse_send_args("--force ", Some(-100)).is_err());
+    }
+
+    #[test]
+    fn send_is_held_to_the_message_limits() {
+        let mut settings = toml::from_str::<crate::config::AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n")
+            .unwrap()
+            .telegram;
+        settings.max_text_bytes = 5;
+        let send = |text: &str| send_message(&settings, &SendArgs { chat_id: 7, text: text.to_string(), force: false });
+        assert_eq!(send("hello").map(|message| message.chat_id), Ok(Some(7)));
+        assert_eq!(send("hello!").map(|message| message.text), Err("Not sent: Text is 6 bytes, over max_text_bytes (5).".to_string()));
+    }
+
+    #[test]
+    fn send_targets_the_forward_origin() {
+        let reply = |origin: serde_json::Value| -> Message {
+            serde_json::from_value(serde_json::json!({
+                "message_id": 6,
+                "date": 0,
+                "chat": { "id": 1, "type": "private", "first_name": "Ann" },
+                "text": "/send hi",
+                "reply_to_message": {
+                    "message_id": 4,
+                    "date": 0,
+                    "chat": { "id": 1, "type": "private", "first_name": "Ann" },
+                    "text": "alert",
+                    "forward_origin": origin
+                }
+            }))
+            .unwrap()
+        };
+        let user = reply(serde_json::json!({
+            "type": "user", "date": 0, "sender_user": { "id": 42, "is_bot": false, "first_name": "Bob" }
+        }));
+        assert_eq!(forward_origin_chat(&user), Ok(Some(42)));
+        let hidden = reply(serde_json::json!({ "type": "hidden_user", "date": 0, "sender_user_name": "Eve" }));
+        assert!(forward_origin_chat(&hidden).is_err());
+        assert_eq!(forward_origin_chat(&message(None)), Ok(None));
+    }
+
+    #[test]
+    fn send_result_reports_the_delivery() {
+        use teloxide::types::MessageId;
+        use crate::errors::ErrorCategory;
+        assert_eq!(send_result_text(5, &[(5, Ok(vec![MessageId(31)]))]), "Sent to 5 as message 31.");
+        assert_eq!(send_result_text(5, &[(5, Ok(vec![MessageId(31), MessageId(32)]))]), "Sent to 5 as messages 31, 32.");
+        assert_eq!(
+            send_result_text(5, &[(5, Err(ErrorCategory::ChatNotFound))]),
+            format!("Failed to send to 5: {}.", ErrorCategory::ChatNotFound)
+        );
+        assert!(send_result_text(5, &[]).starts_with("Nothing sent to 5"));
+    }
+
+    #[test]
+    fn mute_commands_accept_missing_args() {
+        assert!(matches!(Command::parse("/mute", "bot"), Ok(Command::Mute(a)) if a.is_empty()));
+        assert!(matches!(Command::parse("/unmute 7", "bot"), Ok(Command::Unmute(a)) if a == "7"));
+    }
+
+    #[test]
+    fn route_test_names_the_matching_rule() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [2]\n\
+             [[telegram.routes]]\nname = \"disk\"\nstarts_with = \"[DISK]\"\nlist = \"ops\"\n\
+             [[telegram.routes]]\nglob = \"*debug*\"\ndrop = true\n",
+        )
+        .unwrap()
+        .telegram;
+        assert!(settings.validate().is_empty());
+        assert_eq!(route_test_text(&settings, "[DISK] /var 91%"), "Matches route 1 ('disk'): list 'ops'.");
+        assert_eq!(route_test_text(&settings, "a debug line"), "Matches route 2: dropped.");
+        assert_eq!(route_test_text(&settings, "hello"), "Matches none of the 2 routes; it would go to the owners.");
+        assert_eq!(route_test_text(&settings, " "), "Usage: /routetest <text>");
+        assert!(matches!(Command::parse("/routetest [DISK] x", "bot"), Ok(Command::RouteTest(t)) if t == "[DISK] x"));
+    }
+
+    #[test]
+    fn flush_sends_buffered_digest() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [2]\nmetrics = { chats = [3], digest_interval = \"30m\" }\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        assert_eq!(flush_digest(&settings, &state, "nope", &english()), "Unknown subscriber list 'nope'.");
+        assert_eq!(flush_digest(&settings, &state, "ops", &english()), "List 'ops' is not in digest mode.");
+        assert_eq!(flush_digest(&settings, &state, "metrics", &english()), "Nothing buffered for 'metrics'.");
+        let message = serde_json::from_value(serde_json::json!({ "text": "x", "subscriber_list": "metrics" })).unwrap();
+        state.digests.add("metrics", Duration::minutes(30), message, Utc::now());
+        assert!(status_text(&settings, &state).contains("Digest 'metrics': 1 buffered"));
+        assert_eq!(flush_digest(&settings, &state, "metrics", &english()), "Sending digest for 'metrics' now.");
+        assert_eq!(state.outbox.depths(), (0, 1));
+    }
+
+    #[test]
+    fn status_lists_muted_chats() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        assert!(status_text(&settings, &state).contains("Muted chats: none"));
+        state.mutes.mute(5, None);
+        assert!(status_text(&settings, &state).contains("Muted chats:\n  5"));
+    }
+
+    #[test]
+    fn english_help_matches_the_command_descriptions() {
+        let settings = toml::from_str::<crate::config::AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n")
+            .unwrap()
+            .telegram;
+        assert_eq!(help_text(&settings, &english()), Command::descriptions().to_string());
+    }
+
+    #[test]
+    fn help_and_mute_replies_are_translated() {
+        let mut settings = toml::from_str::<crate::config::AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n")
+            .unwrap()
+            .telegram;
+        settings.translations = Arc::new(
+            crate::i18n::Translations::from_toml(
+                "[de]\nhelp_header = \"Unterstützte Befehle:\"\n\"command.help\" = \"Diese Hilfe anzeigen.\"\n\
+                 bad_duration = \"Unbekanntes Argument '{arg}'.\"\n",
+            )
+            .unwrap(),
+        );
+        let texts = settings.translations.texts("de");
+        let help = help_text(&settings, &texts);
+        assert!(help.starts_with("Unterstützte Befehle:\n\n/id — Display"));
+        assert!(help.contains("\n/help — Diese Hilfe anzeigen."));
+        assert_eq!(parse_target("bald", 5, false, &texts), Err("Unbekanntes Argument 'bald'.".to_string()));
+        assert_eq!(parse_target("7", 5, false, &texts), Err("This command is only available to the bot owner.".to_string()));
+    }
+
+    #[test]
+    fn help_lists_configured_commands_after_builtins() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.commands.lights_off]\ndescription = \"Turn the lights off\"\ndestination = \"home\"\n",
+        )
+        .unwrap()
+        .telegram;
+        let text = help_text(&settings, &english());
+        assert!(text.contains("\n/help — Show this help text."));
+        assert!(text.ends_with("\n/lights_off — Turn the lights off"));
+        assert!(builtin_names().contains(&"id".to_string()));
+    }
+}
@@ -0,0 +1,213 @@
+//! Compressed ZMQ payloads.
+//!
+//! Some producers compress their JSON before sending it. With
+//! `zmq_accept_compressed` a payload starting with the gzip or zstd magic
+//! bytes is inflated, up to `zmq_max_inflated_bytes`, and then parsed as
+//! usual. Only the first zstd frame of a payload is read.
+
+use miniz_oxide::inflate::{self, TINFLStatus};
+use ruzstd::decoding::StreamingDecoder;
+use std::fmt;
+use std::io::Read;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+/// Fixed part of a gzip header and the CRC32 and size trailer
+const GZIP_HEADER: usize = 10;
+const GZIP_TRAILER: usize = 8;
+
+/// A compression format recognised by its magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Gzip => "gzip",
+            Format::Zstd => "zstd",
+        })
+    }
+}
+
+/// Why a compressed payload could not be inflated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InflateError {
+    /// Inflating would go past the limit
+    TooLarge { max: usize },
+    /// The stream is cut short or damaged
+    Corrupt(String),
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InflateError::TooLarge { max } => write!(f, "Payload inflates to more than zmq_max_inflated_bytes ({})", max),
+            InflateError::Corrupt(reason) => write!(f, "Compressed payload is damaged: {}", reason),
+        }
+    }
+}
+
+/// The compression `payload` starts with, if any
+pub fn detect(payload: &[u8]) -> Option<Format> {
+    if payload.starts_with(&GZIP_MAGIC) {
+        Some(Format::Gzip)
+    } else if payload.starts_with(&ZSTD_MAGIC) {
+        Some(Format::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Inflate `payload`, compressed as `format`, to at most `max` bytes
+pub fn inflate(payload: &[u8], format: Format, max: usize) -> Result<Vec<u8>, InflateError> {
+    match format {
+        Format::Gzip => gunzip(payload, max),
+        Format::Zstd => unzstd(payload, max),
+    }
+}
+
+/// The content of the first frame of a zstd stream (RFC 8878)
+fn unzstd(payload: &[u8], max: usize) -> Result<Vec<u8>, InflateError> {
+    let corrupt = |reason: String| InflateError::Corrupt(reason.to_lowercase());
+    let mut decoder = StreamingDecoder::new(payload).map_err(|err| corrupt(err.to_string()))?;
+    let mut content = Vec::new();
+    (&mut decoder).take(max as u64 + 1).read_to_end(&mut content).map_err(|err| corrupt(err.to_string()))?;
+    if content.len() > max {
+        return Err(InflateError::TooLarge { max });
+    }
+    let frame = &decoder.decoder;
+    if !frame.is_finished() {
+        return Err(corrupt("truncated stream".to_string()));
+    }
+    if frame.get_checksum_from_data().is_some_and(|checksum| frame.get_calculated_checksum() != Some(checksum)) {
+        return Err(corrupt("checksum mismatch".to_string()));
+    }
+    Ok(content)
+}
+
+/// The content of a single-member gzip stream (RFC 1952)
+fn gunzip(payload: &[u8], max: usize) -> Result<Vec<u8>, InflateError> {
+    let corrupt = |reason: &str| InflateError::Corrupt(reason.to_string());
+    if payload.len() < GZIP_HEADER + GZIP_TRAILER {
+        return Err(corrupt("truncated header"));
+    }
+    if payload[2] != 8 {
+        return Err(corrupt("unknown compression method"));
+    }
+    let flags = payload[3];
+    let mut pos = GZIP_HEADER;
+    let field_end = |pos: usize| payload.get(pos..).and_then(|rest| rest.iter().position(|&b| b == 0)).map(|end| pos + end + 1);
+    if flags & 0x04 != 0 {
+        let len = payload.get(pos..pos + 2).ok_or_else(|| corrupt("truncated header"))?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    if flags & 0x08 != 0 {
+        pos = field_end(pos).ok_or_else(|| corrupt("truncated header"))?;
+    }
+    if flags & 0x10 != 0 {
+        pos = field_end(pos).ok_or_else(|| corrupt("truncated header"))?;
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    let body_end = payload.len() - GZIP_TRAILER;
+    let body = payload.get(pos..body_end).ok_or_else(|| corrupt("truncated header"))?;
+    let content = inflate::decompress_to_vec_with_limit(body, max).map_err(|err| match err.status {
+        TINFLStatus::HasMoreOutput => InflateError::TooLarge { max },
+        _ => InflateError::Corrupt(err.to_string().to_lowercase()),
+    })?;
+    let trailer = &payload[body_end..];
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc32(&content) != crc || content.len() as u32 != size {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(content)
+}
+
+/// CRC-32 as used by gzip
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+pub(crate) fn gzip(content: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(miniz_oxide::deflate::compress_to_vec(content, 6));
+    out.extend(crc32(content).to_le_bytes());
+    out.extend((content.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+pub(crate) fn zstd(content: &[u8]) -> Vec<u8> {
+    ruzstd::encoding::compress_to_vec(content, ruzstd::encoding::CompressionLevel::Fastest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_round_trips() {
+        let json = br#"["ok", "send_message", {"chat_id": 1, "text": "hi"}]"#;
+        let payload = gzip(json);
+        assert_eq!(detect(&payload), Some(Format::Gzip));
+        assert_eq!(inflate(&payload, Format::Gzip, 1024).unwrap(), json);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        // Optional header fields are skipped
+        let mut named = payload[..10].to_vec();
+        named[3] = 0x08;
+        named.extend(b"alert.json\0");
+        named.extend(&payload[10..]);
+        assert_eq!(inflate(&named, Format::Gzip, 1024).unwrap(), json);
+    }
+
+    #[test]
+    fn zstd_round_trips() {
+        let json = br#"["ok", "send_message", {"chat_id": 1, "text": "hi"}]"#;
+        let payload = zstd(json);
+        assert_eq!(detect(&payload), Some(Format::Zstd));
+        assert_eq!(inflate(&payload, Format::Zstd, 1024).unwrap(), json);
+        assert_eq!(detect(b"{\"text\": 1}"), None);
+    }
+
+    #[test]
+    fn truncated_streams_are_corrupt() {
+        let payload = gzip(&b"disk full on db-1 ".repeat(50));
+        for cut in [4, 12, payload.len() / 2, payload.len() - 3] {
+            assert!(matches!(inflate(&payload[..cut], Format::Gzip, 1 << 20), Err(InflateError::Corrupt(_))), "cut at {}", cut);
+        }
+        let mut flipped = payload.clone();
+        let last = flipped.len() - 5;
+        flipped[last] ^= 1;
+        assert_eq!(inflate(&flipped, Format::Gzip, 1 << 20), Err(InflateError::Corrupt("checksum mismatch".to_string())));
+
+        let payload = zstd(&b"disk full on db-1 ".repeat(50));
+        for cut in [2, 6, payload.len() / 2, payload.len() - 3] {
+            assert!(matches!(inflate(&payload[..cut], Format::Zstd, 1 << 20), Err(InflateError::Corrupt(_))), "zstd cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn expansion_stops_at_the_cap() {
+        let bomb = gzip(&vec![0; 8 * 1024 * 1024]);
+        assert!(bomb.len() < 16 * 1024);
+        assert_eq!(inflate(&bomb, Format::Gzip, 1024 * 1024), Err(InflateError::TooLarge { max: 1024 * 1024 }));
+
+        let bomb = zstd(&vec![0; 8 * 1024 * 1024]);
+        assert!(bomb.len() < 16 * 1024);
+        assert_eq!(inflate(&bomb, Format::Zstd, 1024 * 1024), Err(InflateError::TooLarge { max: 1024 * 1024 }));
+    }
+}
@@ -0,0 +1,1608 @@
+//! Configuration loaded from `~/.corky/config.toml`.
+
+use crate::i18n::{self, Translations};
+use crate::logging::LogFilters;
+use crate::quiet_hours::QuietHours;
+use crate::reports::ReportSettings;
+use crate::routes::{self, Route};
+use crate::zmq_listener::{Priority, ZmqMessage};
+use crate::mutes;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+/// Application configuration loaded from TOML
+#[derive(Deserialize, Debug, Clone)]
+pub struct AppConfig {
+    pub telegram: TelegramSettings,
+}
+
+/// Telegram-specific settings
+#[derive(Deserialize, Debug, Clone)]
+pub struct TelegramSettings {
+    /// May be left out when `bot_token_file` or `CORKY_TELEGRAM_BOT_TOKEN` supplies it
+    #[serde(default)]
+    pub bot_token: String,
+    /// File whose trimmed contents are the bot token, taking precedence over `bot_token`
+    #[serde(default)]
+    pub bot_token_file: Option<PathBuf>,
+    /// Chats that get owner notices and may use owner-only commands. The
+    /// older `owner_chat_id = 123` is read as a single owner.
+    #[serde(default, alias = "owner_chat_id", deserialize_with = "deserialize_owners")]
+    pub owner_chat_ids: Vec<i64>,
+    /// Where the values that may be overridden came from
+    #[serde(skip)]
+    pub sources: Sources,
+    #[serde(default)]
+    pub subscriber_lists: HashMap<String, SubscriberList>,
+    /// Default log level: error, warn, info, debug, trace or off
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    /// Per-target levels such as `"zmq=trace,send=warn"`, see `logging::TARGETS`
+    #[serde(default)]
+    pub log_filters: String,
+    /// Extra commands that publish a ZMQ event, keyed by name without the `/`
+    #[serde(default)]
+    pub commands: BTreeMap<String, CustomCommand>,
+    /// Rules giving messages without a target one, tried in order
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Language of replies and command descriptions when neither the user
+    /// nor their list asks for another
+    #[serde(default = "default_language")]
+    pub language: String,
+    /// TOML file with a table of texts per language, read at startup
+    #[serde(default)]
+    pub translations_file: Option<PathBuf>,
+    /// The contents of `translations_file`
+    #[serde(skip)]
+    pub translations: Arc<Translations>,
+    #[serde(default = "default_zmq_endpoint")]
+    pub zmq_endpoint: String,
+    /// Connect a DEALER to a broker, or bind a ROUTER for producers to connect to
+    #[serde(default)]
+    pub zmq_socket_type: SocketType,
+    /// Where to bind a socket publishing bot events; disabled when unset
+    #[serde(default)]
+    pub zmq_events_endpoint: Option<String>,
+    #[serde(default)]
+    pub zmq_events_socket: EventsSocket,
+    /// Event types to publish, from `events::BotEvent::KINDS`
+    #[serde(default = "default_zmq_events")]
+    pub zmq_events: Vec<String>,
+    /// What to do with HTML messages that Telegram would reject
+    #[serde(default)]
+    pub html_mode: HtmlMode,
+    /// SQLite file recording every delivery, for `/history`; disabled when unset
+    #[serde(default)]
+    pub history_db: Option<PathBuf>,
+    /// Days of delivery history to keep (0 keeps everything)
+    #[serde(default = "default_history_keep_days")]
+    pub history_keep_days: u32,
+    /// Reply to /start; the translated `welcome` text when unset
+    #[serde(default)]
+    pub welcome_text: Option<String>,
+    /// Directory for persisted state; ~/.corky when unset
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// File rewritten with the time, chat and kind of the last successful
+    /// send or keepalive, for external monitoring; disabled when unset
+    #[serde(default)]
+    pub heartbeat_file: Option<PathBuf>,
+    /// Check the connection with `get_me` after this many seconds without
+    /// a successful send (0 disables)
+    #[serde(default = "default_keepalive_secs")]
+    pub keepalive_secs: u64,
+    /// Deliver to the union of `chat_ids`, `chat_id` and `subscriber_list`
+    /// when a message sets several; when false the first one set wins
+    #[serde(default = "default_combine_targets")]
+    pub combine_targets: bool,
+    /// Send links without preview cards unless a list or message says otherwise
+    #[serde(default)]
+    pub disable_link_preview: bool,
+    /// Bot API server to talk to instead of api.telegram.org, e.g. a local
+    /// telegram-bot-api instance
+    #[serde(default)]
+    pub api_url: Option<String>,
+    /// Images larger than this are not uploaded; their text is sent instead
+    #[serde(default = "default_max_photo_bytes")]
+    pub max_photo_bytes: u64,
+    /// Documents larger than this are not uploaded; long texts are split instead
+    #[serde(default = "default_max_document_bytes")]
+    pub max_document_bytes: u64,
+    /// Largest image accepted as a ZMQ frame; bigger ones are rejected on receipt
+    #[serde(default = "default_max_image_frame_bytes")]
+    pub max_image_frame_bytes: u64,
+    /// Re-encode images Telegram handles badly as photos (TIFF, BMP, ...) as
+    /// JPEG, and send the ones that cannot be decoded as documents
+    #[serde(default)]
+    pub convert_unsupported_images: bool,
+    /// Inflate gzip- or zstd-compressed payloads instead of rejecting them
+    #[serde(default)]
+    pub zmq_accept_compressed: bool,
+    /// Largest payload a compressed one may inflate to
+    #[serde(default = "default_zmq_max_inflated_bytes")]
+    pub zmq_max_inflated_bytes: u64,
+    /// Longest `text` a message may carry, in bytes
+    #[serde(default = "default_max_text_bytes")]
+    pub max_text_bytes: usize,
+    /// Most chats a message may address through `chat_ids`, `chat_id` and `chat`
+    #[serde(default = "default_max_targets_per_message")]
+    pub max_targets_per_message: usize,
+    /// Longest `image_path` a message may carry, in characters
+    #[serde(default = "default_max_image_path_chars")]
+    pub max_image_path_chars: usize,
+    /// Show "uploading photo…"/"uploading document…" while large files upload
+    #[serde(default)]
+    pub send_chat_actions: bool,
+    /// Files at least this big get an upload indicator when `send_chat_actions` is on
+    #[serde(default = "default_chat_action_min_bytes")]
+    pub chat_action_min_bytes: u64,
+    /// Attachment paths must resolve inside this directory; relative ones are
+    /// taken relative to it. Unset allows any readable file.
+    #[serde(default)]
+    pub media_dir: Option<PathBuf>,
+    /// Tell the owners when a message names a file outside `media_dir`
+    #[serde(default = "default_media_dir_warn_owners")]
+    pub media_dir_warn_owners: bool,
+    /// Delete a message's image file after it reached every chat, unless the
+    /// message says otherwise
+    #[serde(default)]
+    pub delete_after_send: bool,
+    /// Exit with an error when the Telegram dispatcher ends, instead of restarting it
+    #[serde(default)]
+    pub dispatcher_exit_fatal: bool,
+    /// Drop identical messages to the same chat within this many seconds; 0 disables
+    #[serde(default)]
+    pub suppress_duplicates_secs: u64,
+    /// Tell a chat how often a suppressed message was repeated once its window closes
+    #[serde(default = "default_duplicate_summaries")]
+    pub duplicate_summaries: bool,
+    /// Texts longer than this many characters are sent as a .txt document
+    #[serde(default = "default_long_text_as_file_over")]
+    pub long_text_as_file_over: usize,
+    /// Images whose Telegram file ID is remembered to skip re-uploads; 0 disables
+    #[serde(default = "default_file_id_cache_size")]
+    pub file_id_cache_size: usize,
+    /// How long a remembered file ID is used
+    #[serde(default = "default_file_id_cache_ttl_secs")]
+    pub file_id_cache_ttl_secs: u64,
+    /// How long a resolved `@username` is trusted before asking Telegram again
+    #[serde(default = "default_username_cache_secs")]
+    pub username_cache_secs: u64,
+    /// Index of the multipart frame that holds the JSON payload, counted
+    /// after the client identity in ROUTER mode; see `payload_frame`
+    #[serde(default)]
+    pub zmq_payload_frame: Option<usize>,
+    /// Whether the payload wraps the command in an array envelope
+    #[serde(default)]
+    pub zmq_envelope: Envelope,
+    /// Index of the command within the array envelope
+    #[serde(default = "default_zmq_envelope_index")]
+    pub zmq_envelope_index: usize,
+    /// Alert the owner after this many seconds without ZMQ traffic (0 disables)
+    #[serde(default)]
+    pub zmq_down_alert_after_secs: u64,
+    /// Shortest delay before reconnecting the ZMQ socket
+    #[serde(default = "default_zmq_reconnect_min_ms")]
+    pub zmq_reconnect_min_ms: u64,
+    /// Longest delay before reconnecting the ZMQ socket
+    #[serde(default = "default_zmq_reconnect_max_ms")]
+    pub zmq_reconnect_max_ms: u64,
+    /// Receive/poll errors in a row before the socket is recreated
+    #[serde(default = "default_zmq_max_consecutive_errors")]
+    pub zmq_max_consecutive_errors: u32,
+    /// How long each ZMQ poll waits for a message
+    #[serde(default = "default_zmq_poll_timeout_ms")]
+    pub zmq_poll_timeout_ms: u64,
+    /// Capacity of the central event queue
+    #[serde(default = "default_event_queue_size")]
+    pub event_queue_size: usize,
+    /// What the ZMQ thread does when the event queue is full
+    #[serde(default)]
+    pub event_queue_overflow: OverflowPolicy,
+    /// Messages sent at once from the outbox; one extra worker serves only high priority
+    #[serde(default = "default_outbox_workers")]
+    pub outbox_workers: usize,
+    /// Flood waits within `flood_breaker_window_secs` that pause all sends; 0 disables
+    #[serde(default = "default_flood_breaker_threshold")]
+    pub flood_breaker_threshold: u32,
+    #[serde(default = "default_flood_breaker_window_secs")]
+    pub flood_breaker_window_secs: u64,
+    /// How many subscriber-list recipients are sent to at once
+    #[serde(default = "default_broadcast_concurrency")]
+    pub broadcast_concurrency: usize,
+    /// Consecutive blocked/not-found failures before a chat is quarantined (0 disables)
+    #[serde(default = "default_quarantine_after")]
+    pub quarantine_after: u32,
+    /// Whether mutes also suppress messages addressed by `chat_id`
+    #[serde(default)]
+    pub mutes_apply_to_direct: bool,
+    /// Send the owner a version and config summary once the bot is up
+    #[serde(default)]
+    pub notify_owner_on_startup: bool,
+    /// Tell the owner when the bot shuts down
+    #[serde(default)]
+    pub notify_owner_on_shutdown: bool,
+    /// Destination frame for user replies relayed over ZMQ (unset disables)
+    #[serde(default)]
+    pub relay_replies_to: Option<String>,
+    /// Destination frame for inline-button presses relayed over ZMQ (unset disables)
+    #[serde(default)]
+    pub relay_callbacks_to: Option<String>,
+    /// Answer unparseable ZMQ payloads with a `{"type": "error", ...}` frame
+    #[serde(default = "default_zmq_error_replies")]
+    pub zmq_error_replies: bool,
+    /// Minimum seconds between error replies to the same producer
+    #[serde(default = "default_zmq_error_reply_interval_secs")]
+    pub zmq_error_reply_interval_secs: u64,
+    /// Shared secret every ZMQ command must be signed with; unset accepts
+    /// unsigned commands
+    #[serde(default)]
+    pub zmq_hmac_secret: Option<String>,
+    /// How far a signed command's `ts` may be from the bot's clock, in seconds
+    #[serde(default = "default_zmq_hmac_window_secs")]
+    pub zmq_hmac_window_secs: u64,
+    /// Users allowed to press inline buttons (empty allows everyone)
+    #[serde(default)]
+    pub allowed_callback_users: Vec<u64>,
+    /// Append "✅ chosen: X" to a message when one of its buttons is pressed
+    #[serde(default)]
+    pub callback_edit_message: bool,
+    /// Receive updates through a webhook instead of long polling
+    #[serde(default)]
+    pub webhook: Option<WebhookSettings>,
+    /// Daily or weekly delivery report for the owners
+    #[serde(default)]
+    pub reports: Option<ReportSettings>,
+    /// Merge normal-priority texts to the same destination arriving within
+    /// this many milliseconds into one message (0 disables)
+    #[serde(default)]
+    pub aggregate_window_ms: u64,
+}
+
+/// `[telegram.webhook]`: where Telegram should deliver updates
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookSettings {
+    /// Public https URL Telegram posts updates to, e.g. behind a reverse proxy
+    pub url: String,
+    /// Local address the webhook server listens on
+    #[serde(default = "default_webhook_listen")]
+    pub listen: String,
+    /// Sent by Telegram in a header so forged updates can be rejected
+    #[serde(default)]
+    pub secret_token: Option<String>,
+}
+
+impl WebhookSettings {
+    /// The public URL, which must be https
+    pub fn public_url(&self) -> Result<url::Url, String> {
+        let url = url::Url::parse(&self.url).map_err(|e| format!("webhook url '{}' is invalid: {}", self.url, e))?;
+        if url.scheme() != "https" {
+            return Err(format!("webhook url '{}' must use https", self.url));
+        }
+        Ok(url)
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr, String> {
+        self.listen
+            .parse()
+            .map_err(|_| format!("webhook listen '{}' must be an address like 127.0.0.1:8443", self.listen))
+    }
+
+    /// Problems with the section, in the style of `TelegramSettings::validate`
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        errors.extend(self.public_url().err());
+        errors.extend(self.listen_addr().err());
+        if let Some(secret) = &self.secret_token {
+            let valid_chars = secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if secret.is_empty() || secret.len() > 256 || !valid_chars {
+                errors.push("webhook secret_token must be 1-256 characters of A-Z, a-z, 0-9, _ and -".to_string());
+            }
+        }
+        errors
+    }
+}
+
+/// Default local address for the webhook server
+fn default_webhook_listen() -> String {
+    "127.0.0.1:8443".to_string()
+}
+
+/// A command defined in config that publishes a ZMQ event when used
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomCommand {
+    /// Shown in /help
+    pub description: String,
+    /// Destination frame the event is sent with
+    pub destination: String,
+    /// Chats that may use the command (empty allows only the owner chat)
+    #[serde(default)]
+    pub allowed_chats: Vec<i64>,
+    /// Sent as the event's `payload`, with `{chat_id}`, `{user_id}`,
+    /// `{username}` and `{args}` filled in
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+/// A chat given by numeric ID or by a public chat's `@username`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ChatRef {
+    Id(i64),
+    /// Looked up with `get_chat` on first use and cached
+    Username(String),
+}
+
+impl ChatRef {
+    /// Problem with this reference, if any
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ChatRef::Id(0) => Err("chat ID 0".to_string()),
+            ChatRef::Id(_) => Ok(()),
+            ChatRef::Username(name) => {
+                let valid = name.strip_prefix('@').is_some_and(|rest| {
+                    (5..=32).contains(&rest.len()) && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                });
+                match valid {
+                    true => Ok(()),
+                    false => Err(format!("'{}', which is neither a chat ID nor an @username", name)),
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ChatRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChatRef::Id(id) => write!(f, "{}", id),
+            ChatRef::Username(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A named group of chats that broadcasts are sent to
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(from = "SubscriberListConfig")]
+pub struct SubscriberList {
+    pub chats: Vec<ChatRef>,
+    pub quiet_hours: Option<QuietHours>,
+    /// Overrides the global `aggregate_window_ms` for broadcasts to this list
+    pub aggregate_window_ms: Option<u64>,
+    /// Deliver broadcasts as one summary per interval instead of one by one
+    pub digest_interval: Option<chrono::Duration>,
+    /// Default for `protect_content` on broadcasts to this list
+    pub protect_content: bool,
+    /// Default for `spoiler` on broadcasts to this list
+    pub spoiler: bool,
+    /// Overrides the global `disable_link_preview` for broadcasts to this list
+    pub disable_link_preview: Option<bool>,
+    /// Warn the owners when nothing was delivered to this list for this long
+    pub expect_message_every: Option<chrono::Duration>,
+    /// Language of replies to this list's chats
+    pub language: Option<String>,
+}
+
+/// A list is either a bare array of chat IDs or a table with options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SubscriberListConfig {
+    Chats(Vec<ChatRef>),
+    Table {
+        chats: Vec<ChatRef>,
+        #[serde(default)]
+        quiet_hours: Option<QuietHours>,
+        #[serde(default)]
+        aggregate_window_ms: Option<u64>,
+        #[serde(default, deserialize_with = "deserialize_interval")]
+        digest_interval: Option<chrono::Duration>,
+        #[serde(default)]
+        protect_content: bool,
+        #[serde(default)]
+        spoiler: bool,
+        #[serde(default)]
+        disable_link_preview: Option<bool>,
+        #[serde(default, deserialize_with = "deserialize_interval")]
+        expect_message_every: Option<chrono::Duration>,
+        #[serde(default)]
+        language: Option<String>,
+    },
+}
+
+impl From<SubscriberListConfig> for SubscriberList {
+    fn from(config: SubscriberListConfig) -> Self {
+        match config {
+            SubscriberListConfig::Chats(chats) => SubscriberList { chats, ..Default::default() },
+            SubscriberListConfig::Table {
+                chats,
+                quiet_hours,
+                aggregate_window_ms,
+                digest_interval,
+                protect_content,
+                spoiler,
+                disable_link_preview,
+                expect_message_every,
+                language,
+            } => SubscriberList {
+                chats,
+                quiet_hours,
+                aggregate_window_ms,
+                digest_interval,
+                protect_content,
+                spoiler,
+                disable_link_preview,
+                expect_message_every,
+                language,
+            },
+        }
+    }
+}
+
+/// Accept a single chat ID or a list of them, dropping repeats
+/// Where a setting's value came from, in increasing precedence
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Source {
+    /// The config file, or the built-in default if the file leaves it out
+    #[default]
+    ConfigFile,
+    /// The file named by `bot_token_file`
+    SecretFile(PathBuf),
+    /// An environment variable
+    Env(&'static str),
+    /// A command-line option
+    CommandLine(&'static str),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::ConfigFile => write!(f, "config file"),
+            Source::SecretFile(path) => write!(f, "bot_token_file {}", path.display()),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::CommandLine(option) => write!(f, "command-line option {}", option),
+        }
+    }
+}
+
+/// Sources of the settings that can come from outside the config file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sources {
+    pub bot_token: Source,
+    pub owner_chat_ids: Source,
+    pub zmq_endpoint: Source,
+    pub data_dir: Source,
+}
+
+impl Sources {
+    /// " (from <source>)" for a value that did not come from the config file
+    fn suffix(source: &Source) -> String {
+        match source {
+            Source::ConfigFile => String::new(),
+            source => format!(" (from {})", source),
+        }
+    }
+}
+
+pub const ENV_BOT_TOKEN: &str = "CORKY_TELEGRAM_BOT_TOKEN";
+pub const ENV_OWNER_CHAT_ID: &str = "CORKY_TELEGRAM_OWNER_CHAT_ID";
+pub const ENV_ZMQ_ENDPOINT: &str = "CORKY_ZMQ_ENDPOINT";
+pub const ENV_DATA_DIR: &str = "CORKY_DATA_DIR";
+/// Config file to load instead of ~/.corky/config.toml
+pub const ENV_CONFIG: &str = "CORKY_CONFIG";
+
+fn deserialize_owners<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(i64),
+        Many(Vec<i64>),
+    }
+    let mut owners = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(id) => vec![id],
+        OneOrMany::Many(ids) => ids,
+    };
+    let mut seen = Vec::new();
+    owners.retain(|id| !seen.contains(id) && { seen.push(*id); true });
+    Ok(owners)
+}
+
+/// Parse an interval such as `"30m"`, `"2h"` or `"1d"`
+fn deserialize_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<chrono::Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    mutes::parse_duration(&s)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid interval '{}', expected e.g. 30m, 2h or 1d", s)))
+}
+
+/// Handling of messages with `"parse_mode": "html"`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HtmlMode {
+    /// Drop unsupported tags, close unclosed ones and escape stray characters
+    #[default]
+    Sanitize,
+    /// Log the problems and do not send the message
+    Strict,
+}
+
+/// Shape of the JSON payload carrying a command
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Envelope {
+    /// The payload is the command object itself
+    None,
+    /// The payload is an array such as `[status, action, data]`
+    #[default]
+    Array,
+}
+
+/// How the bot attaches to ZMQ
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketType {
+    /// Connect a DEALER socket to a broker at `zmq_endpoint`
+    #[default]
+    Dealer,
+    /// Bind a ROUTER socket at `zmq_endpoint`; producers connect with DEALER
+    /// sockets and each message arrives prefixed with the client identity
+    Router,
+}
+
+/// Socket bound at `zmq_events_endpoint`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EventsSocket {
+    /// Every connected SUB socket receives every event
+    #[default]
+    Pub,
+    /// Events are shared out between connected PULL sockets
+    Push,
+}
+
+/// Behaviour of the event queue when it is full
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// The ZMQ thread waits, letting ZMQ's own high-water mark apply backpressure
+    #[default]
+    Block,
+    /// Discard the oldest queued event to make room
+    DropOldest,
+    /// Discard the incoming event
+    DropNewest,
+}
+
+fn default_zmq_events() -> Vec<String> {
+    crate::events::BotEvent::KINDS.iter().map(|kind| kind.to_string()).collect()
+}
+
+/// Default ZMQ endpoint if none specified
+fn default_zmq_endpoint() -> String {
+    "tcp://127.0.0.1:6565".to_string()
+}
+
+/// A month of delivery history
+fn default_history_keep_days() -> u32 {
+    30
+}
+
+/// Five minutes without traffic before the connection is checked
+fn default_keepalive_secs() -> u64 {
+    300
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+/// Producers are told why their payloads were rejected by default
+fn default_zmq_error_replies() -> bool {
+    true
+}
+
+/// At most one error reply per producer every ten seconds
+fn default_zmq_error_reply_interval_secs() -> u64 {
+    10
+}
+
+/// Signed commands may be half a minute off the bot's clock
+fn default_zmq_hmac_window_secs() -> u64 {
+    30
+}
+
+/// Messages with several targets go to all of them by default
+fn default_combine_targets() -> bool {
+    true
+}
+
+fn default_duplicate_summaries() -> bool {
+    true
+}
+
+/// Attempts to read files outside `media_dir` are worth an alert
+fn default_media_dir_warn_owners() -> bool {
+    true
+}
+
+/// Telegram's limit for photos uploaded by bots (10 MB)
+fn default_max_photo_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// Enough for screenshots and charts while bounding what a producer can push (5 MB)
+fn default_max_image_frame_bytes() -> u64 {
+    5 * 1024 * 1024
+}
+
+/// Far more than any JSON payload needs, small enough to stop zip bombs (4 MB)
+fn default_zmq_max_inflated_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_language() -> String {
+    i18n::ENGLISH.to_string()
+}
+
+/// Far above anything worth reading in a chat, even as a document (1 MB)
+fn default_max_text_bytes() -> usize {
+    1024 * 1024
+}
+
+/// A broadcast to more chats than this belongs in a subscriber list
+fn default_max_targets_per_message() -> usize {
+    100
+}
+
+/// Linux's PATH_MAX
+fn default_max_image_path_chars() -> usize {
+    4096
+}
+
+/// Telegram's limit for files uploaded by bots to the public Bot API (50 MB)
+fn default_max_document_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+/// Uploads smaller than this (1 MB) finish before an indicator is worth showing
+fn default_chat_action_min_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_file_id_cache_size() -> usize {
+    256
+}
+
+/// Telegram keeps file IDs valid far longer; a day bounds stale entries
+fn default_file_id_cache_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+/// Channels rarely change their username; recheck daily
+fn default_username_cache_secs() -> u64 {
+    24 * 60 * 60
+}
+
+/// Default character threshold above which text is sent as a document
+fn default_long_text_as_file_over() -> usize {
+    8000
+}
+
+/// Default payload frame for `socket_type`: after the sender identity a
+/// broker prepends, or the client's only frame when the bot is the ROUTER
+fn default_zmq_payload_frame(socket_type: SocketType) -> usize {
+    match socket_type {
+        SocketType::Dealer => 1,
+        SocketType::Router => 0,
+    }
+}
+
+/// Default envelope index, the `data` element of `[status, action, data]`
+fn default_zmq_envelope_index() -> usize {
+    2
+}
+
+/// Default first reconnect delay
+fn default_zmq_reconnect_min_ms() -> u64 {
+    500
+}
+
+/// Default cap on the reconnect delay
+fn default_zmq_reconnect_max_ms() -> u64 {
+    30_000
+}
+
+/// Default number of consecutive ZMQ errors before reconnecting
+fn default_zmq_max_consecutive_errors() -> u32 {
+    10
+}
+
+/// Default ZMQ poll timeout
+fn default_zmq_poll_timeout_ms() -> u64 {
+    5000
+}
+
+/// Default capacity of the central event queue
+fn default_event_queue_size() -> usize {
+    256
+}
+
+/// Default number of messages processed concurrently
+fn default_outbox_workers() -> usize {
+    4
+}
+
+/// A few flood waits close together mean the whole account is limited
+fn default_flood_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_flood_breaker_window_secs() -> u64 {
+    10
+}
+
+/// Default number of concurrent sends when broadcasting to a list
+fn default_broadcast_concurrency() -> usize {
+    8
+}
+
+/// Default number of consecutive permanent failures before quarantine
+fn default_quarantine_after() -> u32 {
+    3
+}
+
+/// The ~/.corky directory holding the default config, and persisted state
+/// unless `data_dir` is set
+pub fn corky_dir() -> Result<PathBuf, String> {
+    let home = dirs::home_dir()
+        .ok_or_else(|| "Unable to determine home directory".to_string())?;
+    Ok(home.join(".corky"))
+}
+
+impl AppConfig {
+    /// Path of the default config file, ~/.corky/config.toml
+    pub fn default_path() -> Result<PathBuf, String> {
+        Ok(corky_dir()?.join("config.toml"))
+    }
+
+    /// The config file to load: `explicit` if given, else the file named by
+    /// `CORKY_CONFIG`, else ~/.corky/config.toml. Only the last needs a
+    /// home directory.
+    pub fn path(explicit: Option<PathBuf>) -> Result<PathBuf, String> {
+        match explicit.or_else(|| std::env::var_os(ENV_CONFIG).filter(|path| !path.is_empty()).map(PathBuf::from)) {
+            Some(path) => Ok(path),
+            None => Self::default_path(),
+        }
+    }
+
+    /// Load configuration from `CORKY_CONFIG` or ~/.corky/config.toml
+    pub fn load() -> Result<Self, String> {
+        Self::load_from(&Self::path(None)?)
+    }
+
+    /// Load configuration from an explicit path
+    pub fn load_from(config_path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(config_path)
+            .map_err(|e| format!("Failed to read {}: {}", config_path.display(), e))?;
+        let mut config: Self = toml::from_str(&contents)
+            .map_err(|e| format!("Failed to parse config TOML: {}", e))?;
+        config.telegram.apply_overrides(|name| std::env::var(name).ok())?;
+        if let Some(path) = &config.telegram.translations_file {
+            config.telegram.translations = Arc::new(Translations::load(path)?);
+        }
+        Ok(config)
+    }
+}
+
+impl TelegramSettings {
+    /// Replace the token, owners, endpoint and data directory with values from `env` or
+    /// `bot_token_file`. Precedence: environment, then `bot_token_file`,
+    /// then the config file. Empty variables are ignored.
+    pub fn apply_overrides(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let env = |name| env(name).filter(|value: &String| !value.trim().is_empty());
+        if let Some(token) = env(ENV_BOT_TOKEN) {
+            self.bot_token = token.trim().to_string();
+            self.sources.bot_token = Source::Env(ENV_BOT_TOKEN);
+        } else if let Some(path) = &self.bot_token_file {
+            let token = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read bot_token_file {}: {}", path.display(), e))?;
+            self.bot_token = token.trim().to_string();
+            self.sources.bot_token = Source::SecretFile(path.clone());
+        }
+        if let Some(owners) = env(ENV_OWNER_CHAT_ID) {
+            self.owner_chat_ids = owners
+                .split(',')
+                .map(|id| id.trim().parse().map_err(|_| format!("{} must be a chat ID or a comma-separated list, got '{}'", ENV_OWNER_CHAT_ID, id.trim())))
+                .collect::<Result<_, _>>()?;
+            let mut seen = Vec::new();
+            self.owner_chat_ids.retain(|id| !seen.contains(id) && { seen.push(*id); true });
+            self.sources.owner_chat_ids = Source::Env(ENV_OWNER_CHAT_ID);
+        }
+        if let Some(endpoint) = env(ENV_ZMQ_ENDPOINT) {
+            self.zmq_endpoint = endpoint.trim().to_string();
+            self.sources.zmq_endpoint = Source::Env(ENV_ZMQ_ENDPOINT);
+        }
+        if let Some(dir) = env(ENV_DATA_DIR) {
+            self.data_dir = Some(PathBuf::from(dir.trim()));
+            self.sources.data_dir = Source::Env(ENV_DATA_DIR);
+        }
+        Ok(())
+    }
+
+    /// Where the token, owners and endpoint came from, with the token redacted
+    pub fn source_summary(&self) -> String {
+        format!(
+            "bot_token {} from {}, owner_chat_ids from {}, zmq_endpoint from {} (precedence: environment > bot_token_file > config file)",
+            self.redacted_token(),
+            self.sources.bot_token,
+            self.sources.owner_chat_ids,
+            self.sources.zmq_endpoint
+        )
+    }
+
+    /// Check settings that parse fine but cannot work at runtime.
+    /// Returns every problem found rather than stopping at the first.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        match self.bot_token.split_once(':') {
+            Some((id, secret)) if !id.is_empty()
+                && id.chars().all(|c| c.is_ascii_digit())
+                && !secret.is_empty() => {}
+            _ if self.bot_token.is_empty() && self.sources.bot_token == Source::ConfigFile => errors.push(format!(
+                "bot_token is missing; set it, bot_token_file or {}",
+                ENV_BOT_TOKEN
+            )),
+            _ => errors.push(format!(
+                "bot_token{} must look like <bot id>:<secret>",
+                Sources::suffix(&self.sources.bot_token)
+            )),
+        }
+        let owners = Sources::suffix(&self.sources.owner_chat_ids);
+        if self.owner_chat_ids.is_empty() {
+            errors.push(format!("owner_chat_ids{} must list at least one chat", owners));
+        }
+        if self.owner_chat_ids.contains(&0) {
+            errors.push(format!("owner_chat_ids{} must be non-zero chat IDs", owners));
+        }
+        if !self.zmq_endpoint.contains("://") {
+            errors.push(format!(
+                "zmq_endpoint '{}'{} must include a transport, e.g. tcp://127.0.0.1:6565",
+                self.zmq_endpoint,
+                Sources::suffix(&self.sources.zmq_endpoint)
+            ));
+        }
+        if let Err(err) = self.api_url() {
+            errors.push(err);
+        }
+        if self.max_photo_bytes == 0 || self.max_document_bytes == 0 {
+            errors.push("max_photo_bytes and max_document_bytes must be greater than 0".to_string());
+        }
+        if self.max_image_frame_bytes == 0 {
+            errors.push("max_image_frame_bytes must be greater than 0".to_string());
+        }
+        if self.zmq_max_inflated_bytes == 0 {
+            errors.push("zmq_max_inflated_bytes must be greater than 0".to_string());
+        }
+        let languages = std::iter::once(&self.language)
+            .chain(self.subscriber_lists.values().filter_map(|list| list.language.as_ref()));
+        for language in languages {
+            if self.translations.supported(language).as_ref() != Some(language) {
+                errors.push(format!("language '{}' is not English and not in translations_file", language));
+            }
+        }
+        if self.max_text_bytes == 0 || self.max_targets_per_message == 0 || self.max_image_path_chars == 0 {
+            errors.push("max_text_bytes, max_targets_per_message and max_image_path_chars must be greater than 0".to_string());
+        }
+        errors.extend(routes::validate(&self.routes, |list| self.subscriber_lists.contains_key(list)));
+        if let Some(reports) = &self.reports {
+            errors.extend(reports.schedule().err());
+        }
+        for kind in &self.zmq_events {
+            if !crate::events::BotEvent::KINDS.contains(&kind.as_str()) {
+                errors.push(format!(
+                    "zmq_events: unknown event '{}' (expected one of: {})",
+                    kind,
+                    crate::events::BotEvent::KINDS.join(", ")
+                ));
+            }
+        }
+        if self.media_dir.as_ref().is_some_and(|dir| !dir.is_absolute()) {
+            errors.push("media_dir must be an absolute path".to_string());
+        }
+        if self.zmq_hmac_secret.as_ref().is_some_and(|secret| secret.len() < 16) {
+            errors.push("zmq_hmac_secret must be at least 16 characters".to_string());
+        }
+        if self.zmq_hmac_window_secs == 0 {
+            errors.push("zmq_hmac_window_secs must be greater than 0".to_string());
+        }
+        if self.long_text_as_file_over == 0 {
+            errors.push("long_text_as_file_over must be greater than 0".to_string());
+        }
+        if self.zmq_reconnect_min_ms == 0 {
+            errors.push("zmq_reconnect_min_ms must be greater than 0".to_string());
+        }
+        if self.zmq_reconnect_max_ms < self.zmq_reconnect_min_ms {
+            errors.push("zmq_reconnect_max_ms must not be less than zmq_reconnect_min_ms".to_string());
+        }
+        if self.zmq_max_consecutive_errors == 0 {
+            errors.push("zmq_max_consecutive_errors must be greater than 0".to_string());
+        }
+        if self.zmq_poll_timeout_ms == 0 {
+            errors.push("zmq_poll_timeout_ms must be greater than 0".to_string());
+        }
+        if self.event_queue_size == 0 {
+            errors.push("event_queue_size must be greater than 0".to_string());
+        }
+        if self.outbox_workers == 0 {
+            errors.push("outbox_workers must be greater than 0".to_string());
+        }
+        if self.flood_breaker_threshold > 0 && self.flood_breaker_window_secs == 0 {
+            errors.push("flood_breaker_window_secs must be greater than 0".to_string());
+        }
+        if self.broadcast_concurrency == 0 {
+            errors.push("broadcast_concurrency must be greater than 0".to_string());
+        }
+
+        if let Some(webhook) = &self.webhook {
+            errors.extend(webhook.validate());
+        }
+        if let Err(err) = self.log_filters() {
+            errors.push(err);
+        }
+
+        let mut names: Vec<_> = self.subscriber_lists.keys().collect();
+        names.sort();
+        for name in names {
+            let list = &self.subscriber_lists[name];
+            for chat in &list.chats {
+                if let Err(err) = chat.validate() {
+                    errors.push(format!("subscriber list '{}' contains {}", name, err));
+                }
+            }
+            if let Some(quiet) = &list.quiet_hours {
+                if quiet.start == quiet.end {
+                    errors.push(format!("subscriber list '{}' has quiet_hours with equal start and end", name));
+                }
+            }
+        }
+
+        let builtin = crate::commands::builtin_names();
+        for (name, command) in &self.commands {
+            let valid_chars = name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if name.is_empty() || name.len() > 32 || !valid_chars {
+                errors.push(format!("command '{}' must be 1-32 characters of a-z, 0-9 and _", name));
+            }
+            if builtin.contains(name) {
+                errors.push(format!("command '{}' collides with a built-in command", name));
+            }
+            if command.description.trim().is_empty() || command.description.chars().count() > 256 {
+                errors.push(format!("command '{}' needs a description of 1-256 characters", name));
+            }
+            if command.destination.is_empty() {
+                errors.push(format!("command '{}' needs a destination", name));
+            }
+        }
+
+        errors
+    }
+
+    /// `log_level` and `log_filters`, parsed
+    pub fn log_filters(&self) -> Result<LogFilters, String> {
+        LogFilters::parse(&self.log_level, &self.log_filters).map_err(|e| format!("log_filters: {}", e))
+    }
+
+    /// Whether `chat_id` is one of the owner chats
+    pub fn is_owner(&self, chat_id: i64) -> bool {
+        self.owner_chat_ids.contains(&chat_id)
+    }
+
+    /// Whether `chat_id` may use the custom command `name`
+    pub fn command_allowed(&self, name: &str, chat_id: i64) -> bool {
+        match self.commands.get(name) {
+            Some(command) if command.allowed_chats.is_empty() => self.is_owner(chat_id),
+            Some(command) => command.allowed_chats.contains(&chat_id),
+            None => false,
+        }
+    }
+
+    /// Whether `chat_id` is an owner chat, on a subscriber list or allowed a
+    /// custom command, and so is answered when a command is not understood
+    pub fn knows_chat(&self, chat_id: i64) -> bool {
+        self.is_owner(chat_id)
+            || !self.lists_containing(chat_id).is_empty()
+            || self.commands.keys().any(|name| self.command_allowed(name, chat_id))
+    }
+
+    /// Aggregation window for messages addressed like `message`: the list's
+    /// own setting for list broadcasts, otherwise the global one
+    pub fn aggregate_window(&self, message: &ZmqMessage) -> Option<Duration> {
+        let list_window = self.broadcast_list(message).and_then(|list| list.aggregate_window_ms);
+        match list_window.unwrap_or(self.aggregate_window_ms) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// `protect_content` and `spoiler` for `message`: its own flags, else the
+    /// defaults of the list it is broadcast to, else off
+    pub fn content_flags(&self, message: &ZmqMessage) -> (bool, bool) {
+        let list = self.broadcast_list(message);
+        (
+            message.protect_content.or(list.map(|l| l.protect_content)).unwrap_or(false),
+            message.spoiler.or(list.map(|l| l.spoiler)).unwrap_or(false),
+        )
+    }
+
+    /// Whether links in `message` go out without preview cards: its own flag,
+    /// else its list's setting, else the global default
+    pub fn link_preview_disabled(&self, message: &ZmqMessage) -> bool {
+        message
+            .disable_link_preview
+            .or(self.broadcast_list(message).and_then(|list| list.disable_link_preview))
+            .unwrap_or(self.disable_link_preview)
+    }
+
+    /// Whether the image of `message` is deleted once delivered: its own
+    /// flag, else the global default
+    pub fn deletes_after_send(&self, message: &ZmqMessage) -> bool {
+        message.delete_after_send.unwrap_or(self.delete_after_send)
+    }
+
+    /// The list `message` is broadcast to, if it is a broadcast to a known list
+    fn broadcast_list(&self, message: &ZmqMessage) -> Option<&SubscriberList> {
+        self.subscriber_lists.get(message.broadcast_list()?)
+    }
+
+    /// The list and interval when `message` is a normal-priority broadcast
+    /// to a list in digest mode
+    pub fn digest_for<'a>(&self, message: &'a ZmqMessage) -> Option<(&'a str, chrono::Duration)> {
+        // A message protected on its own must not end up in an unprotected summary
+        if message.priority == Priority::High || message.protect_content.is_some() {
+            return None;
+        }
+        let name = message.broadcast_list()?;
+        let interval = self.subscriber_lists.get(name)?.digest_interval?;
+        Some((name, interval))
+    }
+
+    /// Lists with `expect_message_every`, and how often they expect traffic
+    pub fn expected_traffic(&self) -> Vec<(String, chrono::Duration)> {
+        let mut lists: Vec<_> = self
+            .subscriber_lists
+            .iter()
+            .filter_map(|(name, list)| Some((name.clone(), list.expect_message_every?)))
+            .collect();
+        lists.sort();
+        lists
+    }
+
+    /// Language for replies in `chat_id`: the user's own `language_code` if
+    /// translated, else the language of the first list containing the chat
+    /// that sets one, else `language`
+    pub fn language_for(&self, chat_id: i64, user_language: Option<&str>) -> String {
+        if let Some(language) = user_language.and_then(|code| self.translations.supported(code)) {
+            return language;
+        }
+        self.lists_containing(chat_id)
+            .iter()
+            .find_map(|name| self.subscriber_lists[name].language.clone())
+            .unwrap_or_else(|| self.language.clone())
+    }
+
+    /// Names of the subscriber lists containing `chat_id` by ID, sorted
+    pub fn lists_containing(&self, chat_id: i64) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .subscriber_lists
+            .iter()
+            .filter(|(_, list)| list.chats.contains(&ChatRef::Id(chat_id)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Whether `user_id` may press inline buttons
+    pub fn callback_allowed(&self, user_id: u64) -> bool {
+        self.allowed_callback_users.is_empty() || self.allowed_callback_users.contains(&user_id)
+    }
+
+    /// The configured Bot API server, if any. Only http and https are accepted.
+    pub fn api_url(&self) -> Result<Option<url::Url>, String> {
+        let Some(raw) = &self.api_url else {
+            return Ok(None);
+        };
+        match url::Url::parse(raw) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(Some(url)),
+            Ok(_) => Err(format!("api_url '{}' must use http or https", raw)),
+            Err(e) => Err(format!("api_url '{}' is invalid: {}", raw, e)),
+        }
+    }
+
+    /// Frame holding the JSON payload, defaulting by `zmq_socket_type`
+    pub fn payload_frame(&self) -> usize {
+        self.zmq_payload_frame.unwrap_or_else(|| default_zmq_payload_frame(self.zmq_socket_type))
+    }
+
+    /// The bot's own user ID, which is the numeric part of the token
+    pub fn bot_id(&self) -> Option<u64> {
+        self.bot_token.split_once(':').and_then(|(id, _)| id.parse().ok())
+    }
+
+    /// Bot token with the secret part hidden, safe for printing
+    pub fn redacted_token(&self) -> String {
+        match self.bot_token.split_once(':') {
+            Some((id, _)) => format!("{}:***", id),
+            None => "***".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_from(toml_str: &str) -> TelegramSettings {
+        toml::from_str::<AppConfig>(toml_str).unwrap().telegram
+    }
+
+    #[test]
+    fn validate_accepts_example_config() {
+        let settings = settings_from(include_str!("../config.toml"));
+        assert!(settings.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_collects_all_problems() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"nope\"\nowner_chat_id = 0\nzmq_endpoint = \"localhost\"\n\
+             [telegram.subscriber_lists]\nzero = [0]\n",
+        );
+        assert_eq!(settings.validate().len(), 4);
+    }
+
+    #[test]
+    fn owners_accept_a_scalar_or_a_list() {
+        let settings = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_ids = [5, -100200, 5]\n");
+        assert_eq!(settings.owner_chat_ids, vec![5, -100200]);
+        assert!(settings.is_owner(-100200));
+        assert!(!settings.is_owner(6));
+        assert!(settings.validate().is_empty());
+
+        let settings = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 5\n");
+        assert_eq!(settings.owner_chat_ids, vec![5]);
+
+        let settings = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_ids = []\n");
+        assert_eq!(settings.validate(), vec!["owner_chat_ids must list at least one chat".to_string()]);
+    }
+
+    #[test]
+    fn redacted_token_hides_secret() {
+        let settings = settings_from("[telegram]\nbot_token = \"123:secret\"\nowner_chat_id = 1\n");
+        assert_eq!(settings.redacted_token(), "123:***");
+        assert_eq!(settings.bot_id(), Some(123));
+    }
+
+    #[test]
+    fn environment_overrides_secret_file_overrides_config() {
+        let secret = std::env::temp_dir().join(format!("corky-token-{}", std::process::id()));
+        fs::write(&secret, "456:from-file\n").unwrap();
+        let toml = format!("[telegram]\nbot_token_file = {:?}\nowner_chat_id = 1\n", secret);
+        let no_env = |_: &str| None;
+
+        let mut settings = settings_from(&toml);
+        settings.apply_overrides(no_env).unwrap();
+        assert_eq!(settings.bot_token, "456:from-file");
+        assert_eq!(settings.sources.bot_token, Source::SecretFile(secret.clone()));
+        assert!(settings.source_summary().starts_with("bot_token 456:*** from bot_token_file"));
+
+        let env = |name: &str| match name {
+            ENV_BOT_TOKEN => Some("789:from-env".to_string()),
+            ENV_OWNER_CHAT_ID => Some("5, -100200".to_string()),
+            ENV_ZMQ_ENDPOINT => Some(" ".to_string()),
+            ENV_DATA_DIR => Some("/var/lib/corky".to_string()),
+            _ => None,
+        };
+        let mut settings = settings_from(&toml);
+        settings.apply_overrides(env).unwrap();
+        assert_eq!(settings.data_dir, Some(PathBuf::from("/var/lib/corky")));
+        assert_eq!(settings.sources.data_dir, Source::Env(ENV_DATA_DIR));
+        assert_eq!(settings.bot_token, "789:from-env");
+        assert_eq!(settings.owner_chat_ids, vec![5, -100200]);
+        assert_eq!(settings.zmq_endpoint, "tcp://127.0.0.1:6565");
+        assert_eq!(settings.sources.zmq_endpoint, Source::ConfigFile);
+        assert!(!settings.source_summary().contains("from-env"));
+        let _ = fs::remove_file(&secret);
+
+        // The secret file must exist once named
+        assert!(settings_from(&toml).apply_overrides(no_env).unwrap_err().starts_with("Failed to read bot_token_file"));
+    }
+
+    #[test]
+    fn an_explicit_config_path_needs_no_home() {
+        let path = PathBuf::from("/etc/corky/config.toml");
+        assert_eq!(AppConfig::path(Some(path.clone())), Ok(path));
+    }
+
+    #[test]
+    fn validation_names_the_source_of_a_bad_value() {
+        let mut settings = settings_from("[telegram]\n");
+        assert!(settings.validate().contains(&format!("bot_token is missing; set it, bot_token_file or {}", ENV_BOT_TOKEN)));
+        let env = |name: &str| match name {
+            ENV_BOT_TOKEN => Some("nope".to_string()),
+            ENV_ZMQ_ENDPOINT => Some("localhost".to_string()),
+            _ => None,
+        };
+        settings.apply_overrides(env).unwrap();
+        let errors = settings.validate();
+        assert!(errors.contains(&"bot_token (from environment variable CORKY_TELEGRAM_BOT_TOKEN) must look like <bot id>:<secret>".to_string()));
+        assert!(errors.iter().any(|e| e.starts_with("zmq_endpoint 'localhost' (from environment variable CORKY_ZMQ_ENDPOINT)")));
+        assert!(errors.contains(&"owner_chat_ids must list at least one chat".to_string()));
+
+        let bad_owner = |name: &str| (name == ENV_OWNER_CHAT_ID).then(|| "12,me".to_string());
+        assert_eq!(
+            settings.apply_overrides(bad_owner).unwrap_err(),
+            "CORKY_TELEGRAM_OWNER_CHAT_ID must be a chat ID or a comma-separated list, got 'me'"
+        );
+    }
+
+    #[test]
+    fn defaults_apply_when_optional_fields_missing() {
+        let settings = settings_from("[telegram]\nbot_token = \"123:secret\"\nowner_chat_id = 42\n");
+        assert_eq!(settings.owner_chat_ids, vec![42]);
+        assert_eq!(settings.zmq_endpoint, "tcp://127.0.0.1:6565");
+        assert_eq!(settings.long_text_as_file_over, 8000);
+        assert_eq!(settings.zmq_socket_type, SocketType::Dealer);
+        assert_eq!(settings.zmq_events_endpoint, None);
+        assert!(settings.routes.is_empty());
+        assert_eq!(settings.zmq_events_socket, EventsSocket::Pub);
+        assert_eq!(settings.zmq_events.len(), crate::events::BotEvent::KINDS.len());
+        assert_eq!(settings.zmq_payload_frame, None);
+        assert_eq!(settings.payload_frame(), 1);
+        assert_eq!(settings.zmq_envelope, Envelope::Array);
+        assert_eq!(settings.zmq_envelope_index, 2);
+        assert_eq!(settings.event_queue_size, 256);
+        assert_eq!(settings.event_queue_overflow, OverflowPolicy::Block);
+        assert_eq!(settings.broadcast_concurrency, 8);
+        assert_eq!(settings.quarantine_after, 3);
+        assert_eq!(settings.outbox_workers, 4);
+        assert_eq!((settings.flood_breaker_threshold, settings.flood_breaker_window_secs), (5, 10));
+        assert_eq!(settings.zmq_down_alert_after_secs, 0);
+        assert_eq!(settings.zmq_reconnect_min_ms, 500);
+        assert_eq!(settings.zmq_reconnect_max_ms, 30_000);
+        assert_eq!(settings.zmq_max_consecutive_errors, 10);
+        assert_eq!(settings.zmq_poll_timeout_ms, 5000);
+        assert!(!settings.mutes_apply_to_direct);
+        assert!(!settings.notify_owner_on_startup);
+        assert!(!settings.notify_owner_on_shutdown);
+        assert_eq!(settings.aggregate_window_ms, 0);
+        assert_eq!(settings.relay_replies_to, None);
+        assert_eq!(settings.relay_callbacks_to, None);
+        assert!(settings.zmq_error_replies);
+        assert_eq!(settings.zmq_error_reply_interval_secs, 10);
+        assert_eq!(settings.zmq_hmac_secret, None);
+        assert_eq!(settings.zmq_hmac_window_secs, 30);
+        assert_eq!(settings.webhook, None);
+        assert_eq!(settings.api_url, None);
+        assert!(!settings.disable_link_preview);
+        assert_eq!(settings.html_mode, HtmlMode::Sanitize);
+        assert!(settings.combine_targets);
+        assert_eq!(settings.history_db, None);
+        assert_eq!(settings.history_keep_days, 30);
+        assert_eq!(settings.data_dir, None);
+        assert_eq!(settings.welcome_text, None);
+        assert_eq!(settings.heartbeat_file, None);
+        assert_eq!(settings.keepalive_secs, 300);
+        assert_eq!(settings.max_photo_bytes, 10 * 1024 * 1024);
+        assert_eq!(settings.max_document_bytes, 50 * 1024 * 1024);
+        assert_eq!(settings.max_image_frame_bytes, 5 * 1024 * 1024);
+        assert!(!settings.zmq_accept_compressed);
+        assert_eq!(settings.zmq_max_inflated_bytes, 4 * 1024 * 1024);
+        assert_eq!(settings.max_text_bytes, 1024 * 1024);
+        assert_eq!(settings.max_targets_per_message, 100);
+        assert_eq!(settings.max_image_path_chars, 4096);
+        assert_eq!(settings.language, "en");
+        assert_eq!(settings.translations_file, None);
+        assert!(!settings.send_chat_actions);
+        assert_eq!(settings.chat_action_min_bytes, 1024 * 1024);
+        assert!(!settings.delete_after_send);
+        assert_eq!(settings.suppress_duplicates_secs, 0);
+        assert!(!settings.dispatcher_exit_fatal);
+        assert_eq!(settings.bot_token_file, None);
+        assert_eq!(settings.sources, Sources::default());
+        assert!(settings.duplicate_summaries);
+        assert_eq!(settings.media_dir, None);
+        assert!(settings.media_dir_warn_owners);
+        assert_eq!(settings.username_cache_secs, 86400);
+        assert_eq!(settings.file_id_cache_size, 256);
+        assert_eq!(settings.file_id_cache_ttl_secs, 86400);
+        assert!(settings.callback_allowed(12345));
+        assert!(!settings.callback_edit_message);
+        assert!(settings.subscriber_lists.is_empty());
+        assert!(settings.commands.is_empty());
+        assert_eq!(settings.log_level, "info");
+        assert_eq!(settings.log_filters, "");
+        assert_eq!(settings.log_filters().unwrap(), LogFilters::default());
+    }
+
+    #[test]
+    fn envelope_layout_is_configurable() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"123:secret\"\nowner_chat_id = 42\n\
+             zmq_payload_frame = 0\nzmq_envelope = \"none\"\n",
+        );
+        assert_eq!(settings.payload_frame(), 0);
+        assert_eq!(settings.zmq_envelope, Envelope::None);
+    }
+
+    #[test]
+    fn router_mode_defaults_to_the_clients_first_frame() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"123:secret\"\nowner_chat_id = 42\n\
+             zmq_socket_type = \"router\"\nzmq_endpoint = \"tcp://*:6565\"\n",
+        );
+        assert_eq!(settings.zmq_socket_type, SocketType::Router);
+        assert_eq!(settings.payload_frame(), 0);
+    }
+
+    #[test]
+    fn overflow_policy_parses_snake_case() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"123:secret\"\nowner_chat_id = 42\n\
+             event_queue_size = 16\nevent_queue_overflow = \"drop_oldest\"\n",
+        );
+        assert_eq!(settings.event_queue_size, 16);
+        assert_eq!(settings.event_queue_overflow, OverflowPolicy::DropOldest);
+    }
+
+    #[test]
+    fn lists_containing_chat() {
+        let settings = settings_from(include_str!("../config.toml"));
+        assert_eq!(settings.lists_containing(987654321), vec!["friends".to_string()]);
+        assert_eq!(settings.lists_containing(123456789).len(), 3);
+        assert!(settings.lists_containing(1).is_empty());
+    }
+
+    #[test]
+    fn missing_telegram_section_is_rejected() {
+        assert!(toml::from_str::<AppConfig>("[other]\nkey = 1\n").is_err());
+    }
+
+    #[test]
+    fn subscriber_lists_accept_array_or_table() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [1, 2]\n\
+             family = { chats = [3], quiet_hours = { start = \"23:00\", end = \"07:00\", tz = \"Europe/Berlin\", mode = \"defer\" } }\n",
+        );
+        assert_eq!(
+            settings.subscriber_lists["ops"],
+            SubscriberList { chats: vec![ChatRef::Id(1), ChatRef::Id(2)], ..Default::default() }
+        );
+        let family = &settings.subscriber_lists["family"];
+        assert_eq!(family.chats, vec![ChatRef::Id(3)]);
+        let quiet = family.quiet_hours.as_ref().unwrap();
+        assert_eq!(quiet.mode, crate::quiet_hours::QuietMode::Defer);
+        assert_eq!(quiet.tz, chrono_tz::Europe::Berlin);
+        assert!(settings.validate().is_empty());
+    }
+
+    #[test]
+    fn list_entries_may_be_usernames() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nnews = [\"@my_channel\", -100123]\nbad = [\"my_channel\", \"@abc\"]\n",
+        );
+        assert_eq!(
+            settings.subscriber_lists["news"].chats,
+            vec![ChatRef::Username("@my_channel".to_string()), ChatRef::Id(-100123)]
+        );
+        assert_eq!(settings.validate().len(), 2);
+        assert_eq!(settings.lists_containing(-100123), vec!["news".to_string()]);
+    }
+
+    #[test]
+    fn list_aggregate_window_overrides_global() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\naggregate_window_ms = 2000\n\
+             [telegram.subscriber_lists]\nops = [1]\n\
+             noisy = { chats = [2], aggregate_window_ms = 10000 }\n\
+             alerts = { chats = [3], aggregate_window_ms = 0 }\n",
+        );
+        let message = |chat_id: Option<i64>, list: Option<&str>| -> ZmqMessage {
+            serde_json::from_value(serde_json::json!({ "text": "x", "chat_id": chat_id, "subscriber_list": list })).unwrap()
+        };
+        assert_eq!(settings.aggregate_window(&message(None, Some("ops"))), Some(Duration::from_secs(2)));
+        assert_eq!(settings.aggregate_window(&message(None, Some("noisy"))), Some(Duration::from_secs(10)));
+        assert_eq!(settings.aggregate_window(&message(None, Some("alerts"))), None);
+        assert_eq!(settings.aggregate_window(&message(Some(5), Some("noisy"))), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn digest_applies_to_normal_list_broadcasts() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [1]\nmetrics = { chats = [2], digest_interval = \"30m\" }\n",
+        );
+        let message = |json: serde_json::Value| -> ZmqMessage { serde_json::from_value(json).unwrap() };
+        let broadcast = message(serde_json::json!({ "text": "x", "subscriber_list": "metrics" }));
+        assert_eq!(settings.digest_for(&broadcast), Some(("metrics", chrono::Duration::minutes(30))));
+        let urgent = message(serde_json::json!({ "text": "x", "subscriber_list": "metrics", "priority": "high" }));
+        assert_eq!(settings.digest_for(&urgent), None);
+        let direct = message(serde_json::json!({ "text": "x", "subscriber_list": "metrics", "chat_id": 2 }));
+        assert_eq!(settings.digest_for(&direct), None);
+        let other = message(serde_json::json!({ "text": "x", "subscriber_list": "ops" }));
+        assert_eq!(settings.digest_for(&other), None);
+    }
+
+    #[test]
+    fn reply_language_prefers_the_user_then_the_list() {
+        let mut settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nfamily = { chats = [2], language = \"de\" }\nfriends = [3]\n",
+        );
+        assert_eq!(settings.validate(), vec!["language 'de' is not English and not in translations_file".to_string()]);
+        settings.translations = Arc::new(Translations::from_toml("[de]\n[uk]\n").unwrap());
+        assert!(settings.validate().is_empty());
+        assert_eq!(settings.language_for(2, None), "de");
+        assert_eq!(settings.language_for(2, Some("uk")), "uk");
+        // A user language without translations does not override the list's
+        assert_eq!(settings.language_for(2, Some("fr")), "de");
+        assert_eq!(settings.language_for(3, Some("en-US")), "en");
+        assert_eq!(settings.language_for(3, None), "en");
+    }
+
+    #[test]
+    fn invalid_digest_interval_is_rejected() {
+        let toml = "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+                    [telegram.subscriber_lists]\nmetrics = { chats = [2], digest_interval = \"soon\" }\n";
+        assert!(toml::from_str::<AppConfig>(toml).is_err());
+    }
+
+    #[test]
+    fn content_flags_fall_back_to_list_defaults() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\npreviews = { chats = [1], protect_content = true, spoiler = true }\n\
+             team = [2]\n",
+        );
+        let message = |json: serde_json::Value| -> ZmqMessage { serde_json::from_value(json).unwrap() };
+        assert_eq!(settings.content_flags(&message(serde_json::json!({ "text": "a", "subscriber_list": "previews" }))), (true, true));
+        assert_eq!(
+            settings.content_flags(&message(serde_json::json!({ "text": "a", "subscriber_list": "previews", "spoiler": false }))),
+            (true, false)
+        );
+        assert_eq!(settings.content_flags(&message(serde_json::json!({ "text": "a", "subscriber_list": "team" }))), (false, false));
+        assert_eq!(
+            settings.content_flags(&message(serde_json::json!({ "text": "a", "chat_id": 1, "subscriber_list": "previews" }))),
+            (false, false)
+        );
+    }
+
+    #[test]
+    fn link_preview_setting_prefers_message_then_list() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\ndisable_link_preview = true\n\
+             [telegram.subscriber_lists]\nnews = { chats = [1], disable_link_preview = false }\nteam = [2]\n",
+        );
+        let message = |json: serde_json::Value| -> ZmqMessage { serde_json::from_value(json).unwrap() };
+        assert!(settings.link_preview_disabled(&message(serde_json::json!({ "text": "a", "subscriber_list": "team" }))));
+        assert!(!settings.link_preview_disabled(&message(serde_json::json!({ "text": "a", "subscriber_list": "news" }))));
+        assert!(settings.link_preview_disabled(
+            &message(serde_json::json!({ "text": "a", "subscriber_list": "news", "disable_link_preview": true }))
+        ));
+        assert!(settings.link_preview_disabled(&message(serde_json::json!({ "text": "a", "chat_id": 1 }))));
+    }
+
+    #[test]
+    fn api_url_must_be_http() {
+        let local = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\napi_url = \"http://127.0.0.1:8081\"\n",
+        );
+        assert_eq!(local.api_url().unwrap().unwrap().port(), Some(8081));
+        assert!(local.validate().is_empty());
+        let bad = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\napi_url = \"ftp://x\"\n");
+        assert_eq!(bad.validate(), vec!["api_url 'ftp://x' must use http or https".to_string()]);
+    }
+
+    #[test]
+    fn media_dir_must_be_absolute() {
+        let absolute = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\nmedia_dir = \"/srv/media\"\n");
+        assert!(absolute.validate().is_empty());
+        let relative = settings_from("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\nmedia_dir = \"media\"\n");
+        assert_eq!(relative.validate(), vec!["media_dir must be an absolute path".to_string()]);
+    }
+
+    #[test]
+    fn webhook_section_enables_webhook_mode() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.webhook]\nurl = \"https://bot.example.com/telegram\"\nsecret_token = \"s3cret_-\"\n",
+        );
+        let webhook = settings.webhook.as_ref().unwrap();
+        assert_eq!(webhook.listen_addr().unwrap(), "127.0.0.1:8443".parse().unwrap());
+        assert_eq!(webhook.public_url().unwrap().path(), "/telegram");
+        assert!(settings.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_insecure_webhook() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.webhook]\nurl = \"http://bot.example.com/\"\nlisten = \"nowhere\"\nsecret_token = \"a b\"\n",
+        );
+        assert_eq!(
+            settings.validate(),
+            vec![
+                "webhook url 'http://bot.example.com/' must use https".to_string(),
+                "webhook listen 'nowhere' must be an address like 127.0.0.1:8443".to_string(),
+                "webhook secret_token must be 1-256 characters of A-Z, a-z, 0-9, _ and -".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_empty_quiet_window() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists.family]\nchats = [3]\n\
+             quiet_hours = { start = \"07:00\", end = \"07:00\", tz = \"UTC\" }\n",
+        );
+        assert_eq!(
+            settings.validate(),
+            vec!["subscriber list 'family' has quiet_hours with equal start and end".to_string()]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_event_types() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             zmq_events_endpoint = \"tcp://*:6570\"\nzmq_events_socket = \"push\"\n\
+             zmq_events = [\"message_failed\", \"message_sent\"]\n",
+        );
+        assert_eq!(settings.zmq_events_socket, EventsSocket::Push);
+        let errors = settings.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("zmq_events: unknown event 'message_sent'"));
+    }
+
+    #[test]
+    fn custom_commands_parse_and_restrict_chats() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.commands.lights_off]\ndescription = \"Turn the lights off\"\n\
+             destination = \"home\"\npayload = { room = \"{args}\", by = \"{user_id}\" }\n\
+             [telegram.commands.deploy_staging]\ndescription = \"Deploy\"\ndestination = \"ci\"\n\
+             allowed_chats = [5]\n",
+        );
+        assert!(settings.validate().is_empty());
+        assert_eq!(settings.commands["lights_off"].payload["room"], "{args}");
+        assert!(settings.command_allowed("lights_off", 1));
+        assert!(!settings.command_allowed("lights_off", 5));
+        assert!(settings.command_allowed("deploy_staging", 5));
+        assert!(!settings.command_allowed("deploy_staging", 1));
+        assert!(!settings.command_allowed("unknown", 1));
+    }
+
+    #[test]
+    fn validate_rejects_builtin_and_malformed_command_names() {
+        let settings = settings_from(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.commands.help]\ndescription = \"Mine\"\ndestination = \"x\"\n\
+             [telegram.commands.Bad-Name]\ndescription = \"\"\ndestination = \"x\"\n",
+        );
+        assert_eq!(
+            settings.validate(),
+            vec![
+                "command 'Bad-Name' must be 1-32 characters of a-z, 0-9 and _".to_string(),
+                "command 'Bad-Name' needs a description of 1-256 characters".to_string(),
+                "command 'help' collides with a built-in command".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Reports of panics, which would otherwise only show up as silence.
+//!
+//! The panic hook writes a report to `last_panic.txt` in the data directory
+//! and makes one attempt to send it to the owners, from a thread and runtime
+//! of its own because the runtime that panicked may be unusable. The attempt
+//! is cut off after a few seconds, so a panic never turns into a hang. The
+//! next startup mentions the report and archives the file.
+
+use crate::config::TelegramSettings;
+use crate::data_dir::DataDir;
+use crate::sink;
+use chrono::Local;
+use log::{error, warn};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fs;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, PanicHookInfo};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use teloxide::prelude::*;
+
+/// How long a panicking thread waits for the report to reach Telegram
+const SEND_TIMEOUT: Duration = Duration::from_secs(3);
+/// Backtrace lines kept in a report
+const BACKTRACE_LINES: usize = 24;
+/// Reports are cut to fit one Telegram message
+const MAX_REPORT_CHARS: usize = 3500;
+
+/// Set while a report is being made; a panic meanwhile is not reported again
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Where the last panic's report is kept until the next startup, if the
+/// data directory can hold it
+pub fn report_path(settings: &TelegramSettings) -> Option<PathBuf> {
+    DataDir::for_settings(settings).file("last_panic.txt", "Panic reports")
+}
+
+/// Report every panic to the owners and to `path`, if any, then carry on
+/// with the default hook
+pub fn install(settings: &TelegramSettings, path: Option<PathBuf>) {
+    let settings = settings.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !REPORTING.swap(true, Ordering::SeqCst) {
+            let report = report(info, &Backtrace::force_capture().to_string());
+            if let Some(path) = &path {
+                let written = fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).and_then(|_| fs::write(path, &report));
+                if let Err(err) = written {
+                    eprintln!("Failed to write {}: {}", path.display(), err);
+                }
+            }
+            send_report(&settings, &report);
+            REPORTING.store(false, Ordering::SeqCst);
+        }
+        default_hook(info);
+    }));
+}
+
+/// The panic's message, location and the top of `backtrace`, headed by a one-line summary
+fn report(info: &PanicHookInfo, backtrace: &str) -> String {
+    let thread = std::thread::current();
+    let location = match info.location() {
+        Some(location) => format!("{}:{}", location.file(), location.line()),
+        None => "an unknown location".to_string(),
+    };
+    format_report(thread.name().unwrap_or("<unnamed>"), &location, payload_message(info.payload()), backtrace)
+}
+
+fn format_report(thread: &str, location: &str, message: &str, backtrace: &str) -> String {
+    let mut report = format!(
+        "Panicked at {} in thread '{}' at {}: {}\n\nBacktrace:\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        thread,
+        location,
+        message
+    );
+    for line in backtrace.lines().take(BACKTRACE_LINES) {
+        report.push_str(line);
+        report.push('\n');
+    }
+    if let Some((cut, _)) = report.char_indices().nth(MAX_REPORT_CHARS) {
+        report.truncate(cut);
+        report.push_str("\n[…]");
+    }
+    report
+}
+
+/// The text a panic was raised with, if it has one
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (None, Some(message)) => message,
+        (None, None) => "(no message)",
+    }
+}
+
+/// Send `report` to the owners with a fresh client on a thread of its own,
+/// giving up after `SEND_TIMEOUT`
+fn send_report(settings: &TelegramSettings, report: &str) {
+    let (done, finished) = mpsc::channel();
+    let owners = settings.owner_chat_ids.clone();
+    let bot = sink::bot_for(settings);
+    let text = format!("corky-telegram crashed\n\n{}", report);
+    let spawned = std::thread::Builder::new().name("panic-report".to_string()).spawn(move || {
+        let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() else {
+            return;
+        };
+        runtime.block_on(async {
+            let sends = async {
+                for owner in owners {
+                    let _ = bot.send_message(ChatId(owner), &text).await;
+                }
+            };
+            let _ = tokio::time::timeout(SEND_TIMEOUT, sends).await;
+        });
+        runtime.shutdown_background();
+        let _ = done.send(());
+    });
+    if spawned.is_ok() && finished.recv_timeout(SEND_TIMEOUT + Duration::from_millis(500)).is_err() {
+        eprintln!("Gave up sending the panic report to the owners");
+    }
+}
+
+/// The summary line of the report left at `path` by the previous run, if
+/// any. The file is archived next to it so it is reported only once.
+pub fn take_last_report(path: &Path) -> Option<String> {
+    let report = fs::read_to_string(path).ok()?;
+    let archived = path.with_file_name(format!("last_panic.{}.txt", Local::now().format("%Y%m%d-%H%M%S")));
+    if let Err(err) = fs::rename(path, &archived) {
+        warn!("Failed to archive {}: {}", path.display(), err);
+    }
+    Some(report.lines().next().unwrap_or_default().to_string())
+}
+
+/// `task`, logging a panic as the end of task `name` rather than leaving it
+/// in a `JoinError` nobody looks at
+pub async fn observed<F: Future<Output = ()>>(name: &str, task: F) {
+    if let Err(panic) = (CatchUnwind { task: Box::pin(task) }).await {
+        error!("!!! Task '{}' panicked and has stopped: {}", name, payload_message(&*panic));
+    }
+}
+
+/// Spawn `observed(name, task)`
+pub fn spawn<F>(name: &'static str, task: F) -> tokio::task::JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(observed(name, task))
+}
+
+/// A future that turns a panic while polling `task` into an error
+struct CatchUnwind<F> {
+    task: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let task = self.task.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| task.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_start_with_a_summary_and_fit_a_message() {
+        let backtrace: String = (0..100).map(|n| format!("  {}: frame\n", n)).collect();
+        let report = format_report("tokio-runtime-worker", "src/sender.rs:42:5", "boom", &backtrace);
+        let summary = report.lines().next().unwrap();
+        assert!(summary.starts_with("Panicked at "));
+        assert!(summary.ends_with("in thread 'tokio-runtime-worker' at src/sender.rs:42:5: boom"));
+        assert!(report.contains("  23: frame\n") && !report.contains("  24: frame"));
+
+        let report = format_report("main", "here", &"x".repeat(10_000), "");
+        assert!(report.chars().count() < MAX_REPORT_CHARS + 10);
+        assert!(report.ends_with("[…]"));
+    }
+
+    #[test]
+    fn last_report_is_taken_once() {
+        let dir = std::env::temp_dir().join(format!("corky-crash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("last_panic.txt");
+        assert_eq!(take_last_report(&path), None);
+        fs::write(&path, "Panicked at noon in thread 'main' at x: boom\n\nBacktrace:\n").unwrap();
+        assert_eq!(take_last_report(&path).as_deref(), Some("Panicked at noon in thread 'main' at x: boom"));
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(take_last_report(&path), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn panicking_tasks_are_observed() {
+        let handle = spawn("test", async { panic!("task failed") });
+        assert!(handle.await.is_ok());
+        assert_eq!(payload_message(&String::from("owned")), "owned");
+    }
+}
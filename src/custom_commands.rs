@@ -0,0 +1,191 @@
+//! Commands defined in the `[telegram.commands]` config table.
+//!
+//! Each one publishes `{"type": "command", ...}` over ZMQ with its
+//! configured destination frame and replies "Sent." to the user. Commands
+//! that are neither built in nor configured fall through to the next handler.
+
+use crate::config::TelegramSettings;
+use crate::events::BotEvent;
+use crate::relay::{event_frames, EventUser};
+use crate::shared_settings::SharedSettings;
+use crate::state::BotState;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::Me;
+use tokio::sync::mpsc;
+
+/// A configured command as typed by a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub name: String,
+    /// Everything after the command, trimmed
+    pub args: String,
+}
+
+/// Payload published for a configured command, tagged `"type": "command"`
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename = "command")]
+pub struct CommandEvent {
+    pub command: String,
+    pub args: String,
+    pub chat_id: i64,
+    pub user: Option<EventUser>,
+    /// The command's payload template with the placeholders filled in
+    pub payload: Value,
+}
+
+/// Match `text` against the configured commands. `/name@bot` only matches
+/// when addressed to this bot.
+pub fn parse_invocation(text: &str, settings: &TelegramSettings, bot_username: &str) -> Option<Invocation> {
+    let rest = text.strip_prefix('/')?;
+    let (head, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let name = match head.split_once('@') {
+        Some((name, bot)) if bot.eq_ignore_ascii_case(bot_username) => name,
+        Some(_) => return None,
+        None => head,
+    };
+    settings
+        .commands
+        .contains_key(name)
+        .then(|| Invocation { name: name.to_string(), args: args.trim().to_string() })
+}
+
+/// dptree filter: the configured command in `msg`, if any
+pub fn invocation(msg: Message, shared: SharedSettings, me: Me) -> Option<Invocation> {
+    parse_invocation(msg.text()?, &shared.snapshot(), me.username())
+}
+
+/// Fill the placeholders in `template`. A string that is exactly
+/// `{chat_id}` or `{user_id}` becomes a number; elsewhere values are
+/// substituted as text.
+pub fn fill(template: &Value, chat_id: i64, user: Option<&EventUser>, args: &str) -> Value {
+    match template {
+        Value::String(s) => match s.as_str() {
+            "{chat_id}" => Value::from(chat_id),
+            "{user_id}" => user.map(|u| Value::from(u.id)).unwrap_or(Value::Null),
+            _ => Value::String(
+                s.replace("{chat_id}", &chat_id.to_string())
+                    .replace("{user_id}", &user.map(|u| u.id.to_string()).unwrap_or_default())
+                    .replace("{username}", user.and_then(|u| u.username.as_deref()).unwrap_or_default())
+                    .replace("{args}", args),
+            ),
+        },
+        Value::Array(items) => Value::Array(items.iter().map(|v| fill(v, chat_id, user, args)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), fill(v, chat_id, user, args)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Build the event for `invocation` sent from `msg`
+pub fn command_event(invocation: &Invocation, template: &Value, msg: &Message) -> CommandEvent {
+    let user = msg.from.as_ref().map(EventUser::from);
+    CommandEvent {
+        command: invocation.name.clone(),
+        args: invocation.args.clone(),
+        chat_id: msg.chat.id.0,
+        payload: fill(template, msg.chat.id.0, user.as_ref(), &invocation.args),
+        user,
+    }
+}
+
+/// Publish a configured command over ZMQ and tell the user
+pub async fn handle(
+    bot: Bot,
+    msg: Message,
+    invocation: Invocation,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+    outbound: mpsc::Sender<Vec<Vec<u8>>>,
+) -> ResponseResult<()> {
+    let settings = shared.snapshot();
+    let Some(command) = settings.commands.get(&invocation.name) else {
+        return Ok(());
+    };
+    state.events.publish(BotEvent::CommandInvoked {
+        command: invocation.name.clone(),
+        chat_id: msg.chat.id.0,
+        user_id: msg.from.as_ref().map(|user| user.id.to_string()).unwrap_or_default(),
+    });
+    let language = settings.language_for(msg.chat.id.0, msg.from.as_ref().and_then(|user| user.language_code.as_deref()));
+    let texts = settings.translations.texts(&language);
+    if !settings.command_allowed(&invocation.name, msg.chat.id.0) {
+        warn!("Refusing /{} from chat {} not in its allowed_chats", invocation.name, msg.chat.id);
+        bot.send_message(msg.chat.id, texts.text("not_allowed", &[])).await?;
+        return Ok(());
+    }
+    let event = command_event(&invocation, &command.payload, &msg);
+    let reply = match outbound.try_send(event_frames(&command.destination, &event)) {
+        Ok(()) => {
+            info!("Published /{} from chat {} to '{}'", invocation.name, msg.chat.id, command.destination);
+            texts.text("command_sent", &[])
+        }
+        Err(err) => {
+            warn!("Failed to queue /{} for ZMQ: {}", invocation.name, err);
+            texts.text("command_failed", &[])
+        }
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    fn settings() -> TelegramSettings {
+        toml::from_str::<AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 100\n\
+             [telegram.commands.lights_off]\ndescription = \"Lights off\"\ndestination = \"home\"\n\
+             payload = { action = \"off\", room = \"{args}\", chat = \"{chat_id}\", note = \"by @{username}\" }\n",
+        )
+        .unwrap()
+        .telegram
+    }
+
+    fn message(text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+            "from": { "id": 7, "is_bot": false, "first_name": "Ann", "username": "ann" },
+            "text": text
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn configured_commands_match_with_args() {
+        let settings = settings();
+        let got = parse_invocation("/lights_off  kitchen hall ", &settings, "corky_bot").unwrap();
+        assert_eq!(got, Invocation { name: "lights_off".to_string(), args: "kitchen hall".to_string() });
+        assert!(parse_invocation("/lights_off@Corky_Bot", &settings, "corky_bot").is_some());
+        assert!(parse_invocation("/lights_off@other_bot", &settings, "corky_bot").is_none());
+        assert!(parse_invocation("/lights_on", &settings, "corky_bot").is_none());
+        assert!(parse_invocation("lights_off", &settings, "corky_bot").is_none());
+    }
+
+    #[test]
+    fn event_fills_the_template() {
+        let settings = settings();
+        let msg = message("/lights_off kitchen");
+        let invocation = parse_invocation(msg.text().unwrap(), &settings, "corky_bot").unwrap();
+        let event = command_event(&invocation, &settings.commands["lights_off"].payload, &msg);
+        let json: serde_json::Value = serde_json::from_slice(&event_frames("home", &event)[1]).unwrap();
+        assert_eq!(json["type"], "command");
+        assert_eq!(json["command"], "lights_off");
+        assert_eq!(json["args"], "kitchen");
+        assert_eq!(json["user"]["id"], 7);
+        assert_eq!(
+            json["payload"],
+            serde_json::json!({ "action": "off", "room": "kitchen", "chat": 100, "note": "by @ann" })
+        );
+    }
+}
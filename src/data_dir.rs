@@ -0,0 +1,138 @@
+//! The directory persisted state is kept in.
+//!
+//! It is `data_dir` when set (or `CORKY_DATA_DIR`, or `--data-dir`), else
+//! ~/.corky. A missing directory is created. When there is none, because
+//! there is no home directory, or it cannot be written, as on a read-only
+//! filesystem, each feature that keeps a file there falls back to memory
+//! and says so with a warning of its own, instead of the bot refusing to
+//! start.
+
+use crate::config::{self, TelegramSettings};
+use log::warn;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file written and removed to check the directory is writable
+const PROBE: &str = ".corky-write-test";
+
+/// A resolved data directory, usable or not
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    dir: Result<PathBuf, String>,
+}
+
+impl DataDir {
+    /// The data directory `settings` name, or ~/.corky
+    pub fn for_settings(settings: &TelegramSettings) -> Self {
+        DataDir::open(settings.data_dir.as_deref())
+    }
+
+    /// `configured`, or ~/.corky when it is `None`, created if missing and
+    /// checked for writing
+    pub fn open(configured: Option<&Path>) -> Self {
+        let dir = match configured {
+            Some(dir) => Ok(dir.to_path_buf()),
+            None => config::corky_dir().map_err(|err| format!("{} and no data_dir is set", err)),
+        };
+        DataDir { dir: dir.and_then(|dir| usable(&dir).map(|()| dir)) }
+    }
+
+    /// The directory, if state can be kept in it
+    pub fn path(&self) -> Option<&Path> {
+        self.dir.as_deref().ok()
+    }
+
+    /// Why state cannot be kept in the directory, if it cannot
+    pub fn problem(&self) -> Option<&str> {
+        self.dir.as_ref().err().map(String::as_str)
+    }
+
+    /// Path of the file `name` for `feature`, or `None` after warning that
+    /// `feature` will be kept in memory only
+    pub fn file(&self, name: &str, feature: &str) -> Option<PathBuf> {
+        match &self.dir {
+            Ok(dir) => Some(dir.join(name)),
+            Err(problem) => {
+                warn!("{} will not survive a restart: {}", feature, problem);
+                None
+            }
+        }
+    }
+}
+
+/// Replace `path` with `contents` by writing a temporary file beside it and
+/// renaming it over `path`, so a crash never leaves half a file behind
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Create `dir` if missing and check a file can be written in it
+fn usable(dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let probe = dir.join(PROBE);
+    fs::write(&probe, b"")
+        .and_then(|()| fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {}", dir.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("corky-data-dir-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn an_explicit_directory_is_used_and_created() {
+        let root = temp_dir("missing");
+        let dir = root.join("nested").join("state");
+        let data = DataDir::open(Some(&dir));
+        assert_eq!(data.path(), Some(dir.as_path()));
+        assert!(dir.is_dir());
+        assert_eq!(data.file("mutes.json", "Mutes"), Some(dir.join("mutes.json")));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0, "the probe file is removed");
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn a_directory_that_cannot_be_created_disables_files() {
+        let root = temp_dir("blocked");
+        fs::create_dir_all(&root).unwrap();
+        let blocker = root.join("not-a-dir");
+        fs::write(&blocker, "").unwrap();
+        let data = DataDir::open(Some(&blocker.join("state")));
+        assert_eq!(data.path(), None);
+        assert!(data.problem().unwrap().starts_with("cannot create"));
+        assert_eq!(data.file("mutes.json", "Mutes"), None);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_read_only_directory_disables_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("read-only");
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        let data = DataDir::open(Some(&dir));
+        // Root writes through the permissions; /proc refuses everyone
+        let data = match data.path() {
+            Some(_) if Path::new("/proc/self").is_dir() => DataDir::open(Some(Path::new("/proc"))),
+            _ => data,
+        };
+        if data.path().is_none() {
+            assert!(data.problem().unwrap().contains("is not writable"));
+            assert_eq!(data.file("quarantine.json", "Quarantine"), None);
+        }
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,151 @@
+//! Suppression of identical messages repeated to the same chat.
+//!
+//! Protects chats from producers stuck in a loop: the first copy is sent,
+//! repeats within `suppress_duplicates_secs` are dropped and counted, and
+//! once the window closes the count can be reported with one summary.
+
+use crate::file_ids::FileKey;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Windows tracked at once; beyond this the oldest is closed early
+const MAX_ENTRIES: usize = 10_000;
+
+/// Hash of a message's text and attachment
+pub fn content_key(text: &str, attachment: Option<FileKey>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    attachment.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Window {
+    opened_at: Instant,
+    repeats: u32,
+    /// Start of the text, for the summary
+    preview: String,
+}
+
+/// A window that closed after suppressing repeats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Closed {
+    pub chat_id: i64,
+    pub repeats: u32,
+    pub preview: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    windows: HashMap<(i64, u64), Window>,
+    /// Closed windows with repeats, waiting for `take_closed`
+    closed: Vec<(Instant, Closed)>,
+}
+
+impl Inner {
+    fn close(&mut self, chat_id: i64, window: Window) {
+        if window.repeats > 0 {
+            self.closed.push((window.opened_at, Closed { chat_id, repeats: window.repeats, preview: window.preview }));
+        }
+    }
+}
+
+/// Recently sent (chat, content) pairs
+pub struct Duplicates {
+    window: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Duplicates {
+    /// Suppress repeats within `window`; zero disables suppression
+    pub fn new(window: Duration) -> Self {
+        Duplicates { window, inner: Mutex::new(Inner::default()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Whether sending `content` to `chat_id` now repeats a message sent
+    /// within the window. Repeats are counted; anything else opens a window.
+    pub fn is_repeat(&self, chat_id: i64, content: u64, preview: &str, now: Instant) -> Option<u32> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (chat_id, content);
+        if let Some(window) = inner.windows.get_mut(&key) {
+            if now.saturating_duration_since(window.opened_at) < self.window {
+                window.repeats += 1;
+                return Some(window.repeats);
+            }
+        }
+        match inner.windows.remove(&key) {
+            Some(closed) => inner.close(chat_id, closed),
+            None if inner.windows.len() >= MAX_ENTRIES => {
+                let oldest = inner.windows.iter().min_by_key(|(_, w)| w.opened_at).map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    let evicted = inner.windows.remove(&oldest).unwrap();
+                    inner.close(oldest.0, evicted);
+                }
+            }
+            None => {}
+        }
+        inner.windows.insert(key, Window { opened_at: now, repeats: 0, preview: preview.to_string() });
+        None
+    }
+
+    /// Windows that have closed by `now` after suppressing something,
+    /// oldest first
+    pub fn take_closed(&self, now: Instant) -> Vec<Closed> {
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<(i64, u64)> = inner
+            .windows
+            .iter()
+            .filter(|(_, w)| now.saturating_duration_since(w.opened_at) >= self.window)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            let window = inner.windows.remove(&key).unwrap();
+            inner.close(key.0, window);
+        }
+        let mut closed = std::mem::take(&mut inner.closed);
+        closed.sort_by_key(|(opened_at, _)| *opened_at);
+        closed.into_iter().map(|(_, closed)| closed).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_within_the_window_are_counted() {
+        let duplicates = Duplicates::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let alert = content_key("disk full", None);
+        assert_eq!(duplicates.is_repeat(1, alert, "disk full", start), None);
+        assert_eq!(duplicates.is_repeat(1, alert, "disk full", start + Duration::from_secs(1)), Some(1));
+        assert_eq!(duplicates.is_repeat(1, alert, "disk full", start + Duration::from_secs(59)), Some(2));
+        // Other chats and other content are independent
+        assert_eq!(duplicates.is_repeat(2, alert, "disk full", start), None);
+        assert_eq!(duplicates.is_repeat(1, content_key("disk full", Some(crate::file_ids::key_for_bytes(b"png"))), "disk full", start), None);
+
+        assert!(duplicates.take_closed(start + Duration::from_secs(59)).is_empty());
+        let closed = duplicates.take_closed(start + Duration::from_secs(60));
+        assert_eq!(closed, vec![Closed { chat_id: 1, repeats: 2, preview: "disk full".to_string() }]);
+        assert_eq!(duplicates.is_repeat(1, alert, "disk full", start + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn closed_windows_are_not_repeats() {
+        let duplicates = Duplicates::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let key = content_key("x", None);
+        duplicates.is_repeat(1, key, "x", start);
+        duplicates.is_repeat(1, key, "x", start + Duration::from_secs(1));
+        assert_eq!(duplicates.is_repeat(1, key, "x", start + Duration::from_secs(10)), None);
+        // The old window's summary is kept and the copy opened a new window
+        assert_eq!(duplicates.is_repeat(1, key, "x", start + Duration::from_secs(11)), Some(1));
+        assert_eq!(duplicates.take_closed(start + Duration::from_secs(11)).len(), 1);
+    }
+}
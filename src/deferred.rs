@@ -0,0 +1,185 @@
+//! Broadcasts held back by quiet hours until their list's window ends.
+//!
+//! Held messages are persisted so a restart during the night does not lose
+//! them. Messages whose TTL runs out while held are dropped. The spool keeps
+//! messages the same way while the bot token is refused.
+
+use crate::zmq_listener::ZmqMessage;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A broadcast waiting for quiet hours to end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldMessage {
+    pub release_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    pub message: ZmqMessage,
+}
+
+impl HeldMessage {
+    /// Whether the message's TTL ran out before `now`
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        match self.message.ttl {
+            Some(ttl) => {
+                let ttl = Duration::try_seconds(ttl as i64).unwrap_or(Duration::MAX);
+                self.received_at.checked_add_signed(ttl).is_some_and(|deadline| deadline <= now)
+            }
+            None => false,
+        }
+    }
+}
+
+/// On-disk representation
+#[derive(Serialize, Deserialize, Default)]
+struct DeferredFile {
+    messages: Vec<HeldMessage>,
+}
+
+/// Hold queue of deferred broadcasts
+pub struct Deferred {
+    path: Option<PathBuf>,
+    messages: Mutex<Vec<HeldMessage>>,
+}
+
+impl Deferred {
+    /// Hold queue kept only in memory
+    pub fn new() -> Self {
+        Deferred {
+            path: None,
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Hold queue persisted at `path`, loading any previous state
+    pub fn load(path: PathBuf) -> Self {
+        let messages = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<DeferredFile>(&contents) {
+                Ok(file) => file.messages,
+                Err(err) => {
+                    error!("Ignoring unreadable deferred file {}: {}", path.display(), err);
+                    Vec::new()
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        Deferred {
+            path: Some(path),
+            messages: Mutex::new(messages),
+        }
+    }
+
+    /// Hold `message` until `release_at`
+    pub fn hold(&self, message: ZmqMessage, received_at: DateTime<Utc>, release_at: DateTime<Utc>) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push(HeldMessage { release_at, received_at, message });
+        self.save(&messages);
+    }
+
+    /// Remove and return messages due at `now`, oldest first
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<HeldMessage> {
+        let mut messages = self.messages.lock().unwrap();
+        let (due, held): (Vec<_>, Vec<_>) = messages.drain(..).partition(|m| m.release_at <= now);
+        *messages = held;
+        if !due.is_empty() {
+            self.save(&messages);
+        }
+        due
+    }
+
+    /// Remove and return the message held longest
+    pub fn take_oldest(&self) -> Option<HeldMessage> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.is_empty() {
+            return None;
+        }
+        let oldest = messages.remove(0);
+        self.save(&messages);
+        Some(oldest)
+    }
+
+    /// Hold `held` again, ahead of everything else
+    pub fn put_back(&self, held: HeldMessage) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.insert(0, held);
+        self.save(&messages);
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn save(&self, messages: &[HeldMessage]) {
+        let Some(path) = &self.path else { return };
+        let file = DeferredFile { messages: messages.to_vec() };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist deferred messages to {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl Default for Deferred {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str, ttl: Option<u64>) -> ZmqMessage {
+        serde_json::from_value(serde_json::json!({
+            "subscriber_list": "family", "text": text, "ttl": ttl
+        }))
+        .unwrap()
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn releases_only_due_messages() {
+        let deferred = Deferred::new();
+        deferred.hold(message("a", None), at(0), at(100));
+        deferred.hold(message("b", None), at(0), at(200));
+        assert!(deferred.take_due(at(99)).is_empty());
+        let due = deferred.take_due(at(150));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].message.text, "a");
+        assert_eq!(deferred.len(), 1);
+    }
+
+    #[test]
+    fn ttl_counts_from_receipt() {
+        let held = HeldMessage { release_at: at(500), received_at: at(0), message: message("a", Some(60)) };
+        assert!(!held.expired(at(59)));
+        assert!(held.expired(at(60)));
+        let held = HeldMessage { message: message("a", None), ..held };
+        assert!(!held.expired(at(i32::MAX as i64)));
+    }
+
+    #[test]
+    fn held_messages_persist() {
+        let path = std::env::temp_dir().join(format!("corky-deferred-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Deferred::load(path.clone()).hold(message("night", Some(3600)), at(0), at(100));
+        let deferred = Deferred::load(path.clone());
+        let due = deferred.take_due(at(100));
+        assert_eq!(due[0].message.text, "night");
+        assert_eq!(due[0].message.ttl, Some(3600));
+        assert!(Deferred::load(path.clone()).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
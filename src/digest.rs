@@ -0,0 +1,247 @@
+//! Periodic summaries for subscriber lists in digest mode.
+//!
+//! Normal-priority broadcasts to a list with a `digest_interval` are
+//! buffered here and turned into one timestamped message per interval.
+//! Attachments are kept and sent individually right after the digest text.
+
+use crate::sender::TELEGRAM_MAX_MESSAGE_CHARS;
+use crate::html;
+use crate::trace;
+use crate::zmq_listener::{ParseMode, ZmqMessage};
+use chrono::{DateTime, Duration, Local, Utc};
+use log::info;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Characters allowed for a digest's entries, leaving room for its header
+const MAX_ENTRY_CHARS: usize = TELEGRAM_MAX_MESSAGE_CHARS - 100;
+
+struct Buffer {
+    opened_at: DateTime<Utc>,
+    interval: Duration,
+    entries: Vec<String>,
+    chars: usize,
+    attachments: Vec<ZmqMessage>,
+    /// Trace IDs of the buffered messages, logged against the digest's own
+    traces: Vec<String>,
+}
+
+/// A finished digest, ready for the outbox
+#[derive(Debug)]
+pub struct Digest {
+    pub list: String,
+    /// The combined text, addressed to the list
+    pub summary: ZmqMessage,
+    /// Messages with attachments, in arrival order
+    pub attachments: Vec<ZmqMessage>,
+}
+
+impl Digest {
+    /// The summary followed by the attachments
+    pub fn into_messages(self) -> Vec<ZmqMessage> {
+        std::iter::once(self.summary).chain(self.attachments).collect()
+    }
+}
+
+/// Open digest buffers keyed by list name
+#[derive(Default)]
+pub struct Digests {
+    buffers: Mutex<HashMap<String, Buffer>>,
+}
+
+impl Digests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer a message for `list`. Returns the previous digest if adding
+    /// this entry would have made it too long for one message.
+    pub fn add(&self, list: &str, interval: Duration, message: ZmqMessage, now: DateTime<Utc>) -> Option<Digest> {
+        // Digests are plain text, so HTML entries are shown as Telegram would render them
+        let text = match message.parse_mode {
+            Some(ParseMode::Html) => html::plain_text(&message.text),
+            None => message.text.clone(),
+        };
+        let entry = match message.has_image() {
+            true => format!("[{}] {} (attachment follows)", now.with_timezone(&Local).format("%H:%M"), text),
+            false => format!("[{}] {}", now.with_timezone(&Local).format("%H:%M"), text),
+        };
+        let entry_chars = entry.chars().count() + 1;
+        let mut buffers = self.buffers.lock().unwrap();
+        let full = match buffers.get(list) {
+            Some(buffer) if buffer.chars + entry_chars > MAX_ENTRY_CHARS => buffers.remove(list).map(|b| finish(list, b)),
+            _ => None,
+        };
+        let buffer = buffers.entry(list.to_string()).or_insert_with(|| Buffer {
+            opened_at: now,
+            interval,
+            entries: Vec::new(),
+            chars: 0,
+            attachments: Vec::new(),
+            traces: Vec::new(),
+        });
+        buffer.entries.push(entry);
+        buffer.traces.extend(message.trace_id.clone());
+        buffer.chars += entry_chars;
+        if message.has_image() {
+            buffer.attachments.push(message);
+        }
+        full
+    }
+
+    /// Digests whose interval has elapsed
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<Digest> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let mut due: Vec<String> = buffers
+            .iter()
+            .filter(|(_, b)| b.opened_at + b.interval <= now)
+            .map(|(list, _)| list.clone())
+            .collect();
+        due.sort();
+        due.into_iter()
+            .filter_map(|list| buffers.remove(&list).map(|b| finish(&list, b)))
+            .collect()
+    }
+
+    /// The digest for `list` right away, if anything is buffered
+    pub fn take(&self, list: &str) -> Option<Digest> {
+        self.buffers.lock().unwrap().remove(list).map(|b| finish(list, b))
+    }
+
+    /// Every buffered digest (used on shutdown)
+    pub fn take_all(&self) -> Vec<Digest> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let mut lists: Vec<String> = buffers.keys().cloned().collect();
+        lists.sort();
+        lists
+            .into_iter()
+            .filter_map(|list| buffers.remove(&list).map(|b| finish(&list, b)))
+            .collect()
+    }
+
+    /// Buffered entry count per list, sorted by name
+    pub fn pending(&self) -> Vec<(String, usize)> {
+        let buffers = self.buffers.lock().unwrap();
+        let mut pending: Vec<_> = buffers.iter().map(|(list, b)| (list.clone(), b.entries.len())).collect();
+        pending.sort();
+        pending
+    }
+}
+
+fn finish(list: &str, buffer: Buffer) -> Digest {
+    let header = format!(
+        "Digest for '{}': {} message(s) since {}",
+        list,
+        buffer.entries.len(),
+        buffer.opened_at.with_timezone(&Local).format("%H:%M")
+    );
+    let summary = ZmqMessage {
+        chat_ids: Vec::new(),
+        chat_id: None,
+        chat: None,
+        subscriber_list: Some(list.to_string()),
+        text: std::iter::once(header).chain(buffer.entries).collect::<Vec<_>>().join("\n"),
+        image_path: None,
+        image_frame: false,
+        image_bytes: None,
+        summary: None,
+        ttl: None,
+        priority: Default::default(),
+        id: None,
+        protect_content: None,
+        spoiler: None,
+        disable_link_preview: None,
+        parse_mode: None,
+        delete_after_send: None,
+        max_retries: None,
+        retry_base_delay_ms: None,
+        peer: None,
+        tags: None,
+        trace_id: Some(trace::generate()),
+    };
+    info!(
+        "Digest for '{}' trace_id={} covers trace_ids [{}]",
+        list,
+        summary.trace_id.as_deref().unwrap_or_default(),
+        buffer.traces.join(", ")
+    );
+    Digest { list: list.to_string(), summary, attachments: buffer.attachments }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(text: &str) -> ZmqMessage {
+        serde_json::from_value(serde_json::json!({ "text": text, "subscriber_list": "metrics" })).unwrap()
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn digest_is_due_after_interval() {
+        let digests = Digests::new();
+        let interval = Duration::minutes(30);
+        assert!(digests.add("metrics", interval, message("cpu 40%"), at(0)).is_none());
+        assert!(digests.add("metrics", interval, message("cpu 45%"), at(10)).is_none());
+        assert!(digests.take_due(at(29)).is_empty());
+        let digest = digests.take_due(at(30)).pop().unwrap();
+        let lines: Vec<&str> = digest.summary.text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Digest for 'metrics': 2 message(s) since "));
+        assert!(lines[1].ends_with("] cpu 40%"));
+        assert!(lines[2].ends_with("] cpu 45%"));
+        assert_eq!(digest.summary.subscriber_list.as_deref(), Some("metrics"));
+        assert!(digests.pending().is_empty());
+    }
+
+    #[test]
+    fn attachments_follow_the_summary() {
+        let digests = Digests::new();
+        let mut photo = message("graph");
+        photo.image_path = Some("/tmp/graph.png".to_string());
+        digests.add("metrics", Duration::minutes(5), message("a"), at(0));
+        digests.add("metrics", Duration::minutes(5), photo, at(1));
+        let messages = digests.take("metrics").unwrap().into_messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].text.ends_with("] graph (attachment follows)"));
+        assert_eq!(messages[1].image_path.as_deref(), Some("/tmp/graph.png"));
+        assert!(digests.take("metrics").is_none());
+    }
+
+    #[test]
+    fn full_buffer_is_flushed_early() {
+        let digests = Digests::new();
+        let line = "x".repeat(1000);
+        let mut flushed = Vec::new();
+        for minute in 0..6 {
+            flushed.extend(digests.add("metrics", Duration::hours(1), message(&line), at(minute)));
+        }
+        assert_eq!(flushed.len(), 1);
+        assert!(flushed[0].summary.text.chars().count() <= TELEGRAM_MAX_MESSAGE_CHARS);
+        assert_eq!(digests.pending(), vec![("metrics".to_string(), 3)]);
+    }
+
+    #[test]
+    fn html_entries_are_shown_as_plain_text() {
+        let digests = Digests::new();
+        let mut formatted = message("<b>cpu</b> &lt; 50%");
+        formatted.parse_mode = Some(ParseMode::Html);
+        digests.add("metrics", Duration::minutes(5), formatted, at(0));
+        let digest = digests.take("metrics").unwrap();
+        assert!(digest.summary.text.ends_with("] cpu < 50%"));
+    }
+
+    #[test]
+    fn take_all_empties_every_buffer() {
+        let digests = Digests::new();
+        digests.add("b", Duration::hours(1), message("1"), at(0));
+        digests.add("a", Duration::hours(1), message("2"), at(0));
+        let lists: Vec<String> = digests.take_all().into_iter().map(|d| d.list).collect();
+        assert_eq!(lists, vec!["a", "b"]);
+        assert!(digests.pending().is_empty());
+    }
+}
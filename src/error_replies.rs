@@ -0,0 +1,219 @@
+//! Error replies to producers whose payloads could not be parsed, and
+//! acknowledgements of control actions.
+//!
+//! The reply goes back over the DEALER socket addressed to the frame that
+//! identifies the sender, as `{"type": "error", "reason": ..., ...}`. Each
+//! peer gets at most one reply per interval; replies suppressed in between
+//! are counted in the next one. Every control action is answered with
+//! `{"type": "ack", "action": ..., "ok": ..., "detail": ...}`, which is not
+//! rate limited and is sent even when error replies are turned off.
+
+use crate::zmq_listener::{EnvelopeLayout, ParseError};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Bytes of the offending payload echoed back
+pub const ECHO_BYTES: usize = 512;
+
+/// Peers remembered before idle ones are forgotten
+const MAX_PEERS: usize = 1024;
+
+/// Payload sent back for a control action
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename = "ack")]
+pub struct ControlAck {
+    /// The `"action"` of the request, e.g. `list_add`
+    pub action: &'static str,
+    /// Whether the action was carried out
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Payload sent back for a message that could not be parsed
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename = "error")]
+pub struct ErrorEvent {
+    /// Machine-readable code such as `BAD_JSON`, see `ParseError::reason`
+    pub reason: &'static str,
+    pub detail: String,
+    /// Start of the payload frame, lossily decoded
+    pub echo: String,
+    /// Replies not sent to this peer since the previous one
+    pub suppressed: u32,
+}
+
+struct Peer {
+    last_reply: Instant,
+    suppressed: u32,
+}
+
+/// Rate-limited error replies, owned by the event loop
+pub struct ErrorReplies {
+    outbound: Option<mpsc::Sender<Vec<Vec<u8>>>>,
+    /// Whether parse errors are answered, not only control actions
+    report_errors: bool,
+    min_interval: Duration,
+    peers: HashMap<Vec<u8>, Peer>,
+}
+
+impl ErrorReplies {
+    /// Replies over `outbound`, at most one per peer every `min_interval`
+    pub fn new(outbound: mpsc::Sender<Vec<Vec<u8>>>, min_interval: Duration) -> Self {
+        ErrorReplies { outbound: Some(outbound), report_errors: true, min_interval, peers: HashMap::new() }
+    }
+
+    /// Acknowledges control actions over `outbound` but leaves parse errors
+    /// unanswered
+    pub fn acks_only(outbound: mpsc::Sender<Vec<Vec<u8>>>) -> Self {
+        ErrorReplies { outbound: Some(outbound), report_errors: false, min_interval: Duration::ZERO, peers: HashMap::new() }
+    }
+
+    pub fn disabled() -> Self {
+        ErrorReplies { outbound: None, report_errors: false, min_interval: Duration::ZERO, peers: HashMap::new() }
+    }
+
+    /// Send `ack` to the sender of the control action in `frames`, if it can
+    /// be identified
+    pub fn acknowledge(&self, frames: &[Vec<u8>], layout: &EnvelopeLayout, ack: &ControlAck) {
+        let (Some(outbound), Some(peer)) = (&self.outbound, layout.peer(frames)) else {
+            return;
+        };
+        let payload = serde_json::to_vec(ack).expect("acks always serialize");
+        if let Err(err) = outbound.try_send(vec![peer.clone(), payload]) {
+            warn!("Failed to queue control ack for ZMQ: {}", err);
+        }
+    }
+
+    /// Tell the sender of `frames` why they were rejected, unless that peer
+    /// was answered too recently or cannot be identified
+    pub fn report(&mut self, frames: &[Vec<u8>], layout: &EnvelopeLayout, err: &ParseError, now: Instant) {
+        let Some(outbound) = self.outbound.as_ref().filter(|_| self.report_errors) else {
+            return;
+        };
+        // Without an identity frame there is nobody to answer
+        let Some(peer) = layout.peer(frames) else {
+            return;
+        };
+        if self.peers.len() >= MAX_PEERS {
+            let min_interval = self.min_interval;
+            self.peers.retain(|_, p| now.duration_since(p.last_reply) < min_interval);
+        }
+        let suppressed = match self.peers.get_mut(peer) {
+            Some(state) if now.duration_since(state.last_reply) < self.min_interval => {
+                state.suppressed += 1;
+                debug!("Not replying to {:?} again yet ({} suppressed)", String::from_utf8_lossy(peer), state.suppressed);
+                return;
+            }
+            Some(state) => std::mem::take(&mut state.suppressed),
+            None => 0,
+        };
+        self.peers.insert(peer.clone(), Peer { last_reply: now, suppressed: 0 });
+        let event = error_event(frames, layout, err, suppressed);
+        let payload = serde_json::to_vec(&event).expect("error events always serialize");
+        if let Err(err) = outbound.try_send(vec![peer.clone(), payload]) {
+            warn!("Failed to queue error reply for ZMQ: {}", err);
+        }
+    }
+}
+
+/// The reply describing `err` for the message in `frames`
+pub fn error_event(frames: &[Vec<u8>], layout: &EnvelopeLayout, err: &ParseError, suppressed: u32) -> ErrorEvent {
+    let payload = frames.get(layout.payload_index()).map(Vec::as_slice).unwrap_or_default();
+    let echo = String::from_utf8_lossy(&payload[..payload.len().min(ECHO_BYTES)]).into_owned();
+    ErrorEvent { reason: err.reason(), detail: err.to_string(), echo, suppressed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zmq_listener::parse_command;
+
+    fn rejected(payload: &[u8]) -> (Vec<Vec<u8>>, ParseError) {
+        let frames = vec![b"producer".to_vec(), payload.to_vec()];
+        let err = parse_command(&frames, &EnvelopeLayout::default()).unwrap_err();
+        (frames, err)
+    }
+
+    #[test]
+    fn reasons_are_machine_readable() {
+        let layout = EnvelopeLayout::default();
+        let cases: [(&[u8], &str); 5] = [
+            (br#"["ok", "send_message"]"#, "SHORT_ENVELOPE"),
+            (b"{not json", "BAD_JSON"),
+            (br#"["ok", "send_message", {"chat_id": 1}]"#, "MISSING_FIELD"),
+            (&[0xff, 0xfe], "NOT_UTF8"),
+            (br#"["ok", "send_message", {"text": 5}]"#, "INVALID_COMMAND"),
+        ];
+        for (payload, reason) in cases {
+            let (frames, err) = rejected(payload);
+            assert_eq!(error_event(&frames, &layout, &err, 0).reason, reason, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn echo_is_truncated() {
+        let (frames, err) = rejected("x".repeat(2000).as_bytes());
+        let event = error_event(&frames, &EnvelopeLayout::default(), &err, 0);
+        assert_eq!(event.echo.len(), ECHO_BYTES);
+        let json: serde_json::Value = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "error");
+    }
+
+    #[test]
+    fn replies_are_rate_limited_per_peer() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut replies = ErrorReplies::new(tx, Duration::from_secs(10));
+        let layout = EnvelopeLayout::default();
+        let (frames, err) = rejected(b"{bad");
+        let other = vec![b"other".to_vec(), b"{bad".to_vec()];
+        let t0 = Instant::now();
+        replies.report(&frames, &layout, &err, t0);
+        replies.report(&frames, &layout, &err, t0 + Duration::from_secs(1));
+        replies.report(&frames, &layout, &err, t0 + Duration::from_secs(2));
+        replies.report(&other, &layout, &err, t0 + Duration::from_secs(2));
+        replies.report(&frames, &layout, &err, t0 + Duration::from_secs(10));
+
+        let sent: Vec<(Vec<u8>, serde_json::Value)> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|frames| (frames[0].clone(), serde_json::from_slice(&frames[1]).unwrap()))
+            .collect();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].0, b"producer");
+        assert_eq!(sent[1].0, b"other");
+        assert_eq!(sent[2].1["suppressed"], 2);
+    }
+
+    #[test]
+    fn bare_payloads_have_nobody_to_answer() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut replies = ErrorReplies::new(tx, Duration::from_secs(10));
+        let layout = EnvelopeLayout { payload_frame: 0, ..EnvelopeLayout::default() };
+        let frames = vec![b"{bad".to_vec()];
+        let err = parse_command(&frames, &layout).unwrap_err();
+        replies.report(&frames, &layout, &err, Instant::now());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn acks_are_sent_without_error_replies() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut replies = ErrorReplies::acks_only(tx);
+        let layout = EnvelopeLayout::default();
+        let (frames, err) = rejected(b"{bad");
+        replies.report(&frames, &layout, &err, Instant::now());
+        assert!(rx.try_recv().is_err());
+        let ack = ControlAck { action: "list_add", ok: true, detail: "Added chat 5 to list 'ops' (2 chats)".into() };
+        replies.acknowledge(&frames, &layout, &ack);
+        replies.acknowledge(&frames, &layout, &ack);
+        let sent: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(sent.len(), 2, "acks are not rate limited");
+        assert_eq!(sent[0][0], b"producer");
+        let json: serde_json::Value = serde_json::from_slice(&sent[0][1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "type": "ack", "action": "list_add", "ok": true, "detail": "Added chat 5 to list 'ops' (2 chats)" })
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Classification of send failures into transient and permanent categories.
+
+use std::fmt;
+use std::time::Duration;
+use teloxide::{ApiError, RequestError};
+
+/// Why a send attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ErrorCategory {
+    /// Connection problems talking to the Bot API
+    Network,
+    /// No response within our own deadline
+    Timeout,
+    /// Telegram asked us to slow down (HTTP 429)
+    FloodWait,
+    /// Telegram-side failure (5xx or an unparseable response)
+    ServerError,
+    /// The recipient blocked, kicked, or cannot be contacted by the bot
+    Blocked,
+    /// The chat or user does not exist
+    ChatNotFound,
+    /// The group was upgraded to a supergroup with a new chat id
+    ChatMigrated,
+    /// The bot token was rejected
+    Unauthorized,
+    /// Telegram rejected the request itself (other 4xx)
+    BadRequest,
+    /// A local file could not be read for upload
+    LocalIo,
+}
+
+impl ErrorCategory {
+    /// Every category, in display order
+    pub const ALL: [ErrorCategory; 10] = [
+        ErrorCategory::Network,
+        ErrorCategory::Timeout,
+        ErrorCategory::FloodWait,
+        ErrorCategory::ServerError,
+        ErrorCategory::Blocked,
+        ErrorCategory::ChatNotFound,
+        ErrorCategory::ChatMigrated,
+        ErrorCategory::Unauthorized,
+        ErrorCategory::BadRequest,
+        ErrorCategory::LocalIo,
+    ];
+
+    /// Whether retrying the same request could succeed
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            ErrorCategory::Network
+                | ErrorCategory::Timeout
+                | ErrorCategory::FloodWait
+                | ErrorCategory::ServerError
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCategory::Network => "network",
+            ErrorCategory::Timeout => "timeout",
+            ErrorCategory::FloodWait => "flood-wait",
+            ErrorCategory::ServerError => "server-error",
+            ErrorCategory::Blocked => "blocked",
+            ErrorCategory::ChatNotFound => "chat-not-found",
+            ErrorCategory::ChatMigrated => "chat-migrated",
+            ErrorCategory::Unauthorized => "unauthorized",
+            ErrorCategory::BadRequest => "bad-request",
+            ErrorCategory::LocalIo => "local-io",
+        }
+    }
+}
+
+impl serde::Serialize for ErrorCategory {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error returned by a `MessageSink` that knows its category
+pub trait SendError: fmt::Debug + Send {
+    fn category(&self) -> ErrorCategory;
+
+    /// The supergroup a migrated group became, for `ChatMigrated` errors
+    fn migrated_to(&self) -> Option<i64> {
+        None
+    }
+
+    /// Whether Telegram could not parse the message's formatting, so the
+    /// same text without a parse mode may still go through
+    fn is_bad_markup(&self) -> bool {
+        false
+    }
+
+    /// How long Telegram asked to wait, for `FloodWait` errors that say
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl SendError for RequestError {
+    fn category(&self) -> ErrorCategory {
+        classify(self)
+    }
+
+    fn migrated_to(&self) -> Option<i64> {
+        match self {
+            RequestError::MigrateToChatId(chat) => Some(chat.0),
+            _ => None,
+        }
+    }
+
+    fn is_bad_markup(&self) -> bool {
+        matches!(self, RequestError::Api(ApiError::CantParseEntities(_)))
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RequestError::RetryAfter(seconds) => Some(seconds.duration()),
+            _ => None,
+        }
+    }
+}
+
+/// Lets test sinks fail with a chosen category directly
+impl SendError for ErrorCategory {
+    fn category(&self) -> ErrorCategory {
+        *self
+    }
+}
+
+/// Classify a teloxide request error
+pub fn classify(err: &RequestError) -> ErrorCategory {
+    match err {
+        RequestError::Api(api) => classify_api(api),
+        RequestError::MigrateToChatId(_) => ErrorCategory::ChatMigrated,
+        RequestError::RetryAfter(_) => ErrorCategory::FloodWait,
+        RequestError::Network(_) => ErrorCategory::Network,
+        // Gateways in front of the Bot API answer 5xx with HTML
+        RequestError::InvalidJson { .. } => ErrorCategory::ServerError,
+        RequestError::Io(_) => ErrorCategory::LocalIo,
+    }
+}
+
+fn classify_api(err: &ApiError) -> ErrorCategory {
+    match err {
+        ApiError::BotBlocked
+        | ApiError::BotKicked
+        | ApiError::BotKickedFromSupergroup
+        | ApiError::BotKickedFromChannel
+        | ApiError::UserDeactivated
+        | ApiError::CantInitiateConversation
+        | ApiError::CantTalkWithBots
+        | ApiError::NotEnoughRightsToPostMessages => ErrorCategory::Blocked,
+        ApiError::ChatNotFound | ApiError::UserNotFound | ApiError::GroupDeactivated => {
+            ErrorCategory::ChatNotFound
+        }
+        ApiError::InvalidToken => ErrorCategory::Unauthorized,
+        ApiError::Unknown(description) => classify_description(description),
+        _ => ErrorCategory::BadRequest,
+    }
+}
+
+/// Errors teloxide does not recognise keep Telegram's description, whose
+/// prefix mirrors the HTTP status
+fn classify_description(description: &str) -> ErrorCategory {
+    let prefix = description.split(':').next().unwrap_or("").trim();
+    match prefix {
+        "Too Many Requests" => ErrorCategory::FloodWait,
+        "Unauthorized" => ErrorCategory::Unauthorized,
+        "Forbidden" => ErrorCategory::Blocked,
+        "Bad Request" | "Not Found" | "Conflict" | "Request Entity Too Large" => {
+            ErrorCategory::BadRequest
+        }
+        _ => ErrorCategory::ServerError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use teloxide::types::{ChatId, Seconds};
+
+    #[test]
+    fn blocked_and_kicked_are_permanent() {
+        for api in [ApiError::BotBlocked, ApiError::BotKickedFromSupergroup, ApiError::UserDeactivated] {
+            let category = classify(&RequestError::Api(api));
+            assert_eq!(category, ErrorCategory::Blocked);
+            assert!(!category.is_transient());
+        }
+    }
+
+    #[test]
+    fn chat_not_found_is_permanent() {
+        assert_eq!(classify(&RequestError::Api(ApiError::ChatNotFound)), ErrorCategory::ChatNotFound);
+    }
+
+    #[test]
+    fn message_too_long_is_bad_request() {
+        let category = classify(&RequestError::Api(ApiError::MessageIsTooLong));
+        assert_eq!(category, ErrorCategory::BadRequest);
+        assert!(!category.is_transient());
+    }
+
+    #[test]
+    fn flood_waits_carry_their_retry_after() {
+        let err = RequestError::RetryAfter(Seconds::from_seconds(35));
+        assert_eq!(classify(&err), ErrorCategory::FloodWait);
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(35)));
+        assert_eq!(RequestError::Api(ApiError::BotBlocked).retry_after(), None);
+    }
+
+    #[test]
+    fn unparseable_entities_are_bad_markup() {
+        let err = RequestError::Api(ApiError::CantParseEntities("Bad Request: can't parse entities".to_string()));
+        assert_eq!(classify(&err), ErrorCategory::BadRequest);
+        assert!(err.is_bad_markup());
+        assert!(!RequestError::Api(ApiError::MessageIsTooLong).is_bad_markup());
+    }
+
+    #[test]
+    fn invalid_token_is_unauthorized() {
+        assert_eq!(classify(&RequestError::Api(ApiError::InvalidToken)), ErrorCategory::Unauthorized);
+    }
+
+    #[test]
+    fn retry_after_is_transient_flood_wait() {
+        let category = classify(&RequestError::RetryAfter(Seconds::from_seconds(3)));
+        assert_eq!(category, ErrorCategory::FloodWait);
+        assert!(category.is_transient());
+    }
+
+    #[test]
+    fn migration_is_its_own_category() {
+        let err = RequestError::MigrateToChatId(ChatId(-100));
+        assert_eq!(classify(&err), ErrorCategory::ChatMigrated);
+        assert_eq!(err.migrated_to(), Some(-100));
+        assert_eq!(RequestError::Api(ApiError::ChatNotFound).migrated_to(), None);
+    }
+
+    #[test]
+    fn unparseable_response_is_server_error() {
+        let source = Arc::new(serde_json::from_str::<serde_json::Value>("<html>").unwrap_err());
+        let err = RequestError::InvalidJson { source, raw: "<html>502</html>".into() };
+        assert!(classify(&err).is_transient());
+    }
+
+    #[test]
+    fn io_error_is_local() {
+        let err = RequestError::Io(Arc::new(std::io::Error::from(std::io::ErrorKind::NotFound)));
+        assert_eq!(classify(&err), ErrorCategory::LocalIo);
+    }
+
+    #[test]
+    fn unknown_errors_classified_by_description() {
+        let unknown = |d: &str| classify(&RequestError::Api(ApiError::Unknown(d.to_string())));
+        assert_eq!(unknown("Internal Server Error"), ErrorCategory::ServerError);
+        assert_eq!(unknown("Bad Gateway"), ErrorCategory::ServerError);
+        assert_eq!(unknown("Bad Request: PEER_ID_INVALID"), ErrorCategory::BadRequest);
+        assert_eq!(unknown("Forbidden: bot is not a member of the channel chat"), ErrorCategory::Blocked);
+        assert_eq!(unknown("Too Many Requests: retry after 5"), ErrorCategory::FloodWait);
+    }
+}
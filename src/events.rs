@@ -0,0 +1,286 @@
+//! Firehose of bot events published on `zmq_events_endpoint`.
+//!
+//! Each event is sent as two frames: its type, so PUB subscribers can filter
+//! by prefix, and a JSON object with `event`, `at` and the event's fields.
+//! Publishing never waits: events are handed to a dedicated socket thread
+//! through a bounded channel, and are dropped and counted when the channel
+//! or the socket is full. Each `BotState` has its own `Events`, whose socket
+//! is bound when the bot starts running.
+
+use crate::config::{EventsSocket, TelegramSettings};
+use crate::errors::ErrorCategory;
+use crate::stats::Stats;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+
+/// Events waiting for the socket thread before new ones are dropped
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How a message reached a chat
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Delivered,
+    /// Sent as plain text after Telegram could not take its formatting
+    DeliveredDegraded,
+}
+
+/// Something that happened, as published
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BotEvent {
+    MessageDelivered {
+        chat_id: i64,
+        message_id: i32,
+        status: DeliveryStatus,
+        /// The producer's `id` for the message
+        id: Option<String>,
+        list: Option<String>,
+        /// The message's `trace_id`
+        trace_id: Option<String>,
+    },
+    MessageFailed {
+        chat_id: i64,
+        category: ErrorCategory,
+        id: Option<String>,
+        list: Option<String>,
+        trace_id: Option<String>,
+    },
+    /// A message sent to several chats reached `delivered` of them
+    BroadcastSummary { label: String, delivered: usize, failed: usize, id: Option<String>, trace_id: Option<String> },
+    ZmqDisconnected { silent_for_secs: u64 },
+    ZmqReconnected { down_for_secs: u64 },
+    CommandInvoked { command: String, chat_id: i64, user_id: String },
+    SubscriberMuted { chat_id: i64, until: Option<DateTime<Utc>> },
+    SubscriberUnmuted { chat_id: i64 },
+}
+
+impl BotEvent {
+    /// Every event type, as named in `zmq_events` and the first frame
+    pub const KINDS: [&'static str; 8] = [
+        "message_delivered",
+        "message_failed",
+        "broadcast_summary",
+        "zmq_disconnected",
+        "zmq_reconnected",
+        "command_invoked",
+        "subscriber_muted",
+        "subscriber_unmuted",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BotEvent::MessageDelivered { .. } => "message_delivered",
+            BotEvent::MessageFailed { .. } => "message_failed",
+            BotEvent::BroadcastSummary { .. } => "broadcast_summary",
+            BotEvent::ZmqDisconnected { .. } => "zmq_disconnected",
+            BotEvent::ZmqReconnected { .. } => "zmq_reconnected",
+            BotEvent::CommandInvoked { .. } => "command_invoked",
+            BotEvent::SubscriberMuted { .. } => "subscriber_muted",
+            BotEvent::SubscriberUnmuted { .. } => "subscriber_unmuted",
+        }
+    }
+
+    /// The frames published for this event at `at`
+    pub fn frames(&self, at: DateTime<Utc>) -> Vec<Vec<u8>> {
+        #[derive(Serialize)]
+        struct Published<'a> {
+            #[serde(flatten)]
+            event: &'a BotEvent,
+            at: DateTime<Utc>,
+        }
+        let json = serde_json::to_vec(&Published { event: self, at }).unwrap_or_default();
+        vec![self.kind().as_bytes().to_vec(), json]
+    }
+}
+
+/// Hands enabled events to the socket thread
+pub struct Publisher {
+    sender: SyncSender<Vec<Vec<u8>>>,
+    enabled: Vec<String>,
+    stats: Arc<Stats>,
+}
+
+impl Publisher {
+    pub fn new(sender: SyncSender<Vec<Vec<u8>>>, enabled: Vec<String>, stats: Arc<Stats>) -> Self {
+        Publisher { sender, enabled, stats }
+    }
+
+    /// Queue `event` if its type is enabled. Returns false if it was
+    /// dropped because the queue is full or the socket thread is gone.
+    pub fn offer(&self, event: &BotEvent, now: DateTime<Utc>) -> bool {
+        if !self.enabled.iter().any(|kind| kind == event.kind()) {
+            return true;
+        }
+        match self.sender.try_send(event.frames(now)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.stats.record_dropped_publication();
+                false
+            }
+        }
+    }
+}
+
+/// The events of one bot, published once `start` bound the socket
+pub struct Events {
+    publisher: OnceLock<Publisher>,
+    /// Counts the events dropped
+    stats: Arc<Stats>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events::new(Arc::default())
+    }
+}
+
+impl Events {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Events { publisher: OnceLock::new(), stats }
+    }
+
+    /// Publish `event` if the socket was started and its type is enabled
+    pub fn publish(&self, event: BotEvent) {
+        if let Some(publisher) = self.publisher.get() {
+            publisher.offer(&event, Utc::now());
+        }
+    }
+
+    /// Bind the events socket and start its thread, if `zmq_events_endpoint` is set
+    pub fn start(&self, settings: &TelegramSettings) -> Result<(), String> {
+        let Some(endpoint) = &settings.zmq_events_endpoint else {
+            return Ok(());
+        };
+        if self.publisher.get().is_some() {
+            warn!("Events socket started twice; keeping the first");
+            return Ok(());
+        }
+        let sender = bind(endpoint, settings, self.stats.clone())?;
+        let _ = self.publisher.set(Publisher::new(sender, settings.zmq_events.clone(), self.stats.clone()));
+        Ok(())
+    }
+}
+
+/// Bind the events socket at `endpoint` and start the thread sending what
+/// is queued on the returned channel
+fn bind(endpoint: &str, settings: &TelegramSettings, stats: Arc<Stats>) -> Result<SyncSender<Vec<Vec<u8>>>, String> {
+    let context = zmq::Context::new();
+    let kind = match settings.zmq_events_socket {
+        EventsSocket::Pub => zmq::PUB,
+        EventsSocket::Push => zmq::PUSH,
+    };
+    let socket = context.socket(kind).map_err(|e| format!("Events socket: create: {:?}", e))?;
+    if let Err(e) = socket.set_linger(0) {
+        warn!("Failed to set ZMQ linger option on the events socket: {:?}", e);
+    }
+    socket.bind(endpoint).map_err(|e| format!("Events socket: bind {}: {:?}", endpoint, e))?;
+    info!("ZMQ: {:?} events socket bound to {} ({})", settings.zmq_events_socket, endpoint, settings.zmq_events.join(", "));
+
+    let (sender, receiver) = mpsc::sync_channel::<Vec<Vec<u8>>>(CHANNEL_CAPACITY);
+    std::thread::Builder::new()
+        .name("zmq-events".to_string())
+        .spawn(move || {
+            // Keeps the context alive as long as the socket
+            let _context = context;
+            for frames in receiver {
+                // A PUSH socket without peers, or a slow one, would block
+                if socket.send_multipart(frames, zmq::DONTWAIT).is_err() {
+                    stats.record_dropped_publication();
+                }
+            }
+        })
+        .map_err(|e| format!("Events socket: start thread: {}", e))?;
+    Ok(sender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    fn json(event: &BotEvent) -> serde_json::Value {
+        let frames = event.frames(at());
+        assert_eq!(frames[0], event.kind().as_bytes());
+        serde_json::from_slice(&frames[1]).unwrap()
+    }
+
+    #[test]
+    fn events_serialize_with_type_and_time() {
+        let delivered = BotEvent::MessageDelivered {
+            chat_id: 5,
+            message_id: 31,
+            status: DeliveryStatus::Delivered,
+            id: Some("alert-1".into()),
+            list: None,
+            trace_id: Some("t-9".into()),
+        };
+        assert_eq!(
+            json(&delivered),
+            serde_json::json!({
+                "event": "message_delivered", "at": "2023-11-14T22:13:20Z",
+                "chat_id": 5, "message_id": 31, "status": "delivered", "id": "alert-1", "list": null,
+                "trace_id": "t-9"
+            })
+        );
+        let degraded = BotEvent::MessageDelivered {
+            chat_id: 5,
+            message_id: 32,
+            status: DeliveryStatus::DeliveredDegraded,
+            id: None,
+            list: None,
+            trace_id: None,
+        };
+        assert_eq!(json(&degraded)["status"], "delivered_degraded");
+        let failed =
+            BotEvent::MessageFailed { chat_id: 5, category: ErrorCategory::Blocked, id: None, list: Some("ops".into()), trace_id: None };
+        assert_eq!(json(&failed)["category"], "blocked");
+        let muted = BotEvent::SubscriberMuted { chat_id: 7, until: Some(at()) };
+        assert_eq!(json(&muted)["until"], "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn every_kind_is_listed() {
+        let events = [
+            BotEvent::MessageDelivered {
+                chat_id: 1,
+                message_id: 1,
+                status: DeliveryStatus::Delivered,
+                id: None,
+                list: None,
+                trace_id: None,
+            },
+            BotEvent::MessageFailed { chat_id: 1, category: ErrorCategory::Network, id: None, list: None, trace_id: None },
+            BotEvent::BroadcastSummary { label: "'ops'".into(), delivered: 2, failed: 1, id: None, trace_id: None },
+            BotEvent::ZmqDisconnected { silent_for_secs: 60 },
+            BotEvent::ZmqReconnected { down_for_secs: 90 },
+            BotEvent::CommandInvoked { command: "mute".into(), chat_id: 1, user_id: "7".into() },
+            BotEvent::SubscriberMuted { chat_id: 1, until: None },
+            BotEvent::SubscriberUnmuted { chat_id: 1 },
+        ];
+        let kinds: Vec<_> = events.iter().map(BotEvent::kind).collect();
+        assert_eq!(kinds, BotEvent::KINDS);
+        assert!(events.iter().all(|event| json(event)["event"] == event.kind()));
+    }
+
+    #[test]
+    fn disabled_events_are_skipped_and_overflow_is_dropped() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let stats = Arc::new(Stats::new());
+        let publisher = Publisher::new(sender, vec!["zmq_reconnected".to_string()], stats.clone());
+        assert!(publisher.offer(&BotEvent::SubscriberUnmuted { chat_id: 1 }, at()));
+        assert!(receiver.try_recv().is_err());
+
+        let reconnected = BotEvent::ZmqReconnected { down_for_secs: 5 };
+        assert!(publisher.offer(&reconnected, at()));
+        assert!(!publisher.offer(&reconnected, at()));
+        assert_eq!(receiver.try_recv().unwrap()[0], b"zmq_reconnected");
+        assert_eq!(stats.snapshot().dropped_publications, 1);
+    }
+}
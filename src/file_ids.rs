@@ -0,0 +1,130 @@
+//! Telegram file IDs of images that were already uploaded.
+//!
+//! Sending a photo by file ID skips the upload. Files are keyed by size and
+//! a hash of their contents, so the same image sent again from another ZMQ
+//! message (or to the next chat of a broadcast) reuses the first upload.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+/// Identifies a file by its contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileKey {
+    pub len: u64,
+    hash: u64,
+}
+
+/// Key for the file at `path`
+pub fn key_for(path: &Path) -> io::Result<FileKey> {
+    Ok(key_for_bytes(&fs::read(path)?))
+}
+
+/// Key for an image held in memory; equal to that of a file with these contents
+pub fn key_for_bytes(bytes: &[u8]) -> FileKey {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    FileKey { len: bytes.len() as u64, hash: hasher.finish() }
+}
+
+/// Bounded cache of file IDs, oldest entry evicted first
+pub struct FileIds {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<FileKey, (String, Instant)>>,
+}
+
+impl FileIds {
+    /// A cache of `capacity` entries kept for `ttl`; 0 disables it
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        FileIds { capacity, ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The file ID for `key`, unless it is older than the TTL
+    pub fn get(&self, key: FileKey, now: Instant) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some((file_id, at)) if now.saturating_duration_since(*at) < self.ttl => Some(file_id.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: FileKey, file_id: String, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            if let Some(oldest) = entries.iter().min_by_key(|(_, (_, at))| *at).map(|(key, _)| *key) {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (file_id, now));
+    }
+
+    /// Forget `key`, e.g. after Telegram rejected its file ID
+    pub fn invalidate(&self, key: FileKey) {
+        self.entries.lock().unwrap().remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u64) -> FileKey {
+        FileKey { len: n, hash: n }
+    }
+
+    #[test]
+    fn same_contents_give_the_same_key() {
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("corky-file-ids-a-{}", std::process::id()));
+        let b = dir.join(format!("corky-file-ids-b-{}", std::process::id()));
+        fs::write(&a, b"image").unwrap();
+        fs::write(&b, b"image").unwrap();
+        assert_eq!(key_for(&a).unwrap(), key_for(&b).unwrap());
+        assert_eq!(key_for(&a).unwrap(), key_for_bytes(b"image"));
+        fs::write(&b, b"other").unwrap();
+        assert_ne!(key_for(&a).unwrap(), key_for(&b).unwrap());
+        let _ = fs::remove_file(&a);
+        let _ = fs::remove_file(&b);
+    }
+
+    #[test]
+    fn entries_expire_and_oldest_is_evicted() {
+        let cache = FileIds::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        cache.insert(key(1), "one".to_string(), start);
+        cache.insert(key(2), "two".to_string(), start + Duration::from_secs(1));
+        cache.insert(key(3), "three".to_string(), start + Duration::from_secs(2));
+        assert_eq!(cache.get(key(1), start), None);
+        assert_eq!(cache.get(key(2), start).as_deref(), Some("two"));
+        assert_eq!(cache.get(key(3), start + Duration::from_secs(62)), None);
+        cache.invalidate(key(2));
+        assert_eq!(cache.get(key(2), start), None);
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = FileIds::disabled();
+        cache.insert(key(1), "one".to_string(), Instant::now());
+        assert_eq!(cache.get(key(1), Instant::now()), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Circuit breaker for Telegram's flood limits.
+//!
+//! When Telegram rate-limits the whole bot account every send fails with a
+//! flood wait, and retrying each message on its own multiplies traffic
+//! exactly when it should stop. After `threshold` flood waits within
+//! `window` the breaker opens and no send is attempted until the longest
+//! `retry_after` Telegram advertised meanwhile has passed. Messages wait in
+//! the outbox, so high-priority ones go first once it closes. The breaker
+//! is kept in the `BotState` and handed to every sink built from it, since
+//! all of them send for the same bot account.
+//!
+//! A single flood wait only parks the chat it came from: sends to that
+//! chat wait out its `retry_after` while other chats carry on.
+
+use crate::stats::Stats;
+use log::{error, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Pause after a flood wait that did not say how long to wait
+const FALLBACK_PAUSE: Duration = Duration::from_secs(5);
+
+/// The breaker opening or closing, for the owners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Opened { floods: usize, pause: Duration },
+    Closed { paused_for: Duration },
+}
+
+struct Inner {
+    /// Flood waits that open the breaker; 0 never opens it
+    threshold: u32,
+    window: Duration,
+    /// Recent flood waits and the pause each asked for
+    floods: VecDeque<(Instant, Duration)>,
+    /// When it opened and when it closes, while open
+    open: Option<(Instant, Instant)>,
+    changes: Vec<Change>,
+    /// Chats flood-waited on their own, and until when
+    parked: BTreeMap<i64, Instant>,
+}
+
+impl Inner {
+    fn close_if_due(&mut self, now: Instant) {
+        if let Some((opened_at, until)) = self.open.filter(|(_, until)| *until <= now) {
+            self.open = None;
+            let paused_for = until.saturating_duration_since(opened_at);
+            info!("Flood limit pause of {}s is over; sending again", paused_for.as_secs());
+            self.changes.push(Change::Closed { paused_for });
+        }
+    }
+}
+
+pub struct FloodBreaker {
+    inner: Mutex<Inner>,
+    /// Counts the pauses
+    stats: Arc<Stats>,
+}
+
+impl Default for FloodBreaker {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl FloodBreaker {
+    /// A breaker that never opens until configured, counting its pauses in
+    /// `stats`
+    pub fn new(stats: Arc<Stats>) -> Self {
+        FloodBreaker {
+            stats,
+            inner: Mutex::new(Inner {
+                threshold: 0,
+                window: Duration::ZERO,
+                floods: VecDeque::new(),
+                open: None,
+                changes: Vec::new(),
+                parked: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Open after `threshold` flood waits within `window`; 0 disables the breaker
+    pub fn configure(&self, threshold: u32, window: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.threshold = threshold;
+        inner.window = window;
+    }
+
+    /// A send to `chat` failed with a flood wait asking to wait `retry_after`
+    pub fn record(&self, chat: i64, retry_after: Option<Duration>, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.close_if_due(now);
+        let pause = retry_after.unwrap_or(FALLBACK_PAUSE);
+        let until = inner.parked.entry(chat).or_insert(now);
+        *until = (*until).max(now + pause);
+        warn!("Chat {} is flood-waited; holding its messages for {}s", chat, pause.as_secs());
+        if inner.threshold == 0 {
+            return;
+        }
+        if let Some((opened_at, until)) = inner.open {
+            // A longer wait advertised while open extends the pause
+            inner.open = Some((opened_at, until.max(now + pause)));
+            return;
+        }
+        let window = inner.window;
+        inner.floods.retain(|(at, _)| now.saturating_duration_since(*at) < window);
+        inner.floods.push_back((now, pause));
+        if inner.floods.len() < inner.threshold as usize {
+            return;
+        }
+        let floods = inner.floods.len();
+        let pause = inner.floods.drain(..).map(|(_, pause)| pause).max().unwrap_or(pause);
+        error!("!!! Telegram flood limit hit {} times within {}s; pausing all sends for {}s", floods, window.as_secs(), pause.as_secs());
+        inner.open = Some((now, now + pause));
+        inner.changes.push(Change::Opened { floods, pause });
+        self.stats.record_flood_pause();
+    }
+
+    /// When sends may resume, while the breaker is open at `now`
+    pub fn open_until(&self, now: Instant) -> Option<Instant> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.close_if_due(now);
+        inner.open.map(|(_, until)| until)
+    }
+
+    /// When sends to `chat` may resume, while it is flood-waited at `now`
+    pub fn parked_until(&self, chat: i64, now: Instant) -> Option<Instant> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.parked.retain(|_, until| *until > now);
+        inner.parked.get(&chat).copied()
+    }
+
+    /// Flood-waited chats and the wait each has left at `now`, soonest first
+    pub fn parked(&self, now: Instant) -> Vec<(i64, Duration)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.parked.retain(|_, until| *until > now);
+        let mut parked: Vec<_> = inner.parked.iter().map(|(&chat, &until)| (chat, until - now)).collect();
+        parked.sort_unstable_by_key(|&(chat, left)| (left, chat));
+        parked
+    }
+
+    /// Wait until the breaker is closed. Returns whether it had to wait.
+    pub async fn wait(&self) -> bool {
+        self.wait_until(|now| self.open_until(now)).await
+    }
+
+    /// Wait until the breaker is closed and `chat` is not flood-waited
+    pub async fn wait_for(&self, chat: i64) -> bool {
+        self.wait_until(|now| self.open_until(now).max(self.parked_until(chat, now))).await
+    }
+
+    async fn wait_until(&self, until: impl Fn(Instant) -> Option<Instant>) -> bool {
+        let mut waited = false;
+        loop {
+            let now = time::Instant::now().into_std();
+            match until(now) {
+                Some(until) => time::sleep(until - now).await,
+                None => return waited,
+            }
+            waited = true;
+        }
+    }
+
+    /// Openings and closings not yet reported, oldest first
+    pub fn take_changes(&self, now: Instant) -> Vec<Change> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.close_if_due(now);
+        std::mem::take(&mut inner.changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold_for_the_longest_wait() {
+        let breaker = FloodBreaker::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        breaker.record(1, None, at(0));
+        assert!(breaker.take_changes(at(0)).is_empty());
+
+        breaker.configure(3, Duration::from_secs(10));
+        breaker.record(1, Some(Duration::from_secs(20)), at(0));
+        breaker.record(1, None, at(5));
+        // The first has left the window
+        breaker.record(1, None, at(11));
+        assert_eq!(breaker.open_until(at(11)), None);
+        breaker.record(1, Some(Duration::from_secs(7)), at(12));
+        assert_eq!(breaker.open_until(at(12)), Some(at(19)));
+        // A longer wait while open extends the pause
+        breaker.record(1, Some(Duration::from_secs(30)), at(13));
+        assert_eq!(breaker.open_until(at(18)), Some(at(43)));
+
+        assert_eq!(breaker.open_until(at(43)), None);
+        let changes = breaker.take_changes(at(43));
+        assert_eq!(
+            changes,
+            vec![
+                Change::Opened { floods: 3, pause: Duration::from_secs(7) },
+                Change::Closed { paused_for: Duration::from_secs(31) },
+            ]
+        );
+        assert!(breaker.take_changes(at(50)).is_empty());
+    }
+
+    #[test]
+    fn a_flood_wait_parks_only_its_chat() {
+        let breaker = FloodBreaker::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        breaker.record(7, Some(Duration::from_secs(30)), at(0));
+        breaker.record(8, None, at(1));
+        assert_eq!(breaker.open_until(at(1)), None);
+        assert_eq!(breaker.parked_until(7, at(1)), Some(at(30)));
+        assert_eq!(breaker.parked_until(9, at(1)), None);
+        assert_eq!(breaker.parked(at(2)), vec![(8, Duration::from_secs(4)), (7, Duration::from_secs(28))]);
+        // A shorter wait never shortens the parking
+        breaker.record(7, Some(Duration::from_secs(1)), at(10));
+        assert_eq!(breaker.parked(at(10)), vec![(7, Duration::from_secs(20))]);
+        assert!(breaker.parked(at(30)).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_returns_once_closed() {
+        let breaker = FloodBreaker::default();
+        assert!(!breaker.wait().await);
+        breaker.configure(1, Duration::from_secs(10));
+        let start = time::Instant::now();
+        breaker.record(1, Some(Duration::from_secs(3)), start.into_std());
+        assert!(breaker.wait().await);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        breaker.configure(0, Duration::ZERO);
+        breaker.record(1, Some(Duration::from_secs(5)), time::Instant::now().into_std());
+        assert!(!breaker.wait_for(2).await);
+        assert!(breaker.wait_for(1).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(8));
+    }
+}
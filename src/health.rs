@@ -0,0 +1,160 @@
+//! One picture of the bot's health: the ZMQ link, the last delivery and
+//! failure, and the event queue depth.
+//!
+//! Updated only through `Transition`s from the event loop and the send
+//! path. `/status` and anything else reporting health read `snapshot()`, so
+//! they always agree.
+
+use crate::errors::ErrorCategory;
+use crate::zmq_listener::LinkState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// Something that changed the bot's health
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    ZmqConnected,
+    ZmqDisconnected { reason: String },
+    MessageSent { chat: i64 },
+    SendFailed { chat: i64, category: ErrorCategory },
+    /// Events received but not yet handled
+    QueueDepth(usize),
+}
+
+impl From<LinkState> for Transition {
+    fn from(link: LinkState) -> Self {
+        match link {
+            LinkState::Up { .. } => Transition::ZmqConnected,
+            LinkState::Down { silent_for } => Transition::ZmqDisconnected {
+                reason: format!("nothing received for {}s", silent_for.as_secs()),
+            },
+        }
+    }
+}
+
+/// A failed delivery
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    pub chat: i64,
+    pub category: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Health at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthSnapshot {
+    pub zmq_connected: bool,
+    /// Why the link is considered down, while it is
+    pub zmq_down_reason: Option<String>,
+    /// When the link last went up or down
+    pub zmq_since: DateTime<Utc>,
+    pub last_sent_at: Option<DateTime<Utc>>,
+    pub last_sent_chat: Option<i64>,
+    pub last_failure: Option<Failure>,
+    /// Failed deliveries since the last successful one
+    pub consecutive_failures: u32,
+    pub queue_depth: usize,
+}
+
+/// The current health, shared through `BotState`
+pub struct Health {
+    current: Mutex<HealthSnapshot>,
+}
+
+impl Health {
+    /// A bot that just started: the link counts as up until the listener
+    /// says otherwise, and nothing has been sent
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Health {
+            current: Mutex::new(HealthSnapshot {
+                zmq_connected: true,
+                zmq_down_reason: None,
+                zmq_since: now,
+                last_sent_at: None,
+                last_sent_chat: None,
+                last_failure: None,
+                consecutive_failures: 0,
+                queue_depth: 0,
+            }),
+        }
+    }
+
+    pub fn apply(&self, transition: Transition, now: DateTime<Utc>) {
+        let mut current = self.current.lock().unwrap();
+        match transition {
+            Transition::ZmqConnected => {
+                if !current.zmq_connected {
+                    current.zmq_since = now;
+                }
+                current.zmq_connected = true;
+                current.zmq_down_reason = None;
+            }
+            Transition::ZmqDisconnected { reason } => {
+                if current.zmq_connected {
+                    current.zmq_since = now;
+                }
+                current.zmq_connected = false;
+                current.zmq_down_reason = Some(reason);
+            }
+            Transition::MessageSent { chat } => {
+                current.last_sent_at = Some(now);
+                current.last_sent_chat = Some(chat);
+                current.consecutive_failures = 0;
+            }
+            Transition::SendFailed { chat, category } => {
+                current.last_failure = Some(Failure { chat, category: category.to_string(), at: now });
+                current.consecutive_failures += 1;
+            }
+            Transition::QueueDepth(depth) => current.queue_depth = depth,
+        }
+    }
+
+    pub fn snapshot(&self) -> HealthSnapshot {
+        self.current.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn transitions_build_the_snapshot() {
+        let start = Utc::now();
+        let at = |secs| start + Duration::seconds(secs);
+        let health = Health::new(start);
+        health.apply(Transition::QueueDepth(3), at(1));
+        health.apply(Transition::MessageSent { chat: 5 }, at(2));
+        health.apply(Transition::SendFailed { chat: 6, category: ErrorCategory::Blocked }, at(3));
+        health.apply(Transition::SendFailed { chat: 7, category: ErrorCategory::Network }, at(4));
+        health.apply(Transition::ZmqDisconnected { reason: "silent for 60s".to_string() }, at(5));
+        health.apply(Transition::ZmqDisconnected { reason: "silent for 90s".to_string() }, at(6));
+
+        let snapshot = health.snapshot();
+        assert!(!snapshot.zmq_connected);
+        assert_eq!(snapshot.zmq_down_reason.as_deref(), Some("silent for 90s"));
+        assert_eq!(snapshot.zmq_since, at(5));
+        assert_eq!((snapshot.last_sent_at, snapshot.last_sent_chat), (Some(at(2)), Some(5)));
+        assert_eq!(snapshot.last_failure, Some(Failure { chat: 7, category: "network".to_string(), at: at(4) }));
+        assert_eq!(snapshot.consecutive_failures, 2);
+        assert_eq!(snapshot.queue_depth, 3);
+
+        health.apply(Transition::ZmqConnected, at(7));
+        health.apply(Transition::MessageSent { chat: 8 }, at(8));
+        let snapshot = health.snapshot();
+        assert!(snapshot.zmq_connected && snapshot.zmq_down_reason.is_none());
+        assert_eq!(snapshot.zmq_since, at(7));
+        assert_eq!(snapshot.consecutive_failures, 0);
+        assert!(snapshot.last_failure.is_some());
+    }
+
+    #[test]
+    fn snapshot_serializes() {
+        let health = Health::new(Utc::now());
+        let json = serde_json::to_value(health.snapshot()).unwrap();
+        assert_eq!(json["zmq_connected"], true);
+        assert_eq!(json["last_failure"], serde_json::Value::Null);
+    }
+}
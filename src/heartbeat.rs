@@ -0,0 +1,168 @@
+//! Heartbeat file for external monitoring, and the idle time that decides
+//! when the connection is checked.
+//!
+//! After every successful send, and every successful keepalive check, the
+//! file at `heartbeat_file` is replaced with a small JSON object such as
+//! `{"at":"2024-05-01T12:03:00Z","unix":1714564980,"chat_id":123,"kind":"text"}`
+//! (`chat_id` is null and `kind` is `keepalive` for a check). It is written
+//! to a temporary file beside it and renamed over it, so a reader never sees
+//! half of one. The `BotState` owns the heartbeat, its token check sends the
+//! keepalives, and a `TelegramSink` built from it beats after each send. A
+//! failing write is logged once, and again only after a write has worked.
+
+use crate::data_dir;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What the file holds
+#[derive(Serialize)]
+struct Beat<'a> {
+    at: DateTime<Utc>,
+    unix: i64,
+    chat_id: Option<i64>,
+    kind: &'a str,
+}
+
+struct Inner {
+    path: Option<PathBuf>,
+    /// Last successful send or keepalive
+    last: Option<Instant>,
+    /// The last write failed and was logged
+    failing: bool,
+}
+
+/// The last sign of a working connection
+pub struct Heartbeat {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heartbeat {
+    /// A heartbeat that writes no file
+    pub const fn new() -> Self {
+        Heartbeat { inner: Mutex::new(Inner { path: None, last: None, failing: false }) }
+    }
+
+    /// Write the file at `path` from now on; `None` writes none
+    pub fn configure(&self, path: Option<PathBuf>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.path = path;
+        inner.failing = false;
+    }
+
+    /// A `kind` message was sent to `chat_id`
+    pub fn delivered(&self, chat_id: i64, kind: &str) {
+        self.beat(Some(chat_id), kind, Utc::now(), Instant::now());
+    }
+
+    /// A keepalive check found Telegram answering
+    pub fn keepalive(&self) {
+        self.beat(None, "keepalive", Utc::now(), Instant::now());
+    }
+
+    fn beat(&self, chat_id: Option<i64>, kind: &str, at: DateTime<Utc>, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last = Some(now);
+        let Some(path) = inner.path.clone() else { return };
+        let beat = Beat { at, unix: at.timestamp(), chat_id, kind };
+        match write_beat(&path, &beat) {
+            Ok(()) if inner.failing => {
+                info!("Heartbeat file {} is being written again", path.display());
+                inner.failing = false;
+            }
+            Ok(()) => {}
+            Err(err) if !inner.failing => {
+                warn!("Failed to write heartbeat file {}: {}; not logging further failures until it works", path.display(), err);
+                inner.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Whether nothing succeeded for at least `interval` before `now`, so
+    /// the connection should be checked; a zero `interval` never asks
+    pub fn needs_keepalive(&self, interval: Duration, now: Instant) -> bool {
+        if interval.is_zero() {
+            return false;
+        }
+        match self.inner.lock().unwrap().last {
+            Some(last) => now.saturating_duration_since(last) >= interval,
+            None => true,
+        }
+    }
+}
+
+/// Replace `path` with `beat`, see `data_dir::write_atomically`
+fn write_beat(path: &Path, beat: &Beat) -> Result<(), String> {
+    let json = serde_json::to_vec(beat).map_err(|e| e.to_string())?;
+    data_dir::write_atomically(path, &json).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("corky-heartbeat-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn each_success_replaces_the_file() {
+        let dir = temp_dir("writes");
+        let path = dir.join("heartbeat.json");
+        let heartbeat = Heartbeat::new();
+        heartbeat.configure(Some(path.clone()));
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:03:00Z").unwrap().with_timezone(&Utc);
+        heartbeat.beat(Some(-100123), "photo", at, Instant::now());
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({ "at": "2024-05-01T12:03:00Z", "unix": 1714564980, "chat_id": -100123, "kind": "photo" })
+        );
+        heartbeat.keepalive();
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["kind"], "keepalive");
+        assert_eq!(written["chat_id"], serde_json::Value::Null);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "the temporary file is renamed away");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failures_are_remembered_until_a_write_works() {
+        let dir = temp_dir("failures");
+        let path = dir.join("missing").join("heartbeat.json");
+        let heartbeat = Heartbeat::new();
+        heartbeat.configure(Some(path.clone()));
+        heartbeat.delivered(1, "text");
+        assert!(heartbeat.inner.lock().unwrap().failing);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        heartbeat.delivered(1, "text");
+        assert!(!heartbeat.inner.lock().unwrap().failing);
+        assert!(path.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keepalive_is_due_after_an_idle_interval() {
+        let heartbeat = Heartbeat::new();
+        let start = Instant::now();
+        let interval = Duration::from_secs(300);
+        assert!(heartbeat.needs_keepalive(interval, start));
+        heartbeat.beat(Some(1), "text", Utc::now(), start);
+        assert!(!heartbeat.needs_keepalive(interval, start + Duration::from_secs(299)));
+        assert!(heartbeat.needs_keepalive(interval, start + interval));
+        assert!(!heartbeat.needs_keepalive(Duration::ZERO, start + interval));
+    }
+}
@@ -0,0 +1,417 @@
+//! Optional SQLite record of every delivery, for `/history` and auditing.
+//!
+//! Sends only queue an entry; a dedicated writer thread owns the database,
+//! answers `/history` queries and prunes entries older than
+//! `history_keep_days` on startup and once a day.
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Entries waiting for the writer before new ones are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How often old entries are pruned while running
+const PRUNE_EVERY: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Characters of message text kept per entry
+pub const PREVIEW_CHARS: usize = 100;
+
+/// How a message was delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Text,
+    Photo,
+    Document,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Text => "text",
+            Kind::Photo => "photo",
+            Kind::Document => "document",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "photo" => Kind::Photo,
+            "document" => Kind::Document,
+            _ => Kind::Text,
+        }
+    }
+}
+
+/// One delivery attempt to one chat
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    pub chat_id: i64,
+    pub list: Option<String>,
+    pub kind: Kind,
+    pub preview: String,
+    /// ID of the (first) Telegram message sent
+    pub message_id: Option<i32>,
+    /// Error category when the delivery failed
+    pub error: Option<String>,
+    /// The ZMQ message's `trace_id`
+    pub trace_id: Option<String>,
+}
+
+/// The delivery table
+pub struct HistoryDb {
+    conn: Connection,
+}
+
+impl HistoryDb {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| format!("Failed to open history db {}: {}", path.display(), e))?;
+        Self::init(conn)
+    }
+
+    pub fn open_in_memory() -> Result<Self, String> {
+        Self::init(Connection::open_in_memory().map_err(|e| e.to_string())?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, String> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS deliveries (
+                 id INTEGER PRIMARY KEY,
+                 at INTEGER NOT NULL,
+                 chat_id INTEGER NOT NULL,
+                 list TEXT,
+                 kind TEXT NOT NULL,
+                 preview TEXT NOT NULL,
+                 message_id INTEGER,
+                 outcome TEXT NOT NULL,
+                 error TEXT,
+                 trace_id TEXT
+             );
+             CREATE INDEX IF NOT EXISTS deliveries_chat_at ON deliveries (chat_id, at);",
+        )
+        .map_err(|e| format!("Failed to create history table: {}", e))?;
+        // Tables created before trace IDs lack the column
+        let has_trace_id = conn
+            .prepare("SELECT 1 FROM pragma_table_info('deliveries') WHERE name = 'trace_id'")
+            .and_then(|mut stmt| stmt.exists([]))
+            .map_err(|e| format!("Failed to inspect history table: {}", e))?;
+        if !has_trace_id {
+            conn.execute_batch("ALTER TABLE deliveries ADD COLUMN trace_id TEXT")
+                .map_err(|e| format!("Failed to add trace_id to history table: {}", e))?;
+        }
+        Ok(HistoryDb { conn })
+    }
+
+    pub fn insert(&self, entry: &Entry) -> Result<(), String> {
+        self.conn
+            .execute(
+                "INSERT INTO deliveries (at, chat_id, list, kind, preview, message_id, outcome, error, trace_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    entry.at.timestamp_millis(),
+                    entry.chat_id,
+                    entry.list,
+                    entry.kind.as_str(),
+                    entry.preview,
+                    entry.message_id,
+                    if entry.error.is_none() { "delivered" } else { "failed" },
+                    entry.error,
+                    entry.trace_id,
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| format!("Failed to record delivery: {}", e))
+    }
+
+    /// The newest `limit` entries, for one chat or all, newest first
+    pub fn recent(&self, chat_id: Option<i64>, limit: usize) -> Result<Vec<Entry>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT at, chat_id, list, kind, preview, message_id, error, trace_id FROM deliveries
+                 WHERE ?1 IS NULL OR chat_id = ?1 ORDER BY at DESC, id DESC LIMIT ?2",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![chat_id, limit as i64], entry_from_row)
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| format!("Failed to read history: {}", e))
+    }
+
+    /// Entries from `from` (inclusive) to `to` (exclusive), oldest first
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Entry>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT at, chat_id, list, kind, preview, message_id, error, trace_id FROM deliveries
+                 WHERE at >= ?1 AND at < ?2 ORDER BY at, id",
+            )
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![from.timestamp_millis(), to.timestamp_millis()], entry_from_row)
+            .map_err(|e| e.to_string())?;
+        rows.collect::<Result<_, _>>().map_err(|e| format!("Failed to read history: {}", e))
+    }
+
+    /// Delete entries older than `cutoff`, returning how many went
+    pub fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize, String> {
+        self.conn
+            .execute("DELETE FROM deliveries WHERE at < ?1", params![cutoff.timestamp_millis()])
+            .map_err(|e| format!("Failed to prune history: {}", e))
+    }
+}
+
+/// An `Entry` from a row selected as `at, chat_id, list, kind, preview,
+/// message_id, error, trace_id`
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        at: DateTime::from_timestamp_millis(row.get(0)?).unwrap_or_default(),
+        chat_id: row.get(1)?,
+        list: row.get(2)?,
+        kind: Kind::parse(&row.get::<_, String>(3)?),
+        preview: row.get(4)?,
+        message_id: row.get(5)?,
+        error: row.get(6)?,
+        trace_id: row.get(7)?,
+    })
+}
+
+enum Op {
+    Record(Entry),
+    Recent {
+        chat_id: Option<i64>,
+        limit: usize,
+        reply: oneshot::Sender<Result<Vec<Entry>, String>>,
+    },
+    Between {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        reply: oneshot::Sender<Result<Vec<Entry>, String>>,
+    },
+}
+
+/// Handle to the writer thread; does nothing when history is disabled
+pub struct History {
+    ops: Option<SyncSender<Op>>,
+}
+
+impl History {
+    pub fn disabled() -> Self {
+        History { ops: None }
+    }
+
+    /// Open the database at `path` and start its writer thread, keeping
+    /// `keep_days` of history (0 keeps everything)
+    pub fn open(path: &Path, keep_days: u32) -> Result<Self, String> {
+        let db = HistoryDb::open(path)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let prune = move |db: &HistoryDb| {
+            if keep_days == 0 {
+                return;
+            }
+            match db.prune(Utc::now() - Duration::days(keep_days.into())) {
+                Ok(0) => {}
+                Ok(n) => info!("Pruned {} history entries older than {} days", n, keep_days),
+                Err(err) => error!("{}", err),
+            }
+        };
+        thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || {
+                prune(&db);
+                loop {
+                    match rx.recv_timeout(PRUNE_EVERY) {
+                        Ok(Op::Record(entry)) => {
+                            if let Err(err) = db.insert(&entry) {
+                                error!("{}", err);
+                            }
+                        }
+                        Ok(Op::Recent { chat_id, limit, reply }) => {
+                            let _ = reply.send(db.recent(chat_id, limit));
+                        }
+                        Ok(Op::Between { from, to, reply }) => {
+                            let _ = reply.send(db.between(from, to));
+                        }
+                        Err(RecvTimeoutError::Timeout) => prune(&db),
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+            .map_err(|e| format!("Failed to start history writer: {}", e))?;
+        info!("Recording delivery history in {}", path.display());
+        Ok(History { ops: Some(tx) })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ops.is_some()
+    }
+
+    /// Queue an entry without waiting for the database
+    pub fn record(&self, entry: Entry) {
+        let Some(ops) = &self.ops else {
+            return;
+        };
+        match ops.try_send(Op::Record(entry)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("History writer is behind; dropping an entry"),
+            Err(TrySendError::Disconnected(_)) => error!("History writer has stopped; dropping an entry"),
+        }
+    }
+
+    /// The newest `limit` entries, for one chat or all
+    pub async fn recent(&self, chat_id: Option<i64>, limit: usize) -> Result<Vec<Entry>, String> {
+        let ops = self.ops.as_ref().ok_or("History is disabled; set history_db in config.toml.")?;
+        let (reply, response) = oneshot::channel();
+        ops.try_send(Op::Recent { chat_id, limit, reply }).map_err(|_| "History writer is busy or stopped.")?;
+        response.await.map_err(|_| "History writer stopped.".to_string())?
+    }
+
+    /// Entries from `from` to `to`, oldest first
+    pub async fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Entry>, String> {
+        let ops = self.ops.as_ref().ok_or("History is disabled; set history_db in config.toml.")?;
+        let (reply, response) = oneshot::channel();
+        ops.try_send(Op::Between { from, to, reply }).map_err(|_| "History writer is busy or stopped.")?;
+        response.await.map_err(|_| "History writer stopped.".to_string())?
+    }
+}
+
+/// One line per entry, e.g. `05-01 12:03 ✓ 123 [ops] photo #812: disk full`
+pub fn format_entries(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let mut line = format!(
+                "{} {} {}",
+                entry.at.with_timezone(&chrono::Local).format("%m-%d %H:%M"),
+                if entry.error.is_none() { "✓" } else { "✗" },
+                entry.chat_id
+            );
+            if let Some(list) = &entry.list {
+                line.push_str(&format!(" [{}]", list));
+            }
+            line.push(' ');
+            line.push_str(entry.kind.as_str());
+            if let Some(id) = entry.message_id {
+                line.push_str(&format!(" #{}", id));
+            }
+            if let Some(error) = &entry.error {
+                line.push_str(&format!(" ({})", error));
+            }
+            line.push_str(": ");
+            line.push_str(&entry.preview.replace('\n', " "));
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(chat_id: i64, minute: u32, error: Option<&str>) -> Entry {
+        Entry {
+            at: Utc.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap(),
+            chat_id,
+            list: Some("ops".to_string()),
+            kind: Kind::Text,
+            preview: format!("message {}", minute),
+            message_id: error.is_none().then_some(minute as i32),
+            error: error.map(str::to_string),
+            trace_id: Some(format!("trace-{}", minute)),
+        }
+    }
+
+    #[test]
+    fn recent_is_newest_first_and_filters_by_chat() {
+        let db = HistoryDb::open_in_memory().unwrap();
+        for (chat, minute) in [(1, 0), (2, 1), (1, 2), (1, 3)] {
+            db.insert(&entry(chat, minute, None)).unwrap();
+        }
+        let all = db.recent(None, 10).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[0], entry(1, 3, None));
+        let chat = db.recent(Some(1), 2).unwrap();
+        assert_eq!(chat.iter().map(|e| e.preview.as_str()).collect::<Vec<_>>(), vec!["message 3", "message 2"]);
+    }
+
+    #[test]
+    fn failures_keep_their_error() {
+        let db = HistoryDb::open_in_memory().unwrap();
+        db.insert(&entry(1, 0, Some("blocked"))).unwrap();
+        let got = db.recent(Some(1), 1).unwrap().pop().unwrap();
+        assert_eq!(got.error.as_deref(), Some("blocked"));
+        assert_eq!(got.message_id, None);
+    }
+
+    #[test]
+    fn between_is_oldest_first_within_the_range() {
+        let db = HistoryDb::open_in_memory().unwrap();
+        for minute in [5, 0, 10, 20] {
+            db.insert(&entry(1, minute, None)).unwrap();
+        }
+        let at = |minute| Utc.with_ymd_and_hms(2024, 5, 1, 12, minute, 0).unwrap();
+        let got = db.between(at(0), at(20)).unwrap();
+        assert_eq!(got.iter().map(|e| e.preview.as_str()).collect::<Vec<_>>(), vec!["message 0", "message 5", "message 10"]);
+    }
+
+    #[test]
+    fn tables_from_before_trace_ids_gain_the_column() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE deliveries (
+                 id INTEGER PRIMARY KEY, at INTEGER NOT NULL, chat_id INTEGER NOT NULL, list TEXT,
+                 kind TEXT NOT NULL, preview TEXT NOT NULL, message_id INTEGER, outcome TEXT NOT NULL, error TEXT
+             );
+             INSERT INTO deliveries (at, chat_id, kind, preview, outcome) VALUES (0, 1, 'text', 'old', 'delivered');",
+        )
+        .unwrap();
+        let db = HistoryDb::init(conn).unwrap();
+        db.insert(&entry(1, 0, None)).unwrap();
+        let traces: Vec<Option<String>> = db.recent(Some(1), 5).unwrap().into_iter().map(|e| e.trace_id).collect();
+        assert_eq!(traces, vec![Some("trace-0".to_string()), None]);
+    }
+
+    #[test]
+    fn prune_removes_old_entries() {
+        let db = HistoryDb::open_in_memory().unwrap();
+        db.insert(&entry(1, 0, None)).unwrap();
+        db.insert(&entry(1, 30, None)).unwrap();
+        assert_eq!(db.prune(Utc.with_ymd_and_hms(2024, 5, 1, 12, 15, 0).unwrap()).unwrap(), 1);
+        assert_eq!(db.recent(None, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn entries_format_compactly() {
+        let text = format_entries(&[entry(5, 3, None), entry(6, 4, Some("blocked"))]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].ends_with(" ✓ 5 [ops] text #3: message 3"));
+        assert!(lines[1].ends_with(" ✗ 6 [ops] text (blocked): message 4"));
+    }
+
+    #[tokio::test]
+    async fn writer_thread_records_and_answers() {
+        let dir = std::env::temp_dir().join(format!("corky-history-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        let history = History::open(&path, 30).unwrap();
+        history.record(Entry { at: Utc::now(), ..entry(9, 0, None) });
+        let got = history.recent(Some(9), 5).await.unwrap();
+        assert_eq!(got.len(), 1);
+        drop(history);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn disabled_history_explains_itself() {
+        let history = History::disabled();
+        history.record(entry(1, 0, None));
+        assert!(history.recent(None, 5).await.unwrap_err().contains("history_db"));
+    }
+}
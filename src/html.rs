@@ -0,0 +1,549 @@
+//! Cleanup of producer-supplied HTML before it is sent with `parse_mode` HTML.
+//!
+//! Telegram rejects a whole message over one unsupported tag or unclosed
+//! entity. `sanitize` turns any text into markup Telegram accepts: unknown
+//! tags are dropped (their content is kept), unclosed tags are closed, and
+//! stray `<`, `>` and `&` are escaped. `validate` reports the same problems
+//! instead of fixing them, for `html_mode = "strict"`. `split` cuts long
+//! markup into messages that are each valid on their own.
+
+/// Tags Telegram understands, including its aliases (`strong` for `b`, ...)
+const SUPPORTED: &[&str] = &[
+    "b", "strong", "i", "em", "u", "ins", "s", "strike", "del", "a", "code", "pre", "tg-spoiler", "blockquote",
+];
+
+/// Named entities accepted by the Bot API; numeric ones are always accepted
+const NAMED_ENTITIES: &[&str] = &["lt", "gt", "amp", "quot"];
+
+/// A tag as written in the input
+struct Tag<'a> {
+    closing: bool,
+    name: String,
+    attrs: Vec<(String, Option<&'a str>)>,
+    /// Bytes of input the tag spans, including `<` and `>`
+    len: usize,
+}
+
+/// Make `text` safe to send with `parse_mode` HTML
+pub fn sanitize(text: &str) -> String {
+    process(text).0
+}
+
+/// Check `text` without changing it. Returns every problem found.
+pub fn validate(text: &str) -> Result<(), String> {
+    let problems = process(text).1;
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+/// Plain text of `text` as Telegram would display it: tags removed and
+/// entities decoded. Used where markup cannot be sent, such as digests.
+pub fn plain_text(text: &str) -> String {
+    let sanitized = sanitize(text);
+    let mut out = String::with_capacity(sanitized.len());
+    let mut rest = sanitized.as_str();
+    while let Some(pos) = rest.find(['<', '&']) {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with('<') {
+            // Every `<` left after sanitizing starts a tag
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            rest = &rest[end..];
+        } else {
+            let len = entity_len(rest).unwrap_or(1);
+            out.push(decode_entity(&rest[..len]).unwrap_or('&'));
+            rest = &rest[len..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escape `text` so it is shown literally
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// A tag or a single displayed character of sanitized markup
+#[derive(Clone, Copy)]
+enum Piece<'a> {
+    /// An opening tag, as written including its attributes
+    Open(&'a str),
+    Close(&'a str),
+    /// One character, or one entity such as `&amp;`
+    Text(&'a str),
+}
+
+impl Piece<'_> {
+    fn raw(&self) -> &str {
+        match self {
+            Piece::Open(raw) | Piece::Close(raw) | Piece::Text(raw) => raw,
+        }
+    }
+}
+
+/// Split `text` into parts of at most `limit` characters of markup, each
+/// valid HTML on its own. Parts end where no tag is open when possible,
+/// preferring the end of a line; a tag that has to span two parts is closed
+/// at the end of one and opened again, with its attributes, at the start of
+/// the next. Tags and entities are never cut. Returns `None` when a part
+/// cannot fit, e.g. a link whose opening tag is longer than `limit`.
+pub fn split(text: &str, limit: usize) -> Option<Vec<String>> {
+    if text.chars().count() <= limit {
+        return Some(vec![text.to_string()]);
+    }
+    let clean = sanitize(text);
+    let pieces = pieces(&clean);
+    let mut parts = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut start = 0;
+    while start < pieces.len() {
+        let reopened: usize = open.iter().map(|raw| raw.chars().count()).sum();
+        let mut stack = open.clone();
+        let mut len = reopened;
+        // (end, rank, open tags at end) of every place this part could end
+        let mut cuts = Vec::new();
+        for (offset, piece) in pieces[start..].iter().enumerate() {
+            len += piece.raw().chars().count();
+            if len > limit {
+                break;
+            }
+            match piece {
+                Piece::Open(raw) => stack.push(raw),
+                Piece::Close(_) => {
+                    stack.pop();
+                }
+                Piece::Text(_) => {}
+            }
+            let closing: usize = stack.iter().map(|raw| tag_name(raw).len() + 3).sum();
+            // Ending right after an opening tag would leave it empty
+            if len + closing > limit || matches!(piece, Piece::Open(_)) {
+                continue;
+            }
+            let rank = (stack.is_empty(), matches!(piece, Piece::Text("\n")));
+            cuts.push((start + offset + 1, rank, stack.clone()));
+        }
+        let &(furthest, _, _) = cuts.last()?;
+        let (end, _, stack) = match furthest == pieces.len() {
+            true => cuts.pop()?,
+            // Prefer a cleaner cut, but not one that leaves a short part
+            false => cuts
+                .into_iter()
+                .filter(|(end, _, _)| (end - start) * 2 >= furthest - start)
+                .max_by_key(|(end, rank, _)| (*rank, *end))?,
+        };
+        let mut part: String = open.concat();
+        part.extend(pieces[start..end].iter().map(Piece::raw));
+        for raw in stack.iter().rev() {
+            part.push_str(&format!("</{}>", tag_name(raw)));
+        }
+        parts.push(part);
+        open = stack;
+        start = end;
+    }
+    Some(parts)
+}
+
+/// Sanitized markup as tags and displayed characters
+fn pieces(clean: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = clean;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            // Sanitized markup has only whole tags and valid entities
+            '<' => rest.find('>').map_or(rest.len(), |i| i + 1),
+            '&' => entity_len(rest).unwrap_or(1),
+            _ => c.len_utf8(),
+        };
+        let raw = &rest[..len];
+        pieces.push(match c {
+            '<' if raw.starts_with("</") => Piece::Close(raw),
+            '<' => Piece::Open(raw),
+            _ => Piece::Text(raw),
+        });
+        rest = &rest[len..];
+    }
+    pieces
+}
+
+/// Name of the sanitized opening tag `raw`
+fn tag_name(raw: &str) -> &str {
+    let inner = &raw[1..raw.len() - 1];
+    inner.split(' ').next().unwrap_or(inner)
+}
+
+/// Sanitized output and a description of everything that had to change
+fn process(text: &str) -> (String, Vec<String>) {
+    let mut out = String::with_capacity(text.len());
+    let mut problems = Vec::new();
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => match parse_tag(rest) {
+                Some(tag) => {
+                    rest = &rest[tag.len..];
+                    handle_tag(tag, &mut open, &mut out, &mut problems);
+                }
+                None => {
+                    problems.push("stray '<' (escape it as &lt;)".to_string());
+                    out.push_str("&lt;");
+                    rest = &rest[1..];
+                }
+            },
+            '>' => {
+                problems.push("stray '>' (escape it as &gt;)".to_string());
+                out.push_str("&gt;");
+                rest = &rest[1..];
+            }
+            '&' => match entity_len(rest) {
+                Some(len) => {
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+                None => {
+                    problems.push("stray '&' (escape it as &amp;)".to_string());
+                    out.push_str("&amp;");
+                    rest = &rest[1..];
+                }
+            },
+            _ => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    while let Some(name) = open.pop() {
+        problems.push(format!("<{}> is never closed", name));
+        out.push_str(&format!("</{}>", name));
+    }
+    (out, problems)
+}
+
+/// Emit a supported tag, closing or dropping tags as needed to keep nesting valid
+fn handle_tag(tag: Tag, open: &mut Vec<String>, out: &mut String, problems: &mut Vec<String>) {
+    if !SUPPORTED.contains(&tag.name.as_str()) {
+        problems.push(format!("unsupported tag <{}{}>", if tag.closing { "/" } else { "" }, tag.name));
+        return;
+    }
+    if tag.closing {
+        match open.iter().rposition(|name| *name == tag.name) {
+            Some(index) => {
+                for name in open.drain(index..).rev() {
+                    if name != tag.name {
+                        problems.push(format!("<{}> is closed by </{}>", name, tag.name));
+                    }
+                    out.push_str(&format!("</{}>", name));
+                }
+            }
+            None => problems.push(format!("</{}> has no matching opening tag", tag.name)),
+        }
+        return;
+    }
+    // Only <code> may appear inside <pre>; nothing may appear inside <code>
+    let inside_pre = open.last().is_some_and(|name| name == "pre");
+    let in_code = open.iter().any(|name| name == "code" || name == "pre");
+    if in_code && !(inside_pre && tag.name == "code") {
+        problems.push(format!("<{}> is not allowed inside <pre> or <code>", tag.name));
+        return;
+    }
+    if tag.name == "a" && open.iter().any(|name| name == "a") {
+        problems.push("<a> is not allowed inside another <a>".to_string());
+        return;
+    }
+    let mut rendered = format!("<{}", tag.name);
+    for (attr, value) in &tag.attrs {
+        match (tag.name.as_str(), attr.as_str(), value) {
+            ("a", "href", Some(value)) => rendered.push_str(&format!(" href=\"{}\"", attribute_value(value))),
+            ("code", "class", Some(value)) if value.starts_with("language-") => {
+                rendered.push_str(&format!(" class=\"{}\"", attribute_value(value)))
+            }
+            ("blockquote", "expandable", None) => rendered.push_str(" expandable"),
+            _ => problems.push(format!("attribute '{}' is not allowed on <{}>", attr, tag.name)),
+        }
+    }
+    rendered.push('>');
+    out.push_str(&rendered);
+    open.push(tag.name);
+}
+
+/// Parse the tag starting at `text[0] == '<'`, or `None` if it is not one
+fn parse_tag(text: &str) -> Option<Tag<'_>> {
+    let end = text.find('>')?;
+    let inner = &text[1..end];
+    if inner.contains('<') {
+        return None;
+    }
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner),
+    };
+    let name_len = inner.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-')).unwrap_or(inner.len());
+    if name_len == 0 || !inner.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let name = inner[..name_len].to_ascii_lowercase();
+    let attrs = parse_attrs(inner[name_len..].trim())?;
+    if closing && !attrs.is_empty() {
+        return None;
+    }
+    Some(Tag { closing, name, attrs, len: end + 1 })
+}
+
+/// `key="value" key='value' key=value key`, or `None` if malformed
+fn parse_attrs(mut text: &str) -> Option<Vec<(String, Option<&str>)>> {
+    let mut attrs = Vec::new();
+    while !text.is_empty() {
+        let key_len = text.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(text.len());
+        if key_len == 0 {
+            return None;
+        }
+        let key = text[..key_len].to_ascii_lowercase();
+        text = text[key_len..].trim_start();
+        let value = match text.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, rest) = match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let close = after[1..].find(quote)? + 1;
+                        (&after[1..close], &after[close + 1..])
+                    }
+                    Some(_) => {
+                        let len = after.find(char::is_whitespace).unwrap_or(after.len());
+                        (&after[..len], &after[len..])
+                    }
+                    None => return None,
+                };
+                text = rest.trim_start();
+                Some(value)
+            }
+            None => None,
+        };
+        attrs.push((key, value));
+    }
+    Some(attrs)
+}
+
+/// An attribute value with quotes and stray markup escaped
+fn attribute_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '&' => match entity_len(rest) {
+                Some(len) => {
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                    continue;
+                }
+                None => out.push_str("&amp;"),
+            },
+            '"' => out.push_str("&quot;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            _ => out.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+/// Length of the entity at the start of `text` (which starts with `&`) if it is one Telegram accepts
+fn entity_len(text: &str) -> Option<usize> {
+    let end = text.find(';')?;
+    let body = &text[1..end];
+    let valid = if let Some(hex) = body.strip_prefix("#x").or_else(|| body.strip_prefix("#X")) {
+        !hex.is_empty() && u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).is_some()
+    } else if let Some(dec) = body.strip_prefix('#') {
+        !dec.is_empty() && dec.parse::<u32>().ok().and_then(char::from_u32).is_some()
+    } else {
+        NAMED_ENTITIES.contains(&body)
+    };
+    valid.then_some(end + 1)
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    let body = entity.strip_prefix('&')?.strip_suffix(';')?;
+    match body {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        _ => {
+            let code = match body.strip_prefix("#x").or_else(|| body.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => body.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_markup_is_unchanged() {
+        let text = "<b>bold</b> <i>it</i> <a href=\"https://x.io/?a=1&amp;b=2\">link</a> \
+                    <pre><code class=\"language-rust\">let x = 1 &lt; 2;</code></pre> \
+                    <tg-spoiler>s</tg-spoiler> <blockquote expandable>q</blockquote> &#128512; &quot;";
+        assert_eq!(sanitize(text), text);
+        assert_eq!(validate(text), Ok(()));
+    }
+
+    #[test]
+    fn unsupported_tags_are_dropped_but_content_kept() {
+        assert_eq!(sanitize("<div>hi <span>there</span></div>"), "hi there");
+        assert_eq!(validate("<div>hi</div>"), Err("unsupported tag <div>; unsupported tag </div>".to_string()));
+    }
+
+    #[test]
+    fn unclosed_tags_are_closed_in_order() {
+        assert_eq!(sanitize("<b>bold <i>both"), "<b>bold <i>both</i></b>");
+        assert_eq!(validate("<b>x"), Err("<b> is never closed".to_string()));
+    }
+
+    #[test]
+    fn misnested_tags_are_closed_at_the_outer_close() {
+        assert_eq!(sanitize("<b>a<i>b</b>c</i>"), "<b>a<i>b</i></b>c");
+        assert_eq!(
+            validate("<b>a<i>b</b>c</i>"),
+            Err("<i> is closed by </b>; </i> has no matching opening tag".to_string())
+        );
+    }
+
+    #[test]
+    fn stray_characters_are_escaped() {
+        assert_eq!(sanitize("1 < 2 & 3 > 2"), "1 &lt; 2 &amp; 3 &gt; 2");
+        assert_eq!(sanitize("a <<b>x</b>"), "a &lt;<b>x</b>");
+        assert_eq!(sanitize("AT&T &nbsp; &#xZZ;"), "AT&amp;T &amp;nbsp; &amp;#xZZ;");
+        assert!(validate("1 < 2").unwrap_err().contains("stray '<'"));
+    }
+
+    #[test]
+    fn disallowed_attributes_are_removed() {
+        assert_eq!(sanitize("<b class=\"x\">b</b>"), "<b>b</b>");
+        assert_eq!(sanitize("<a href='x\"y' onclick=\"evil()\">l</a>"), "<a href=\"x&quot;y\">l</a>");
+        assert_eq!(sanitize("<code class=\"evil\">c</code>"), "<code>c</code>");
+    }
+
+    #[test]
+    fn nothing_nests_inside_code() {
+        assert_eq!(sanitize("<code><b>x</b></code>"), "<code>x</code>");
+        assert_eq!(sanitize("<pre><i>x</i></pre>"), "<pre>x</pre>");
+        assert_eq!(sanitize("<a href=\"1\"><a href=\"2\">x</a></a>"), "<a href=\"1\">x</a>");
+    }
+
+    #[test]
+    fn tag_names_are_case_insensitive() {
+        assert_eq!(sanitize("<B>x</b>"), "<b>x</b>");
+    }
+
+    #[test]
+    fn malformed_tags_are_text() {
+        assert_eq!(sanitize("<b"), "&lt;b");
+        assert_eq!(sanitize("< b>"), "&lt; b&gt;");
+        assert_eq!(sanitize("<!-- c -->"), "&lt;!-- c --&gt;");
+        assert_eq!(sanitize("</b x=1>"), "&lt;/b x=1&gt;");
+    }
+
+    #[test]
+    fn non_ascii_text_survives() {
+        assert_eq!(sanitize("<b>grüße 🚀</b> <"), "<b>grüße 🚀</b> &lt;");
+    }
+
+    #[test]
+    fn plain_text_strips_markup() {
+        assert_eq!(plain_text("<b>a &lt; b</b> &amp; <i>c"), "a < b & c");
+        assert_eq!(plain_text("x &#128512;"), "x 😀");
+    }
+
+    #[test]
+    fn escape_makes_text_literal() {
+        assert_eq!(escape("<b>&"), "&lt;b&gt;&amp;");
+        assert_eq!(sanitize(&escape("<b>&")), escape("<b>&"));
+    }
+
+    /// Every part of `split(text, limit)` is valid, within `limit`, and
+    /// together they display the same text
+    fn assert_split(text: &str, limit: usize) -> Vec<String> {
+        let parts = split(text, limit).unwrap();
+        for part in &parts {
+            assert!(part.chars().count() <= limit, "{:?} is over {}", part, limit);
+            assert_eq!(validate(part), Ok(()), "{:?}", part);
+            assert!(!plain_text(part).is_empty(), "empty part in {:?}", parts);
+        }
+        assert_eq!(parts.iter().map(|part| plain_text(part)).collect::<String>(), plain_text(text));
+        parts
+    }
+
+    #[test]
+    fn split_leaves_short_markup_alone() {
+        assert_eq!(split("<b>a < b</b>", 20).unwrap(), vec!["<b>a < b</b>"]);
+    }
+
+    #[test]
+    fn split_prefers_points_where_no_tag_is_open() {
+        let text = "<b>one two</b> three <i>four five</i>";
+        assert_eq!(assert_split(text, 24), vec!["<b>one two</b> three ", "<i>four five</i>"]);
+    }
+
+    #[test]
+    fn split_prefers_line_ends() {
+        let text = "<b>aa bb</b>\ncc <i>dd</i> ee ff";
+        assert_eq!(assert_split(text, 24), vec!["<b>aa bb</b>\n", "cc <i>dd</i> ee ff"]);
+    }
+
+    #[test]
+    fn split_reopens_spanning_tags_with_attributes() {
+        let text = format!("<a href=\"https://x.io\"><b>{}</b></a>", "x".repeat(60));
+        let parts = assert_split(&text, 50);
+        assert!(parts.len() > 1);
+        assert!(parts[1..].iter().all(|part| part.starts_with("<a href=\"https://x.io\"><b>")));
+        assert!(parts.iter().all(|part| part.ends_with("</b></a>")));
+
+        let code = format!("<pre><code class=\"language-rust\">{}</code></pre>", "let x = 1;\n".repeat(20));
+        let parts = assert_split(&code, 120);
+        assert!(parts.iter().all(|part| part.starts_with("<pre><code class=\"language-rust\">")));
+        assert!(parts[..parts.len() - 1].iter().all(|part| part.ends_with("\n</code></pre>")));
+    }
+
+    #[test]
+    fn split_never_cuts_entities_or_tags() {
+        let text = "&amp;&lt;&#128512;<i>i</i>".repeat(20);
+        for limit in 20..40 {
+            for part in assert_split(&text, limit) {
+                assert!(!part.ends_with('&') && !part.ends_with('<'));
+            }
+        }
+    }
+
+    #[test]
+    fn split_holds_for_any_limit() {
+        let text = "<b>bold <i>both &amp; more</i></b> plain\n<tg-spoiler>s 🚀</tg-spoiler> \
+                    <blockquote expandable>quote <a href=\"https://example.org/a?b=1&amp;c=2\">link</a></blockquote>";
+        // From the smallest limit that fits one character of the link inside the quote
+        for limit in 85..=text.chars().count() {
+            assert_split(text, limit);
+        }
+        assert_eq!(split(text, 84), None);
+    }
+
+    #[test]
+    fn split_cleans_up_markup_it_has_to_cut() {
+        let parts = assert_split(&format!("<b>{} <div>x", "y".repeat(30)), 20);
+        assert!(parts.iter().all(|part| !part.contains("div")));
+    }
+
+    #[test]
+    fn split_gives_up_when_a_tag_cannot_fit() {
+        let text = format!("<a href=\"https://x.io/{}\">link</a> and more text", "p".repeat(50));
+        assert_eq!(split(&text, 40), None);
+        // Reopening three tags plus closing them leaves no room for text
+        assert_eq!(split(&format!("<b><i><u>{}</u></i></b>", "x".repeat(30)), 21), None);
+    }
+}
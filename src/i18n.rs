@@ -0,0 +1,171 @@
+//! Translations of command descriptions and user-facing replies.
+//!
+//! English is built in. Other languages come from the TOML file named by
+//! `translations_file`, one table per language code, so a language can be
+//! added without rebuilding the bot:
+//!
+//! ```toml
+//! [de]
+//! owner_only = "Dieser Befehl ist nur für den Besitzer des Bots."
+//! "command.help" = "Diese Hilfe anzeigen."
+//! ```
+//!
+//! Keys missing from a language fall back to English. `{name}` placeholders
+//! are filled in by the caller. Log messages are never translated.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The language of the built-in texts
+pub const ENGLISH: &str = "en";
+
+/// Built-in texts. Command descriptions (`command.<name>`) and the
+/// `help_header` default to those of `commands::Command`.
+const ENGLISH_TEXTS: &[(&str, &str)] = &[
+    ("owner_only", "This command is only available to the bot owner."),
+    ("not_allowed", "This command is not available in this chat."),
+    ("not_subscribed", "This chat is not on any subscriber list."),
+    ("unknown_list", "Unknown subscriber list '{list}'."),
+    ("muted_until", "Chat {chat} muted until {until}."),
+    ("muted", "Chat {chat} muted until /unmute."),
+    ("unmuted", "Chat {chat} unmuted."),
+    ("not_muted", "Chat {chat} is not muted."),
+    ("bad_duration", "Unrecognised argument '{arg}'. Use e.g. 30m, 12h, 7d or 2w."),
+    ("command_sent", "Sent."),
+    ("command_failed", "Could not send the command; try again shortly."),
+    ("welcome", "Hello! This bot delivers notifications. Send /help to see what it can do."),
+    ("unknown_command", "Unknown command {command}; try /help."),
+    ("bad_command_args", "Could not read the arguments of {command}; try /help."),
+];
+
+/// Texts per language, keyed by language code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Translations {
+    languages: HashMap<String, HashMap<String, String>>,
+}
+
+impl Translations {
+    /// Parse a translations file
+    pub fn from_toml(contents: &str) -> Result<Self, String> {
+        let languages: HashMap<String, HashMap<String, String>> =
+            toml::from_str(contents).map_err(|e| format!("Failed to parse translations: {}", e))?;
+        let languages = languages.into_iter().map(|(code, texts)| (code.to_lowercase(), texts)).collect();
+        Ok(Translations { languages })
+    }
+
+    /// Read and parse the translations file at `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read translations_file {}: {}", path.display(), e))?;
+        Self::from_toml(&contents).map_err(|e| format!("{} in {}", e, path.display()))
+    }
+
+    /// Translated languages other than English, sorted
+    pub fn languages(&self) -> Vec<&str> {
+        let mut codes: Vec<&str> = self.languages.keys().map(String::as_str).filter(|code| *code != ENGLISH).collect();
+        codes.sort_unstable();
+        codes
+    }
+
+    /// The language to use for a Telegram `language_code` such as `pt-br`:
+    /// the code itself if translated, else its primary language
+    pub fn supported(&self, code: &str) -> Option<String> {
+        let code = code.trim().to_lowercase();
+        let primary = code.split(['-', '_']).next().unwrap_or_default().to_string();
+        [code, primary].into_iter().find(|code| code == ENGLISH || self.languages.contains_key(code))
+    }
+
+    /// The text for `key` in `language`, falling back to English. `None`
+    /// for keys with no built-in text that `language` does not translate.
+    pub fn lookup(&self, language: &str, key: &str) -> Option<&str> {
+        self.languages
+            .get(language)
+            .and_then(|texts| texts.get(key))
+            .map(String::as_str)
+            .or_else(|| ENGLISH_TEXTS.iter().find(|(name, _)| *name == key).map(|(_, text)| *text))
+    }
+
+    /// The text for `key` in `language` with each `{name}` in `args` filled in
+    pub fn text(&self, language: &str, key: &str, args: &[(&str, &str)]) -> String {
+        let mut text = self.lookup(language, key).unwrap_or(key).to_string();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        text
+    }
+}
+
+/// The texts of one language
+pub struct Texts<'a> {
+    translations: &'a Translations,
+    language: String,
+}
+
+impl Texts<'_> {
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    /// See `Translations::text`
+    pub fn text(&self, key: &str, args: &[(&str, &str)]) -> String {
+        self.translations.text(&self.language, key, args)
+    }
+
+    /// See `Translations::lookup`
+    pub fn lookup(&self, key: &str) -> Option<&str> {
+        self.translations.lookup(&self.language, key)
+    }
+}
+
+impl Translations {
+    /// The texts of `language`
+    pub fn texts(&self, language: &str) -> Texts<'_> {
+        Texts { translations: self, language: language.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn german() -> Translations {
+        Translations::from_toml(
+            "[DE]\nunmuted = \"Chat {chat} ist nicht mehr stumm.\"\n\"command.help\" = \"Diese Hilfe anzeigen.\"\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_texts_fall_back_to_english() {
+        let translations = german();
+        assert_eq!(translations.text("de", "unmuted", &[("chat", "5")]), "Chat 5 ist nicht mehr stumm.");
+        assert_eq!(translations.text("de", "not_muted", &[("chat", "5")]), "Chat 5 is not muted.");
+        assert_eq!(translations.text("fr", "unmuted", &[("chat", "5")]), "Chat 5 unmuted.");
+        assert_eq!(translations.lookup("de", "command.help"), Some("Diese Hilfe anzeigen."));
+        assert_eq!(translations.lookup("en", "command.help"), None);
+    }
+
+    #[test]
+    fn telegram_codes_match_their_primary_language() {
+        let translations = german();
+        assert_eq!(translations.languages(), vec!["de"]);
+        assert_eq!(translations.supported("de-AT").as_deref(), Some("de"));
+        assert_eq!(translations.supported("en-GB").as_deref(), Some("en"));
+        assert_eq!(translations.supported("fr"), None);
+    }
+
+    #[test]
+    fn example_file_parses() {
+        let translations = Translations::from_toml(include_str!("../translations.example.toml")).unwrap();
+        assert_eq!(translations.languages(), vec!["de"]);
+        // Every translated text is one the bot uses
+        let known = |key: &str| key == "help_header" || key.starts_with("command.") || translations.lookup("xx", key).is_some();
+        assert!(translations.languages["de"].keys().all(|key| known(key)));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert!(Translations::from_toml("de = \"not a table\"").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Conversion of images Telegram handles badly as photos.
+//!
+//! The format is read from the image's first bytes, whatever its file name
+//! says. JPEG, PNG and WebP within Telegram's photo dimensions are sent as
+//! they are; other formats are re-encoded as JPEG. Images that cannot be
+//! decoded, or whose dimensions Telegram refuses, are sent as documents.
+//!
+//! Dimensions are read from the header before anything is decoded, and
+//! decoding runs under `limits`, so a small file claiming a huge image
+//! cannot make the bot allocate for it.
+
+use image::{ImageFormat, ImageReader, Limits};
+use std::fmt;
+use std::io::Cursor;
+
+/// Largest width plus height Telegram accepts for a photo
+pub const MAX_PHOTO_DIMENSIONS: u32 = 10_000;
+
+/// Largest ratio of the longer side to the shorter one Telegram accepts
+pub const MAX_PHOTO_RATIO: u32 = 20;
+
+/// Quality of the JPEG a converted image is encoded as
+const JPEG_QUALITY: u8 = 90;
+
+/// Most memory a decoder may allocate: a 5000x5000 image with four 16-bit
+/// channels, the largest one that fits the photo limits
+const MAX_DECODE_BYTES: u64 = 5000 * 5000 * 8;
+
+/// What to send for an image
+#[derive(Debug, PartialEq, Eq)]
+pub enum Prepared {
+    /// Telegram takes it as a photo as it is
+    Unchanged,
+    /// Re-encoded as a JPEG, which Telegram takes
+    Converted { from: ImageFormat, jpeg: Vec<u8> },
+    /// Not usable as a photo
+    Document(Unusable),
+}
+
+/// Why an image is sent as a document
+#[derive(Debug, PartialEq, Eq)]
+pub enum Unusable {
+    /// Its first bytes are not those of any known image format
+    UnknownFormat,
+    /// It claims to be `format` but does not decode
+    Undecodable { format: ImageFormat, error: String },
+    /// Width and height outside Telegram's photo limits
+    Dimensions { width: u32, height: u32 },
+}
+
+impl fmt::Display for Unusable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unusable::UnknownFormat => f.write_str("not a recognised image format"),
+            Unusable::Undecodable { format, error } => write!(f, "{} that does not decode: {}", name(*format), error),
+            Unusable::Dimensions { width, height } => write!(f, "{}x{} is outside Telegram's photo limits", width, height),
+        }
+    }
+}
+
+/// The format `bytes` start like, if any
+pub fn detect(bytes: &[u8]) -> Option<ImageFormat> {
+    image::guess_format(bytes).ok()
+}
+
+/// Short name of `format` for log lines
+pub fn name(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("image")
+}
+
+/// Decide how to send the image in `bytes`, converting it when needed
+pub fn prepare(bytes: &[u8]) -> Prepared {
+    let Some(format) = detect(bytes) else {
+        return Prepared::Document(Unusable::UnknownFormat);
+    };
+    match reader(bytes, format).into_dimensions() {
+        Ok((width, height)) if !fits(width, height) => return Prepared::Document(Unusable::Dimensions { width, height }),
+        Ok(_) => {}
+        Err(err) => return Prepared::Document(Unusable::Undecodable { format, error: err.to_string() }),
+    }
+    if matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP) {
+        return Prepared::Unchanged;
+    }
+    let decoded = match reader(bytes, format).decode() {
+        Ok(decoded) => decoded,
+        Err(err) => return Prepared::Document(Unusable::Undecodable { format, error: err.to_string() }),
+    };
+    // JPEG has no alpha and no 16-bit samples
+    let rgb = decoded.to_rgb8();
+    let mut jpeg = Vec::new();
+    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY);
+    match rgb.write_with_encoder(encoder) {
+        Ok(()) => Prepared::Converted { from: format, jpeg },
+        Err(err) => Prepared::Document(Unusable::Undecodable { format, error: err.to_string() }),
+    }
+}
+
+/// A reader of `bytes` as `format` that refuses images larger than a photo
+/// can be
+fn reader(bytes: &[u8], format: ImageFormat) -> ImageReader<Cursor<&[u8]>> {
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits());
+    reader
+}
+
+fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_PHOTO_DIMENSIONS);
+    limits.max_image_height = Some(MAX_PHOTO_DIMENSIONS);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    limits
+}
+
+/// Whether Telegram accepts a photo of `width` by `height`
+fn fits(width: u32, height: u32) -> bool {
+    let (long, short) = (width.max(height), width.min(height));
+    short > 0 && width + height <= MAX_PHOTO_DIMENSIONS && long <= short.saturating_mul(MAX_PHOTO_RATIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEBP: &[u8] = include_bytes!("../tests/fixtures/thermal.webp");
+    const TIFF_16BIT: &[u8] = include_bytes!("../tests/fixtures/thermal-16bit.tiff");
+    const CORRUPT_PNG: &[u8] = include_bytes!("../tests/fixtures/corrupt.png");
+
+    #[test]
+    fn formats_are_read_from_the_bytes() {
+        assert_eq!(detect(WEBP), Some(ImageFormat::WebP));
+        assert_eq!(detect(TIFF_16BIT), Some(ImageFormat::Tiff));
+        assert_eq!(detect(CORRUPT_PNG), Some(ImageFormat::Png));
+        assert_eq!(detect(b"just some text"), None);
+    }
+
+    #[test]
+    fn webp_within_the_limits_is_sent_as_it_is() {
+        assert_eq!(prepare(WEBP), Prepared::Unchanged);
+    }
+
+    #[test]
+    fn sixteen_bit_tiff_becomes_a_jpeg() {
+        let Prepared::Converted { from, jpeg } = prepare(TIFF_16BIT) else {
+            panic!("TIFF was not converted");
+        };
+        assert_eq!(from, ImageFormat::Tiff);
+        assert_eq!(detect(&jpeg), Some(ImageFormat::Jpeg));
+        let converted = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((converted.width(), converted.height()), (16, 12));
+    }
+
+    #[test]
+    fn corrupt_and_unknown_files_become_documents() {
+        assert!(matches!(
+            prepare(CORRUPT_PNG),
+            Prepared::Document(Unusable::Undecodable { format: ImageFormat::Png, .. })
+        ));
+        assert_eq!(prepare(b"just some text"), Prepared::Document(Unusable::UnknownFormat));
+    }
+
+    #[test]
+    fn dimensions_are_checked_before_decoding() {
+        let mut bmp = Vec::new();
+        image::RgbImage::new(2001, 100).write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp).unwrap();
+        // Only the header: decoding would fail, the dimensions are enough
+        bmp.truncate(64);
+        assert_eq!(prepare(&bmp), Prepared::Document(Unusable::Dimensions { width: 2001, height: 100 }));
+    }
+
+    #[test]
+    fn photo_limits() {
+        assert!(fits(1280, 720));
+        assert!(fits(5000, 5000));
+        assert!(!fits(5000, 5001));
+        assert!(fits(2000, 100));
+        assert!(!fits(2001, 100));
+        assert!(!fits(0, 10));
+    }
+}
@@ -0,0 +1,323 @@
+//! Progress of broadcasts, persisted so a restart resumes them.
+//!
+//! A broadcast is written down with its targets when its fan-out starts and
+//! each chat is crossed off once a send to it succeeds. An entry still
+//! present at startup belongs to a broadcast the process did not finish,
+//! and only its remaining chats are sent to.
+//!
+//! The progress of every broadcast is kept in one small file. The message
+//! itself, which may be large, is written once to a file of its own in a
+//! directory beside it, so crossing off a chat does not rewrite it. Both
+//! are replaced atomically, so a crash cannot leave either half written.
+
+use crate::data_dir;
+use crate::sink::SendOptions;
+use crate::zmq_listener::ZmqMessage;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A broadcast in progress
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub id: u64,
+    /// Content hash of the text and attachment, for the logs
+    pub hash: u64,
+    /// How the targets were described in the log, e.g. `'ops'`
+    pub label: String,
+    /// The message as it is sent, after sanitizing and attachment checks
+    pub message: ZmqMessage,
+    pub opts: SendOptions,
+    pub started_at: DateTime<Utc>,
+    /// Chats not yet sent to successfully
+    pub remaining: BTreeSet<i64>,
+}
+
+impl Broadcast {
+    /// Whether the message's TTL, counted from the start of the broadcast,
+    /// ran out before `now`
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        match self.message.ttl {
+            Some(ttl) => {
+                let ttl = Duration::try_seconds(ttl as i64).unwrap_or(Duration::MAX);
+                self.started_at.checked_add_signed(ttl).is_some_and(|deadline| deadline <= now)
+            }
+            None => false,
+        }
+    }
+}
+
+/// What is rewritten as chats are crossed off: everything but the message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Progress {
+    hash: u64,
+    label: String,
+    opts: SendOptions,
+    started_at: DateTime<Utc>,
+    remaining: BTreeSet<i64>,
+}
+
+/// On-disk representation of the progress file
+#[derive(Serialize, Deserialize, Default)]
+struct JournalFile {
+    next_id: u64,
+    broadcasts: BTreeMap<u64, Progress>,
+}
+
+struct Entries {
+    file: JournalFile,
+    messages: HashMap<u64, ZmqMessage>,
+}
+
+/// Broadcasts that have not finished yet
+pub struct Journal {
+    path: Option<PathBuf>,
+    entries: Mutex<Entries>,
+}
+
+impl Journal {
+    /// A journal kept only in memory
+    pub fn new() -> Self {
+        let entries = Entries { file: JournalFile::default(), messages: HashMap::new() };
+        Journal { path: None, entries: Mutex::new(entries) }
+    }
+
+    /// A journal persisted at `path`, with the messages in the directory of
+    /// the same name without its extension, loading broadcasts left
+    /// unfinished
+    pub fn load(path: PathBuf) -> Self {
+        let mut file = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<JournalFile>(&contents).unwrap_or_else(|err| {
+                error!("Ignoring unreadable broadcast journal {}: {}", path.display(), err);
+                JournalFile::default()
+            }),
+            Err(_) => JournalFile::default(),
+        };
+        let dir = messages_dir(&path);
+        let mut messages = HashMap::new();
+        file.broadcasts.retain(|&id, _| match read_message(&dir, id) {
+            Ok(message) => {
+                messages.insert(id, message);
+                true
+            }
+            Err(err) => {
+                error!("Dropping unfinished broadcast {}: its message cannot be read: {}", id, err);
+                false
+            }
+        });
+        // Messages of broadcasts that finished just before a crash
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let id = entry.path().file_stem().and_then(|stem| stem.to_str()?.parse::<u64>().ok());
+            if !id.is_some_and(|id| file.broadcasts.contains_key(&id)) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Journal { path: Some(path), entries: Mutex::new(Entries { file, messages }) }
+    }
+
+    /// Write down a broadcast of `message` to `targets` and return its ID
+    pub fn begin(
+        &self,
+        hash: u64,
+        label: &str,
+        message: &ZmqMessage,
+        opts: SendOptions,
+        targets: &[i64],
+        now: DateTime<Utc>,
+    ) -> u64 {
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.file.next_id;
+        entries.file.next_id += 1;
+        let progress = Progress {
+            hash,
+            label: label.to_string(),
+            opts,
+            started_at: now,
+            remaining: targets.iter().copied().collect(),
+        };
+        entries.file.broadcasts.insert(id, progress);
+        entries.messages.insert(id, message.clone());
+        // The message goes first, so the progress never names a missing one
+        self.save_message(id, message);
+        self.save(&entries.file);
+        id
+    }
+
+    /// The send to `chat` succeeded
+    pub fn done(&self, id: u64, chat: i64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(progress) = entries.file.broadcasts.get_mut(&id) {
+            if progress.remaining.remove(&chat) {
+                self.save(&entries.file);
+            }
+        }
+    }
+
+    /// The broadcast is over; forget it
+    pub fn finish(&self, id: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.messages.remove(&id);
+        if entries.file.broadcasts.remove(&id).is_some() {
+            self.save(&entries.file);
+            if let Some(path) = &self.path {
+                let _ = fs::remove_file(message_path(&messages_dir(path), id));
+            }
+        }
+    }
+
+    /// Unfinished broadcasts, oldest first
+    pub fn incomplete(&self) -> Vec<Broadcast> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .file
+            .broadcasts
+            .iter()
+            .filter_map(|(&id, progress)| {
+                let message = entries.messages.get(&id)?.clone();
+                Some(Broadcast {
+                    id,
+                    hash: progress.hash,
+                    label: progress.label.clone(),
+                    message,
+                    opts: progress.opts,
+                    started_at: progress.started_at,
+                    remaining: progress.remaining.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().file.broadcasts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn save(&self, file: &JournalFile) {
+        let Some(path) = &self.path else { return };
+        let result = serde_json::to_vec(file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| data_dir::write_atomically(path, &json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist broadcast journal to {}: {}", path.display(), err);
+        }
+    }
+
+    fn save_message(&self, id: u64, message: &ZmqMessage) {
+        let Some(path) = &self.path else { return };
+        let dir = messages_dir(path);
+        let result = serde_json::to_vec(message).map_err(|e| e.to_string()).and_then(|json| {
+            fs::create_dir_all(&dir)
+                .and_then(|()| data_dir::write_atomically(&message_path(&dir, id), &json))
+                .map_err(|e| e.to_string())
+        });
+        if let Err(err) = result {
+            warn!("Failed to persist broadcast {} to {}: {}", id, dir.display(), err);
+        }
+    }
+}
+
+/// The directory the messages of the journal at `path` are kept in
+fn messages_dir(path: &Path) -> PathBuf {
+    path.with_extension("")
+}
+
+fn message_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+fn read_message(dir: &Path, id: u64) -> Result<ZmqMessage, String> {
+    let contents = fs::read_to_string(message_path(dir, id)).map_err(|e| e.to_string())?;
+    serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ttl: Option<u64>) -> ZmqMessage {
+        ZmqMessage { text: "hi".to_string(), subscriber_list: Some("ops".to_string()), ttl, ..Default::default() }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn finished_chats_are_crossed_off() {
+        let journal = Journal::new();
+        let id = journal.begin(7, "'ops'", &message(None), SendOptions::default(), &[1, 2, 3], at(0));
+        journal.done(id, 2);
+        journal.done(id, 9);
+        assert_eq!(journal.incomplete()[0].remaining, BTreeSet::from([1, 3]));
+        journal.finish(id);
+        assert!(journal.is_empty());
+    }
+
+    #[test]
+    fn unfinished_broadcasts_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("corky-journal-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let journal = Journal::load(path.clone());
+        let first = journal.begin(7, "'ops'", &message(Some(60)), SendOptions::default(), &[1, 2], at(0));
+        journal.done(first, 1);
+        let second = journal.begin(8, "[4, 5]", &message(None), SendOptions::default(), &[4, 5], at(0));
+        journal.finish(second);
+
+        let restarted = Journal::load(path.clone());
+        let left = restarted.incomplete();
+        assert_eq!(left.len(), 1);
+        assert_eq!((left[0].id, left[0].hash, &left[0].remaining), (first, 7, &BTreeSet::from([2])));
+        assert!(!left[0].expired(at(59)) && left[0].expired(at(60)));
+        // New broadcasts do not reuse the IDs of old ones
+        assert_eq!(restarted.begin(9, "'ops'", &message(None), SendOptions::default(), &[1], at(0)), second + 1);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir_all(messages_dir(&path));
+    }
+
+    #[test]
+    fn crossing_off_leaves_the_message_alone() {
+        let path = std::env::temp_dir().join(format!("corky-journal-message-{}.json", std::process::id()));
+        let dir = messages_dir(&path);
+        let _ = fs::remove_dir_all(&dir);
+        let journal = Journal::load(path.clone());
+        let long = ZmqMessage { text: "x".repeat(100_000), ..message(None) };
+        let id = journal.begin(7, "'ops'", &long, SendOptions::default(), &[1, 2], at(0));
+        let written = fs::metadata(message_path(&dir, id)).unwrap().modified().unwrap();
+        journal.done(id, 1);
+        assert!(fs::metadata(&path).unwrap().len() < 1000, "the progress file holds no text");
+        assert_eq!(fs::metadata(message_path(&dir, id)).unwrap().modified().unwrap(), written);
+        assert_eq!(Journal::load(path.clone()).incomplete()[0].message.text, long.text);
+
+        journal.finish(id);
+        assert!(!message_path(&dir, id).exists());
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_broadcast_without_its_message_is_dropped() {
+        let path = std::env::temp_dir().join(format!("corky-journal-lost-{}.json", std::process::id()));
+        let _ = fs::remove_dir_all(messages_dir(&path));
+        let journal = Journal::load(path.clone());
+        let lost = journal.begin(7, "'ops'", &message(None), SendOptions::default(), &[1], at(0));
+        let kept = journal.begin(8, "'ops'", &message(None), SendOptions::default(), &[2], at(0));
+        fs::remove_file(message_path(&messages_dir(&path), lost)).unwrap();
+        let left: Vec<u64> = Journal::load(path.clone()).incomplete().iter().map(|b| b.id).collect();
+        assert_eq!(left, vec![kept]);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir_all(messages_dir(&path));
+    }
+}
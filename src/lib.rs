@@ -0,0 +1,59 @@
+//! Corky Telegram: a bridge that relays ZMQ messages to Telegram chats.
+
+pub mod activity;
+pub mod aggregate;
+pub mod bridge;
+pub mod build_info;
+pub mod chat_order;
+pub mod check;
+pub mod commands;
+pub mod compression;
+pub mod config;
+pub mod crash;
+pub mod data_dir;
+pub mod dedupe;
+pub mod custom_commands;
+pub mod deferred;
+pub mod digest;
+pub mod error_replies;
+pub mod errors;
+pub mod events;
+pub mod file_ids;
+pub mod flood;
+pub mod health;
+pub mod heartbeat;
+pub mod history;
+pub mod html;
+pub mod logging;
+pub mod media;
+pub mod menu;
+pub mod i18n;
+pub mod images;
+pub mod journal;
+pub mod migrations;
+pub mod mutes;
+pub mod notices;
+pub mod oneshot;
+pub mod outbox;
+pub mod quarantine;
+pub mod queue;
+pub mod relay;
+pub mod reports;
+pub mod routes;
+pub mod runtime;
+pub mod quiet_hours;
+pub mod sender;
+pub mod sent;
+pub mod shared_settings;
+pub mod signing;
+pub mod spool;
+pub mod sink;
+pub mod state;
+pub mod stats;
+pub mod trace;
+pub mod traffic;
+pub mod unknown_commands;
+pub mod usernames;
+pub mod zmq_listener;
+
+pub use bridge::{CorkyBot, Error};
@@ -0,0 +1,299 @@
+//! Condensed, colorful console logger.
+//!
+//! Levels come from `log_level`, refined per target by `log_filters`
+//! (`"zmq=trace,send=warn"`). Targets are either the short names in
+//! `TARGETS` or module paths such as `teloxide::dispatching`.
+
+use chrono::Local;
+use log::{Level, LevelFilter, Metadata, Record};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Short target names and the modules they cover. `bot` is the whole crate,
+/// so it applies wherever a more specific name is not set.
+pub const TARGETS: &[(&str, &[&str])] = &[
+    ("zmq", &["corky_telegram::zmq_listener", "corky_telegram::error_replies"]),
+    ("send", &["corky_telegram::sender", "corky_telegram::sink", "corky_telegram::outbox"]),
+    ("bot", &["corky_telegram"]),
+    ("history", &["corky_telegram::history"]),
+    ("telegram", &["teloxide", "teloxide_core"]),
+];
+
+/// Per-target levels on top of a default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilters {
+    default: LevelFilter,
+    /// Module prefixes and their levels
+    rules: Vec<(String, LevelFilter)>,
+    /// Targets that are neither short names nor module paths
+    pub unknown_targets: Vec<String>,
+}
+
+impl Default for LogFilters {
+    fn default() -> Self {
+        LogFilters { default: LevelFilter::Info, rules: Vec::new(), unknown_targets: Vec::new() }
+    }
+}
+
+impl LogFilters {
+    /// Parse `filters` (`target=level,...`, RUST_LOG style) on top of the
+    /// `default` level. A bare level replaces the default, and a repeated
+    /// target keeps its last level.
+    pub fn parse(default: &str, filters: &str) -> Result<Self, String> {
+        let level = |s: &str| LevelFilter::from_str(s.trim()).map_err(|_| format!("unknown log level '{}'", s.trim()));
+        let mut parsed = LogFilters { default: level(default)?, ..LogFilters::default() };
+        for directive in filters.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let Some((target, value)) = directive.split_once('=') else {
+                parsed.default = level(directive)?;
+                continue;
+            };
+            let (target, value) = (target.trim(), level(value)?);
+            if target.is_empty() {
+                return Err(format!("log filter '{}' has no target", directive));
+            }
+            let modules: Vec<String> = match TARGETS.iter().find(|(name, _)| *name == target) {
+                Some((_, modules)) => modules.iter().map(|m| m.to_string()).collect(),
+                None => {
+                    if !target.contains("::") && !["corky_telegram", "teloxide", "teloxide_core"].contains(&target) {
+                        parsed.unknown_targets.push(target.to_string());
+                    }
+                    vec![target.to_string()]
+                }
+            };
+            for module in modules {
+                parsed.rules.retain(|(existing, _)| *existing != module);
+                parsed.rules.push((module, value));
+            }
+        }
+        // Most specific prefix first
+        parsed.rules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(parsed)
+    }
+
+    /// Level for records from `target`: the longest matching module prefix
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.rules
+            .iter()
+            .find(|(module, _)| {
+                target == module || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level any target uses
+    pub fn max_level(&self) -> LevelFilter {
+        self.rules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+/// The short target names, comma-separated
+pub fn target_names() -> String {
+    TARGETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+/// Filters in effect; Info everywhere until `apply` is called
+static FILTERS: RwLock<Option<LogFilters>> = RwLock::new(None);
+
+/// Switch the logger to `filters`
+pub fn apply(filters: LogFilters) {
+    log::set_max_level(filters.max_level());
+    *FILTERS.write().unwrap() = Some(filters);
+}
+
+/// Shorten a `ZMQ:` log line to its important part, or `None` for polling
+/// noise. Debug and trace lines were asked for through `log_filters` and are
+/// kept whole.
+fn condense_zmq(message: &str, level: Level) -> Option<String> {
+    if level > Level::Info {
+        return Some(message.replace("ZMQ: ", ""));
+    }
+    if ["poll detected", "entering", "poll error", "timeout"].iter().any(|noise| message.contains(noise)) {
+        return None;
+    }
+    let condensed = if let Some((_, sender)) = message.split_once("Frame 0:") {
+        format!("From: {}", sender.trim())
+    } else if let Some((_, content)) = message.split_once("Frame 1:").filter(|_| message.contains("send_message")) {
+        let content = content.trim();
+        let text = content
+            .split_once("\"text\":")
+            .map(|(_, rest)| rest.trim_start().strip_prefix('"').unwrap_or(rest))
+            .and_then(|rest| rest.split_once("\",").or_else(|| rest.split_once("\"}")))
+            .map(|(text, _)| text);
+        match text {
+            Some(text) => format!("Content: {}", text),
+            None => format!("Message: {}", content),
+        }
+    } else if message.contains("Successfully extracted command") {
+        match message.split_once("command:") {
+            Some((_, command)) => format!("Command: {}", command.trim()),
+            None => message.to_string(),
+        }
+    } else if message.contains("Processing ZMQ message") {
+        "Processing message".to_string()
+    } else {
+        message.replace("ZMQ: ", "")
+    };
+    Some(condensed)
+}
+
+/// `message` tagged with the trace it was logged under, unless it names
+/// one itself
+fn with_trace(message: String, field: Option<String>) -> String {
+    match field {
+        Some(field) if !message.contains("trace_id=") => format!("{} {}", message, field),
+        _ => message,
+    }
+}
+
+/// Set up a custom logger with condensed, colorful output
+pub fn setup_logger() {
+    struct CustomLogger;
+
+    impl log::Log for CustomLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            let max = match &*FILTERS.read().unwrap() {
+                Some(filters) => filters.level_for(metadata.target()),
+                None => LevelFilter::Info,
+            };
+            metadata.level() <= max
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                let timestamp = Local::now().format("%H:%M:%S").to_string();
+                let message = record.args().to_string();
+
+                // Color coding based on message type and level
+                let (color_code, prefix) = match record.level() {
+                    Level::Error => ("\x1b[31m", "ERROR"), // Red for errors
+                    Level::Warn => ("\x1b[33m", "WARN "), // Yellow for warnings
+                    Level::Info => {
+                        if message.contains("ZMQ:") {
+                            if message.contains("received message") || message.contains("Received message") {
+                                ("\x1b[36m", "ZMQ ") // Cyan for ZMQ received messages
+                            } else {
+                                ("\x1b[90m", "ZMQ ") // Dark gray for other ZMQ messages
+                            }
+                        } else if message.contains("telegram") || message.contains("bot") {
+                            ("\x1b[32m", "BOT ") // Green for bot-related messages
+                        } else if message.contains("Processing") || message.contains("command") {
+                            ("\x1b[35m", "CMD ") // Magenta for command processing
+                        } else if message.contains("Sent message") {
+                            ("\x1b[34m", "MSG ") // Blue for sent messages
+                        } else {
+                            ("\x1b[0m", "INFO") // Default for other info messages
+                        }
+                    }
+                    Level::Debug => ("\x1b[90m", "DEBUG"),
+                    Level::Trace => ("\x1b[90m", "TRACE"),
+                };
+
+                // Reset color code at the end
+                let reset_code = "\x1b[0m";
+                let log_message = if message.contains("ZMQ:") {
+                    match condense_zmq(&message, record.level()) {
+                        Some(condensed) => condensed,
+                        // Skip verbose polling messages
+                        None => return,
+                    }
+                } else {
+                    message
+                };
+                let log_message = with_trace(log_message, crate::trace::log_field());
+
+                // Condensed output format: [time] [type] message
+                println!("{}{} [{}] {}{}", color_code, timestamp, prefix, log_message, reset_code);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    let _ = log::set_boxed_logger(Box::new(CustomLogger)).map(|()| log::set_max_level(LevelFilter::Info));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_carry_the_current_trace_once() {
+        let field = || Some("trace_id=t1#2".to_string());
+        assert_eq!(with_trace("Sent message".into(), field()), "Sent message trace_id=t1#2");
+        assert_eq!(with_trace("Buffering trace_id=t0".into(), field()), "Buffering trace_id=t0");
+        assert_eq!(with_trace("Idle".into(), None), "Idle");
+    }
+
+    #[test]
+    fn empty_filters_use_the_default_everywhere() {
+        let filters = LogFilters::parse("warn", "").unwrap();
+        assert_eq!(filters.level_for("corky_telegram::sender"), LevelFilter::Warn);
+        assert_eq!(filters.max_level(), LevelFilter::Warn);
+        assert_eq!(LogFilters::parse("info", " , ").unwrap(), LogFilters::default());
+    }
+
+    #[test]
+    fn short_names_cover_their_modules() {
+        let filters = LogFilters::parse("info", "zmq=trace,send=warn").unwrap();
+        assert_eq!(filters.level_for("corky_telegram::zmq_listener::task"), LevelFilter::Trace);
+        assert_eq!(filters.level_for("corky_telegram::sender"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("corky_telegram::commands"), LevelFilter::Info);
+        // A prefix only matches whole path segments
+        assert_eq!(filters.level_for("corky_telegram::sender_extra"), LevelFilter::Info);
+        assert_eq!(filters.max_level(), LevelFilter::Trace);
+        assert!(filters.unknown_targets.is_empty());
+    }
+
+    #[test]
+    fn most_specific_target_wins() {
+        let filters = LogFilters::parse("info", "bot=warn,corky_telegram::commands=debug").unwrap();
+        assert_eq!(filters.level_for("corky_telegram::commands"), LevelFilter::Debug);
+        assert_eq!(filters.level_for("corky_telegram::relay"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("corky_telegram"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("corky_telegram::state"), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn repeated_targets_keep_the_last_level() {
+        let filters = LogFilters::parse("info", "zmq=trace,zmq=error").unwrap();
+        assert_eq!(filters.level_for("corky_telegram::zmq_listener"), LevelFilter::Error);
+        assert_eq!(filters.max_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn bad_levels_and_empty_targets_are_errors() {
+        assert_eq!(LogFilters::parse("loud", "").unwrap_err(), "unknown log level 'loud'");
+        assert_eq!(LogFilters::parse("info", "zmq=chatty").unwrap_err(), "unknown log level 'chatty'");
+        assert!(LogFilters::parse("info", "=debug").is_err());
+    }
+
+    #[test]
+    fn bare_level_replaces_default_and_unknown_targets_are_kept() {
+        let filters = LogFilters::parse("info", "debug,zmqq=trace").unwrap();
+        assert_eq!(filters.level_for("corky_telegram::sender"), LevelFilter::Debug);
+        assert_eq!(filters.unknown_targets, vec!["zmqq".to_string()]);
+    }
+    #[test]
+    fn zmq_lines_are_condensed() {
+        assert_eq!(condense_zmq("ZMQ: Frame 0: sensör", Level::Info).unwrap(), "From: sensör");
+        assert_eq!(
+            condense_zmq(r#"ZMQ: Frame 1: {"command":"send_message","text":"héllo 👋🏽","chat_id":1}"#, Level::Info).unwrap(),
+            "Content: héllo 👋🏽"
+        );
+        assert_eq!(condense_zmq("ZMQ: Successfully extracted command: 送信", Level::Info).unwrap(), "Command: 送信");
+        assert_eq!(condense_zmq("ZMQ: poll detected input", Level::Info), None);
+        assert_eq!(condense_zmq("ZMQ: poll detected input", Level::Debug).unwrap(), "poll detected input");
+    }
+
+    #[test]
+    fn zmq_markers_at_the_end_do_not_panic() {
+        // Multibyte text right before or after a marker, and markers with nothing after them
+        assert_eq!(condense_zmq("ZMQ: é Frame 0:", Level::Info).unwrap(), "From: ");
+        assert_eq!(condense_zmq("ZMQ: send_message Frame 1:", Level::Info).unwrap(), "Message: ");
+        assert_eq!(
+            condense_zmq(r#"ZMQ: send_message Frame 1: {"text":"日本"#, Level::Info).unwrap(),
+            r#"Message: {"text":"日本"#
+        );
+        assert_eq!(condense_zmq("ZMQ: Successfully extracted command:", Level::Info).unwrap(), "Command: ");
+    }
+}
@@ -0,0 +1,101 @@
+//! Confinement of attachment paths to `media_dir`.
+//!
+//! Without it anyone who can reach the ZMQ endpoint can make the bot upload
+//! any file the process can read. With it, `image_path` is resolved against
+//! the directory and must still be inside it after symlinks are followed.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Why an attachment path was not accepted
+#[derive(Debug)]
+pub enum Rejection {
+    /// Resolves outside `media_dir`, through `..` or a symlink
+    Outside(PathBuf),
+    /// Could not be resolved, e.g. because it or a parent does not exist
+    Unresolvable(io::Error),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Outside(resolved) => write!(f, "resolves to {}, outside media_dir", resolved.display()),
+            Rejection::Unresolvable(err) => write!(f, "cannot be resolved: {}", err),
+        }
+    }
+}
+
+/// `requested` as a canonical path inside `media_dir`. Relative paths are
+/// taken relative to `media_dir`.
+pub fn confine(media_dir: &Path, requested: &str) -> Result<PathBuf, Rejection> {
+    let root = media_dir.canonicalize().map_err(Rejection::Unresolvable)?;
+    let resolved = root.join(requested).canonicalize().map_err(Rejection::Unresolvable)?;
+    match resolved.starts_with(&root) {
+        true => Ok(resolved),
+        false => Err(Rejection::Outside(resolved)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A fresh `media` directory with `media/a.png`, next to `secret.txt`
+    fn layout(name: &str) -> PathBuf {
+        let base = std::env::temp_dir().join(format!("corky-media-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("media/sub")).unwrap();
+        fs::write(base.join("media/a.png"), b"a").unwrap();
+        fs::write(base.join("secret.txt"), b"secret").unwrap();
+        base
+    }
+
+    #[test]
+    fn paths_inside_are_accepted() {
+        let base = layout("inside");
+        let media = base.join("media");
+        let expected = media.join("a.png").canonicalize().unwrap();
+        assert_eq!(confine(&media, "a.png").unwrap(), expected);
+        assert_eq!(confine(&media, "sub/../a.png").unwrap(), expected);
+        assert_eq!(confine(&media, media.join("a.png").to_str().unwrap()).unwrap(), expected);
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn traversal_and_absolute_paths_outside_are_rejected() {
+        let base = layout("traversal");
+        let media = base.join("media");
+        assert!(matches!(confine(&media, "../secret.txt"), Err(Rejection::Outside(_))));
+        assert!(matches!(confine(&media, "sub/../../secret.txt"), Err(Rejection::Outside(_))));
+        let secret = base.join("secret.txt");
+        assert!(matches!(confine(&media, secret.to_str().unwrap()), Err(Rejection::Outside(_))));
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_directory_are_rejected() {
+        let base = layout("symlink");
+        let media = base.join("media");
+        std::os::unix::fs::symlink(base.join("secret.txt"), media.join("link.png")).unwrap();
+        std::os::unix::fs::symlink(&base, media.join("escape")).unwrap();
+        assert!(matches!(confine(&media, "link.png"), Err(Rejection::Outside(_))));
+        assert!(matches!(confine(&media, "escape/secret.txt"), Err(Rejection::Outside(_))));
+        // A link that stays inside is fine
+        std::os::unix::fs::symlink(media.join("a.png"), media.join("sub/alias.png")).unwrap();
+        assert!(confine(&media, "sub/alias.png").is_ok());
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn missing_files_and_parents_are_unresolvable() {
+        let base = layout("missing");
+        let media = base.join("media");
+        assert!(matches!(confine(&media, "none.png"), Err(Rejection::Unresolvable(_))));
+        assert!(matches!(confine(&media, "no/such/dir/../../../a.png"), Err(Rejection::Unresolvable(_))));
+        assert!(matches!(confine(&base.join("gone"), "a.png"), Err(Rejection::Unresolvable(_))));
+        let _ = fs::remove_dir_all(&base);
+    }
+}
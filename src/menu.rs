@@ -0,0 +1,140 @@
+//! The command menu Telegram shows when "/" is typed.
+//!
+//! Public built-ins are registered for the default scope, once more for
+//! each translated language. Owner chats and chats allowed a custom command
+//! get a list of their own, which repeats the public commands because a
+//! chat's scope replaces the default one.
+
+use crate::commands::{self, Command, OWNER_COMMANDS};
+use crate::config::TelegramSettings;
+use log::{debug, info, warn};
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, BotCommandScope, Recipient};
+use teloxide::utils::command::BotCommands;
+
+/// Every scope to register, with its language if any, and its commands,
+/// default scope first
+pub fn menu(settings: &TelegramSettings) -> Vec<(BotCommandScope, Option<String>, Vec<BotCommand>)> {
+    let builtin: Vec<BotCommand> = Command::bot_commands()
+        .into_iter()
+        .map(|command| BotCommand::new(command.command.trim_start_matches('/'), command.description))
+        .collect();
+    let public: Vec<BotCommand> =
+        builtin.iter().filter(|command| !OWNER_COMMANDS.contains(&command.command.as_str())).cloned().collect();
+
+    let mut chats: Vec<i64> = settings.owner_chat_ids.clone();
+    chats.extend(settings.commands.values().flat_map(|command| command.allowed_chats.iter().copied()));
+    chats.sort_unstable();
+    chats.dedup();
+
+    let mut scopes = vec![(BotCommandScope::Default, None, public.clone())];
+    for language in settings.translations.languages() {
+        let texts = settings.translations.texts(language);
+        let localized =
+            public.iter().map(|command| BotCommand::new(&command.command, commands::describe(&texts, command))).collect();
+        scopes.push((BotCommandScope::Default, Some(language.to_string()), localized));
+    }
+    for chat in chats {
+        let mut commands = match settings.is_owner(chat) {
+            true => builtin.clone(),
+            false => public.clone(),
+        };
+        commands.extend(
+            settings
+                .commands
+                .iter()
+                .filter(|(name, _)| settings.command_allowed(name, chat))
+                .map(|(name, command)| BotCommand::new(name, &command.description)),
+        );
+        scopes.push((BotCommandScope::Chat { chat_id: Recipient::Id(ChatId(chat)) }, None, commands));
+    }
+    scopes
+}
+
+/// Register `menu(settings)`, skipping scopes whose commands are already
+/// current. Failures are logged and otherwise ignored.
+pub async fn register(bot: &Bot, settings: &TelegramSettings) {
+    for (scope, language, commands) in menu(settings) {
+        let mut get = bot.get_my_commands().scope(scope.clone());
+        let mut set = bot.set_my_commands(commands.clone()).scope(scope.clone());
+        if let Some(language) = &language {
+            get = get.language_code(language);
+            set = set.language_code(language);
+        }
+        let language = language.as_deref().unwrap_or("default language");
+        match get.await {
+            Ok(current) if current == commands => {
+                debug!("Command menu for {:?} ({}) is up to date", scope, language);
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) => debug!("Could not read the command menu for {:?} ({}): {}", scope, language, err),
+        }
+        match set.await {
+            Ok(_) => info!("Registered {} commands for {:?} ({})", commands.len(), scope, language),
+            Err(err) => warn!("Failed to register the command menu for {:?} ({}): {}", scope, language, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    fn names(commands: &[BotCommand]) -> Vec<&str> {
+        commands.iter().map(|command| command.command.as_str()).collect()
+    }
+
+    #[test]
+    fn owner_commands_stay_out_of_the_default_scope() {
+        let settings = toml::from_str::<AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_ids = [1, 2]\n\
+             [telegram.commands.deploy]\ndescription = \"Deploy\"\ndestination = \"ops\"\n\
+             [telegram.commands.lights]\ndescription = \"Lights\"\ndestination = \"home\"\nallowed_chats = [2, 7]\n",
+        )
+        .unwrap()
+        .telegram;
+        let scopes = menu(&settings);
+        assert_eq!(scopes.len(), 4);
+
+        let (scope, language, public) = &scopes[0];
+        assert_eq!((scope, language), (&BotCommandScope::Default, &None));
+        assert!(names(public).contains(&"help"));
+        assert!(!names(public).iter().any(|name| OWNER_COMMANDS.contains(name) || *name == "deploy"));
+
+        let chat = |id| BotCommandScope::Chat { chat_id: Recipient::Id(ChatId(id)) };
+        let owner_one = &scopes[1].2;
+        assert_eq!(scopes[1].0, chat(1));
+        assert!(names(owner_one).contains(&"history") && names(owner_one).contains(&"deploy"));
+        assert!(!names(owner_one).contains(&"lights"));
+        assert!(names(&scopes[2].2).ends_with(&["deploy", "lights"]));
+        let (scope, _, guest) = &scopes[3];
+        assert_eq!(*scope, chat(7));
+        assert!(names(guest).ends_with(&["lights"]) && !names(guest).contains(&"status"));
+        assert!(names(guest).iter().all(|name| !name.starts_with('/')));
+    }
+
+    #[test]
+    fn translated_languages_get_their_own_default_menu() {
+        let mut settings = toml::from_str::<AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n").unwrap().telegram;
+        settings.translations = std::sync::Arc::new(
+            crate::i18n::Translations::from_toml("[de]\n\"command.help\" = \"Diese Hilfe anzeigen.\"\n").unwrap(),
+        );
+        let scopes = menu(&settings);
+        let (scope, language, german) = &scopes[1];
+        assert_eq!((scope, language.as_deref()), (&BotCommandScope::Default, Some("de")));
+        assert_eq!(names(german), names(&scopes[0].2));
+        let help = german.iter().find(|command| command.command == "help").unwrap();
+        assert_eq!(help.description, "Diese Hilfe anzeigen.");
+        // Untranslated descriptions stay English
+        let id = german.iter().find(|command| command.command == "id").unwrap();
+        assert_eq!(id.description, scopes[0].2.iter().find(|command| command.command == "id").unwrap().description);
+    }
+
+    #[test]
+    fn owner_commands_exist() {
+        let builtin = crate::commands::builtin_names();
+        assert!(OWNER_COMMANDS.iter().all(|name| builtin.iter().any(|b| b == name)));
+    }
+}
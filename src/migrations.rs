@@ -0,0 +1,193 @@
+//! Groups that were upgraded to supergroups.
+//!
+//! Telegram answers sends to the old group ID with the supergroup's new
+//! `-100…` ID. The send helpers follow it straight away and record the
+//! mapping here, so later sends and subscriber lists are rewritten before
+//! they reach Telegram. The map is owned by the `BotState`, which hands it
+//! to its sink so the helpers reach it through the sink they send with, and
+//! it is persisted so the rewrite survives restarts until the config is
+//! fixed.
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// On-disk representation
+#[derive(Serialize, Deserialize, Default)]
+struct MigrationsFile {
+    /// Old chat ID (as a string, for JSON) to new chat ID
+    chats: BTreeMap<String, i64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    path: Option<PathBuf>,
+    chats: BTreeMap<i64, i64>,
+    /// Recorded but not yet reported to the owners
+    unannounced: Vec<(i64, i64)>,
+}
+
+/// Old chat ID to new chat ID
+pub struct Migrations {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Migrations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Migrations {
+    /// An empty, in-memory map
+    pub const fn new() -> Self {
+        Migrations {
+            inner: Mutex::new(Inner { path: None, chats: BTreeMap::new(), unannounced: Vec::new() }),
+        }
+    }
+
+    /// Persist to `path` from now on, adding any mappings saved there before
+    pub fn load(&self, path: PathBuf) {
+        let saved = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<MigrationsFile>(&contents) {
+                Ok(file) => file.chats,
+                Err(err) => {
+                    error!("Ignoring unreadable migrations file {}: {}", path.display(), err);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        for (old, new) in saved {
+            match old.parse::<i64>() {
+                Ok(old) => {
+                    inner.chats.insert(old, new);
+                }
+                Err(_) => warn!("Ignoring migration of non-numeric chat '{}' in {}", old, path.display()),
+            }
+        }
+        if !inner.chats.is_empty() {
+            info!("Rewriting {} migrated chat(s): {:?}", inner.chats.len(), inner.chats);
+        }
+        inner.path = Some(path);
+    }
+
+    /// The chat that `chat` now is, following repeated migrations
+    pub fn resolve(&self, chat: i64) -> i64 {
+        *Self::chain(&self.inner.lock().unwrap(), chat).last().unwrap()
+    }
+
+    /// `chat` followed by every ID it migrated to, in order
+    fn chain(inner: &Inner, chat: i64) -> Vec<i64> {
+        let mut chain = vec![chat];
+        // Bounded in case a bad file contains a cycle
+        for _ in 0..8 {
+            match inner.chats.get(chain.last().unwrap()) {
+                Some(next) if !chain.contains(next) => chain.push(*next),
+                _ => break,
+            }
+        }
+        chain
+    }
+
+    /// Record that `old` became `new`. Returns false if this was already known.
+    pub fn record(&self, old: i64, new: i64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if old == new || inner.chats.get(&old) == Some(&new) {
+            return false;
+        }
+        warn!("Chat {} migrated to {}; sending there from now on", old, new);
+        inner.chats.insert(old, new);
+        inner.unannounced.push((old, new));
+        Self::save(&inner);
+        true
+    }
+
+    /// Not yet reported migrations of `chat` (or what it became), to tell
+    /// the owners about once
+    pub fn take_unannounced(&self, chat: i64) -> Vec<(i64, i64)> {
+        let mut inner = self.inner.lock().unwrap();
+        let chain = Self::chain(&inner, chat);
+        let (taken, kept) = inner.unannounced.drain(..).partition(|(old, _)| chain.contains(old));
+        inner.unannounced = kept;
+        taken
+    }
+
+    /// All mappings in ascending order of old chat ID
+    pub fn chats(&self) -> Vec<(i64, i64)> {
+        self.inner.lock().unwrap().chats.iter().map(|(&old, &new)| (old, new)).collect()
+    }
+
+    fn save(inner: &Inner) {
+        let Some(path) = &inner.path else { return };
+        let file = MigrationsFile { chats: inner.chats.iter().map(|(old, new)| (old.to_string(), *new)).collect() };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist migrations to {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Owner notice for a newly seen migration
+pub fn notice(old: i64, new: i64) -> String {
+    format!(
+        "Chat {} was upgraded to a supergroup and is now {}.\n\
+         Messages are being sent to the new ID; replace {} with {} in the config.",
+        old, new, old, new
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_follows_chains() {
+        let migrations = Migrations::new();
+        assert_eq!(migrations.resolve(-5), -5);
+        assert!(migrations.record(-5, -1005));
+        assert!(migrations.record(-1005, -1006));
+        assert_eq!(migrations.resolve(-5), -1006);
+        assert_eq!(migrations.resolve(7), 7);
+    }
+
+    #[test]
+    fn each_migration_is_announced_once() {
+        let migrations = Migrations::new();
+        assert!(migrations.record(-5, -1005));
+        assert!(!migrations.record(-5, -1005));
+        assert!(migrations.record(-6, -1006));
+        assert_eq!(migrations.take_unannounced(-5), vec![(-5, -1005)]);
+        assert!(migrations.take_unannounced(-5).is_empty());
+        assert_eq!(migrations.take_unannounced(-6), vec![(-6, -1006)]);
+    }
+
+    #[test]
+    fn cycles_do_not_hang() {
+        let migrations = Migrations::new();
+        migrations.record(-1, -2);
+        migrations.record(-2, -1);
+        assert_eq!(migrations.resolve(-1), -2);
+    }
+
+    #[test]
+    fn mappings_are_persisted() {
+        let path = std::env::temp_dir().join(format!("corky-migrations-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let migrations = Migrations::new();
+        migrations.load(path.clone());
+        migrations.record(-5, -1005);
+        let reloaded = Migrations::new();
+        reloaded.load(path.clone());
+        assert_eq!(reloaded.chats(), vec![(-5, -1005)]);
+        // Loaded mappings were announced by the run that recorded them
+        assert!(reloaded.take_unannounced(-5).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,181 @@
+//! Subscriber-controlled pauses of broadcast deliveries.
+//!
+//! A mute is either indefinite or expires at a fixed time. Mutes are
+//! persisted so they survive restarts; expired entries are dropped lazily.
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// On-disk representation: chat id -> expiry as unix seconds (None = indefinite)
+#[derive(Serialize, Deserialize, Default)]
+struct MutesFile {
+    chats: BTreeMap<i64, Option<i64>>,
+}
+
+/// Muted chats and when their mutes expire
+pub struct Mutes {
+    path: Option<PathBuf>,
+    chats: Mutex<BTreeMap<i64, Option<i64>>>,
+}
+
+impl Mutes {
+    /// Mutes kept only in memory
+    pub fn new() -> Self {
+        Mutes {
+            path: None,
+            chats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Mutes persisted at `path`, loading any previous state
+    pub fn load(path: PathBuf) -> Self {
+        let chats = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<MutesFile>(&contents) {
+                Ok(file) => file.chats,
+                Err(err) => {
+                    error!("Ignoring unreadable mutes file {}: {}", path.display(), err);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        Mutes {
+            path: Some(path),
+            chats: Mutex::new(chats),
+        }
+    }
+
+    /// Mute `chat` until `until`, or indefinitely when `None`
+    pub fn mute(&self, chat: i64, until: Option<DateTime<Utc>>) {
+        let mut chats = self.chats.lock().unwrap();
+        chats.insert(chat, until.map(|t| t.timestamp()));
+        self.save(&chats);
+    }
+
+    /// Remove a mute. Returns false if the chat was not muted.
+    pub fn unmute(&self, chat: i64) -> bool {
+        let mut chats = self.chats.lock().unwrap();
+        let removed = chats.remove(&chat).is_some();
+        if removed {
+            self.save(&chats);
+        }
+        removed
+    }
+
+    /// Whether `chat` is muted at `now`, forgetting the mute if it expired
+    pub fn is_muted(&self, chat: i64, now: DateTime<Utc>) -> bool {
+        let mut chats = self.chats.lock().unwrap();
+        match chats.get(&chat) {
+            None => false,
+            Some(None) => true,
+            Some(Some(until)) if *until > now.timestamp() => true,
+            Some(Some(_)) => {
+                chats.remove(&chat);
+                self.save(&chats);
+                false
+            }
+        }
+    }
+
+    /// Active mutes at `now` with their expiry, in chat id order
+    pub fn active(&self, now: DateTime<Utc>) -> Vec<(i64, Option<DateTime<Utc>>)> {
+        self.chats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| until.is_none_or(|t| t > now.timestamp()))
+            .map(|(&chat, until)| (chat, until.and_then(|t| DateTime::from_timestamp(t, 0))))
+            .collect()
+    }
+
+    fn save(&self, chats: &BTreeMap<i64, Option<i64>>) {
+        let Some(path) = &self.path else { return };
+        let file = MutesFile { chats: chats.clone() };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist mutes to {}: {}", path.display(), err);
+        }
+    }
+}
+
+impl Default for Mutes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse a mute duration such as `30m`, `12h`, `7d`, or `2w`
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (digits, unit) = s.split_at(split);
+    let n: i64 = digits.parse().ok()?;
+    if n <= 0 {
+        return None;
+    }
+    match unit {
+        "m" => Duration::try_minutes(n),
+        "h" => Duration::try_hours(n),
+        "d" => Duration::try_days(n),
+        "w" => Duration::try_weeks(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("12h"), Some(Duration::hours(12)));
+        assert_eq!(parse_duration("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_duration("2w"), Some(Duration::weeks(2)));
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("0d"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("5y"), None);
+    }
+
+    #[test]
+    fn timed_mute_expires() {
+        let mutes = Mutes::new();
+        mutes.mute(5, Some(at(1_000)));
+        assert!(mutes.is_muted(5, at(999)));
+        assert!(!mutes.is_muted(5, at(1_000)));
+        assert!(mutes.active(at(0)).is_empty());
+    }
+
+    #[test]
+    fn indefinite_mute_until_unmuted() {
+        let mutes = Mutes::new();
+        mutes.mute(5, None);
+        assert!(mutes.is_muted(5, at(i32::MAX as i64)));
+        assert!(mutes.unmute(5));
+        assert!(!mutes.unmute(5));
+        assert!(!mutes.is_muted(5, at(0)));
+    }
+
+    #[test]
+    fn mutes_persist() {
+        let path = std::env::temp_dir().join(format!("corky-mutes-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Mutes::load(path.clone()).mute(3, Some(at(500)));
+        Mutes::load(path.clone()).mute(4, None);
+        let mutes = Mutes::load(path.clone());
+        assert_eq!(mutes.active(at(100)), vec![(3, Some(at(500))), (4, None)]);
+        let _ = fs::remove_file(&path);
+    }
+}
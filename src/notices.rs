@@ -0,0 +1,115 @@
+//! Messages telling the owner about the bot's own lifecycle.
+
+use crate::build_info;
+use crate::config::TelegramSettings;
+use std::time::Duration;
+
+/// Text of the notice sent to the owner once the bot is up, with the summary
+/// of the panic that ended the previous run, if any
+pub fn startup_notice(username: &str, settings: &TelegramSettings, last_panic: Option<&str>) -> String {
+    let mut lines = vec![
+        format!("{} started as @{}", build_info::summary(), username),
+        format!("ZMQ endpoint: {}", settings.zmq_endpoint),
+    ];
+    let mut lists: Vec<_> = settings.subscriber_lists.iter().collect();
+    lists.sort_by(|a, b| a.0.cmp(b.0));
+    if lists.is_empty() {
+        lines.push("Subscriber lists: none".to_string());
+    } else {
+        let lists: Vec<String> = lists
+            .iter()
+            .map(|(name, list)| format!("{} ({})", name, list.chats.len()))
+            .collect();
+        lines.push(format!("Subscriber lists: {}", lists.join(", ")));
+    }
+    if let Some(summary) = last_panic {
+        lines.push(format!("Previous run crashed: {}", summary));
+    }
+    lines.join("\n")
+}
+
+/// Compact uptime such as "3d 4h", "2h 5m" or "42s"
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// What was still waiting when shutdown began
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pending {
+    /// Received events that will not be processed
+    pub events: usize,
+    /// Parsed messages in the outbox; workers get a short while to send them
+    pub outbox: usize,
+}
+
+/// Text of the notice sent to the owner on shutdown
+pub fn shutdown_notice(signal: &str, uptime: Duration, delivered: u64, pending: Pending) -> String {
+    let mut notice = format!(
+        "corky-telegram shutting down (signal: {}), uptime {}, {} messages delivered",
+        signal,
+        format_uptime(uptime),
+        delivered
+    );
+    if pending == Pending::default() {
+        notice.push_str(". Nothing queued.");
+    } else {
+        notice.push_str(&format!(
+            ". Abandoning {} queued event(s); {} message(s) left in the outbox.",
+            pending.events, pending.outbox
+        ));
+    }
+    notice
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_notice_summarises_config() {
+        let settings: TelegramSettings = toml::from_str(
+            "bot_token = \"123:secret\"\nowner_chat_id = 1\n\
+             [subscriber_lists]\nteam = [1, 2, 3]\nfamily = [4]\n",
+        )
+        .unwrap();
+        let notice = startup_notice("corky_bot", &settings, None);
+        let lines: Vec<&str> = notice.lines().collect();
+        assert_eq!(lines[0], format!("{} started as @corky_bot", build_info::summary()));
+        assert_eq!(lines[1], "ZMQ endpoint: tcp://127.0.0.1:6565");
+        assert_eq!(lines[2], "Subscriber lists: family (1), team (3)");
+        assert_eq!(lines.len(), 3);
+        assert!(!notice.contains("secret"));
+
+        let notice = startup_notice("corky_bot", &settings, Some("Panicked at noon"));
+        assert!(notice.ends_with("\nPrevious run crashed: Panicked at noon"));
+    }
+
+    #[test]
+    fn uptime_uses_two_largest_units() {
+        assert_eq!(format_uptime(Duration::from_secs(42)), "42s");
+        assert_eq!(format_uptime(Duration::from_secs(5 * 60 + 7)), "5m");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 3600 + 5 * 60)), "2h 5m");
+        assert_eq!(format_uptime(Duration::from_secs(3 * 86_400 + 4 * 3600 + 59)), "3d 4h");
+    }
+
+    #[test]
+    fn shutdown_notice_reports_pending_work() {
+        let uptime = Duration::from_secs(3 * 86_400 + 4 * 3600);
+        assert_eq!(
+            shutdown_notice("SIGTERM", uptime, 1240, Pending::default()),
+            "corky-telegram shutting down (signal: SIGTERM), uptime 3d 4h, 1240 messages delivered. Nothing queued."
+        );
+        let notice = shutdown_notice("SIGINT", uptime, 0, Pending { events: 3, outbox: 2 });
+        assert!(notice.ends_with("Abandoning 3 queued event(s); 2 message(s) left in the outbox."));
+    }
+}
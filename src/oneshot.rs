@@ -0,0 +1,147 @@
+//! `send` mode: deliver one message through the production send path and
+//! exit, to test a deployment without a ZMQ producer.
+
+use crate::config::AppConfig;
+use crate::sender::{self, Delivery};
+use crate::state::BotState;
+use crate::zmq_listener::ZmqMessage;
+use crate::{logging, sink};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+pub const USAGE: &str =
+    "usage: corky-telegram send (--chat <id or @username> | --list <name>) --text <text> [--image <path>]";
+
+/// Exit code for arguments that could not be understood
+const EXIT_USAGE: i32 = 2;
+
+/// Run `send` with the arguments after it and return the process exit code:
+/// 0 if every chat got the message, 1 otherwise. Starts neither the
+/// dispatcher nor the ZMQ listener, and keeps its state in memory so a
+/// running bot's files are left alone.
+pub async fn run(args: &[String]) -> i32 {
+    let cmd = match parse_args(args) {
+        Ok(cmd) => cmd,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let settings = match AppConfig::load() {
+        Ok(config) => config.telegram,
+        Err(err) => {
+            println!("FAIL {}", err);
+            return 1;
+        }
+    };
+    let problems = settings.validate();
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("FAIL Invalid config: {}", problem);
+        }
+        return 1;
+    }
+    logging::setup_logger();
+    if let Ok(filters) = settings.log_filters() {
+        logging::apply(filters);
+    }
+
+    let state = Arc::new(BotState::in_memory(&settings));
+    let bot = state.sink(sink::bot_for(&settings));
+    let (lines, code) = report(&sender::process_zmq_message(&bot, &settings, &state, cmd).await);
+    for line in lines {
+        println!("{}", line);
+    }
+    code
+}
+
+/// The message described by `args`, built as a producer's JSON payload would be
+fn parse_args(args: &[String]) -> Result<ZmqMessage, String> {
+    let mut payload = Map::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+        let (key, value) = match flag.as_str() {
+            "--chat" => match value.parse::<i64>() {
+                Ok(id) => ("chat_id", json!(id)),
+                Err(_) => ("chat", json!(value)),
+            },
+            "--list" => ("subscriber_list", json!(value)),
+            "--text" => ("text", json!(value)),
+            "--image" => ("image_path", json!(value)),
+            _ => return Err(format!("unknown option {}", flag)),
+        };
+        if payload.insert(key.to_string(), value).is_some() {
+            return Err(format!("{} given twice", flag));
+        }
+    }
+    if !payload.contains_key("text") {
+        return Err("--text is required".to_string());
+    }
+    let targets = ["chat_id", "chat", "subscriber_list"].iter().filter(|key| payload.contains_key(**key)).count();
+    if targets != 1 {
+        return Err("give exactly one of --chat and --list".to_string());
+    }
+    serde_json::from_value(Value::Object(payload)).map_err(|err| err.to_string())
+}
+
+/// One line per chat and the exit code for `outcomes`
+fn report(outcomes: &[(i64, Delivery)]) -> (Vec<String>, i32) {
+    if outcomes.is_empty() {
+        return (vec!["FAIL Not sent to any chat; see the log above".to_string()], 1);
+    }
+    let lines = outcomes
+        .iter()
+        .map(|(chat, outcome)| match outcome {
+            Ok(ids) => {
+                let ids: Vec<String> = ids.iter().map(|id| id.0.to_string()).collect();
+                format!("OK   {}: message {}", chat, ids.join(", "))
+            }
+            Err(category) => format!("FAIL {}: {}", chat, category),
+        })
+        .collect();
+    let code = match outcomes.iter().all(|(_, outcome)| outcome.is_ok()) {
+        true => 0,
+        false => 1,
+    };
+    (lines, code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChatRef;
+    use crate::errors::ErrorCategory;
+    use teloxide::types::MessageId;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn arguments_become_a_payload() {
+        let cmd = parse_args(&args("--chat 42 --text hello --image /tmp/a.png")).unwrap();
+        assert_eq!((cmd.chat_id, cmd.text.as_str(), cmd.image_path.as_deref()), (Some(42), "hello", Some("/tmp/a.png")));
+        let cmd = parse_args(&args("--list team --text hi")).unwrap();
+        assert_eq!(cmd.subscriber_list.as_deref(), Some("team"));
+        assert_eq!(parse_args(&args("--chat @ops_room --text hi")).unwrap().chat, Some(ChatRef::Username("@ops_room".to_string())));
+
+        assert_eq!(parse_args(&args("--chat 1")).unwrap_err(), "--text is required");
+        assert_eq!(parse_args(&args("--text hi")).unwrap_err(), "give exactly one of --chat and --list");
+        assert_eq!(parse_args(&args("--chat 1 --list team --text hi")).unwrap_err(), "give exactly one of --chat and --list");
+        assert_eq!(parse_args(&args("--text hi --text again")).unwrap_err(), "--text given twice");
+        assert_eq!(parse_args(&args("--chat 1 --text")).unwrap_err(), "--text needs a value");
+        assert_eq!(parse_args(&args("--to 1")).unwrap_err(), "unknown option --to");
+    }
+
+    #[test]
+    fn any_failure_fails_the_run() {
+        let sent: Delivery = Ok(vec![MessageId(7), MessageId(8)]);
+        let (lines, code) = report(&[(1, sent.clone())]);
+        assert_eq!((lines, code), (vec!["OK   1: message 7, 8".to_string()], 0));
+        let (lines, code) = report(&[(1, sent), (2, Err(ErrorCategory::Blocked))]);
+        assert_eq!(code, 1);
+        assert!(lines[1].starts_with("FAIL 2: "));
+        assert_eq!(report(&[]).1, 1);
+    }
+}
@@ -0,0 +1,256 @@
+//! Parsed messages waiting for a send worker, in two priority lanes.
+//!
+//! Workers always take high-priority messages first, so an alert queued
+//! behind a burst of normal traffic goes out as soon as a worker is free.
+//! One worker serves only the high lane, so even a fully busy pool cannot
+//! hold an alert back. Messages whose `ttl` runs out while sends are
+//! paused for flood limits are taken out rather than sent late.
+//!
+//! Messages for chats that are flood-waited on their own are held aside,
+//! so they take no worker, and go back to the front of their lane in their
+//! original order once the wait is over.
+
+use crate::zmq_listener::{Priority, ZmqMessage};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time;
+
+/// A message and when it was queued
+type Queued = (Instant, ZmqMessage);
+
+#[derive(Default)]
+struct Lanes {
+    high: VecDeque<Queued>,
+    normal: VecDeque<Queued>,
+    /// Messages held until their chats' flood waits end, in the order held
+    held: Vec<(Instant, Queued)>,
+    closed: bool,
+}
+
+impl Lanes {
+    /// Put held messages that are due at `now` back at the front of their lanes
+    fn release_due(&mut self, now: Instant) {
+        let (due, held): (Vec<_>, Vec<_>) = self.held.drain(..).partition(|(until, _)| *until <= now);
+        self.held = held;
+        for (_, queued) in due.into_iter().rev() {
+            match queued.1.priority {
+                Priority::High => self.high.push_front(queued),
+                Priority::Normal => self.normal.push_front(queued),
+            }
+        }
+    }
+}
+
+/// Which lanes a worker takes messages from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Serve {
+    /// High first, then normal
+    All,
+    /// Only the high lane
+    HighOnly,
+}
+
+/// Unbounded two-lane queue of messages to send
+#[derive(Default)]
+pub struct Outbox {
+    lanes: Mutex<Lanes>,
+    available: Notify,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a message in the lane matching its priority
+    pub fn push(&self, message: ZmqMessage) {
+        {
+            let mut lanes = self.lanes.lock().unwrap();
+            let queued = (time::Instant::now().into_std(), message);
+            match queued.1.priority {
+                Priority::High => lanes.high.push_back(queued),
+                Priority::Normal => lanes.normal.push_back(queued),
+            }
+        }
+        self.available.notify_waiters();
+    }
+
+    /// Set `message` aside until `until`, when it goes back to the front
+    /// of its lane. Its `ttl` counts from when it is held.
+    pub fn hold(&self, message: ZmqMessage, until: Instant) {
+        let now = time::Instant::now().into_std();
+        self.lanes.lock().unwrap().held.push((until, (now, message)));
+    }
+
+    /// Messages held for flood-waited chats
+    pub fn held(&self) -> usize {
+        self.lanes.lock().unwrap().held.len()
+    }
+
+    /// Take the next message without waiting
+    pub fn try_next(&self, serve: Serve) -> Option<ZmqMessage> {
+        let mut lanes = self.lanes.lock().unwrap();
+        lanes.release_due(time::Instant::now().into_std());
+        let queued = match lanes.high.pop_front() {
+            Some(queued) => Some(queued),
+            None if serve == Serve::All => lanes.normal.pop_front(),
+            None => None,
+        };
+        queued.map(|(_, message)| message)
+    }
+
+    /// Remove the messages whose `ttl`, counted from when they were queued,
+    /// has run out by `now`
+    pub fn take_expired(&self, now: Instant) -> Vec<ZmqMessage> {
+        let mut guard = self.lanes.lock().unwrap();
+        let lanes = &mut *guard;
+        let expired = |(queued_at, message): &Queued| {
+            message.ttl.is_some_and(|ttl| now.saturating_duration_since(*queued_at) >= Duration::from_secs(ttl))
+        };
+        let mut taken = Vec::new();
+        for lane in [&mut lanes.high, &mut lanes.normal] {
+            let (gone, kept): (VecDeque<_>, VecDeque<_>) = lane.drain(..).partition(expired);
+            *lane = kept;
+            taken.extend(gone.into_iter().map(|(_, message)| message));
+        }
+        let (gone, kept): (Vec<_>, Vec<_>) = lanes.held.drain(..).partition(|(_, queued)| expired(queued));
+        lanes.held = kept;
+        taken.extend(gone.into_iter().map(|(_, (_, message))| message));
+        taken
+    }
+
+    /// Wait for the next message. Returns `None` once the outbox is closed
+    /// and the lanes this worker serves are empty.
+    pub async fn next(&self, serve: Serve) -> Option<ZmqMessage> {
+        loop {
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(message) = self.try_next(serve) {
+                return Some(message);
+            }
+            let (closed, release) = {
+                let lanes = self.lanes.lock().unwrap();
+                (lanes.closed, lanes.held.iter().map(|(until, _)| *until).min())
+            };
+            if closed {
+                return None;
+            }
+            match release {
+                Some(until) => {
+                    let _ = time::timeout_at(until.into(), notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Let workers exit once the lanes they serve are empty
+    pub fn close(&self) {
+        self.lanes.lock().unwrap().closed = true;
+        self.available.notify_waiters();
+    }
+
+    /// Queued (high, normal) message counts
+    pub fn depths(&self) -> (usize, usize) {
+        let lanes = self.lanes.lock().unwrap();
+        (lanes.high.len(), lanes.normal.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn message(text: &str, priority: Priority) -> ZmqMessage {
+        let mut message: ZmqMessage = serde_json::from_value(serde_json::json!({ "text": text })).unwrap();
+        message.priority = priority;
+        message
+    }
+
+    #[test]
+    fn high_priority_jumps_the_queue() {
+        let outbox = Outbox::new();
+        outbox.push(message("n1", Priority::Normal));
+        outbox.push(message("n2", Priority::Normal));
+        outbox.push(message("alert", Priority::High));
+        assert_eq!(outbox.depths(), (1, 2));
+        let order: Vec<_> = std::iter::from_fn(|| outbox.try_next(Serve::All)).map(|m| m.text).collect();
+        assert_eq!(order, vec!["alert", "n1", "n2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_messages_are_taken_out() {
+        let outbox = Outbox::new();
+        let mut short = message("short", Priority::High);
+        short.ttl = Some(5);
+        outbox.push(short);
+        outbox.push(message("forever", Priority::Normal));
+        let later = time::Instant::now().into_std() + Duration::from_secs(5);
+        let expired: Vec<_> = outbox.take_expired(later).into_iter().map(|m| m.text).collect();
+        assert_eq!(expired, vec!["short"]);
+        assert_eq!(outbox.depths(), (0, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_messages_return_in_order_when_due() {
+        let outbox = Outbox::new();
+        let until = time::Instant::now().into_std() + Duration::from_secs(30);
+        outbox.hold(message("a1", Priority::Normal), until);
+        outbox.hold(message("a2", Priority::Normal), until);
+        outbox.push(message("b", Priority::Normal));
+        assert_eq!(outbox.held(), 2);
+        assert_eq!(outbox.next(Serve::All).await.unwrap().text, "b");
+
+        let start = time::Instant::now();
+        assert_eq!(outbox.next(Serve::All).await.unwrap().text, "a1");
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        outbox.push(message("c", Priority::Normal));
+        let order: Vec<_> = std::iter::from_fn(|| outbox.try_next(Serve::All)).map(|m| m.text).collect();
+        assert_eq!(order, vec!["a2", "c"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_messages_expire() {
+        let outbox = Outbox::new();
+        let mut short = message("short", Priority::Normal);
+        short.ttl = Some(5);
+        outbox.hold(short, time::Instant::now().into_std() + Duration::from_secs(30));
+        let later = time::Instant::now().into_std() + Duration::from_secs(5);
+        let expired: Vec<_> = outbox.take_expired(later).into_iter().map(|m| m.text).collect();
+        assert_eq!(expired, vec!["short"]);
+        assert_eq!(outbox.held(), 0);
+    }
+
+    #[test]
+    fn high_only_worker_ignores_normal_lane() {
+        let outbox = Outbox::new();
+        outbox.push(message("n1", Priority::Normal));
+        assert!(outbox.try_next(Serve::HighOnly).is_none());
+        assert_eq!(outbox.depths(), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn waiting_worker_wakes_on_push_and_exits_on_close() {
+        let outbox = Arc::new(Outbox::new());
+        let worker = {
+            let outbox = outbox.clone();
+            tokio::spawn(async move {
+                let mut seen = Vec::new();
+                while let Some(message) = outbox.next(Serve::All).await {
+                    seen.push(message.text);
+                }
+                seen
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        outbox.push(message("late", Priority::Normal));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        outbox.close();
+        assert_eq!(worker.await.unwrap(), vec!["late"]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Automatic quarantine of chats that can no longer receive messages.
+//!
+//! A chat that fails with "blocked" or "chat not found" errors on enough
+//! consecutive sends is skipped until the owner releases it. The set of
+//! quarantined chats is persisted so it survives restarts.
+
+use crate::errors::ErrorCategory;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// On-disk representation
+#[derive(Serialize, Deserialize, Default)]
+struct QuarantineFile {
+    chats: BTreeSet<i64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    strikes: HashMap<i64, u32>,
+    chats: BTreeSet<i64>,
+}
+
+/// Tracks consecutive permanent failures per chat
+pub struct Quarantine {
+    threshold: u32,
+    path: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+/// Whether a failure suggests the chat is gone for good
+pub fn counts_toward_quarantine(category: ErrorCategory) -> bool {
+    matches!(category, ErrorCategory::Blocked | ErrorCategory::ChatNotFound)
+}
+
+impl Quarantine {
+    /// In-memory quarantine; `threshold` of 0 disables quarantining
+    pub fn new(threshold: u32) -> Self {
+        Quarantine {
+            threshold,
+            path: None,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Quarantine persisted at `path`, loading any previous state
+    pub fn load(path: PathBuf, threshold: u32) -> Self {
+        let chats = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<QuarantineFile>(&contents) {
+                Ok(file) => file.chats,
+                Err(err) => {
+                    error!("Ignoring unreadable quarantine file {}: {}", path.display(), err);
+                    BTreeSet::new()
+                }
+            },
+            Err(_) => BTreeSet::new(),
+        };
+        Quarantine {
+            threshold,
+            path: Some(path),
+            inner: Mutex::new(Inner { strikes: HashMap::new(), chats }),
+        }
+    }
+
+    pub fn is_quarantined(&self, chat: i64) -> bool {
+        self.inner.lock().unwrap().chats.contains(&chat)
+    }
+
+    /// Feed a delivery outcome back. Returns true when this outcome pushed
+    /// the chat into quarantine.
+    pub fn record(&self, chat: i64, outcome: Result<(), ErrorCategory>) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match outcome {
+            Err(category) if counts_toward_quarantine(category) => {
+                let strikes = inner.strikes.entry(chat).or_insert(0);
+                *strikes += 1;
+                if *strikes >= self.threshold && inner.chats.insert(chat) {
+                    inner.strikes.remove(&chat);
+                    self.save(&inner);
+                    return true;
+                }
+                false
+            }
+            // Transient failures neither clear nor add strikes
+            Err(_) => false,
+            Ok(()) => {
+                inner.strikes.remove(&chat);
+                false
+            }
+        }
+    }
+
+    /// Release a chat from quarantine. Returns false if it was not quarantined.
+    pub fn release(&self, chat: i64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.strikes.remove(&chat);
+        let released = inner.chats.remove(&chat);
+        if released {
+            self.save(&inner);
+        }
+        released
+    }
+
+    /// Quarantined chats in ascending order
+    pub fn chats(&self) -> Vec<i64> {
+        self.inner.lock().unwrap().chats.iter().copied().collect()
+    }
+
+    fn save(&self, inner: &Inner) {
+        let Some(path) = &self.path else { return };
+        let file = QuarantineFile { chats: inner.chats.clone() };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist quarantine to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantines_after_threshold_consecutive_failures() {
+        let q = Quarantine::new(3);
+        assert!(!q.record(5, Err(ErrorCategory::Blocked)));
+        assert!(!q.record(5, Err(ErrorCategory::Blocked)));
+        assert!(q.record(5, Err(ErrorCategory::ChatNotFound)));
+        assert!(q.is_quarantined(5));
+        // Already quarantined: no second notification
+        assert!(!q.record(5, Err(ErrorCategory::Blocked)));
+    }
+
+    #[test]
+    fn success_resets_strikes() {
+        let q = Quarantine::new(2);
+        q.record(5, Err(ErrorCategory::Blocked));
+        q.record(5, Ok(()));
+        assert!(!q.record(5, Err(ErrorCategory::Blocked)));
+        assert!(!q.is_quarantined(5));
+    }
+
+    #[test]
+    fn transient_failures_do_not_count() {
+        let q = Quarantine::new(1);
+        assert!(!q.record(5, Err(ErrorCategory::Network)));
+        assert!(!q.record(5, Err(ErrorCategory::BadRequest)));
+        assert!(!q.is_quarantined(5));
+    }
+
+    #[test]
+    fn zero_threshold_disables() {
+        let q = Quarantine::new(0);
+        assert!(!q.record(5, Err(ErrorCategory::Blocked)));
+        assert!(!q.is_quarantined(5));
+    }
+
+    #[test]
+    fn release_and_persistence() {
+        let path = std::env::temp_dir().join(format!("corky-quarantine-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let q = Quarantine::load(path.clone(), 1);
+            q.record(7, Err(ErrorCategory::Blocked));
+            q.record(8, Err(ErrorCategory::Blocked));
+        }
+        let q = Quarantine::load(path.clone(), 1);
+        assert_eq!(q.chats(), vec![7, 8]);
+        assert!(q.release(7));
+        assert!(!q.release(7));
+        assert_eq!(Quarantine::load(path.clone(), 1).chats(), vec![8]);
+        let _ = fs::remove_file(&path);
+    }
+}
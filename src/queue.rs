@@ -0,0 +1,305 @@
+//! Bounded central event queue between the ZMQ thread and the tokio runtime.
+//!
+//! Unlike a plain `mpsc` channel the producer can evict the oldest event
+//! when full, and shutdown bypasses the capacity limit entirely.
+
+use crate::config::OverflowPolicy;
+use crate::zmq_listener::LinkState;
+use crate::stats::Stats;
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+/// Events sent to the central queue
+pub enum Event {
+    Zmq(Vec<Vec<u8>>),
+    /// The ZMQ link went quiet or came back
+    ZmqStateChanged(LinkState),
+    Shutdown,
+}
+
+/// Returned by `push` once the queue has been closed
+#[derive(Debug, PartialEq, Eq)]
+pub struct Closed;
+
+/// Why `try_push` did not enqueue an event
+enum Rejected {
+    Closed,
+    /// Full under the `Block` policy; the event is handed back
+    Full(Event),
+}
+
+struct State {
+    events: VecDeque<Event>,
+    shutdown: bool,
+    closed: bool,
+}
+
+/// Bounded multi-producer, single-consumer event queue
+pub struct EventQueue {
+    state: Mutex<State>,
+    not_full: Condvar,
+    not_full_async: Notify,
+    not_empty: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+    /// Where drops are counted for `/status`
+    stats: Arc<Stats>,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy, stats: Arc<Stats>) -> Self {
+        EventQueue {
+            state: Mutex::new(State {
+                events: VecDeque::with_capacity(capacity),
+                shutdown: false,
+                closed: false,
+            }),
+            not_full: Condvar::new(),
+            not_full_async: Notify::new(),
+            not_empty: Notify::new(),
+            capacity: capacity.max(1),
+            policy,
+            dropped: AtomicU64::new(0),
+            stats,
+        }
+    }
+
+    /// Enqueue an event from synchronous code, applying the overflow policy
+    /// when full. With `Block` this waits for room, giving up once `stop` is
+    /// cancelled. Returns `Err(Closed)` when the consumer has gone away.
+    pub fn push(&self, mut event: Event, stop: &CancellationToken) -> Result<(), Closed> {
+        loop {
+            match self.try_push(event) {
+                Ok(()) => return Ok(()),
+                Err(Rejected::Closed) => return Err(Closed),
+                Err(Rejected::Full(rejected)) => event = rejected,
+            }
+            if stop.is_cancelled() {
+                return Err(Closed);
+            }
+            let state = self.state.lock().unwrap();
+            if state.events.len() >= self.capacity && !state.closed {
+                let _ = self.not_full.wait_timeout(state, Duration::from_millis(50)).unwrap();
+            }
+        }
+    }
+
+    /// Like `push`, but waits for room without blocking the runtime
+    pub async fn push_async(&self, mut event: Event, stop: &CancellationToken) -> Result<(), Closed> {
+        loop {
+            match self.try_push(event) {
+                Ok(()) => return Ok(()),
+                Err(Rejected::Closed) => return Err(Closed),
+                Err(Rejected::Full(rejected)) => event = rejected,
+            }
+            tokio::select! {
+                _ = self.not_full_async.notified() => {}
+                _ = stop.cancelled() => return Err(Closed),
+            }
+        }
+    }
+
+    /// Enqueue without waiting, applying the drop policies
+    fn try_push(&self, event: Event) -> Result<(), Rejected> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Rejected::Closed);
+        }
+        if state.events.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => return Err(Rejected::Full(event)),
+                OverflowPolicy::DropOldest => {
+                    state.events.pop_front();
+                    self.record_drop("oldest");
+                }
+                OverflowPolicy::DropNewest => {
+                    self.record_drop("newest");
+                    return Ok(());
+                }
+            }
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Request shutdown. Delivered ahead of queued events and never
+    /// subject to the capacity limit.
+    pub fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.not_full.notify_all();
+        self.not_full_async.notify_one();
+        self.not_empty.notify_one();
+    }
+
+    /// Close the queue: producers get `Err(Closed)`, the consumer drains
+    /// what is left and then receives `None`.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_full.notify_all();
+        self.not_full_async.notify_one();
+        self.not_empty.notify_one();
+    }
+
+    /// Wait for the next event. Must only be called from one task at a time.
+    pub async fn recv(&self) -> Option<Event> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.shutdown {
+                    state.shutdown = false;
+                    return Some(Event::Shutdown);
+                }
+                if let Some(event) = state.events.pop_front() {
+                    drop(state);
+                    self.not_full.notify_one();
+                    self.not_full_async.notify_one();
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.not_empty.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of events discarded by this queue's overflow policy
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record_drop(&self, which: &str) {
+        let total = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        self.stats.record_dropped_event();
+        warn!(
+            "ZMQ: Event queue full ({}), dropped {} event ({} dropped so far)",
+            self.capacity, which, total
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zmq(tag: u8) -> Event {
+        Event::Zmq(vec![vec![tag]])
+    }
+
+    fn tag(event: Option<Event>) -> Option<u8> {
+        match event {
+            Some(Event::Zmq(frames)) => Some(frames[0][0]),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest_events() {
+        let queue = EventQueue::new(2, OverflowPolicy::DropOldest, Arc::default());
+        let stop = CancellationToken::new();
+        for i in 1..=4 {
+            queue.push(zmq(i), &stop).unwrap();
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(tag(queue.recv().await), Some(3));
+        assert_eq!(tag(queue.recv().await), Some(4));
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_oldest_events() {
+        let queue = EventQueue::new(2, OverflowPolicy::DropNewest, Arc::default());
+        let stop = CancellationToken::new();
+        for i in 1..=4 {
+            queue.push(zmq(i), &stop).unwrap();
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(tag(queue.recv().await), Some(1));
+        assert_eq!(tag(queue.recv().await), Some(2));
+    }
+
+    #[tokio::test]
+    async fn shutdown_jumps_a_full_queue() {
+        let queue = EventQueue::new(1, OverflowPolicy::Block, Arc::default());
+        let stop = CancellationToken::new();
+        queue.push(zmq(1), &stop).unwrap();
+        queue.shutdown();
+        assert!(matches!(queue.recv().await, Some(Event::Shutdown)));
+        assert_eq!(tag(queue.recv().await), Some(1));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let queue = Arc::new(EventQueue::new(1, OverflowPolicy::Block, Arc::default()));
+        let stop = CancellationToken::new();
+        queue.push(zmq(1), &stop).unwrap();
+        let producer = {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || queue.push(zmq(2), &stop))
+        };
+        assert_eq!(tag(queue.recv().await), Some(1));
+        assert_eq!(tag(queue.recv().await), Some(2));
+        assert_eq!(producer.join().unwrap(), Ok(()));
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn block_gives_up_when_stopped() {
+        let queue = EventQueue::new(1, OverflowPolicy::Block, Arc::default());
+        let stop = CancellationToken::new();
+        queue.push(zmq(1), &stop).unwrap();
+        stop.cancel();
+        assert_eq!(queue.push(zmq(2), &stop), Err(Closed));
+    }
+
+    #[tokio::test]
+    async fn close_drains_then_ends() {
+        let queue = EventQueue::new(4, OverflowPolicy::Block, Arc::default());
+        let stop = CancellationToken::new();
+        queue.push(zmq(1), &stop).unwrap();
+        queue.close();
+        assert_eq!(queue.push(zmq(2), &stop), Err(Closed));
+        assert_eq!(tag(queue.recv().await), Some(1));
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn push_async_waits_for_room_and_stops_on_cancel() {
+        let queue = Arc::new(EventQueue::new(1, OverflowPolicy::Block, Arc::default()));
+        let stop = CancellationToken::new();
+        queue.push_async(zmq(1), &stop).await.unwrap();
+        let producer = {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            tokio::spawn(async move { queue.push_async(zmq(2), &stop).await })
+        };
+        assert_eq!(tag(queue.recv().await), Some(1));
+        assert_eq!(tag(queue.recv().await), Some(2));
+        assert_eq!(producer.await.unwrap(), Ok(()));
+
+        queue.push_async(zmq(3), &stop).await.unwrap();
+        let blocked = {
+            let queue = queue.clone();
+            let stop = stop.clone();
+            tokio::spawn(async move { queue.push_async(zmq(4), &stop).await })
+        };
+        stop.cancel();
+        assert_eq!(blocked.await.unwrap(), Err(Closed));
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-list quiet hours: a daily local-time window during which broadcasts
+//! are sent silently or held until the window ends.
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer};
+
+/// What happens to a broadcast sent during quiet hours
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietMode {
+    /// Deliver now with `disable_notification`
+    #[default]
+    Silent,
+    /// Hold the message and deliver it when the window ends
+    Defer,
+}
+
+/// Daily window such as 23:00–07:00 in a given time zone
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    #[serde(deserialize_with = "deserialize_hh_mm")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_hh_mm")]
+    pub end: NaiveTime,
+    pub tz: Tz,
+    #[serde(default)]
+    pub mode: QuietMode,
+}
+
+impl QuietHours {
+    /// Whether `now` falls inside the window. Windows may cross midnight.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.tz).time();
+        if self.start <= self.end {
+            self.start <= local && local < self.end
+        } else {
+            local >= self.start || local < self.end
+        }
+    }
+
+    /// The first moment after `now` at which the window ends
+    pub fn next_end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.with_timezone(&self.tz).date_naive();
+        (0..=2)
+            .filter_map(|days| today.checked_add_signed(Duration::days(days)))
+            .map(|date| local_instant(self.tz, date, self.end))
+            .find(|end| *end > now)
+            .unwrap_or(now)
+    }
+}
+
+/// Map a local wall-clock time in `tz` to an instant. Times skipped by a DST
+/// jump resolve to the first valid minute after the gap; repeated times to
+/// the earlier occurrence.
+pub fn local_instant(tz: Tz, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    let mut local = date.and_time(time);
+    for _ in 0..=180 {
+        if let Some(instant) = tz.from_local_datetime(&local).earliest() {
+            return instant.with_timezone(&Utc);
+        }
+        local += Duration::minutes(1);
+    }
+    tz.from_utc_datetime(&date.and_time(time)).with_timezone(&Utc)
+}
+
+/// Parse `"HH:MM"` into a time of day
+fn deserialize_hh_mm<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M")
+        .map_err(|_| serde::de::Error::custom(format!("invalid time '{}', expected HH:MM", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: &str, end: &str, tz: &str) -> QuietHours {
+        toml::from_str(&format!("start = \"{}\"\nend = \"{}\"\ntz = \"{}\"\n", start, end, tz)).unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_mode_and_rejects_bad_times() {
+        let q: QuietHours =
+            toml::from_str("start = \"23:00\"\nend = \"07:00\"\ntz = \"UTC\"\nmode = \"defer\"\n").unwrap();
+        assert_eq!(q.mode, QuietMode::Defer);
+        assert_eq!(window("23:00", "07:00", "UTC").mode, QuietMode::Silent);
+        assert!(toml::from_str::<QuietHours>("start = \"25:00\"\nend = \"07:00\"\ntz = \"UTC\"\n").is_err());
+        assert!(toml::from_str::<QuietHours>("start = \"23:00\"\nend = \"07:00\"\ntz = \"Mars/Base\"\n").is_err());
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        let q = window("23:00", "07:00", "UTC");
+        assert!(q.contains(utc("2026-01-10T23:00:00Z")));
+        assert!(q.contains(utc("2026-01-11T03:00:00Z")));
+        assert!(!q.contains(utc("2026-01-11T07:00:00Z")));
+        assert!(!q.contains(utc("2026-01-11T12:00:00Z")));
+        assert_eq!(q.next_end(utc("2026-01-10T23:30:00Z")), utc("2026-01-11T07:00:00Z"));
+        assert_eq!(q.next_end(utc("2026-01-11T03:00:00Z")), utc("2026-01-11T07:00:00Z"));
+    }
+
+    #[test]
+    fn daytime_window() {
+        let q = window("12:00", "13:30", "UTC");
+        assert!(!q.contains(utc("2026-01-10T11:59:00Z")));
+        assert!(q.contains(utc("2026-01-10T12:00:00Z")));
+        assert!(!q.contains(utc("2026-01-10T13:30:00Z")));
+    }
+
+    #[test]
+    fn uses_local_time_of_the_zone() {
+        // Berlin is UTC+1 in winter and UTC+2 in summer
+        let q = window("23:00", "07:00", "Europe/Berlin");
+        assert!(q.contains(utc("2026-01-10T22:30:00Z")));
+        assert!(!q.contains(utc("2026-07-10T05:30:00Z")));
+        assert!(q.contains(utc("2026-07-10T04:30:00Z")));
+        assert_eq!(q.next_end(utc("2026-07-10T04:30:00Z")), utc("2026-07-10T05:00:00Z"));
+    }
+
+    #[test]
+    fn end_across_spring_forward() {
+        // Berlin skips 02:00–03:00 on 2026-03-29; the night is one hour shorter
+        let q = window("23:00", "07:00", "Europe/Berlin");
+        let start = utc("2026-03-28T22:00:00Z");
+        assert!(q.contains(start));
+        assert_eq!(q.next_end(start), utc("2026-03-29T05:00:00Z"));
+    }
+
+    #[test]
+    fn end_inside_spring_forward_gap() {
+        // 02:30 does not exist that night, so the window ends when clocks jump to 03:00
+        let q = window("01:00", "02:30", "Europe/Berlin");
+        let now = utc("2026-03-29T00:15:00Z");
+        assert!(q.contains(now));
+        assert_eq!(q.next_end(now), utc("2026-03-29T01:00:00Z"));
+    }
+
+    #[test]
+    fn end_across_fall_back() {
+        // Berlin repeats 02:00–03:00 on 2026-10-25; the night is one hour longer
+        let q = window("23:00", "07:00", "Europe/Berlin");
+        let start = utc("2026-10-24T21:00:00Z");
+        assert!(q.contains(start));
+        assert_eq!(q.next_end(start), utc("2026-10-25T06:00:00Z"));
+        // A repeated end time resolves to its first occurrence
+        let q = window("01:00", "02:30", "Europe/Berlin");
+        assert_eq!(q.next_end(utc("2026-10-24T23:30:00Z")), utc("2026-10-25T00:30:00Z"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Relay of user replies and inline-button presses back over ZMQ.
+
+use crate::sent::SentMessages;
+use crate::shared_settings::SharedSettings;
+use crate::state::BotState;
+use log::{info, warn};
+use serde::Serialize;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::User;
+use tokio::sync::mpsc;
+
+/// Who wrote a reply or pressed a button
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct EventUser {
+    pub id: u64,
+    pub username: Option<String>,
+    pub first_name: String,
+}
+
+/// Payload pushed to ZMQ for a reply, tagged `"type": "reply"`
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename = "reply")]
+pub struct ReplyEvent {
+    /// Whether the replied-to message was found in the sent-message cache
+    pub correlated: bool,
+    pub chat_id: i64,
+    pub user: Option<EventUser>,
+    pub text: String,
+    /// ID of the reply itself
+    pub message_id: i32,
+    /// ID of the bot's message that was replied to
+    pub reply_to_message_id: i32,
+    /// The `id` of the ZMQ message that produced the replied-to message
+    pub id: Option<String>,
+    pub subscriber_list: Option<String>,
+    /// The `trace_id` of that ZMQ message
+    pub trace_id: Option<String>,
+}
+
+/// Payload pushed to ZMQ for an inline-button press, tagged `"type": "callback"`
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename = "callback")]
+pub struct CallbackEvent {
+    /// Whether the message carrying the button was found in the sent-message cache
+    pub correlated: bool,
+    /// The button's `callback_data`
+    pub data: Option<String>,
+    /// Chat and ID of the message carrying the button, if Telegram sent them
+    pub chat_id: Option<i64>,
+    pub message_id: Option<i32>,
+    /// False when the message is too old or gone and only its IDs are known
+    pub message_available: bool,
+    pub user: EventUser,
+    /// The `id` of the ZMQ message that produced the message with the button
+    pub id: Option<String>,
+    pub subscriber_list: Option<String>,
+    pub trace_id: Option<String>,
+}
+
+impl From<&User> for EventUser {
+    fn from(user: &User) -> Self {
+        EventUser { id: user.id.0, username: user.username.clone(), first_name: user.first_name.clone() }
+    }
+}
+
+/// Build the event for a button press
+pub fn callback_event(query: &CallbackQuery, sent: &SentMessages) -> CallbackEvent {
+    let chat_id = query.message.as_ref().map(|m| m.chat().id.0);
+    let message_id = query.message.as_ref().map(|m| m.id().0);
+    let correlation = chat_id.zip(message_id).and_then(|(chat, id)| sent.get(chat, id));
+    CallbackEvent {
+        correlated: correlation.is_some(),
+        data: query.data.clone(),
+        chat_id,
+        message_id,
+        message_available: query.regular_message().is_some(),
+        user: EventUser::from(&query.from),
+        id: correlation.as_ref().and_then(|c| c.id.clone()),
+        trace_id: correlation.as_ref().and_then(|c| c.trace_id.clone()),
+        subscriber_list: correlation.and_then(|c| c.subscriber_list),
+    }
+}
+
+/// Text of the pressed message once the choice is recorded
+pub fn confirmation_text(original: &str, data: &str) -> String {
+    format!("{}\n\n✅ chosen: {}", original, data)
+}
+
+/// Build the event for `msg` if it replies to a message sent by bot `bot_id`
+pub fn reply_event(msg: &Message, bot_id: u64, sent: &SentMessages) -> Option<ReplyEvent> {
+    let original = msg.reply_to_message()?;
+    if original.from.as_ref().map(|user| user.id.0) != Some(bot_id) {
+        return None;
+    }
+    let text = msg.text().or_else(|| msg.caption())?;
+    let correlation = sent.get(msg.chat.id.0, original.id.0);
+    Some(ReplyEvent {
+        correlated: correlation.is_some(),
+        chat_id: msg.chat.id.0,
+        user: msg.from.as_ref().map(EventUser::from),
+        text: text.to_string(),
+        message_id: msg.id.0,
+        reply_to_message_id: original.id.0,
+        id: correlation.as_ref().and_then(|c| c.id.clone()),
+        trace_id: correlation.as_ref().and_then(|c| c.trace_id.clone()),
+        subscriber_list: correlation.and_then(|c| c.subscriber_list),
+    })
+}
+
+/// Frames sent to the producer: the configured destination, then the JSON event
+pub fn event_frames<E: Serialize>(destination: &str, event: &E) -> Vec<Vec<u8>> {
+    let payload = serde_json::to_vec(event).expect("relay events always serialize");
+    vec![destination.as_bytes().to_vec(), payload]
+}
+
+/// Handle a non-command message: forward it over ZMQ if it replies to the bot
+pub async fn handle(
+    msg: Message,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+    outbound: mpsc::Sender<Vec<Vec<u8>>>,
+) -> ResponseResult<()> {
+    let settings = shared.snapshot();
+    let (Some(destination), Some(bot_id)) = (&settings.relay_replies_to, settings.bot_id()) else {
+        return Ok(());
+    };
+    let Some(event) = reply_event(&msg, bot_id, &state.sent) else {
+        return Ok(());
+    };
+    info!(
+        "Relaying reply from chat {} to message {} (correlated: {})",
+        event.chat_id, event.reply_to_message_id, event.correlated
+    );
+    if let Err(err) = outbound.try_send(event_frames(destination, &event)) {
+        warn!("Failed to queue reply for ZMQ: {}", err);
+    }
+    Ok(())
+}
+
+/// Handle an inline-button press: stop the client's spinner, optionally
+/// mark the choice on the message, and forward the press over ZMQ
+pub async fn handle_callback(
+    bot: Bot,
+    query: CallbackQuery,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+    outbound: mpsc::Sender<Vec<Vec<u8>>>,
+) -> ResponseResult<()> {
+    let settings = shared.snapshot();
+    if !settings.callback_allowed(query.from.id.0) {
+        warn!("Ignoring button press from user {} not in allowed_callback_users", query.from.id);
+        bot.answer_callback_query(query.id.clone())
+            .text("You are not allowed to use these buttons.")
+            .await?;
+        return Ok(());
+    }
+    let event = callback_event(&query, &state.sent);
+    bot.answer_callback_query(query.id.clone()).await?;
+    info!(
+        "Button press from user {} on message {:?} (correlated: {})",
+        event.user.id, event.message_id, event.correlated
+    );
+
+    if settings.callback_edit_message {
+        if let (Some(message), Some(data)) = (query.regular_message(), &query.data) {
+            if let Some(text) = message.text() {
+                if let Err(err) = bot.edit_message_text(message.chat.id, message.id, confirmation_text(text, data)).await {
+                    warn!("Failed to mark choice on message {}: {}", message.id, err);
+                }
+            }
+        }
+    }
+
+    if let Some(destination) = &settings.relay_callbacks_to {
+        if let Err(err) = outbound.try_send(event_frames(destination, &event)) {
+            warn!("Failed to queue button press for ZMQ: {}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sent::Correlation;
+    use chrono::Utc;
+
+    fn reply(from_bot: u64) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 8,
+            "date": 0,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+            "from": { "id": 7, "is_bot": false, "first_name": "Ann", "username": "ann" },
+            "text": "acknowledged, looking",
+            "reply_to_message": {
+                "message_id": 5,
+                "date": 0,
+                "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+                "from": { "id": from_bot, "is_bot": true, "first_name": "corky" },
+                "text": "disk full"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn correlated_reply_carries_producer_id() {
+        let sent = SentMessages::new(8);
+        sent.record(
+            100,
+            5,
+            Correlation {
+                id: Some("alert-42".to_string()),
+                subscriber_list: None,
+                sent_at: Utc::now(),
+                trace_id: Some("t-42".to_string()),
+            },
+        );
+        let event = reply_event(&reply(1), 1, &sent).unwrap();
+        assert!(event.correlated);
+        assert_eq!(event.id.as_deref(), Some("alert-42"));
+        let json: serde_json::Value = serde_json::from_slice(&event_frames("incidents", &event)[1]).unwrap();
+        assert_eq!(json["type"], "reply");
+        assert_eq!(json["text"], "acknowledged, looking");
+        assert_eq!(json["reply_to_message_id"], 5);
+        assert_eq!(json["trace_id"], "t-42");
+        assert_eq!(json["user"]["username"], "ann");
+    }
+
+    #[test]
+    fn unknown_original_is_flagged_uncorrelated() {
+        let event = reply_event(&reply(1), 1, &SentMessages::new(8)).unwrap();
+        assert!(!event.correlated);
+        assert_eq!(event.id, None);
+    }
+
+    #[test]
+    fn replies_to_other_users_are_ignored() {
+        assert!(reply_event(&reply(2), 1, &SentMessages::new(8)).is_none());
+    }
+
+    fn callback(message: serde_json::Value) -> CallbackQuery {
+        serde_json::from_value(serde_json::json!({
+            "id": "cb1",
+            "from": { "id": 7, "is_bot": false, "first_name": "Ann" },
+            "chat_instance": "x",
+            "data": "restart",
+            "message": message
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn callback_on_known_message_is_correlated() {
+        let sent = SentMessages::new(8);
+        sent.record(
+            100,
+            5,
+            Correlation { id: Some("deploy-9".to_string()), subscriber_list: None, sent_at: Utc::now(), trace_id: None },
+        );
+        let query = callback(serde_json::json!({
+            "message_id": 5,
+            "date": 1700000000,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" },
+            "text": "Deploy failed"
+        }));
+        let event = callback_event(&query, &sent);
+        assert!(event.correlated && event.message_available);
+        assert_eq!(event.id.as_deref(), Some("deploy-9"));
+        let json: serde_json::Value = serde_json::from_slice(&event_frames("ops", &event)[1]).unwrap();
+        assert_eq!(json["type"], "callback");
+        assert_eq!(json["data"], "restart");
+        assert_eq!(json["user"]["id"], 7);
+    }
+
+    #[test]
+    fn callback_after_message_edited_away_still_forwards_ids() {
+        // Telegram sends date 0 once the message is no longer accessible
+        let query = callback(serde_json::json!({
+            "message_id": 5,
+            "date": 0,
+            "chat": { "id": 100, "type": "private", "first_name": "Ann" }
+        }));
+        let event = callback_event(&query, &SentMessages::new(8));
+        assert!(!event.message_available);
+        assert!(!event.correlated);
+        assert_eq!((event.chat_id, event.message_id), (Some(100), Some(5)));
+        assert_eq!(event.data.as_deref(), Some("restart"));
+    }
+
+    #[test]
+    fn confirmation_appends_choice() {
+        assert_eq!(confirmation_text("Deploy failed", "restart"), "Deploy failed\n\n✅ chosen: restart");
+    }
+}
@@ -0,0 +1,443 @@
+//! Daily or weekly delivery report for the owners.
+//!
+//! Deliveries, failures and messages per producer are counted here since
+//! the last report and persisted with its time, so a restart neither loses
+//! the period nor sends a report twice. The schedule is checked against the
+//! wall clock on every tick rather than slept towards, so clock adjustments
+//! at most delay a report.
+
+use crate::errors::ErrorCategory;
+use crate::history::Entry;
+use crate::quiet_hours::local_instant;
+use crate::zmq_listener::ZmqMessage;
+use chrono::{DateTime, Datelike, Duration, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Deliveries addressed by chat ID rather than through a list
+const DIRECT: &str = "(direct)";
+
+/// Messages whose producer cannot be told apart
+const UNLABELLED: &str = "(unlabelled)";
+
+/// `[telegram.reports]`: when to send the report and what it includes
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ReportSettings {
+    /// `daily`, `weekly`, a time for every day (`"08:00"`) or a weekday and
+    /// time (`"Mon 08:00"`)
+    #[serde(default = "default_schedule")]
+    pub schedule: String,
+    /// Time zone of the schedule and of the times in the report
+    #[serde(default = "default_tz")]
+    pub tz: Tz,
+    /// Attach the period's deliveries as CSV when `history_db` is set
+    #[serde(default)]
+    pub csv: bool,
+    /// Producers listed by message count
+    #[serde(default = "default_top_producers")]
+    pub top_producers: usize,
+}
+
+fn default_schedule() -> String {
+    "weekly".to_string()
+}
+
+fn default_tz() -> Tz {
+    chrono_tz::UTC
+}
+
+fn default_top_producers() -> usize {
+    5
+}
+
+impl ReportSettings {
+    pub fn schedule(&self) -> Result<Schedule, String> {
+        Schedule::parse(&self.schedule)
+    }
+}
+
+/// When reports go out, in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Daily(NaiveTime),
+    Weekly(Weekday, NaiveTime),
+}
+
+impl Schedule {
+    /// Parse `daily` (08:00), `weekly` (Monday 08:00), `"HH:MM"` or `"Mon HH:MM"`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let eight = NaiveTime::from_hms_opt(8, 0, 0).unwrap_or_default();
+        let invalid = || format!("reports schedule '{}' must be daily, weekly, \"HH:MM\" or \"Mon HH:MM\"", s);
+        let time = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| invalid());
+        match s.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["daily"] => Ok(Schedule::Daily(eight)),
+            ["weekly"] => Ok(Schedule::Weekly(Weekday::Mon, eight)),
+            [at] => Ok(Schedule::Daily(time(at)?)),
+            [day, at] => Ok(Schedule::Weekly(day.parse::<Weekday>().map_err(|_| invalid())?, time(at)?)),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Time between two reports
+    pub fn period(&self) -> Duration {
+        match self {
+            Schedule::Daily(_) => Duration::days(1),
+            Schedule::Weekly(..) => Duration::weeks(1),
+        }
+    }
+
+    /// The first scheduled time strictly after `after`
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let today = after.with_timezone(&tz).date_naive();
+        let (day, time) = match *self {
+            Schedule::Daily(time) => (None, time),
+            Schedule::Weekly(day, time) => (Some(day), time),
+        };
+        (0..=8)
+            .filter_map(|days| today.checked_add_signed(Duration::days(days)))
+            .filter(|date| day.is_none_or(|day| date.weekday() == day))
+            .map(|date| local_instant(tz, date, time))
+            .find(|at| *at > after)
+            .unwrap_or(after + self.period())
+    }
+}
+
+/// On-disk representation; times are unix seconds
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct ReportFile {
+    /// When the current period started: the last report, or the first start
+    #[serde(default)]
+    since: Option<i64>,
+    /// Deliveries per list, direct ones under `DIRECT`
+    #[serde(default)]
+    delivered: BTreeMap<String, u64>,
+    /// Failed deliveries per error category
+    #[serde(default)]
+    failures: BTreeMap<String, u64>,
+    /// Messages per producer
+    #[serde(default)]
+    producers: BTreeMap<String, u64>,
+}
+
+/// The counts of one report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Period {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub delivered: Vec<(String, u64)>,
+    pub failures: Vec<(String, u64)>,
+    /// Busiest first
+    pub producers: Vec<(String, u64)>,
+}
+
+struct Inner {
+    file: ReportFile,
+    dirty: bool,
+}
+
+/// Counts for the report in progress
+pub struct Reports {
+    path: Option<PathBuf>,
+    inner: Mutex<Inner>,
+}
+
+impl Reports {
+    /// Counts kept only in memory, starting a period at `now`
+    pub fn new(now: DateTime<Utc>) -> Self {
+        let file = ReportFile { since: Some(now.timestamp()), ..ReportFile::default() };
+        Reports { path: None, inner: Mutex::new(Inner { file, dirty: false }) }
+    }
+
+    /// Counts persisted at `path`, continuing the period a previous run started
+    pub fn load(path: PathBuf, now: DateTime<Utc>) -> Self {
+        let mut file = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<ReportFile>(&contents).unwrap_or_else(|err| {
+                error!("Ignoring unreadable report file {}: {}", path.display(), err);
+                ReportFile::default()
+            }),
+            Err(_) => ReportFile::default(),
+        };
+        let dirty = file.since.is_none();
+        file.since.get_or_insert(now.timestamp());
+        Reports { path: Some(path), inner: Mutex::new(Inner { file, dirty }) }
+    }
+
+    /// A message was received from `producer`
+    pub fn record_message(&self, producer: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.file.producers.entry(producer.to_string()).or_default() += 1;
+        inner.dirty = true;
+    }
+
+    /// A delivery to one chat through `list` (or directly) ended with `outcome`
+    pub fn record_delivery(&self, list: Option<&str>, outcome: Result<(), ErrorCategory>) {
+        let mut inner = self.inner.lock().unwrap();
+        match outcome {
+            Ok(()) => *inner.file.delivered.entry(list.unwrap_or(DIRECT).to_string()).or_default() += 1,
+            Err(category) => *inner.file.failures.entry(category.to_string()).or_default() += 1,
+        }
+        inner.dirty = true;
+    }
+
+    /// Whether `schedule` had a report time since the period started. A
+    /// start more than a period in the future, left by a clock that was
+    /// wrong, counts as now.
+    pub fn due(&self, schedule: &Schedule, tz: Tz, now: DateTime<Utc>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let since = inner.file.since.and_then(|since| DateTime::from_timestamp(since, 0)).unwrap_or(now);
+        if since - now > schedule.period() {
+            warn!("Report period starts in the future ({}); restarting it now", since);
+            inner.file.since = Some(now.timestamp());
+            inner.dirty = true;
+            return false;
+        }
+        schedule.next_after(since, tz) <= now
+    }
+
+    /// The counts so far, leaving the period running
+    pub fn current(&self, now: DateTime<Utc>) -> Period {
+        period(&self.inner.lock().unwrap().file, now)
+    }
+
+    /// The counts so far, starting a new period at `now`. Saved right away
+    /// so a restart does not send the same report again.
+    pub fn take(&self, now: DateTime<Utc>) -> Period {
+        let taken = {
+            let mut inner = self.inner.lock().unwrap();
+            let taken = period(&inner.file, now);
+            inner.file = ReportFile { since: Some(now.timestamp()), ..ReportFile::default() };
+            inner.dirty = true;
+            taken
+        };
+        self.save();
+        taken
+    }
+
+    /// Write the counts out if anything changed since the last save
+    pub fn save(&self) {
+        let Some(path) = &self.path else { return };
+        let file = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            inner.file.clone()
+        };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist report counts to {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn period(file: &ReportFile, now: DateTime<Utc>) -> Period {
+    let mut producers: Vec<_> = file.producers.iter().map(|(name, &n)| (name.clone(), n)).collect();
+    producers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Period {
+        from: file.since.and_then(|since| DateTime::from_timestamp(since, 0)).unwrap_or(now),
+        to: now,
+        delivered: file.delivered.iter().map(|(list, &n)| (list.clone(), n)).collect(),
+        failures: file.failures.iter().map(|(category, &n)| (category.clone(), n)).collect(),
+        producers,
+    }
+}
+
+/// Who sent `msg`: the part of its `id` before the first `:` (so
+/// `"backup:1234"` counts as `backup`), else the ZMQ peer
+pub fn producer(msg: &ZmqMessage) -> String {
+    match (msg.id.as_deref().and_then(|id| id.split_once(':')), &msg.peer) {
+        (Some((prefix, _)), _) if !prefix.is_empty() => prefix.to_string(),
+        (_, Some(peer)) => peer.clone(),
+        _ => UNLABELLED.to_string(),
+    }
+}
+
+/// The report text. `quarantined` lists each quarantined chat with its lists.
+pub fn report_text(title: &str, period: &Period, quarantined: &[(i64, Vec<String>)], tz: Tz, top: usize) -> String {
+    let format = |at: DateTime<Utc>| at.with_timezone(&tz).format("%Y-%m-%d %H:%M").to_string();
+    let mut lines = vec![format!("{}, {} – {} ({})", title, format(period.from), format(period.to), tz)];
+    let total = |counts: &[(String, u64)]| counts.iter().map(|(_, n)| n).sum::<u64>();
+    lines.push(format!("Delivered: {}", total(&period.delivered)));
+    lines.extend(period.delivered.iter().map(|(list, n)| format!("  {}: {}", list, n)));
+    lines.push(format!("Failed: {}", total(&period.failures)));
+    lines.extend(period.failures.iter().map(|(category, n)| format!("  {}: {}", category, n)));
+    if top > 0 && !period.producers.is_empty() {
+        lines.push("Top producers:".to_string());
+        lines.extend(period.producers.iter().take(top).map(|(name, n)| format!("  {}: {} message(s)", name, n)));
+    }
+    if quarantined.is_empty() {
+        lines.push("Quarantined chats: none".to_string());
+    } else {
+        lines.push("Quarantined chats:".to_string());
+        for (chat_id, lists) in quarantined {
+            match lists.is_empty() {
+                true => lines.push(format!("  {}", chat_id)),
+                false => lines.push(format!("  {} (lists: {})", chat_id, lists.join(", "))),
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+/// History entries as CSV with a header row
+pub fn csv(entries: &[Entry]) -> String {
+    let mut out = String::from("at,chat_id,list,kind,message_id,error,trace_id,preview\n");
+    for entry in entries {
+        let fields = [
+            entry.at.to_rfc3339(),
+            entry.chat_id.to_string(),
+            entry.list.clone().unwrap_or_default(),
+            entry.kind.as_str().to_string(),
+            entry.message_id.map(|id| id.to_string()).unwrap_or_default(),
+            entry.error.clone().unwrap_or_default(),
+            entry.trace_id.clone().unwrap_or_default(),
+            entry.preview.clone(),
+        ];
+        out.push_str(&fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// Quote fields containing separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Kind;
+    use chrono::TimeZone;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn schedules_parse() {
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        assert_eq!(Schedule::parse("daily"), Ok(Schedule::Daily(time(8))));
+        assert_eq!(Schedule::parse("weekly"), Ok(Schedule::Weekly(Weekday::Mon, time(8))));
+        assert_eq!(Schedule::parse("18:00"), Ok(Schedule::Daily(time(18))));
+        assert_eq!(Schedule::parse("Fri 17:00"), Ok(Schedule::Weekly(Weekday::Fri, time(17))));
+        assert!(Schedule::parse("monthly").is_err());
+        assert!(Schedule::parse("Mon 25:00").is_err());
+        assert!(Schedule::parse("Mon 08:00 UTC").is_err());
+    }
+
+    #[test]
+    fn next_report_is_in_local_time() {
+        let weekly = Schedule::parse("Mon 08:00").unwrap();
+        // Wednesday 2024-05-01; the next Monday is the 6th
+        assert_eq!(weekly.next_after(at(2024, 5, 1, 12, 0), chrono_tz::UTC), at(2024, 5, 6, 8, 0));
+        assert_eq!(weekly.next_after(at(2024, 5, 6, 8, 0), chrono_tz::UTC), at(2024, 5, 13, 8, 0));
+        // 08:00 in Berlin is 06:00 UTC in summer
+        let daily = Schedule::parse("daily").unwrap();
+        assert_eq!(daily.next_after(at(2024, 5, 1, 7, 0), chrono_tz::Europe::Berlin), at(2024, 5, 2, 6, 0));
+    }
+
+    #[test]
+    fn a_report_is_due_once_per_slot() {
+        let daily = Schedule::parse("08:00").unwrap();
+        let reports = Reports::new(at(2024, 5, 1, 9, 0));
+        assert!(!reports.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 7, 59)));
+        assert!(reports.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 8, 0)));
+        reports.take(at(2024, 5, 2, 8, 0));
+        assert!(!reports.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 8, 1)));
+        // The clock went back a few minutes after the report
+        assert!(!reports.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 7, 58)));
+    }
+
+    #[test]
+    fn a_start_far_in_the_future_restarts_the_period() {
+        let daily = Schedule::parse("daily").unwrap();
+        let reports = Reports::new(at(2030, 1, 1, 0, 0));
+        assert!(!reports.due(&daily, chrono_tz::UTC, at(2024, 5, 1, 9, 0)));
+        assert!(reports.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 8, 0)));
+    }
+
+    #[test]
+    fn the_period_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("corky-reports-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let reports = Reports::load(path.clone(), at(2024, 5, 1, 9, 0));
+        reports.record_delivery(Some("ops"), Ok(()));
+        reports.save();
+        let daily = Schedule::parse("daily").unwrap();
+
+        let restarted = Reports::load(path.clone(), at(2024, 5, 2, 7, 0));
+        assert!(restarted.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 8, 0)));
+        assert_eq!(restarted.take(at(2024, 5, 2, 8, 0)).delivered, vec![("ops".to_string(), 1)]);
+
+        // Restarted again right after the report went out
+        let restarted = Reports::load(path.clone(), at(2024, 5, 2, 8, 1));
+        assert!(!restarted.due(&daily, chrono_tz::UTC, at(2024, 5, 2, 8, 1)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn producers_come_from_the_id_prefix_or_peer() {
+        let msg = |id: Option<&str>, peer: Option<&str>| ZmqMessage {
+            id: id.map(str::to_string),
+            peer: peer.map(str::to_string),
+            ..ZmqMessage::default()
+        };
+        assert_eq!(producer(&msg(Some("backup:1234"), Some("host-a"))), "backup");
+        assert_eq!(producer(&msg(Some("1234"), Some("host-a"))), "host-a");
+        assert_eq!(producer(&msg(None, None)), UNLABELLED);
+    }
+
+    #[test]
+    fn report_lists_counts_and_quarantined_chats() {
+        let reports = Reports::new(at(2024, 5, 6, 8, 0));
+        reports.record_delivery(Some("ops"), Ok(()));
+        reports.record_delivery(Some("ops"), Ok(()));
+        reports.record_delivery(None, Ok(()));
+        reports.record_delivery(Some("ops"), Err(ErrorCategory::Blocked));
+        for producer in ["backup", "disk", "backup"] {
+            reports.record_message(producer);
+        }
+        let period = reports.take(at(2024, 5, 13, 8, 0));
+        let text = report_text("Weekly report", &period, &[(5, vec!["ops".to_string()])], chrono_tz::UTC, 1);
+        assert_eq!(
+            text,
+            "Weekly report, 2024-05-06 08:00 – 2024-05-13 08:00 (UTC)\n\
+             Delivered: 3\n  (direct): 1\n  ops: 2\n\
+             Failed: 1\n  blocked: 1\n\
+             Top producers:\n  backup: 2 message(s)\n\
+             Quarantined chats:\n  5 (lists: ops)"
+        );
+        assert!(reports.current(at(2024, 5, 13, 9, 0)).delivered.is_empty());
+    }
+
+    #[test]
+    fn csv_quotes_awkward_fields() {
+        let entry = Entry {
+            at: at(2024, 5, 1, 12, 0),
+            chat_id: 5,
+            list: Some("ops".to_string()),
+            kind: Kind::Text,
+            preview: "disk \"sda\" full, 99%".to_string(),
+            message_id: Some(7),
+            error: None,
+            trace_id: Some("t-1".to_string()),
+        };
+        assert_eq!(
+            csv(&[entry]),
+            "at,chat_id,list,kind,message_id,error,trace_id,preview\n\
+             2024-05-01T12:00:00+00:00,5,ops,text,7,,t-1,\"disk \"\"sda\"\" full, 99%\"\n"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Routing of messages that name no target, by their content.
+//!
+//! Rules come from `[[telegram.routes]]` and are tried in order; the first
+//! whose conditions all hold decides where the message goes. A message that
+//! matches no rule goes to the owners, as without routes.
+
+use crate::zmq_listener::ZmqMessage;
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// One `[[telegram.routes]]` entry: conditions on the message, and a target
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Shown in logs and `/routetest`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// `text` contains this
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// `text` starts with this
+    #[serde(default)]
+    pub starts_with: Option<String>,
+    /// `text` matches this whole, with `*` for any run of characters and `?` for one
+    #[serde(default)]
+    pub glob: Option<String>,
+    /// `text` contains a match of this regular expression; `(?i)` makes it
+    /// ignore case
+    #[serde(default)]
+    pub regex: Option<Pattern>,
+    /// Compare `contains`, `starts_with` and `glob` ignoring case
+    #[serde(default)]
+    pub ignore_case: bool,
+    /// JSON pointer (e.g. `/team`) that must exist in the message's `tags`
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Value the `tag` must have; any value when unset
+    #[serde(default)]
+    pub equals: Option<serde_json::Value>,
+    #[serde(default)]
+    pub list: Option<String>,
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    /// Discard matching messages
+    #[serde(default)]
+    pub drop: bool,
+}
+
+/// A `regex` condition, compiled when the settings are read so that a bad
+/// pattern stops them from loading
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Regex::new(&s)
+            .map(Pattern)
+            .map_err(|err| serde::de::Error::custom(format!("invalid regex '{}': {}", s, err)))
+    }
+}
+
+/// Where a rule sends the messages it matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target<'a> {
+    List(&'a str),
+    Chat(i64),
+    Drop,
+}
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::List(list) => write!(f, "list '{}'", list),
+            Target::Chat(chat) => write!(f, "chat {}", chat),
+            Target::Drop => f.write_str("dropped"),
+        }
+    }
+}
+
+impl Route {
+    /// The rule's target, if exactly one is set
+    pub fn target(&self) -> Option<Target<'_>> {
+        match (&self.list, self.chat_id, self.drop) {
+            (Some(list), None, false) => Some(Target::List(list)),
+            (None, Some(chat), false) => Some(Target::Chat(chat)),
+            (None, None, true) => Some(Target::Drop),
+            _ => None,
+        }
+    }
+
+    /// Whether the message with `text` and `tags` meets every condition
+    pub fn matches(&self, text: &str, tags: Option<&serde_json::Value>) -> bool {
+        if self.regex.as_ref().is_some_and(|pattern| !pattern.is_match(text)) {
+            return false;
+        }
+        let fold = |s: &str| match self.ignore_case {
+            true => s.to_lowercase(),
+            false => s.to_string(),
+        };
+        let text = fold(text);
+        self.contains.as_ref().is_none_or(|needle| text.contains(&fold(needle)))
+            && self.starts_with.as_ref().is_none_or(|prefix| text.starts_with(&fold(prefix)))
+            && self.glob.as_ref().is_none_or(|glob| glob_match(&fold(glob), &text))
+            && self.tag.as_ref().is_none_or(|pointer| {
+                let value = tags.and_then(|tags| tags.pointer(pointer));
+                match &self.equals {
+                    Some(expected) => value == Some(expected),
+                    None => value.is_some(),
+                }
+            })
+    }
+
+    /// How the rule is named in logs: its `name`, or its position from 1
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("route {} ('{}')", index + 1, name),
+            None => format!("route {}", index + 1),
+        }
+    }
+
+    /// Problems that make the rule unusable
+    fn problems(&self, index: usize, is_list: &dyn Fn(&str) -> bool) -> Vec<String> {
+        let label = self.label(index);
+        let mut problems = Vec::new();
+        let conditions = [
+            self.contains.is_some(),
+            self.starts_with.is_some(),
+            self.glob.is_some(),
+            self.regex.is_some(),
+            self.tag.is_some(),
+        ];
+        if !conditions.contains(&true) {
+            problems.push(format!("{} needs contains, starts_with, glob, regex or tag", label));
+        }
+        match self.target() {
+            None => problems.push(format!("{} needs exactly one of list, chat_id or drop = true", label)),
+            Some(Target::List(list)) if !is_list(list) => {
+                problems.push(format!("{} targets unknown subscriber list '{}'", label, list))
+            }
+            Some(_) => {}
+        }
+        if self.tag.as_ref().is_some_and(|pointer| !pointer.is_empty() && !pointer.starts_with('/')) {
+            problems.push(format!("{} has tag '{}', which is not a JSON pointer (e.g. \"/team\")", label, self.tag.as_deref().unwrap_or_default()));
+        }
+        if self.equals.is_some() && self.tag.is_none() {
+            problems.push(format!("{} sets equals without tag", label));
+        }
+        problems
+    }
+}
+
+/// Problems with every rule in `routes`, given which list names exist
+pub fn validate(routes: &[Route], is_list: impl Fn(&str) -> bool) -> Vec<String> {
+    routes.iter().enumerate().flat_map(|(index, route)| route.problems(index, &is_list)).collect()
+}
+
+/// The first rule matching `text` and `tags`, with its index
+pub fn find<'a>(routes: &'a [Route], text: &str, tags: Option<&serde_json::Value>) -> Option<(usize, &'a Route)> {
+    routes.iter().enumerate().find(|(_, route)| route.matches(text, tags))
+}
+
+/// Give a message without a target the target of the first matching rule.
+/// Returns `None` if a rule drops it.
+pub fn apply(routes: &[Route], mut cmd: ZmqMessage) -> Option<ZmqMessage> {
+    if cmd.target_count() > 0 {
+        return Some(cmd);
+    }
+    let Some((index, route)) = find(routes, &cmd.text, cmd.tags.as_ref()) else {
+        return Some(cmd);
+    };
+    let target = route.target()?;
+    info!("Message {:?} matches {}: {}", cmd.id, route.label(index), target);
+    match target {
+        Target::List(list) => cmd.subscriber_list = Some(list.to_string()),
+        Target::Chat(chat) => cmd.chat_id = Some(chat),
+        Target::Drop => return None,
+    }
+    Some(cmd)
+}
+
+/// Whether `text` matches `pattern` whole, `*` matching any run of
+/// characters and `?` any single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it is matched up to
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` take one more character
+                Some((after, matched)) => {
+                    star = Some((after, matched + 1));
+                    p = after;
+                    t = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn routes(toml_str: &str) -> Vec<Route> {
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: Vec<Route>,
+        }
+        toml::from_str::<Routes>(toml_str).unwrap().routes
+    }
+
+    fn message(text: &str) -> ZmqMessage {
+        ZmqMessage { text: text.to_string(), ..ZmqMessage::default() }
+    }
+
+    #[test]
+    fn glob_matches_whole_text() {
+        assert!(glob_match("[DISK]*", "[DISK] /var at 91%"));
+        assert!(glob_match("*backup*failed*", "nightly backup of db failed"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("*.log", "x.log.gz"));
+        assert!(glob_match("grüße *", "grüße 🚀"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let routes = routes(
+            "[[routes]]\nname = \"disk\"\nstarts_with = \"[DISK]\"\nlist = \"ops\"\n\
+             [[routes]]\ncontains = \"backup\"\nignore_case = true\nchat_id = 5\n\
+             [[routes]]\nglob = \"*debug*\"\ndrop = true\n",
+        );
+        let routed = |text: &str| find(&routes, text, None).map(|(index, route)| (index, route.target()));
+        assert_eq!(routed("[DISK] backup full"), Some((0, Some(Target::List("ops")))));
+        assert_eq!(routed("BACKUP done"), Some((1, Some(Target::Chat(5)))));
+        assert_eq!(routed("a debug line"), Some((2, Some(Target::Drop))));
+        assert_eq!(routed("[disk] lower case"), None);
+    }
+
+    #[test]
+    fn regex_matches_anywhere_in_the_text() {
+        let routes = routes(
+            "[[routes]]\nregex = '^\\[(DISK|RAID)\\] .* (9\\d|100)%$'\nlist = \"ops\"\n\
+             [[routes]]\nregex = '(?i)backup \\w+ failed'\nchat_id = 5\n",
+        );
+        let routed = |text: &str| find(&routes, text, None).map(|(index, _)| index);
+        assert_eq!(routed("[RAID] md0 at 97%"), Some(0));
+        assert_eq!(routed("[DISK] /var at 42%"), None);
+        assert_eq!(routed("nightly Backup db FAILED again"), Some(1));
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected_when_loading() {
+        let err = toml::from_str::<Route>("regex = \"disk (full\"\nlist = \"ops\"\n").unwrap_err();
+        assert!(err.to_string().contains("invalid regex 'disk (full'"), "{}", err);
+    }
+
+    #[test]
+    fn tags_match_by_json_pointer() {
+        let routes = routes(
+            "[[routes]]\ntag = \"/team\"\nequals = \"storage\"\nlist = \"storage\"\n\
+             [[routes]]\ntag = \"/labels/0\"\nchat_id = 7\n",
+        );
+        let tags = json!({ "team": "storage" });
+        assert_eq!(find(&routes, "x", Some(&tags)).map(|(index, _)| index), Some(0));
+        let tags = json!({ "team": "web", "labels": ["urgent"] });
+        assert_eq!(find(&routes, "x", Some(&tags)).map(|(index, _)| index), Some(1));
+        assert!(find(&routes, "x", None).is_none());
+    }
+
+    #[test]
+    fn apply_only_routes_messages_without_a_target() {
+        let routes = routes("[[routes]]\ncontains = \"disk\"\nlist = \"ops\"\n[[routes]]\ncontains = \"noise\"\ndrop = true\n");
+        assert_eq!(apply(&routes, message("disk full")).unwrap().subscriber_list.as_deref(), Some("ops"));
+        let direct = ZmqMessage { chat_id: Some(3), ..message("disk full") };
+        let direct = apply(&routes, direct).unwrap();
+        assert_eq!((direct.chat_id, direct.subscriber_list), (Some(3), None));
+        assert!(apply(&routes, message("noise")).is_none());
+        assert_eq!(apply(&routes, message("other")).unwrap().target_count(), 0);
+    }
+
+    #[test]
+    fn validation_names_the_rule() {
+        let routes = routes(
+            "[[routes]]\nlist = \"ops\"\n\
+             [[routes]]\nname = \"both\"\ncontains = \"x\"\nlist = \"ops\"\ndrop = true\n\
+             [[routes]]\ncontains = \"x\"\nlist = \"nope\"\n\
+             [[routes]]\ntag = \"team\"\nchat_id = 1\n",
+        );
+        assert_eq!(
+            validate(&routes, |list| list == "ops"),
+            vec![
+                "route 1 needs contains, starts_with, glob, regex or tag",
+                "route 2 ('both') needs exactly one of list, chat_id or drop = true",
+                "route 3 targets unknown subscriber list 'nope'",
+                "route 4 has tag 'team', which is not a JSON pointer (e.g. \"/team\")",
+            ]
+        );
+    }
+}
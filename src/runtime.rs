@@ -0,0 +1,502 @@
+//! The running bot: the event loop that turns ZMQ frames into queued
+//! messages, the outbox workers, the Telegram dispatcher and the background
+//! tasks around them, from startup until an orderly shutdown.
+//!
+//! The dispatcher is watched by the event loop and restarted with backoff
+//! when it ends, unless `dispatcher_exit_fatal` asks for the process to
+//! exit instead. On shutdown, open aggregation windows and digests are
+//! flushed into the outbox and the workers get a bounded time to drain it.
+
+use crate::aggregate::Aggregator;
+use crate::bridge::CorkyBot;
+use crate::config::{TelegramSettings, WebhookSettings};
+use crate::data_dir::DataDir;
+use crate::error_replies::ErrorReplies;
+use crate::errors::{ErrorCategory, SendError};
+use crate::events::BotEvent;
+use crate::health::Transition;
+use crate::outbox::Serve;
+use crate::queue::{Event, EventQueue};
+use crate::shared_settings::SharedSettings;
+use crate::sink::{SendOptions, TelegramSink};
+use crate::state::BotState;
+use crate::zmq_listener::{self, LinkState};
+use crate::{commands, crash, custom_commands, logging, menu, notices, relay, sender, unknown_commands};
+use log::{error, info, warn};
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use teloxide::dispatching::ShutdownToken;
+use teloxide::error_handlers::LoggingErrorHandler;
+use teloxide::prelude::*;
+use teloxide::update_listeners::{webhooks, UpdateListener};
+use tokio::task::JoinHandle;
+use tokio::{signal, time};
+use tokio_util::sync::CancellationToken;
+
+/// How often a refused bot token is checked again
+const TOKEN_RECHECK: Duration = Duration::from_secs(60);
+
+/// A dispatcher restarted after running at least this long is not backing off
+const DISPATCHER_STABLE: Duration = Duration::from_secs(600);
+
+/// How long the outbox workers, the dispatcher and the listener each get
+/// to stop
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Run the bot until CTRL+C, SIGTERM or a fatal dispatcher exit. Returns the
+/// process exit code.
+pub async fn run(corky: CorkyBot) -> i32 {
+    let started = Instant::now();
+    let (bot, shared, state) = (corky.sink().clone(), corky.settings().clone(), corky.state().clone());
+    // Settings fixed for the run; tasks that keep running take a fresh snapshot
+    let settings = shared.snapshot();
+    if let Some(dir) = DataDir::for_settings(&settings).path() {
+        info!("Keeping state in {}", dir.display());
+    }
+
+    // Report panics to the owners; mention the previous run's, if it had one
+    let panic_report = crash::report_path(&settings);
+    crash::install(&settings, panic_report.clone());
+    let last_panic = panic_report.and_then(|path| crash::take_last_report(&path));
+    if let Some(summary) = &last_panic {
+        warn!("The previous run ended in a panic: {}", summary);
+    }
+    match settings.log_filters() {
+        Ok(filters) => {
+            for target in &filters.unknown_targets {
+                warn!("log_filters: unknown target '{}'; use one of {} or a module path", target, logging::target_names());
+            }
+            logging::apply(filters);
+        }
+        Err(err) => error!("{}", err),
+    }
+
+    info!("Using Bot API server {}", bot.api_url());
+
+    // Publish bot events, if configured
+    if let Err(err) = state.events.start(&settings) {
+        error!("{}; continuing without publishing events", err);
+    }
+
+    // Central event queue (bounded, with a configurable overflow policy)
+    let queue = Arc::new(EventQueue::new(settings.event_queue_size, settings.event_queue_overflow, state.stats.clone()));
+
+    // Shutdown token shared with the ZMQ listener
+    let shutdown = CancellationToken::new();
+
+    // Spawn the ZMQ listener (a tokio task, or a thread without `async-zmq`)
+    let zmq_listener =
+        zmq_listener::spawn(zmq_listener::ListenerOptions::from_settings(&settings), queue.clone(), shutdown.clone());
+
+    let stop_signal: Arc<OnceLock<&'static str>> = Arc::new(OnceLock::new());
+    spawn_signal_handler(shutdown.clone(), queue.clone(), stop_signal.clone());
+
+    // Send workers draining the outbox, plus one reserved for high priority
+    let mut workers = tokio::task::JoinSet::new();
+    let serves = std::iter::repeat_n(Serve::All, settings.outbox_workers).chain([Serve::HighOnly]);
+    for serve in serves {
+        workers.spawn(crash::observed("outbox worker", corky.clone().run_outbox_worker(serve)));
+    }
+
+    let deferred_task = spawn_deferred_releases(&corky);
+    let token_task = spawn_token_check(&corky);
+    let mut dispatcher = Watchdog::start(&bot, &shared, &state, zmq_listener.outbound());
+
+    // Offer the commands when "/" is typed, without holding up startup
+    {
+        let bot = bot.clone();
+        let settings = settings.clone();
+        crash::spawn("command menu", async move { menu::register(&bot, &settings).await });
+    }
+
+    // Confirm to the owner that the bot came back up, without holding up startup
+    if settings.notify_owner_on_startup {
+        let bot = bot.clone();
+        let settings = settings.clone();
+        crash::spawn("startup notice", async move { notify_owner_of_startup(&bot, &settings, last_panic.as_deref()).await });
+    }
+
+    // Central event loop: handle ZMQ messages until shutdown
+    let mut exit_code = 0;
+    // Set once a fatal dispatcher exit is being shut down for; its finished
+    // task must not be polled again
+    let mut dispatcher_fatal = false;
+    let mut aggregator = Aggregator::default();
+    let mut error_replies = if settings.zmq_error_replies {
+        ErrorReplies::new(zmq_listener.outbound(), Duration::from_secs(settings.zmq_error_reply_interval_secs))
+    } else {
+        ErrorReplies::acks_only(zmq_listener.outbound())
+    };
+    loop {
+        // Wake up for whichever comes first: an event, a closing aggregation
+        // window or the end of the dispatcher
+        let deadline = aggregator.next_deadline();
+        let event = tokio::select! {
+            event = queue.recv() => event,
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                for message in aggregator.take_due(Instant::now()) {
+                    state.outbox.push(message);
+                }
+                continue;
+            }
+            _ = &mut dispatcher.task, if !dispatcher_fatal => {
+                state.stats.record_dispatcher_restart();
+                if settings.dispatcher_exit_fatal {
+                    error!("!!! Telegram dispatcher stopped unexpectedly; exiting (dispatcher_exit_fatal = true)");
+                    dispatcher_fatal = true;
+                    exit_code = 1;
+                    let _ = stop_signal.set("dispatcher stopped");
+                    queue.shutdown();
+                    continue;
+                }
+                dispatcher.restart(&bot, &shared, &state, zmq_listener.outbound());
+                continue;
+            }
+        };
+        state.health.apply(Transition::QueueDepth(queue.len()), chrono::Utc::now());
+        match event {
+            Some(Event::Zmq(frames)) => corky.handle_zmq_frames(&mut aggregator, &mut error_replies, frames),
+            Some(Event::ZmqStateChanged(link)) => {
+                state.health.apply(link.into(), chrono::Utc::now());
+                state.events.publish(match link {
+                    LinkState::Down { silent_for } => BotEvent::ZmqDisconnected { silent_for_secs: silent_for.as_secs() },
+                    LinkState::Up { down_for } => BotEvent::ZmqReconnected { down_for_secs: down_for.as_secs() },
+                });
+                // Goes straight to the owner; the ZMQ link is the thing that is broken
+                let notice = link.notice(&shared.snapshot().zmq_endpoint);
+                state.activity.record_zmq(&notice);
+                spawn_owner_notice("link notice", &bot, &shared, notice);
+            }
+            Some(Event::Shutdown) => {
+                info!("Shutdown signal received; exiting event loop");
+                if settings.notify_owner_on_shutdown {
+                    let signal = stop_signal.get().copied().unwrap_or("unknown");
+                    notify_owner_of_shutdown(&bot, &shared.snapshot(), &queue, &state, signal, started.elapsed()).await;
+                }
+                break;
+            }
+            None => {
+                info!("Event queue closed; exiting event loop");
+                break;
+            }
+        }
+    }
+    queue.close();
+    deferred_task.abort();
+    token_task.abort();
+
+    // Open aggregation windows and digests are cut short rather than lost
+    for message in aggregator.drain() {
+        state.outbox.push(message);
+    }
+    for digest in state.digests.take_all() {
+        info!("Sending digest for '{}' early because of shutdown", digest.list);
+        state.push_digest(digest);
+    }
+
+    // Let workers finish what is already queued, within reason
+    state.outbox.close();
+    let drain = async { while workers.join_next().await.is_some() {} };
+    if time::timeout(SHUTDOWN_GRACE, drain).await.is_err() {
+        let (high, normal) = state.outbox.depths();
+        warn!("Outbox drain timed out with {} high and {} normal message(s) unsent", high, normal);
+        workers.abort_all();
+    }
+    if !state.deferred.is_empty() {
+        info!("{} deferred broadcast(s) will be delivered after restart", state.deferred.len());
+    }
+    state.traffic.save();
+    if !state.spool.is_empty() {
+        warn!("{} spooled message(s) will be replayed once the bot token is accepted", state.spool.len());
+    }
+
+    dispatcher.stop().await;
+
+    // Signal the ZMQ listener to stop and wait for it
+    shutdown.cancel();
+    info!("Waiting for ZMQ listener to exit...");
+    if time::timeout(SHUTDOWN_GRACE, zmq_listener.join()).await.is_err() {
+        warn!("ZMQ listener did not exit in time");
+    }
+
+    let snapshot = state.stats.snapshot();
+    if snapshot.dropped_events > 0 {
+        warn!("{} event(s) were dropped because the event queue was full", snapshot.dropped_events);
+    }
+    for (category, count) in &snapshot.failures {
+        info!("Failed send attempts ({}): {}", category, count);
+    }
+
+    if exit_code != 0 {
+        error!("telegram_zmq_bot has shut down after a fatal error");
+    } else {
+        info!("telegram_zmq_bot has shut down gracefully");
+    }
+    exit_code
+}
+
+/// Wait for CTRL+C or SIGTERM (from systemd), then cancel `shutdown` and
+/// put the shutdown ahead of any queued events
+fn spawn_signal_handler(shutdown: CancellationToken, queue: Arc<EventQueue>, stop_signal: Arc<OnceLock<&'static str>>) {
+    crash::spawn("signal handler", async move {
+        let name = wait_for_signal().await;
+        info!("{} received; initiating shutdown", name);
+        let _ = stop_signal.set(name);
+        shutdown.cancel();
+        queue.shutdown();
+    });
+}
+
+/// Resume broadcasts a previous run left unfinished, then deliver
+/// broadcasts held by quiet hours once their window ends, and digests once
+/// their interval has elapsed
+fn spawn_deferred_releases(corky: &CorkyBot) -> JoinHandle<()> {
+    let (bot, shared, state) = (corky.sink().clone(), corky.settings().clone(), corky.state().clone());
+    crash::spawn("deferred releases", async move {
+        sender::resume_broadcasts(&bot, &shared.snapshot(), &state, chrono::Utc::now()).await;
+        let mut tick = time::interval(Duration::from_secs(30));
+        loop {
+            tick.tick().await;
+            let settings = shared.snapshot();
+            let now = chrono::Utc::now();
+            for digest in state.digests.take_due(now) {
+                state.push_digest(digest);
+            }
+            sender::release_deferred(&bot, &settings, &state, now).await;
+            sender::send_duplicate_summaries(&bot, &settings, &state).await;
+            sender::announce_flood_changes(&bot, &settings).await;
+            sender::check_traffic(&bot, &settings, &state).await;
+            sender::send_due_report(&bot, &settings, &state, now).await;
+        }
+    })
+}
+
+/// Check the token at startup and, while Telegram refuses it, every
+/// minute; messages are spooled meanwhile and replayed once it works.
+/// When nothing was sent for `keepalive_secs` the same check confirms the
+/// connection still works and refreshes the heartbeat file.
+fn spawn_token_check(corky: &CorkyBot) -> JoinHandle<()> {
+    let (bot, shared, state) = (corky.sink().clone(), corky.settings().clone(), corky.state().clone());
+    crash::spawn("token check", async move {
+        let mut tick = time::interval(TOKEN_RECHECK);
+        let mut checked = false;
+        loop {
+            tick.tick().await;
+            let keepalive = Duration::from_secs(shared.snapshot().keepalive_secs);
+            let idle = state.heartbeat.needs_keepalive(keepalive, Instant::now());
+            if checked && !state.spool.is_active() && !idle {
+                continue;
+            }
+            let result = match time::timeout(Duration::from_secs(15), bot.get_me()).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(err.category()),
+                Err(_elapsed) => Err(ErrorCategory::Timeout),
+            };
+            match result {
+                Ok(()) => state.heartbeat.keepalive(),
+                Err(category) if checked => warn!("Keepalive check failed: Telegram get_me returned {}", category),
+                Err(_) => {}
+            }
+            checked = true;
+            sender::token_checked(&bot, &shared.snapshot(), &state, result).await;
+        }
+    })
+}
+
+/// Send `notice` to the owners from a task of its own
+fn spawn_owner_notice(name: &'static str, bot: &TelegramSink, shared: &SharedSettings, notice: String) {
+    let (bot, settings) = (bot.clone(), shared.snapshot());
+    crash::spawn(name, async move {
+        sender::send_to_owners(&bot, &settings, &notice, SendOptions::default()).await;
+    });
+}
+
+/// The Telegram dispatcher: commands (also as channel posts), configured
+/// commands, unknown commands, replies and button presses. The event loop
+/// polls `task` and restarts the dispatcher when it ends.
+struct Watchdog {
+    task: JoinHandle<()>,
+    shutdown: ShutdownToken,
+    started: Instant,
+    /// Restarts in a row without a stable run in between
+    failures: u32,
+}
+
+impl Watchdog {
+    fn start(bot: &Bot, shared: &SharedSettings, state: &Arc<BotState>, outbound: OutboundFrames) -> Self {
+        let (task, shutdown) = spawn_dispatcher(bot, shared, state, outbound, Duration::ZERO);
+        Watchdog { task, shutdown, started: Instant::now(), failures: 0 }
+    }
+
+    /// Start the dispatcher again after a backoff, telling the owners
+    fn restart(&mut self, bot: &TelegramSink, shared: &SharedSettings, state: &Arc<BotState>, outbound: OutboundFrames) {
+        // A dispatcher that ran for a while starts over from a short delay
+        if self.started.elapsed() >= DISPATCHER_STABLE {
+            self.failures = 0;
+        }
+        let delay = dispatcher_backoff(self.failures);
+        self.failures += 1;
+        error!("!!! Telegram dispatcher stopped unexpectedly; commands are not handled. Restarting in {:?}", delay);
+        let notice = format!("Telegram dispatcher stopped unexpectedly; restarting in {}s", delay.as_secs());
+        spawn_owner_notice("dispatcher notice", bot, shared, notice);
+        (self.task, self.shutdown) = spawn_dispatcher(bot, shared, state, outbound, delay);
+        self.started = Instant::now() + delay;
+    }
+
+    /// Shut the dispatcher down gracefully, within `SHUTDOWN_GRACE`
+    async fn stop(self) {
+        if let Ok(fut) = self.shutdown.shutdown() {
+            if time::timeout(SHUTDOWN_GRACE, fut).await.is_err() {
+                warn!("Telegram dispatcher shutdown timed out");
+            }
+        }
+        self.task.abort();
+    }
+}
+
+/// Frames queued for the ZMQ socket, handed to the handlers for replies
+type OutboundFrames = tokio::sync::mpsc::Sender<Vec<Vec<u8>>>;
+
+/// Delay before restarting the dispatcher after `failures` restarts in a row:
+/// 1s, doubling up to a minute
+fn dispatcher_backoff(failures: u32) -> Duration {
+    Duration::from_secs(1u64 << failures.min(6)).min(Duration::from_secs(60))
+}
+
+/// Build the dispatcher and spawn it after `delay`, returning its task and
+/// the token that shuts it down. It has no CTRL+C handler of its own.
+fn spawn_dispatcher(
+    bot: &Bot,
+    shared: &SharedSettings,
+    state: &Arc<BotState>,
+    outbound: OutboundFrames,
+    delay: Duration,
+) -> (JoinHandle<()>, ShutdownToken) {
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .branch(dptree::entry().filter_command::<commands::Command>().endpoint(commands::handle))
+                .branch(dptree::filter_map(custom_commands::invocation).endpoint(custom_commands::handle))
+                .branch(dptree::filter_map(unknown_commands::unknown).endpoint(unknown_commands::handle))
+                .branch(dptree::endpoint(relay::handle)),
+        )
+        .branch(Update::filter_channel_post().filter_command::<commands::Command>().endpoint(commands::handle))
+        .branch(Update::filter_callback_query().endpoint(relay::handle_callback));
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![shared.clone(), state.clone(), outbound])
+        .build();
+    let shutdown = dispatcher.shutdown_token();
+    let bot = bot.clone();
+    let webhook = shared.snapshot().webhook.clone();
+    let task = crash::spawn("dispatcher", async move {
+        time::sleep(delay).await;
+        match webhook_listener(bot, webhook).await {
+            Some(listener) => {
+                let on_error = LoggingErrorHandler::with_custom_text("An error from the webhook listener");
+                dispatcher.dispatch_with_listener(listener, on_error).await
+            }
+            None => dispatcher.dispatch().await,
+        }
+    });
+    (task, shutdown)
+}
+
+/// Register the webhook and start its server when `[telegram.webhook]` is
+/// configured. Returns `None` to use long polling, including when
+/// registration fails.
+async fn webhook_listener(bot: Bot, webhook: Option<WebhookSettings>) -> Option<impl UpdateListener<Err = Infallible>> {
+    let webhook = webhook?;
+    // Both were checked by `validate` before startup
+    let (url, address) = (webhook.public_url().ok()?, webhook.listen_addr().ok()?);
+    let mut options = webhooks::Options::new(address, url.clone());
+    if let Some(secret) = webhook.secret_token {
+        options = options.secret_token(secret);
+    }
+    match webhooks::axum(bot, options).await {
+        Ok(listener) => {
+            info!("Receiving updates via webhook {} (listening on {})", url, address);
+            Some(listener)
+        }
+        Err(err) => {
+            error!("!!! Failed to register webhook {}: {}", url, err);
+            error!("!!! Falling back to long polling; commands still work but check the webhook setup");
+            None
+        }
+    }
+}
+
+/// Wait for CTRL+C or (on Unix) SIGTERM and return the signal's name
+async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = signal::ctrl_c() => "SIGINT",
+                _ = term.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+        "CTRL+C"
+    }
+}
+
+/// Verify the token with `get_me`, then send the owner a config summary.
+/// Failures are logged; the bot keeps running either way.
+async fn notify_owner_of_startup(bot: &TelegramSink, settings: &TelegramSettings, last_panic: Option<&str>) {
+    let me = match time::timeout(Duration::from_secs(15), bot.get_me()).await {
+        Ok(Ok(me)) => me,
+        Ok(Err(err)) => {
+            error!("Startup notice not sent: get_me failed: {}", err);
+            return;
+        }
+        Err(_elapsed) => {
+            error!("Startup notice not sent: get_me timed out");
+            return;
+        }
+    };
+    let notice = notices::startup_notice(me.username(), settings, last_panic);
+    for (owner, category) in sender::send_to_owners(bot, settings, &notice, SendOptions::default()).await {
+        error!("Failed to send startup notice to owner {} ({})", owner, category);
+    }
+}
+
+/// Tell the owner the bot is going down. Bounded so that an unreachable
+/// Telegram cannot stall shutdown.
+async fn notify_owner_of_shutdown(
+    bot: &TelegramSink,
+    settings: &TelegramSettings,
+    queue: &EventQueue,
+    state: &BotState,
+    signal: &str,
+    uptime: Duration,
+) {
+    let (high, normal) = state.outbox.depths();
+    let pending = notices::Pending { events: queue.len(), outbox: high + normal };
+    let notice = notices::shutdown_notice(signal, uptime, state.stats.snapshot().delivered, pending);
+    let opts = SendOptions { max_attempts: 1, ..SendOptions::default() };
+    let send = sender::send_to_owners(bot, settings, &notice, opts);
+    if time::timeout(Duration::from_secs(3), send).await.is_err() {
+        warn!("Shutdown notice to the owners timed out");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatcher_backoff_doubles_up_to_a_minute() {
+        let delays: Vec<u64> = (0..8).map(|failures| dispatcher_backoff(failures).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+}
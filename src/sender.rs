@@ -0,0 +1,3146 @@
+//! Delivery of ZMQ commands to Telegram chats with retries.
+
+use crate::config::{ChatRef, HtmlMode, TelegramSettings};
+use crate::dedupe;
+use crate::errors::{ErrorCategory, SendError};
+use crate::events::{BotEvent, DeliveryStatus};
+use crate::file_ids::{self, FileIds};
+use crate::health::Transition;
+use crate::flood;
+use crate::history;
+use crate::html;
+use crate::images;
+use crate::media::{self, Rejection};
+use crate::migrations::{self, Migrations};
+use crate::sink::{MessageSink, SendOptions, MAX_RETRIES, MAX_RETRY_DELAY_MS};
+use crate::sent::Correlation;
+use crate::error_replies::ControlAck;
+use crate::shared_settings::{ListChange, ListError, SharedSettings};
+use crate::state::BotState;
+use crate::trace;
+use crate::zmq_listener::{ControlAction, ImageBytes, ParseMode, ZmqMessage};
+use crate::outbox::Serve;
+use crate::quiet_hours::QuietMode;
+use crate::reports;
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error, info, warn};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::future::Future;
+use teloxide::types::{ChatAction, ChatId, MessageId};
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Safely truncate a string to at most `max_chars` characters,
+/// never splitting a multi-byte UTF-8 character.
+pub fn truncate_str(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        Some((byte_idx, _)) => &s[..byte_idx],
+        None => s,
+    }
+}
+
+/// Characters of a message shown in send and failure logs
+const LOG_PREVIEW_CHARS: usize = 30;
+
+/// Telegram shows a chat action for about 5 seconds
+const CHAT_ACTION_REFRESH: time::Duration = time::Duration::from_secs(4);
+
+/// One-line preview of `text` for logs and history. Line breaks become
+/// spaces, and text longer than `max_chars` user-perceived characters is cut
+/// on a grapheme boundary and ends with "…", so emoji and accented letters
+/// are never split.
+pub fn preview(text: &str, max_chars: usize) -> String {
+    let mut graphemes = text.graphemes(true);
+    let mut out: String = graphemes
+        .by_ref()
+        .take(max_chars)
+        .map(|g| if g.contains(['\r', '\n']) { " " } else { g })
+        .collect();
+    if graphemes.next().is_some() {
+        out.push('…');
+    }
+    out
+}
+
+/// Most chat IDs listed one by one in a log line
+const LOGGED_CHATS: usize = 5;
+
+/// Who `cmd` is addressed to, for log lines
+fn describe_recipients(cmd: &ZmqMessage) -> String {
+    let mut parts = Vec::new();
+    let chats = cmd.explicit_chats(true);
+    match chats.len() {
+        0 => {}
+        1 => parts.push(format!("chat {}", chats[0])),
+        n if n <= LOGGED_CHATS => {
+            parts.push(format!("chats {}", chats.iter().map(|chat| chat.to_string()).collect::<Vec<_>>().join(", ")))
+        }
+        n => parts.push(format!("{} chats", n)),
+    }
+    if let Some(chat) = &cmd.chat {
+        parts.push(format!("chat {}", chat));
+    }
+    if let Some(list) = &cmd.subscriber_list {
+        parts.push(format!("list '{}'", list));
+    }
+    match parts.is_empty() {
+        true => "no target".to_string(),
+        false => parts.join(", "),
+    }
+}
+
+/// Maximum number of characters Telegram accepts in a single text message
+pub const TELEGRAM_MAX_MESSAGE_CHARS: usize = 4096;
+
+/// Maximum number of characters Telegram accepts in a media caption
+pub const TELEGRAM_MAX_CAPTION_CHARS: usize = 1024;
+
+/// Split text into chunks of at most `max_chars` characters, preferring to
+/// break after a newline so log lines stay intact.
+pub fn split_text(text: &str, max_chars: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.chars().count() > max_chars {
+        let head = truncate_str(rest, max_chars);
+        let cut = match head.rfind('\n') {
+            Some(idx) if idx > 0 => idx + 1,
+            _ => head.len(),
+        };
+        chunks.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    if !rest.is_empty() || chunks.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+/// Split text sent with `parse_mode` into chunks of at most `max_chars`
+/// characters that Telegram can parse on their own (see `html::split`).
+/// `None` if the markup has no safe place to split.
+pub fn split_formatted(text: &str, parse_mode: Option<ParseMode>, max_chars: usize) -> Option<Vec<Cow<'_, str>>> {
+    match parse_mode {
+        None => Some(split_text(text, max_chars).into_iter().map(Cow::Borrowed).collect()),
+        Some(ParseMode::Html) => Some(html::split(text, max_chars)?.into_iter().map(Cow::Owned).collect()),
+    }
+}
+
+/// Outcome of delivering to one chat: the IDs of the messages sent, or the
+/// failure category if nothing got through
+pub type Delivery = Result<Vec<MessageId>, ErrorCategory>;
+
+tokio::task_local! {
+    /// Set while delivering to a chat once a send there lost its formatting
+    static DEGRADED: Cell<bool>;
+}
+
+/// Note that the delivery in progress went out as less than was asked for
+fn mark_degraded() {
+    let _ = DEGRADED.try_with(|degraded| degraded.set(true));
+}
+
+/// Dispatch ZMQ command to appropriate chats, returning the outcome for each
+/// chat a send was attempted to
+pub async fn process_zmq_message<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    cmd: ZmqMessage,
+) -> Vec<(i64, Delivery)> {
+    process_zmq_message_at(bot, settings, state, cmd, Utc::now()).await
+}
+
+/// Dispatch ZMQ command as of `now`, which decides mutes and quiet hours.
+/// Spooled instead while Telegram refuses the token.
+async fn process_zmq_message_at<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    mut cmd: ZmqMessage,
+    now: DateTime<Utc>,
+) -> Vec<(i64, Delivery)> {
+    let trace_id = trace::ensure(cmd.trace_id.as_deref());
+    cmd.trace_id = Some(trace_id.clone());
+    trace::scope(&trace_id, async move {
+        let id = cmd.id.clone();
+        state.reports.record_message(&reports::producer(&cmd));
+        let Some(cmd) = state.spool.hold_if_active(cmd, now) else {
+            info!("Spooled ZMQ message {:?} until the bot token is accepted", id);
+            return Vec::new();
+        };
+        let original = cmd.clone();
+        let outcomes = dispatch_at(bot, settings, state, cmd, now).await;
+        if token_refused(&outcomes) && state.spool.refused(Some(original), now) {
+            error!("!!! Telegram refuses the bot token; spooling messages to disk until it is accepted");
+        }
+        outcomes
+    })
+    .await
+}
+
+/// Whether every send failed because Telegram refused the token
+fn token_refused(outcomes: &[(i64, Delivery)]) -> bool {
+    !outcomes.is_empty() && outcomes.iter().all(|(_, outcome)| *outcome == Err(ErrorCategory::Unauthorized))
+}
+
+/// Act on a check of the bot token: start spooling if Telegram refused it,
+/// or replay the spool in order once it is accepted. Other failures say
+/// nothing about the token and change nothing.
+pub async fn token_checked<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    result: Result<(), ErrorCategory>,
+) {
+    match result {
+        Err(ErrorCategory::Unauthorized) => {
+            if state.spool.refused(None, Utc::now()) {
+                error!("!!! Telegram refuses the bot token; spooling messages to disk until it is accepted");
+            }
+        }
+        Err(_) => {}
+        Ok(()) if state.spool.is_active() => replay_spool(bot, settings, state).await,
+        Ok(()) => {}
+    }
+}
+
+/// Send the spooled messages, oldest first, stopping if the token is
+/// refused again
+async fn replay_spool<S: MessageSink>(bot: &S, settings: &TelegramSettings, state: &Arc<BotState>) {
+    info!("Bot token accepted; replaying {} spooled message(s)", state.spool.len());
+    let now = Utc::now();
+    while let Some(held) = state.spool.take_next() {
+        if held.expired(now) {
+            warn!("Dropping spooled message {:?} received at {}: TTL expired", held.message.id, held.received_at);
+            continue;
+        }
+        let trace_id = trace::ensure(held.message.trace_id.as_deref());
+        let outcomes = trace::scope(&trace_id, dispatch_at(bot, settings, state, held.message.clone(), Utc::now())).await;
+        if token_refused(&outcomes) {
+            error!("Telegram refused the bot token again; {} message(s) stay spooled", state.spool.len() + 1);
+            state.spool.put_back(held);
+            return;
+        }
+    }
+    info!("Spool replayed");
+}
+
+/// Dispatch a message that is not spooled
+async fn dispatch_at<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    mut cmd: ZmqMessage,
+    now: DateTime<Utc>,
+) -> Vec<(i64, Delivery)> {
+    info!(
+        "Processing ZMQ message {:?} (trace {:?}) to {}: {} bytes of text",
+        cmd.id,
+        cmd.trace_id,
+        describe_recipients(&cmd),
+        cmd.text.len()
+    );
+    debug!("ZMQ message in full: {:?}", cmd);
+    if !settings.combine_targets && cmd.target_count() > 1 {
+        warn!("Message {:?} sets more than one of chat_ids, chat_id, chat and subscriber_list; using the first", cmd.id);
+    }
+
+    // A chat given by username is a chat_id once Telegram has resolved it
+    if let Some(chat) = cmd.chat.take() {
+        match resolve_chats(bot, settings, state, std::slice::from_ref(&chat)).await.first() {
+            Some(&id) if cmd.chat_id.is_none() => cmd.chat_id = Some(id),
+            Some(_) => warn!("Message {:?} sets both chat_id and chat; using chat_id", cmd.id),
+            // Like an unknown list: warned about, never a fallback to the owners
+            None if cmd.target_count() == 0 => return Vec::new(),
+            None => {}
+        }
+    }
+
+    let mut opts = SendOptions::for_priority(cmd.priority);
+    (opts.protect_content, opts.spoiler) = settings.content_flags(&cmd);
+    opts.disable_link_preview = settings.link_preview_disabled(&cmd);
+    opts.chat_action_min_bytes = settings.send_chat_actions.then_some(settings.chat_action_min_bytes);
+    apply_retry_overrides(&cmd, &mut opts);
+
+    // Telegram rejects a whole HTML message over a single bad tag
+    if cmd.parse_mode == Some(ParseMode::Html) {
+        match settings.html_mode {
+            // Valid markup is sent as written, even where sanitizing would
+            // only reformat it
+            HtmlMode::Sanitize => {
+                if let Err(problems) = html::validate(&cmd.text) {
+                    warn!("Sanitized HTML in message {:?}: {}", cmd.id, problems);
+                    cmd.text = html::sanitize(&cmd.text);
+                }
+            }
+            HtmlMode::Strict => {
+                if let Err(problems) = html::validate(&cmd.text) {
+                    error!("Rejecting HTML message {:?} (html_mode = \"strict\"): {}", cmd.id, problems);
+                    return Vec::new();
+                }
+            }
+        }
+        opts.html = true;
+    }
+
+    // Quiet hours apply to subscriber-list broadcasts only
+    if let Some(list_name) = cmd.broadcast_list() {
+        let quiet = settings
+            .subscriber_lists
+            .get(list_name)
+            .and_then(|list| list.quiet_hours.as_ref())
+            .filter(|quiet| quiet.contains(now));
+        match quiet.map(|quiet| (quiet.mode, quiet.next_end(now))) {
+            Some((QuietMode::Silent, _)) => opts.disable_notification = true,
+            Some((QuietMode::Defer, release_at)) => {
+                info!("Holding broadcast to '{}' until quiet hours end at {}", list_name, release_at);
+                state.deferred.hold(cmd, now, release_at);
+                return Vec::new();
+            }
+            None => {}
+        }
+    }
+
+    // Producers may only attach files from media_dir
+    if let (Some(dir), Some(img_path)) = (&settings.media_dir, cmd.image_path.clone()) {
+        cmd.image_path = None;
+        match media::confine(dir, &img_path) {
+            Ok(path) => cmd.image_path = Some(path.to_string_lossy().into_owned()),
+            Err(rejection @ Rejection::Unresolvable(_)) => {
+                warn!("Image {} {}; sending text only", img_path, rejection);
+                cmd.text = format!("{} (Image attachment failed: {})", cmd.text, img_path);
+            }
+            Err(rejection @ Rejection::Outside(_)) => {
+                error!("Refusing image {} of message {:?} from {:?}: {}", img_path, cmd.id, cmd.peer, rejection);
+                if settings.media_dir_warn_owners {
+                    let warning = format!("Security warning: refused to attach {} ({}); sent the text only", img_path, rejection);
+                    send_to_owners(bot, settings, &warning, SendOptions::default()).await;
+                }
+                cmd.text = format!("{} (Image attachment refused: {})", cmd.text, img_path);
+            }
+        }
+    }
+
+    if cmd.image_frame && cmd.image_bytes.is_none() {
+        warn!("Image frame of message {:?} was lost (deferred across a restart); sending text only", cmd.id);
+    }
+    if cmd.image_bytes.is_some() && cmd.image_path.is_some() {
+        warn!("Message {:?} has both an image frame and image_path; sending the frame", cmd.id);
+        cmd.image_path = None;
+    }
+    if let Some(image) = cmd.image_bytes.take_if(|image| image.len() as u64 > settings.max_photo_bytes) {
+        warn!("Image frame of message {:?} is {} bytes, over max_photo_bytes; sending text only", cmd.id, image.len());
+        cmd.text = format!("{} (Image attachment too large: {})", cmd.text, image);
+    }
+
+    // Images the Bot API server would refuse are not uploaded at all
+    if let Some(img_path) = cmd.image_path.take() {
+        match oversized(Path::new(&img_path), settings.max_photo_bytes) {
+            Some(size) => {
+                warn!("Image {} is {} bytes, over max_photo_bytes; sending text only", img_path, size);
+                cmd.text = format!("{} (Image attachment too large: {})", cmd.text, img_path);
+            }
+            None => cmd.image_path = Some(img_path),
+        }
+    }
+
+    // Only an image that is actually sent is deleted, and only once all chats have it
+    let delete_image = cmd.image_path.clone().filter(|_| settings.deletes_after_send(&cmd));
+
+    // Decoding and re-encoding take a while; keep them off the runtime's threads
+    let converted = match settings.convert_unsupported_images {
+        true => match tokio::task::spawn_blocking(move || convert_image(cmd)).await {
+            Ok((converted_cmd, converted)) => {
+                cmd = converted_cmd;
+                converted
+            }
+            Err(err) => {
+                error!("Converting the image of a message failed: {}", err);
+                return Vec::new();
+            }
+        },
+        false => ConvertedImage::default(),
+    };
+    let text_document = match converted.document {
+        Some(_) => None,
+        None => text_document(settings, &cmd),
+    };
+    let document = converted.document.clone().or_else(|| text_document.clone());
+    let caption = caption_for(&cmd, opts);
+
+    // Summaries of closed duplicate windows go out before anything new
+    let content = match state.duplicates.is_enabled() {
+        true => {
+            send_duplicate_summaries(bot, settings, state).await;
+            Some(dedupe::content_key(&cmd.text, attachment_key(&cmd)))
+        }
+        false => None,
+    };
+
+    let id = cmd.id.clone();
+    let (mut delivered, mut targeted) = (0, 0);
+    let mut outcomes = Vec::new();
+
+    let explicit = cmd.explicit_chats(settings.combine_targets);
+    let list_name = cmd.target_list(settings.combine_targets);
+    let list = match list_name {
+        Some(name) => {
+            let list = settings.subscriber_lists.get(name);
+            if list.is_none() {
+                warn!("Subscriber list '{}' not found", name);
+                let warning = format!("Warning: unknown subscriber list '{}'", name);
+                send_to_owners(bot, settings, &warning, SendOptions::default()).await;
+            }
+            list
+        }
+        None => None,
+    };
+
+    match (list, explicit.as_slice()) {
+        // The owners are the fallback only when no target was given at all
+        (None, []) if list_name.is_none() => {
+            let owners = without_repeats(state, settings.owner_chat_ids.clone(), content, &cmd);
+            let tickets: Vec<_> = owners.iter().map(|&owner| state.chat_order.ticket(owner)).collect();
+            for (owner, ticket) in owners.into_iter().zip(tickets) {
+                ticket.turn().await;
+                let (outcome, degraded) =
+                    deliver_to_chat(bot, &state.file_ids, ChatId(owner), &cmd, document.as_deref(), &caption, opts).await;
+                drop(ticket);
+                targeted += 1;
+                delivered += usize::from(outcome.is_ok());
+                outcomes.push((owner, outcome.clone()));
+                track_outcome(bot, settings, state, owner, &cmd, outcome, degraded).await;
+            }
+        }
+        (None, []) => {}
+        (None, &[chat_id]) if cmd.chat_ids.is_empty() => {
+            let chat_id = bot.migrations().resolve(chat_id);
+            if state.quarantine.is_quarantined(chat_id) {
+                warn!("Not sending to quarantined chat {}", chat_id);
+            } else if settings.mutes_apply_to_direct && state.mutes.is_muted(chat_id, now) {
+                info!("Not sending to muted chat {}", chat_id);
+            } else if without_repeats(state, vec![chat_id], content, &cmd).is_empty() {
+                targeted = 1;
+            } else {
+                let ticket = state.chat_order.ticket(chat_id);
+                ticket.turn().await;
+                let (outcome, degraded) =
+                    deliver_to_chat(bot, &state.file_ids, ChatId(chat_id), &cmd, document.as_deref(), &caption, opts).await;
+                drop(ticket);
+                targeted = 1;
+                delivered = usize::from(outcome.is_ok());
+                outcomes.push((chat_id, outcome.clone()));
+                track_outcome(bot, settings, state, chat_id, &cmd, outcome, degraded).await;
+            }
+        }
+        _ => {
+            let label = match (list.and(list_name), explicit.is_empty()) {
+                (Some(name), true) => format!("'{}'", name),
+                (Some(name), false) => format!("'{}' + {:?}", name, explicit),
+                (None, _) => format!("{:?}", explicit),
+            };
+            // Chats addressed by ID are direct messages as far as mutes are concerned
+            let mut subs = deliverable(state, bot.migrations(), &label, explicit, settings.mutes_apply_to_direct, now);
+            let members = match list {
+                Some(list) => resolve_chats(bot, settings, state, &list.chats).await,
+                None => Vec::new(),
+            };
+            for id in deliverable(state, bot.migrations(), &label, members, true, now) {
+                if !subs.contains(&id) {
+                    subs.push(id);
+                }
+            }
+            if cmd.target_count() > 1 {
+                info!("Message {:?} resolved to chats {:?}", cmd.id, subs);
+            }
+            let subs = without_repeats(state, subs, content, &cmd);
+            targeted = subs.len();
+            let hash = content.unwrap_or_else(|| dedupe::content_key(&cmd.text, attachment_key(&cmd)));
+            let journal = state.broadcasts.begin(hash, &label, &cmd, opts, &subs, now);
+            outcomes = fan_out(bot, settings, state, &label, subs, cmd, document.clone(), caption, opts, journal).await;
+            delivered = outcomes.iter().filter(|(_, outcome)| outcome.is_ok()).count();
+        }
+    }
+
+    for path in text_document.iter().chain(&converted.temp) {
+        if let Err(err) = fs::remove_file(path) {
+            warn!("Failed to remove temp file {}: {}", path.display(), err);
+        }
+    }
+    if let Some(path) = delete_image {
+        remove_delivered_image(Path::new(&path), id.as_deref(), delivered, targeted);
+    }
+    outcomes
+}
+
+/// Files made by `convert_image`
+#[derive(Default)]
+struct ConvertedImage {
+    /// Temporary file to remove once the message is sent
+    temp: Option<PathBuf>,
+    /// The image as a file to send as a document instead of a photo
+    document: Option<PathBuf>,
+}
+
+/// Re-encode the image of `cmd` as JPEG if Telegram handles its format
+/// badly as a photo, or turn it into a document if it is no usable photo.
+/// A file that cannot be read is left for the send path to report.
+fn convert_image(mut cmd: ZmqMessage) -> (ZmqMessage, ConvertedImage) {
+    let mut converted = ConvertedImage::default();
+    if let Some(image) = &cmd.image_bytes {
+        match images::prepare(&image.0) {
+            images::Prepared::Unchanged => {}
+            images::Prepared::Converted { from, jpeg } => {
+                info!(
+                    "Converted {} of message {:?} from {} to JPEG ({} -> {} bytes)",
+                    image,
+                    cmd.id,
+                    images::name(from),
+                    image.len(),
+                    jpeg.len()
+                );
+                cmd.image_bytes = Some(ImageBytes(Arc::new(jpeg)));
+            }
+            images::Prepared::Document(why) => {
+                let extension = images::detect(&image.0).map_or("bin", images::name);
+                match write_bytes(&image.0, extension) {
+                    Ok(path) => {
+                        warn!("Sending {} of message {:?} as a document: {}", image, cmd.id, why);
+                        converted = ConvertedImage { temp: Some(path.clone()), document: Some(path) };
+                        cmd.image_bytes = None;
+                    }
+                    Err(err) => warn!("Failed to write {} of message {:?} to a file: {}", image, cmd.id, err),
+                }
+            }
+        }
+    } else if let Some(img_path) = cmd.image_path.clone() {
+        let Ok(bytes) = fs::read(&img_path) else {
+            return (cmd, converted);
+        };
+        match images::prepare(&bytes) {
+            images::Prepared::Unchanged => {}
+            images::Prepared::Converted { from, jpeg } => match write_bytes(&jpeg, "jpg") {
+                Ok(path) => {
+                    info!(
+                        "Converted image {} of message {:?} from {} to JPEG {} ({} -> {} bytes)",
+                        img_path,
+                        cmd.id,
+                        images::name(from),
+                        path.display(),
+                        bytes.len(),
+                        jpeg.len()
+                    );
+                    cmd.image_path = Some(path.to_string_lossy().into_owned());
+                    converted.temp = Some(path);
+                }
+                Err(err) => warn!("Failed to write the JPEG of image {}: {}; sending it as it is", img_path, err),
+            },
+            images::Prepared::Document(why) => {
+                warn!("Sending image {} of message {:?} as a document: {}", img_path, cmd.id, why);
+                cmd.image_path = None;
+                converted.document = Some(PathBuf::from(img_path));
+            }
+        }
+    }
+    (cmd, converted)
+}
+
+/// Very long texts go out as a single .txt attachment instead of many
+/// chunks; the path of that file, when `cmd` is one of them
+fn text_document(settings: &TelegramSettings, cmd: &ZmqMessage) -> Option<PathBuf> {
+    if cmd.has_image() || cmd.text.chars().count() <= settings.long_text_as_file_over {
+        return None;
+    }
+    match write_text_document(&cmd.text) {
+        Ok(path) => match oversized(&path, settings.max_document_bytes) {
+            None => Some(path),
+            Some(size) => {
+                warn!("Text document is {} bytes, over max_document_bytes; splitting instead", size);
+                let _ = fs::remove_file(&path);
+                None
+            }
+        },
+        Err(err) => {
+            error!("Failed to write long text to temp file: {}", err);
+            None
+        }
+    }
+}
+
+/// Caption of a document or photo sent for `cmd`
+fn caption_for(cmd: &ZmqMessage, opts: SendOptions) -> String {
+    match opts.html {
+        // Truncation may have cut a tag in half
+        true => html::sanitize(&document_caption(cmd)),
+        false => document_caption(cmd),
+    }
+}
+
+/// Identity of the attached image for duplicate detection
+fn attachment_key(cmd: &ZmqMessage) -> Option<file_ids::FileKey> {
+    match (&cmd.image_bytes, &cmd.image_path) {
+        (Some(image), _) => Some(file_ids::key_for_bytes(&image.0)),
+        (None, Some(path)) => file_ids::key_for(Path::new(path)).ok(),
+        (None, None) => None,
+    }
+}
+
+/// `chats` without those that got the same `content` within
+/// `suppress_duplicates_secs`; all of them when `content` is `None`
+fn without_repeats(state: &BotState, chats: Vec<i64>, content: Option<u64>, cmd: &ZmqMessage) -> Vec<i64> {
+    let Some(content) = content else {
+        return chats;
+    };
+    let now = time::Instant::now().into_std();
+    let summary = preview(&cmd.text, LOG_PREVIEW_CHARS);
+    chats
+        .into_iter()
+        .filter(|&chat| match state.duplicates.is_repeat(chat, content, &summary, now) {
+            Some(repeats) => {
+                info!("Suppressed duplicate to {} (x{}): \"{}\"", chat, repeats, summary);
+                state.stats.record_duplicate_suppressed();
+                false
+            }
+            None => true,
+        })
+        .collect()
+}
+
+/// Tell chats how often a message was repeated once its duplicate window
+/// has closed, if `duplicate_summaries` is on
+pub async fn send_duplicate_summaries<S: MessageSink>(bot: &S, settings: &TelegramSettings, state: &BotState) {
+    for closed in state.duplicates.take_closed(time::Instant::now().into_std()) {
+        info!("Suppressed {} duplicate(s) to {}: \"{}\"", closed.repeats, closed.chat_id, closed.preview);
+        if settings.duplicate_summaries {
+            let text = format!("Previous message repeated {} time(s): \"{}\"", closed.repeats, closed.preview);
+            let opts = SendOptions { disable_notification: true, ..SendOptions::default() };
+            let _ = send_to_chat_with_retry(bot, ChatId(closed.chat_id), &text, opts).await;
+        }
+    }
+}
+
+/// Delete the image of message `id` if it reached all `targeted` chats,
+/// otherwise log why it is kept. Only regular files are ever removed.
+fn remove_delivered_image(path: &Path, id: Option<&str>, delivered: usize, targeted: usize) {
+    if targeted == 0 {
+        info!("Keeping {}: message {:?} was not sent to any chat", path.display(), id);
+        return;
+    }
+    if delivered < targeted {
+        warn!("Keeping {}: message {:?} reached only {}/{} chats", path.display(), id, delivered, targeted);
+        return;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_file() => match fs::remove_file(path) {
+            Ok(()) => info!("Deleted {} after delivering message {:?}", path.display(), id),
+            Err(err) => warn!("Failed to delete {}: {}", path.display(), err),
+        },
+        Ok(_) => warn!("Not deleting {}: not a regular file", path.display()),
+        Err(err) => warn!("Failed to delete {}: {}", path.display(), err),
+    }
+}
+
+/// Send messages from the outbox until it is closed and drained, each with
+/// the settings current when it is taken
+pub async fn run_outbox_worker<S: MessageSink>(
+    bot: S,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+    serve: Serve,
+) {
+    loop {
+        // Nothing is taken while sends are paused, so the high lane goes
+        // first once they resume
+        if bot.flood_breaker().wait().await || state.outbox.held() > 0 {
+            drop_expired(&state);
+        }
+        let Some(cmd) = state.outbox.next(serve).await else { break };
+        let settings = shared.snapshot();
+        if let Some(until) = parked_until(&bot, &settings, &cmd) {
+            debug!("Holding message {:?} until its flood-waited chats can be sent to again", cmd.id);
+            state.outbox.hold(cmd, until);
+            continue;
+        }
+        process_zmq_message(&bot, &settings, &state, cmd).await;
+    }
+}
+
+/// When `cmd` can be sent, if it goes only to chats that are flood-waited
+fn parked_until<S: MessageSink>(bot: &S, settings: &TelegramSettings, cmd: &ZmqMessage) -> Option<std::time::Instant> {
+    if cmd.target_list(settings.combine_targets).is_some() || cmd.chat.is_some() {
+        return None;
+    }
+    let now = time::Instant::now().into_std();
+    let chats = cmd.explicit_chats(settings.combine_targets);
+    let parked = chats.iter().map(|&chat| bot.flood_breaker().parked_until(bot.migrations().resolve(chat), now));
+    // None as soon as one chat is free, and for a message with no chats
+    parked.collect::<Option<Vec<_>>>()?.into_iter().max()
+}
+
+/// Tell the owners about lists that went quiet or resumed, and persist
+/// the delivery timestamps
+pub async fn check_traffic<S: MessageSink>(bot: &S, settings: &TelegramSettings, state: &BotState) {
+    for alert in state.traffic.check(&settings.expected_traffic(), Utc::now()) {
+        let notice = alert.notice();
+        warn!("{}", notice);
+        send_to_owners(bot, settings, &notice, SendOptions::default()).await;
+    }
+    state.traffic.save();
+}
+
+/// Send the owners the delivery report if one is due, starting a new period
+pub async fn send_due_report<S: MessageSink>(bot: &S, settings: &TelegramSettings, state: &BotState, now: DateTime<Utc>) {
+    let Some(config) = &settings.reports else { return };
+    let Ok(schedule) = config.schedule() else { return };
+    if state.reports.due(&schedule, config.tz, now) {
+        let title = match schedule {
+            reports::Schedule::Daily(_) => "Daily report",
+            reports::Schedule::Weekly(..) => "Weekly report",
+        };
+        send_report(bot, settings, state, &settings.owner_chat_ids, title, state.reports.take(now)).await;
+    } else {
+        state.reports.save();
+    }
+}
+
+/// Send `chats` the report for `period`, with its deliveries as CSV when
+/// `csv` is on and history is recorded. Returns the chats the text failed for.
+pub async fn send_report<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &BotState,
+    chats: &[i64],
+    title: &str,
+    period: reports::Period,
+) -> Vec<(i64, ErrorCategory)> {
+    let (tz, top, csv) = settings.reports.as_ref().map_or((chrono_tz::UTC, 5, false), |r| (r.tz, r.top_producers, r.csv));
+    let quarantined: Vec<_> =
+        state.quarantine.chats().into_iter().map(|chat| (chat, settings.lists_containing(chat))).collect();
+    let text = reports::report_text(title, &period, &quarantined, tz, top);
+    info!("Sending {} to {:?}", title.to_lowercase(), chats);
+    let mut failed = Vec::new();
+    for &chat in chats {
+        if let Err(category) = send_to_chat_with_retry(bot, ChatId(chat), &text, SendOptions::default()).await {
+            failed.push((chat, category));
+        }
+    }
+    if !csv || !state.history.is_enabled() {
+        return failed;
+    }
+    let entries = match state.history.between(period.from, period.to).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Report CSV not sent: {}", err);
+            return failed;
+        }
+    };
+    let path = match write_document(&reports::csv(&entries), "csv") {
+        Ok(path) => path,
+        Err(err) => {
+            error!("Report CSV not sent: failed to write it: {}", err);
+            return failed;
+        }
+    };
+    let caption = format!("{} deliveries, {} – {}", entries.len(), period.from.format("%Y-%m-%d"), period.to.format("%Y-%m-%d"));
+    let fallback = format!("The report's CSV of {} deliveries could not be uploaded; see /history.", entries.len());
+    for &chat in chats {
+        let _ = send_to_chat_with_document_retry(bot, ChatId(chat), &fallback, &path, &caption, SendOptions::default()).await;
+    }
+    if let Err(err) = fs::remove_file(&path) {
+        warn!("Failed to delete {}: {}", path.display(), err);
+    }
+    failed
+}
+
+/// Tell the owners when the flood breaker of `bot`'s account opened or closed
+pub async fn announce_flood_changes<S: MessageSink>(bot: &S, settings: &TelegramSettings) {
+    for change in bot.flood_breaker().take_changes(time::Instant::now().into_std()) {
+        let notice = match change {
+            flood::Change::Opened { floods, pause } => format!(
+                "Telegram flood limit hit {} times; all sends paused for {}s. Messages stay queued and high-priority ones go first when sending resumes.",
+                floods,
+                pause.as_secs()
+            ),
+            flood::Change::Closed { paused_for } => {
+                format!("Sending resumed after a {}s pause for Telegram's flood limit.", paused_for.as_secs())
+            }
+        };
+        send_to_owners(bot, settings, &notice, SendOptions::default()).await;
+    }
+}
+
+/// Drop queued messages whose TTL ran out while sends were paused
+fn drop_expired(state: &BotState) {
+    for message in state.outbox.take_expired(time::Instant::now().into_std()) {
+        warn!("Dropping message {:?}, TTL expired while sends were paused: \"{}\"", message.id, preview(&message.text, LOG_PREVIEW_CHARS));
+    }
+}
+
+/// Deliver held broadcasts whose quiet hours have ended, dropping any whose
+/// TTL ran out while they waited
+pub async fn release_deferred<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    now: DateTime<Utc>,
+) {
+    for held in state.deferred.take_due(now) {
+        let list_name = held.message.subscriber_list.as_deref().unwrap_or("(none)");
+        if held.expired(now) {
+            warn!(
+                "Dropping deferred broadcast to '{}' received at {}: TTL expired",
+                list_name, held.received_at
+            );
+            continue;
+        }
+        info!("Releasing deferred broadcast to '{}'", list_name);
+        process_zmq_message_at(bot, settings, state, held.message, now).await;
+    }
+}
+
+/// Finish the broadcasts a previous run left unfinished, sending only to
+/// the chats that had not got them. Broadcasts whose TTL ran out meanwhile
+/// are abandoned and the owners told.
+pub async fn resume_broadcasts<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    now: DateTime<Utc>,
+) {
+    for broadcast in state.broadcasts.incomplete() {
+        let remaining: Vec<i64> = broadcast.remaining.iter().copied().collect();
+        let summary = preview(&broadcast.message.text, LOG_PREVIEW_CHARS);
+        if broadcast.expired(now) {
+            let notice = format!(
+                "Abandoned broadcast to {} of \"{}\": its TTL ran out before the restart, {} chat(s) did not get it",
+                broadcast.label,
+                summary,
+                remaining.len()
+            );
+            warn!("{} ({:?})", notice, remaining);
+            state.broadcasts.finish(broadcast.id);
+            send_to_owners(bot, settings, &notice, SendOptions::default()).await;
+            continue;
+        }
+        info!(
+            "Resuming broadcast {:016x} to {} of \"{}\" for {} remaining chat(s)",
+            broadcast.hash,
+            broadcast.label,
+            summary,
+            remaining.len()
+        );
+        let document = text_document(settings, &broadcast.message);
+        let caption = caption_for(&broadcast.message, broadcast.opts);
+        let (label, opts) = (broadcast.label.clone(), broadcast.opts);
+        let trace_id = trace::ensure(broadcast.message.trace_id.as_deref());
+        let resumed = fan_out(bot, settings, state, &label, remaining, broadcast.message, document.clone(), caption, opts, broadcast.id);
+        trace::scope(&trace_id, resumed).await;
+        if let Some(path) = document {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Record the delivery in the history, remember what was sent so replies can
+/// be correlated, and feed the outcome to the quarantine, which never takes
+/// the owner chat, telling the owner when a chat gets quarantined
+async fn track_outcome<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &BotState,
+    chat_id: i64,
+    cmd: &ZmqMessage,
+    outcome: Delivery,
+    degraded: bool,
+) {
+    for (old, new) in bot.migrations().take_unannounced(chat_id) {
+        send_to_owners(bot, settings, &migrations::notice(old, new), SendOptions::default()).await;
+    }
+    let direct = cmd.explicit_chats(settings.combine_targets).contains(&chat_id);
+    // Replies and history belong to the chat the message actually reached
+    let chat_id = bot.migrations().resolve(chat_id);
+    if outcome.is_ok() {
+        state.traffic.record(cmd.target_list(settings.combine_targets), direct.then_some(chat_id), Utc::now());
+    }
+    let list = if direct { None } else { cmd.target_list(settings.combine_targets) };
+    state.reports.record_delivery(list, outcome.as_ref().map(|_| ()).map_err(|category| *category));
+    let transition = match &outcome {
+        Ok(_) => Transition::MessageSent { chat: chat_id },
+        Err(category) => Transition::SendFailed { chat: chat_id, category: *category },
+    };
+    state.health.apply(transition, Utc::now());
+    state.events.publish(match &outcome {
+        Ok(sent) => BotEvent::MessageDelivered {
+            chat_id,
+            message_id: sent.first().map_or(0, |id| id.0),
+            status: match degraded {
+                true => DeliveryStatus::DeliveredDegraded,
+                false => DeliveryStatus::Delivered,
+            },
+            id: cmd.id.clone(),
+            list: cmd.subscriber_list.clone(),
+            trace_id: cmd.trace_id.clone(),
+        },
+        Err(category) => BotEvent::MessageFailed {
+            chat_id,
+            category: *category,
+            id: cmd.id.clone(),
+            list: cmd.subscriber_list.clone(),
+            trace_id: cmd.trace_id.clone(),
+        },
+    });
+    state.history.record(history::Entry {
+        at: Utc::now(),
+        chat_id,
+        list: cmd.subscriber_list.clone(),
+        kind: match (cmd.has_image(), cmd.text.chars().count() > settings.long_text_as_file_over) {
+            (true, _) => history::Kind::Photo,
+            (false, true) => history::Kind::Document,
+            (false, false) => history::Kind::Text,
+        },
+        preview: preview(&cmd.text, history::PREVIEW_CHARS),
+        message_id: outcome.as_ref().ok().and_then(|sent| sent.first()).map(|id| id.0),
+        error: outcome.as_ref().err().map(|category| category.to_string()),
+        trace_id: cmd.trace_id.clone(),
+    });
+    let outcome = outcome.map(|sent| {
+        let sent_at = Utc::now();
+        for message_id in sent {
+            let correlation = Correlation {
+                id: cmd.id.clone(),
+                subscriber_list: cmd.subscriber_list.clone(),
+                sent_at,
+                trace_id: cmd.trace_id.clone(),
+            };
+            state.sent.record(chat_id, message_id.0, correlation);
+        }
+    });
+    if settings.is_owner(chat_id) {
+        return;
+    }
+    if !state.quarantine.record(chat_id, outcome) {
+        return;
+    }
+    let category = outcome.err().map(|c| c.as_str()).unwrap_or("unknown");
+    let lists = settings.lists_containing(chat_id);
+    warn!("Quarantined chat {} after repeated '{}' failures", chat_id, category);
+    let notice = format!(
+        "Chat {} has been quarantined after {} consecutive '{}' failures and will be skipped.\n\
+         Lists: {}\n\
+         Send /unquarantine {} to resume deliveries.",
+        chat_id,
+        settings.quarantine_after,
+        category,
+        if lists.is_empty() { "(none)".to_string() } else { lists.join(", ") },
+        chat_id
+    );
+    send_to_owners(bot, settings, &notice, SendOptions::default()).await;
+}
+
+/// Send `text` to every owner chat, returning the owners it failed for
+pub async fn send_to_owners<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    text: &str,
+    opts: SendOptions,
+) -> Vec<(i64, ErrorCategory)> {
+    let mut failed = Vec::new();
+    for &owner in &settings.owner_chat_ids {
+        if let Err(category) = send_to_chat_with_retry(bot, ChatId(owner), text, opts).await {
+            failed.push((owner, category));
+        }
+    }
+    failed
+}
+
+/// Apply a ZMQ control action and describe the outcome for the producer
+pub fn process_control(shared: &SharedSettings, state: &BotState, action: ControlAction) -> ControlAck {
+    info!("Processing ZMQ control action: {:?}", action);
+    let name = action.name();
+    let (ok, detail) = match action {
+        ControlAction::Unquarantine { chat_id } => {
+            if state.quarantine.release(chat_id) {
+                info!("Released chat {} from quarantine", chat_id);
+                (true, format!("Released chat {} from quarantine", chat_id))
+            } else {
+                warn!("Chat {} was not quarantined", chat_id);
+                (false, format!("Chat {} was not quarantined", chat_id))
+            }
+        }
+        ControlAction::ListAdd { list, chat_id, create } => {
+            list_change(&list, chat_id, shared.add_list_chat(&list, chat_id, create), "set \"create\": true to create it")
+        }
+        ControlAction::ListDel { list, chat_id } => {
+            list_change(&list, chat_id, shared.remove_list_chat(&list, chat_id), "")
+        }
+    };
+    ControlAck { action: name, ok, detail }
+}
+
+/// Log the result of a list change requested over ZMQ and describe it.
+/// `create_hint` follows the error for a list that does not exist.
+fn list_change(list: &str, chat_id: i64, result: Result<ListChange, ListError>, create_hint: &str) -> (bool, String) {
+    match result {
+        Ok(change) => {
+            let detail = change.describe(list, chat_id);
+            info!("ZMQ: {}", detail);
+            (true, detail)
+        }
+        Err(err) => {
+            let mut detail = err.describe(list);
+            if err == ListError::UnknownList && !create_hint.is_empty() {
+                detail = format!("{}; {}", detail, create_hint);
+            }
+            warn!("ZMQ: {}", detail);
+            (false, detail)
+        }
+    }
+}
+
+/// IDs for `chats`, looking up usernames that are not cached. Usernames
+/// that cannot be resolved are left out and the owners are warned.
+async fn resolve_chats<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &BotState,
+    chats: &[ChatRef],
+) -> Vec<i64> {
+    let mut ids = Vec::with_capacity(chats.len());
+    for chat in chats {
+        let username = match chat {
+            ChatRef::Id(id) => {
+                ids.push(*id);
+                continue;
+            }
+            ChatRef::Username(username) => username,
+        };
+        let now = time::Instant::now().into_std();
+        if let Some(id) = state.usernames.get(username, now) {
+            ids.push(id);
+            continue;
+        }
+        let category = match time::timeout(time::Duration::from_secs(15), bot.resolve_username(username)).await {
+            Ok(Ok(id)) => {
+                info!("Resolved {} to chat {}", username, id);
+                state.usernames.insert(username, id.0, now);
+                ids.push(id.0);
+                continue;
+            }
+            Ok(Err(err)) => {
+                error!("Could not resolve {}: {:?}", username, err);
+                err.category()
+            }
+            Err(_elapsed) => {
+                error!("Timed out resolving {}", username);
+                ErrorCategory::Timeout
+            }
+        };
+        let warning = format!("Warning: could not resolve chat {} ({})", username, category);
+        send_to_owners(bot, settings, &warning, SendOptions::default()).await;
+    }
+    ids
+}
+
+/// `ids` without quarantined chats and, if `apply_mutes`, muted ones
+/// after following migrated chats to their new IDs
+fn deliverable(
+    state: &BotState,
+    migrations: &Migrations,
+    label: &str,
+    ids: Vec<i64>,
+    apply_mutes: bool,
+    now: DateTime<Utc>,
+) -> Vec<i64> {
+    let mut resolved: Vec<i64> = Vec::with_capacity(ids.len());
+    for id in ids.into_iter().map(|id| migrations.resolve(id)) {
+        if !resolved.contains(&id) {
+            resolved.push(id);
+        }
+    }
+    let ids = resolved;
+    let (skipped, ids): (Vec<i64>, Vec<i64>) = ids.into_iter().partition(|&id| state.quarantine.is_quarantined(id));
+    if !skipped.is_empty() {
+        debug!("Broadcast to {} skipping quarantined chats {:?}", label, skipped);
+    }
+    let (muted, ids): (Vec<i64>, Vec<i64>) =
+        ids.into_iter().partition(|&id| apply_mutes && state.mutes.is_muted(id, now));
+    if !muted.is_empty() {
+        debug!("Broadcast to {} skipping muted chats {:?}", label, muted);
+    }
+    ids
+}
+
+/// Deliver `cmd` to every chat in `subs` concurrently, bounded by
+/// `broadcast_concurrency`, and log a summary of the failures. Returns the
+/// outcome for each chat once the last of them is done.
+#[allow(clippy::too_many_arguments)]
+async fn fan_out<S: MessageSink>(
+    bot: &S,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    label: &str,
+    subs: Vec<i64>,
+    cmd: ZmqMessage,
+    document: Option<PathBuf>,
+    caption: String,
+    opts: SendOptions,
+    journal: u64,
+) -> Vec<(i64, Delivery)> {
+    let cmd = Arc::new(cmd);
+    let document = Arc::new(document);
+    let caption = Arc::new(caption);
+    // Bound how many recipients are in flight so a slow chat only holds one slot
+    let limit = Arc::new(Semaphore::new(settings.broadcast_concurrency.max(1)));
+    // An image goes to the first chat alone so the others can reuse its file ID
+    let gate = Arc::new(RwLock::new(()));
+    let mut first_upload = match cmd.has_image() && state.file_ids.is_enabled() {
+        true => gate.clone().try_write_owned().ok(),
+        false => None,
+    };
+    let mut tasks = tokio::task::JoinSet::new();
+    for &sub_id in &subs {
+        let bot = bot.clone();
+        let state = state.clone();
+        let cmd = cmd.clone();
+        let document = document.clone();
+        let caption = caption.clone();
+        let limit = limit.clone();
+        let gate = gate.clone();
+        // A flood-waited chat would keep the others waiting for its upload
+        let parked = bot.flood_breaker().parked_until(sub_id, time::Instant::now().into_std()).is_some();
+        let first = if parked { None } else { first_upload.take() };
+        let ticket = state.chat_order.ticket(sub_id);
+        tasks.spawn(trace::inherit(async move {
+            // Earlier messages to the chat, and its flood wait, go first
+            // without holding a slot
+            ticket.turn().await;
+            bot.flood_breaker().wait_for(sub_id).await;
+            if first.is_none() {
+                drop(gate.read().await);
+            }
+            let _permit = limit.acquire_owned().await;
+            let (outcome, degraded) =
+                deliver_to_chat(&bot, &state.file_ids, ChatId(sub_id), &cmd, (*document).as_deref(), &caption, opts).await;
+            drop(first);
+            drop(ticket);
+            (sub_id, outcome, degraded)
+        }));
+    }
+    let mut failed = Vec::new();
+    let mut outcomes = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((sub_id, outcome, degraded)) => {
+                match outcome {
+                    Ok(_) => state.broadcasts.done(journal, sub_id),
+                    Err(_) => failed.push(sub_id),
+                }
+                outcomes.push((sub_id, outcome.clone()));
+                track_outcome(bot, settings, state, sub_id, &cmd, outcome, degraded).await;
+            }
+            Err(err) => error!("Broadcast task failed: {:?}", err),
+        }
+    }
+    state.broadcasts.finish(journal);
+    state.events.publish(BotEvent::BroadcastSummary {
+        label: label.to_string(),
+        delivered: outcomes.len() - failed.len(),
+        failed: failed.len(),
+        id: cmd.id.clone(),
+        trace_id: cmd.trace_id.clone(),
+    });
+    if failed.is_empty() {
+        info!("Broadcast to {} delivered to all {} chats", label, subs.len());
+    } else {
+        failed.sort_unstable();
+        warn!(
+            "Broadcast to {} of \"{}\" delivered to {}/{} chats; failed: {:?}",
+            label,
+            preview(&cmd.text, LOG_PREVIEW_CHARS),
+            subs.len() - failed.len(),
+            subs.len(),
+            failed
+        );
+    }
+    outcomes
+}
+
+/// Use the retry policy `cmd` asks for, within `MAX_RETRIES` and
+/// `MAX_RETRY_DELAY_MS`
+fn apply_retry_overrides(cmd: &ZmqMessage, opts: &mut SendOptions) {
+    if let Some(retries) = cmd.max_retries {
+        if retries > MAX_RETRIES as u32 {
+            warn!("Message {:?} asks for {} retries; using {}", cmd.id, retries, MAX_RETRIES);
+        }
+        opts.max_attempts = retries.min(MAX_RETRIES as u32) as u8 + 1;
+    }
+    if let Some(delay) = cmd.retry_base_delay_ms {
+        if delay > MAX_RETRY_DELAY_MS {
+            warn!("Message {:?} asks for a {}ms retry delay; using {}ms", cmd.id, delay, MAX_RETRY_DELAY_MS);
+        }
+        opts.retry_base_delay_ms = delay.min(MAX_RETRY_DELAY_MS);
+    }
+}
+
+/// The chat `err` says `chat` was migrated to, recorded so later sends go
+/// there directly
+fn followed_migration<S: MessageSink>(bot: &S, chat: ChatId, err: &S::Error) -> Option<ChatId> {
+    let new = err.migrated_to().filter(|&new| new != chat.0)?;
+    bot.migrations().record(chat.0, new);
+    Some(ChatId(new))
+}
+
+/// Send a ZMQ command to a single chat, picking image, document, or text delivery.
+/// Fails only if nothing at all reached the chat. Also says whether the
+/// formatting had to be dropped to get it there.
+async fn deliver_to_chat<S: MessageSink>(
+    bot: &S,
+    file_ids: &FileIds,
+    chat: ChatId,
+    cmd: &ZmqMessage,
+    document: Option<&Path>,
+    caption: &str,
+    opts: SendOptions,
+) -> (Delivery, bool) {
+    let deliver = async {
+        let outcome = if let Some(image) = &cmd.image_bytes {
+            send_to_chat_with_image_retry(bot, file_ids, chat, &cmd.text, Image::Bytes(image), opts).await
+        } else if let Some(img_path) = &cmd.image_path {
+            send_to_chat_with_image_retry(bot, file_ids, chat, &cmd.text, Image::File(img_path), opts).await
+        } else if let Some(doc_path) = document {
+            send_to_chat_with_document_retry(bot, chat, &cmd.text, doc_path, caption, opts).await
+        } else {
+            send_to_chat_with_retry(bot, chat, &cmd.text, opts).await
+        };
+        (outcome, DEGRADED.with(Cell::get))
+    };
+    DEGRADED.scope(Cell::new(false), deliver).await
+}
+
+/// The size of the file at `path` if it exceeds `max_bytes`. Files that
+/// cannot be read are left for the send path to report.
+fn oversized(path: &Path, max_bytes: u64) -> Option<u64> {
+    let size = fs::metadata(path).ok()?.len();
+    (size > max_bytes).then_some(size)
+}
+
+/// Run `upload` of `size` bytes while showing `action` in `chat`, refreshed
+/// until it finishes. Only files of at least `opts.chat_action_min_bytes`
+/// get the indicator, and its errors never reach the upload. The indicator
+/// runs in the same future as the upload, so it also stops when a timeout
+/// drops the upload.
+async fn with_chat_action<S: MessageSink, T>(
+    bot: &S,
+    chat: ChatId,
+    action: ChatAction,
+    size: u64,
+    opts: SendOptions,
+    upload: impl Future<Output = T>,
+) -> T {
+    let large = opts.chat_action_min_bytes.is_some_and(|min| size >= min);
+    if !large {
+        return upload.await;
+    }
+    let indicator = async {
+        loop {
+            if let Err(err) = bot.send_chat_action(chat, action).await {
+                debug!("Failed to show {:?} in {}: {:?}", action, chat, err);
+            }
+            time::sleep(CHAT_ACTION_REFRESH).await;
+        }
+    };
+    tokio::select! {
+        biased;
+        never = indicator => never,
+        result = upload => result,
+    }
+}
+
+/// Size of the file at `path`, 0 if it cannot be read
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+/// Write text to a timestamped .txt file in the system temp directory
+fn write_text_document(text: &str) -> std::io::Result<PathBuf> {
+    write_document(text, "txt")
+}
+
+/// Write text to a timestamped file with `extension` in the system temp directory
+fn write_document(text: &str, extension: &str) -> std::io::Result<PathBuf> {
+    write_bytes(text.as_bytes(), extension)
+}
+
+/// Write `bytes` to a timestamped file with `extension` in the system temp directory
+fn write_bytes(bytes: &[u8], extension: &str) -> std::io::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let file_name = format!(
+        "corky-{}-{}.{}",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        seq,
+        extension
+    );
+    let path = std::env::temp_dir().join(file_name);
+    fs::write(&path, bytes)?;
+    Ok(path)
+}
+
+/// Caption for a long-text document: the supplied summary, or the first line of the text
+fn document_caption(cmd: &ZmqMessage) -> String {
+    let caption = match &cmd.summary {
+        Some(summary) if !summary.trim().is_empty() => summary.trim(),
+        _ => cmd.text.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim(),
+    };
+    truncate_str(caption, TELEGRAM_MAX_CAPTION_CHARS).to_string()
+}
+
+/// Count a message accepted by Telegram
+fn record_delivered<S: MessageSink>(bot: &S, chat: ChatId, kind: history::Kind, text: &str) {
+    bot.stats().record_delivered();
+    bot.activity().record_delivered(chat.0, text);
+    if let Some(heartbeat) = bot.heartbeat() {
+        heartbeat.delivered(chat.0, kind.as_str());
+    }
+}
+
+/// Count a failed attempt, reporting flood waits to the breaker
+fn record_failure<S: MessageSink>(bot: &S, chat: ChatId, err: &S::Error, text: &str) -> ErrorCategory {
+    let category = err.category();
+    bot.stats().record_failure(category);
+    bot.activity().record_failure(chat.0, category, text);
+    if category == ErrorCategory::FloodWait {
+        bot.flood_breaker().record(chat.0, err.retry_after(), time::Instant::now().into_std());
+    }
+    category
+}
+
+/// Count an attempt that timed out
+fn record_timeout<S: MessageSink>(bot: &S, chat: ChatId, text: &str) {
+    bot.stats().record_failure(ErrorCategory::Timeout);
+    bot.activity().record_failure(chat.0, ErrorCategory::Timeout, text);
+}
+
+/// Milliseconds to wait before retrying after failed attempt `attempt`:
+/// the backoff, or longer if Telegram asked for it
+fn retry_delay_ms<E: SendError>(err: &E, opts: SendOptions, attempt: u8) -> u64 {
+    let backoff = opts.retry_delay_ms(attempt);
+    err.retry_after().map_or(backoff, |after| backoff.max(after.as_millis() as u64))
+}
+
+/// How a success log mentions the attempts it took, counted from 0
+fn attempts_used(attempt: u8) -> String {
+    match attempt {
+        0 => String::new(),
+        n => format!(" after {} attempts", n + 1),
+    }
+}
+
+/// Send a message with retry logic, splitting texts over Telegram's length limit.
+/// Fails if any chunk could not be delivered.
+pub async fn send_to_chat_with_retry<S: MessageSink>(bot: &S, chat: ChatId, text: &str, mut opts: SendOptions) -> Delivery {
+    let mut sent = Vec::new();
+    let mut failure = None;
+    let parse_mode = opts.html.then_some(ParseMode::Html);
+    let chunks = match split_formatted(text, parse_mode, TELEGRAM_MAX_MESSAGE_CHARS) {
+        Some(chunks) => chunks,
+        None => {
+            warn!("No safe place to split HTML message to {}; sending it as plain text", chat);
+            mark_degraded();
+            opts.html = false;
+            let plain = html::plain_text(text);
+            split_text(&plain, TELEGRAM_MAX_MESSAGE_CHARS).into_iter().map(|chunk| Cow::Owned(chunk.to_string())).collect()
+        }
+    };
+    for (index, chunk) in chunks.iter().enumerate() {
+        let send = send_chunk_with_retry(bot, chat, chunk, opts);
+        let result = match chunks.len() {
+            1 => send.await,
+            _ => trace::part(index + 1, send).await,
+        };
+        match result {
+            Ok(id) => sent.push(id),
+            Err(category) if !category.is_transient() => return Err(category),
+            Err(category) => failure = Some(category),
+        }
+    }
+    match failure {
+        Some(category) => Err(category),
+        None => Ok(sent),
+    }
+}
+
+/// Send a single message-sized chunk with retry logic for resilience
+async fn send_chunk_with_retry<S: MessageSink>(
+    bot: &S,
+    chat: ChatId,
+    text: &str,
+    opts: SendOptions,
+) -> Result<MessageId, ErrorCategory> {
+    let max_retries = opts.max_attempts.max(1);
+    let chat = ChatId(bot.migrations().resolve(chat.0));
+
+    let mut last_error = ErrorCategory::Timeout;
+
+    for attempt in 0..max_retries {
+        bot.flood_breaker().wait_for(chat.0).await;
+        match time::timeout(
+            time::Duration::from_secs(30),
+            bot.send_text(chat, text, opts),
+        ).await {
+            Ok(Ok(id)) => {
+                record_delivered(bot, chat, history::Kind::Text, text);
+                info!("Sent message to {}{}: \"{}\"", chat, attempts_used(attempt), preview(text, LOG_PREVIEW_CHARS));
+                return Ok(id);
+            }
+            Ok(Err(err)) => {
+                let category = record_failure(bot, chat, &err, text);
+                last_error = category;
+                if let Some(new_chat) = followed_migration(bot, chat, &err) {
+                    return Box::pin(send_chunk_with_retry(bot, new_chat, text, opts)).await;
+                } else if opts.html && err.is_bad_markup() {
+                    // Retrying the same markup cannot help; the words still can.
+                    // Messages carry no reply_markup, so plain text is the only
+                    // downgrade there is.
+                    warn!("Telegram rejected the HTML of a message to {} ({:?}); sending it as plain text", chat, err);
+                    mark_degraded();
+                    let plain = html::plain_text(text);
+                    return Box::pin(send_chunk_with_retry(bot, chat, &plain, SendOptions { html: false, ..opts })).await;
+                } else if !category.is_transient() {
+                    error!("Failed to send to {} ({}, not retrying): {:?}", chat, category, err);
+                    return Err(category);
+                } else if attempt < max_retries - 1 {
+                    let delay = retry_delay_ms(&err, opts, attempt);
+                    warn!("Failed to send to {} (attempt {}/{}): {:?}, retrying in {}ms",
+                          chat, attempt + 1, max_retries, err, delay);
+                    time::sleep(time::Duration::from_millis(delay)).await;
+                } else {
+                    error!("Failed to send to {} after {} attempts: {:?}", chat, max_retries, err);
+                }
+            }
+            Err(_elapsed) => {
+                record_timeout(bot, chat, text);
+                if attempt < max_retries - 1 {
+                    warn!("Timeout sending to {} (attempt {}/{}), retrying", chat, attempt + 1, max_retries);
+                } else {
+                    error!("Timeout sending to {} after {} attempts", chat, max_retries);
+                }
+                last_error = ErrorCategory::Timeout;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Send an image uploaded before by its cached file ID. `None` means it
+/// has to be uploaded; a rejected file ID is dropped from the cache.
+async fn send_cached_image<S: MessageSink>(
+    bot: &S,
+    file_ids: &FileIds,
+    key: file_ids::FileKey,
+    chat: ChatId,
+    text: &str,
+    opts: SendOptions,
+) -> Option<MessageId> {
+    let file_id = file_ids.get(key, time::Instant::now().into_std())?;
+    bot.flood_breaker().wait_for(chat.0).await;
+    match time::timeout(time::Duration::from_secs(30), bot.send_photo_by_id(chat, &file_id, text, opts)).await {
+        Ok(Ok(id)) => {
+            record_delivered(bot, chat, history::Kind::Photo, text);
+            bot.stats().record_file_id_hit(key.len);
+            info!("Sent image message to {}: \"{}\" by file ID", chat, preview(text, LOG_PREVIEW_CHARS));
+            Some(id)
+        }
+        Ok(Err(err)) => {
+            let category = record_failure(bot, chat, &err, text);
+            if category == ErrorCategory::BadRequest {
+                warn!("Telegram rejected the cached file ID for {} ({:?}); uploading again", chat, err);
+                file_ids.invalidate(key);
+            } else {
+                warn!("Failed to send image to {} by file ID ({}); uploading instead", chat, category);
+            }
+            None
+        }
+        Err(_elapsed) => {
+            record_timeout(bot, chat, text);
+            warn!("Timeout sending image to {} by file ID; uploading instead", chat);
+            None
+        }
+    }
+}
+
+/// An image to send: a file, or bytes received with the message
+#[derive(Debug, Clone, Copy)]
+pub enum Image<'a> {
+    File(&'a str),
+    Bytes(&'a ImageBytes),
+}
+
+impl std::fmt::Display for Image<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Image::File(path) => f.write_str(path),
+            Image::Bytes(image) => write!(f, "{}", image),
+        }
+    }
+}
+
+/// Send a message with an image with retry logic for resilience, reusing
+/// the file ID of an earlier upload of the same image when there is one
+pub async fn send_to_chat_with_image_retry<S: MessageSink>(
+    bot: &S,
+    file_ids: &FileIds,
+    chat: ChatId,
+    text: &str,
+    image: Image<'_>,
+    opts: SendOptions,
+) -> Delivery {
+    let max_retries = opts.max_attempts.max(1);
+
+    let chat = ChatId(bot.migrations().resolve(chat.0));
+    let (size, key) = match image {
+        Image::File(image_path) => {
+            let path = Path::new(image_path);
+            if !path.exists() {
+                error!("Image file not found: {}", image_path);
+                // Fall back to sending just the text
+                return send_to_chat_with_retry(bot, chat, text, opts).await;
+            }
+            let key = match file_ids.is_enabled() {
+                true => file_ids::key_for(path).ok(),
+                false => None,
+            };
+            (file_size(path), key)
+        }
+        Image::Bytes(image) => (image.len() as u64, file_ids.is_enabled().then(|| file_ids::key_for_bytes(&image.0))),
+    };
+    if let Some(key) = key {
+        if let Some(id) = send_cached_image(bot, file_ids, key, chat, text, opts).await {
+            return Ok(vec![id]);
+        }
+    }
+
+    for attempt in 0..max_retries {
+        bot.flood_breaker().wait_for(chat.0).await;
+        match time::timeout(
+            time::Duration::from_secs(60),
+            with_chat_action(bot, chat, ChatAction::UploadPhoto, size, opts, async {
+                match image {
+                    Image::File(image_path) => bot.send_photo(chat, Path::new(image_path), text, opts).await,
+                    Image::Bytes(image) => bot.send_photo_bytes(chat, image, text, opts).await,
+                }
+            }),
+        ).await {
+            Ok(Ok(sent)) => {
+                record_delivered(bot, chat, history::Kind::Photo, text);
+                info!("Sent image message to {}{}: \"{}\" with image {}",
+                      chat,
+                      attempts_used(attempt),
+                      preview(text, LOG_PREVIEW_CHARS),
+                      image);
+                if let (Some(key), Some(file_id)) = (key, sent.file_id) {
+                    file_ids.insert(key, file_id, time::Instant::now().into_std());
+                }
+                return Ok(vec![sent.id]);
+            }
+            Ok(Err(err)) => {
+                let category = record_failure(bot, chat, &err, text);
+                if let Some(new_chat) = followed_migration(bot, chat, &err) {
+                    return Box::pin(send_to_chat_with_image_retry(bot, file_ids, new_chat, text, image, opts)).await;
+                } else if !category.is_transient() {
+                    error!("Failed to send image to {} ({}, not retrying): {:?}", chat, category, err);
+                    warn!("Falling back to text-only message");
+                    return send_to_chat_with_retry(bot, chat, &format!("{} (Image attachment failed: {})", text, image), opts).await;
+                } else if attempt < max_retries - 1 {
+                    let delay = retry_delay_ms(&err, opts, attempt);
+                    warn!("Failed to send image to {} (attempt {}/{}): {:?}, retrying in {}ms",
+                          chat, attempt + 1, max_retries, err, delay);
+                    time::sleep(time::Duration::from_millis(delay)).await;
+                } else {
+                    error!("Failed to send image to {} after {} attempts: {:?}", chat, max_retries, err);
+                    warn!("Falling back to text-only message");
+                    return send_to_chat_with_retry(bot, chat, &format!("{} (Image attachment failed: {})", text, image), opts).await;
+                }
+            }
+            Err(_elapsed) => {
+                record_timeout(bot, chat, text);
+                if attempt < max_retries - 1 {
+                    warn!("Timeout sending image to {} (attempt {}/{}), retrying", chat, attempt + 1, max_retries);
+                } else {
+                    error!("Timeout sending image to {} after {} attempts", chat, max_retries);
+                    warn!("Falling back to text-only message");
+                    return send_to_chat_with_retry(bot, chat, &format!("{} (Image attachment failed: {})", text, image), opts).await;
+                }
+            }
+        }
+    }
+    Err(ErrorCategory::Timeout)
+}
+
+/// Send a text file as a document with retry logic, falling back to chunked text
+pub async fn send_to_chat_with_document_retry<S: MessageSink>(
+    bot: &S,
+    chat: ChatId,
+    text: &str,
+    doc_path: &Path,
+    caption: &str,
+    opts: SendOptions,
+) -> Delivery {
+    let max_retries = opts.max_attempts.max(1);
+    let chat = ChatId(bot.migrations().resolve(chat.0));
+
+    for attempt in 0..max_retries {
+        bot.flood_breaker().wait_for(chat.0).await;
+        match time::timeout(
+            time::Duration::from_secs(60),
+            with_chat_action(bot, chat, ChatAction::UploadDocument, file_size(doc_path), opts, bot.send_document(chat, doc_path, caption, opts)),
+        ).await {
+            Ok(Ok(id)) => {
+                record_delivered(bot, chat, history::Kind::Document, caption);
+                info!("Sent document message to {}{}: \"{}\" ({} chars)",
+                      chat,
+                      attempts_used(attempt),
+                      preview(caption, LOG_PREVIEW_CHARS),
+                      text.chars().count());
+                return Ok(vec![id]);
+            }
+            Ok(Err(err)) => {
+                let category = record_failure(bot, chat, &err, caption);
+                if let Some(new_chat) = followed_migration(bot, chat, &err) {
+                    return Box::pin(send_to_chat_with_document_retry(bot, new_chat, text, doc_path, caption, opts)).await;
+                } else if !category.is_transient() {
+                    error!("Failed to send document to {} ({}, not retrying): {:?}", chat, category, err);
+                    break;
+                } else if attempt < max_retries - 1 {
+                    let delay = retry_delay_ms(&err, opts, attempt);
+                    warn!("Failed to send document to {} (attempt {}/{}): {:?}, retrying in {}ms",
+                          chat, attempt + 1, max_retries, err, delay);
+                    time::sleep(time::Duration::from_millis(delay)).await;
+                } else {
+                    error!("Failed to send document to {} after {} attempts: {:?}", chat, max_retries, err);
+                }
+            }
+            Err(_elapsed) => {
+                record_timeout(bot, chat, caption);
+                if attempt < max_retries - 1 {
+                    warn!("Timeout sending document to {} (attempt {}/{}), retrying", chat, attempt + 1, max_retries);
+                } else {
+                    error!("Timeout sending document to {} after {} attempts", chat, max_retries);
+                }
+            }
+        }
+    }
+
+    warn!("Falling back to chunked text message");
+    send_to_chat_with_retry(bot, chat, text, opts).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Activity;
+    use crate::sink::SentPhoto;
+    use crate::stats::Stats;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn recipients_are_summarised_for_the_log() {
+        let cmd = |chat_ids: Vec<i64>, list: Option<&str>| ZmqMessage {
+            chat_ids,
+            subscriber_list: list.map(str::to_string),
+            ..ZmqMessage::default()
+        };
+        assert_eq!(describe_recipients(&cmd(vec![], None)), "no target");
+        assert_eq!(describe_recipients(&cmd(vec![5, 5], Some("ops"))), "chat 5, list 'ops'");
+        assert_eq!(describe_recipients(&cmd(vec![1, 2, 3], None)), "chats 1, 2, 3");
+        assert_eq!(describe_recipients(&cmd((1..=40).collect(), None)), "40 chats");
+    }
+
+    #[test]
+    fn truncate_ascii() {
+        assert_eq!(truncate_str("hello world", 5), "hello");
+    }
+
+    #[test]
+    fn truncate_short_string() {
+        assert_eq!(truncate_str("hi", 10), "hi");
+    }
+
+    #[test]
+    fn truncate_empty() {
+        assert_eq!(truncate_str("", 5), "");
+    }
+
+    #[test]
+    fn truncate_emoji() {
+        // Each emoji is one char but multiple bytes
+        let s = "\u{1F600}\u{1F601}\u{1F602}\u{1F603}\u{1F604}"; // 5 emojis
+        let result = truncate_str(s, 3);
+        assert_eq!(result, "\u{1F600}\u{1F601}\u{1F602}");
+    }
+
+    #[test]
+    fn truncate_mixed_utf8() {
+        let s = "aBC\u{00E9}\u{00E8}fg"; // a B C é è f g
+        let result = truncate_str(s, 4);
+        assert_eq!(result, "aBC\u{00E9}");
+    }
+
+    #[test]
+    fn truncate_exact_boundary() {
+        assert_eq!(truncate_str("abcde", 5), "abcde");
+    }
+
+    #[test]
+    fn truncate_zero() {
+        assert_eq!(truncate_str("hello", 0), "");
+    }
+
+    #[test]
+    fn truncate_cjk() {
+        let s = "\u{4F60}\u{597D}\u{4E16}\u{754C}"; // 你好世界
+        let result = truncate_str(s, 2);
+        assert_eq!(result, "\u{4F60}\u{597D}");
+    }
+
+    #[test]
+    fn preview_keeps_short_text_whole() {
+        assert_eq!(preview("disk full", 30), "disk full");
+        assert_eq!(preview("abcde", 5), "abcde");
+        assert_eq!(preview("", 5), "");
+    }
+
+    #[test]
+    fn preview_cuts_emoji_at_the_boundary() {
+        // Byte 30 would land inside the emoji
+        let text = format!("{}😀 tail", "x".repeat(29));
+        assert_eq!(preview(&text, 30), format!("{}😀…", "x".repeat(29)));
+        assert_eq!(preview(&text, 29), format!("{}…", "x".repeat(29)));
+        // A family emoji is several code points joined into one character
+        assert_eq!(preview("ab👨\u{200D}👩\u{200D}👧cd", 3), "ab👨\u{200D}👩\u{200D}👧…");
+    }
+
+    #[test]
+    fn preview_keeps_combining_characters_together() {
+        let text = "cafe\u{0301}s"; // e + combining acute accent
+        assert_eq!(preview(text, 4), "cafe\u{0301}…");
+        assert_eq!(preview(text, 5), text);
+    }
+
+    #[test]
+    fn preview_cjk_at_the_boundary() {
+        assert_eq!(preview("你好世界", 2), "你好…");
+        assert_eq!(preview("你好世界", 4), "你好世界");
+    }
+
+    #[test]
+    fn preview_stays_on_one_line() {
+        assert_eq!(preview("line one\nline two\r\nthree", 100), "line one line two three");
+        assert_eq!(preview("a\nb", 0), "…");
+    }
+
+    #[test]
+    fn split_short_text_is_single_chunk() {
+        assert_eq!(split_text("hello", 10), vec!["hello"]);
+        assert_eq!(split_text("", 10), vec![""]);
+    }
+
+    #[test]
+    fn split_prefers_newlines() {
+        assert_eq!(split_text("abc\ndef\nghi", 9), vec!["abc\ndef\n", "ghi"]);
+    }
+
+    #[test]
+    fn split_hard_cuts_long_lines() {
+        assert_eq!(split_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn split_never_breaks_multibyte_chars() {
+        let s = "\u{1F600}\u{1F601}\u{1F602}\u{1F603}\u{1F604}";
+        let chunks = split_text(s, 2);
+        assert_eq!(chunks, vec!["\u{1F600}\u{1F601}", "\u{1F602}\u{1F603}", "\u{1F604}"]);
+    }
+
+    fn zmq_message(text: &str, summary: Option<&str>) -> ZmqMessage {
+        ZmqMessage {
+            chat_ids: Vec::new(),
+            chat_id: None,
+            chat: None,
+            subscriber_list: None,
+            text: text.to_string(),
+            image_path: None,
+            image_frame: false,
+            image_bytes: None,
+            summary: summary.map(str::to_string),
+            ttl: None,
+            priority: Default::default(),
+            id: None,
+            protect_content: None,
+            spoiler: None,
+            disable_link_preview: None,
+            parse_mode: None,
+            delete_after_send: None,
+            max_retries: None,
+            retry_base_delay_ms: None,
+            peer: None,
+            tags: None,
+            trace_id: None,
+        }
+    }
+
+    #[test]
+    fn caption_uses_summary_when_given() {
+        let cmd = zmq_message("line one\nline two", Some("Nightly log dump"));
+        assert_eq!(document_caption(&cmd), "Nightly log dump");
+    }
+
+    #[test]
+    fn caption_falls_back_to_first_line() {
+        let cmd = zmq_message("\n  line one  \nline two", None);
+        assert_eq!(document_caption(&cmd), "line one");
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Kind {
+        Text,
+        Photo,
+        PhotoById,
+        Document,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Call {
+        kind: Kind,
+        chat: i64,
+        text: String,
+        silent: bool,
+        protected: bool,
+        spoiler: bool,
+        no_preview: bool,
+        html: bool,
+        at: time::Instant,
+    }
+
+    /// Records every send and fails the next N sends per chat on request
+    #[derive(Clone, Default)]
+    struct MockSink {
+        calls: Arc<Mutex<Vec<Call>>>,
+        failures: Arc<Mutex<HashMap<i64, (u32, ErrorCategory)>>>,
+        delays: Arc<Mutex<HashMap<i64, time::Duration>>>,
+        upload_delay: Arc<Mutex<Option<time::Duration>>>,
+        actions: Arc<Mutex<Vec<(i64, ChatAction, time::Instant)>>>,
+        fail_actions: Arc<Mutex<bool>>,
+        migrated: Arc<Mutex<HashMap<i64, i64>>>,
+        usernames: Arc<Mutex<HashMap<String, i64>>>,
+        lookups: Arc<Mutex<Vec<String>>>,
+        reject_file_ids: Arc<Mutex<bool>>,
+        reject_html: Arc<Mutex<bool>>,
+        /// `retry_after` of flood waits, by chat
+        flood_waits: Arc<Mutex<HashMap<i64, std::time::Duration>>>,
+        /// Each mock is its own bot account
+        breaker: Arc<flood::FloodBreaker>,
+        migrations: Arc<Migrations>,
+        stats: Arc<Stats>,
+        activity: Arc<Activity>,
+    }
+
+    #[derive(Debug)]
+    struct MockError {
+        category: ErrorCategory,
+        migrated_to: Option<i64>,
+        bad_markup: bool,
+        retry_after: Option<std::time::Duration>,
+    }
+
+    impl SendError for MockError {
+        fn category(&self) -> ErrorCategory {
+            self.category
+        }
+
+        fn migrated_to(&self) -> Option<i64> {
+            self.migrated_to
+        }
+
+        fn is_bad_markup(&self) -> bool {
+            self.bad_markup
+        }
+
+        fn retry_after(&self) -> Option<std::time::Duration> {
+            self.retry_after
+        }
+    }
+
+    impl MockSink {
+        fn fail_next(&self, chat: i64, times: u32) {
+            self.fail_next_with(chat, times, ErrorCategory::Network);
+        }
+
+        fn fail_next_with(&self, chat: i64, times: u32, category: ErrorCategory) {
+            self.failures.lock().unwrap().insert(chat, (times, category));
+        }
+
+        /// Fail the next send to `chat` with a flood wait of `retry_after`
+        fn flood_wait(&self, chat: i64, retry_after: time::Duration) {
+            self.flood_waits.lock().unwrap().insert(chat, retry_after);
+            self.fail_next_with(chat, 1, ErrorCategory::FloodWait);
+        }
+
+        fn delay(&self, chat: i64, delay: time::Duration) {
+            self.delays.lock().unwrap().insert(chat, delay);
+        }
+
+        fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// Fail every send to `old` as migrated to `new`
+        fn migrate(&self, old: i64, new: i64) {
+            self.migrated.lock().unwrap().insert(old, new);
+        }
+
+        fn record(&self, kind: Kind, chat: ChatId, text: &str, opts: SendOptions) -> Result<MessageId, MockError> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(Call {
+                kind,
+                chat: chat.0,
+                text: text.to_string(),
+                silent: opts.disable_notification,
+                protected: opts.protect_content,
+                spoiler: opts.spoiler,
+                no_preview: opts.disable_link_preview,
+                html: opts.html,
+                at: time::Instant::now(),
+            });
+            let id = MessageId(calls.len() as i32);
+            drop(calls);
+            if opts.html && *self.reject_html.lock().unwrap() {
+                return Err(MockError { category: ErrorCategory::BadRequest, migrated_to: None, bad_markup: true, retry_after: None });
+            }
+            if let Some(&new) = self.migrated.lock().unwrap().get(&chat.0) {
+                return Err(MockError { category: ErrorCategory::ChatMigrated, migrated_to: Some(new), bad_markup: false, retry_after: None });
+            }
+            let mut failures = self.failures.lock().unwrap();
+            match failures.get_mut(&chat.0) {
+                Some((n, category)) if *n > 0 => {
+                    *n -= 1;
+                    let retry_after = self.flood_waits.lock().unwrap().get(&chat.0).copied();
+                    Err(MockError { category: *category, migrated_to: None, bad_markup: false, retry_after })
+                }
+                _ => Ok(id),
+            }
+        }
+    }
+
+    impl MessageSink for MockSink {
+        type Error = MockError;
+
+        async fn send_text(&self, chat: ChatId, text: &str, opts: SendOptions) -> Result<MessageId, MockError> {
+            let result = self.record(Kind::Text, chat, text, opts);
+            let delay = self.delays.lock().unwrap().get(&chat.0).copied();
+            if let Some(delay) = delay {
+                time::sleep(delay).await;
+            }
+            result
+        }
+
+        async fn send_photo(&self, chat: ChatId, _path: &Path, caption: &str, opts: SendOptions) -> Result<SentPhoto, MockError> {
+            let delay = *self.upload_delay.lock().unwrap();
+            if let Some(delay) = delay {
+                time::sleep(delay).await;
+            }
+            let id = self.record(Kind::Photo, chat, caption, opts)?;
+            Ok(SentPhoto { id, file_id: Some(format!("file-{}", id.0)) })
+        }
+
+        async fn send_photo_bytes(&self, chat: ChatId, _image: &ImageBytes, caption: &str, opts: SendOptions) -> Result<SentPhoto, MockError> {
+            let id = self.record(Kind::Photo, chat, caption, opts)?;
+            Ok(SentPhoto { id, file_id: Some(format!("file-{}", id.0)) })
+        }
+
+        async fn send_photo_by_id(&self, chat: ChatId, _file_id: &str, caption: &str, opts: SendOptions) -> Result<MessageId, MockError> {
+            let id = self.record(Kind::PhotoById, chat, caption, opts)?;
+            match *self.reject_file_ids.lock().unwrap() {
+                true => Err(MockError { category: ErrorCategory::BadRequest, migrated_to: None, bad_markup: false, retry_after: None }),
+                false => Ok(id),
+            }
+        }
+
+        async fn send_document(&self, chat: ChatId, _path: &Path, caption: &str, opts: SendOptions) -> Result<MessageId, MockError> {
+            self.record(Kind::Document, chat, caption, opts)
+        }
+
+        async fn resolve_username(&self, username: &str) -> Result<ChatId, MockError> {
+            self.lookups.lock().unwrap().push(username.to_string());
+            match self.usernames.lock().unwrap().get(username) {
+                Some(&id) => Ok(ChatId(id)),
+                None => Err(MockError { category: ErrorCategory::ChatNotFound, migrated_to: None, bad_markup: false, retry_after: None }),
+            }
+        }
+
+        async fn send_chat_action(&self, chat: ChatId, action: ChatAction) -> Result<(), MockError> {
+            self.actions.lock().unwrap().push((chat.0, action, time::Instant::now()));
+            match *self.fail_actions.lock().unwrap() {
+                true => Err(MockError { category: ErrorCategory::Network, migrated_to: None, bad_markup: false, retry_after: None }),
+                false => Ok(()),
+            }
+        }
+
+        fn flood_breaker(&self) -> &flood::FloodBreaker {
+            &self.breaker
+        }
+
+        fn migrations(&self) -> &Migrations {
+            &self.migrations
+        }
+
+        fn stats(&self) -> &Stats {
+            &self.stats
+        }
+
+        fn activity(&self) -> &Activity {
+            &self.activity
+        }
+    }
+
+    fn state() -> Arc<BotState> {
+        Arc::new(BotState::in_memory(&settings()))
+    }
+
+    fn settings() -> TelegramSettings {
+        toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 99\nlong_text_as_file_over = 50\n\
+             broadcast_concurrency = 2\nquarantine_after = 2\n\
+             [telegram.subscriber_lists]\nteam = [1, 2, 3]\nbig = [1, 2, 3, 4]\n",
+        )
+        .unwrap()
+        .telegram
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_stop_after_success() {
+        let sink = MockSink::default();
+        sink.fail_next(1, 1);
+        let _ = send_to_chat_with_retry(&sink, ChatId(1), "hello", SendOptions::default()).await;
+        assert_eq!(sink.calls().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_between_attempts() {
+        let sink = MockSink::default();
+        sink.fail_next(1, 3);
+        let _ = send_to_chat_with_retry(&sink, ChatId(1), "hello", SendOptions::default()).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1].at - calls[0].at, time::Duration::from_millis(500));
+        assert_eq!(calls[2].at - calls[1].at, time::Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_image_falls_back_to_text() {
+        let sink = MockSink::default();
+        let _ = send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File("/nonexistent/image.png"), SendOptions::default()).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].kind, Kind::Text);
+        assert_eq!(calls[0].text, "caption");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_uploads_refresh_the_chat_action() {
+        let sink = MockSink::default();
+        *sink.upload_delay.lock().unwrap() = Some(time::Duration::from_secs(10));
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let opts = SendOptions { chat_action_min_bytes: Some(1), ..SendOptions::default() };
+        assert!(send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), opts).await.is_ok());
+        let actions = sink.actions.lock().unwrap().clone();
+        // At 0s, 4s and 8s, then none once the upload is done
+        assert_eq!(actions.len(), 3);
+        assert!(actions.iter().all(|(chat, action, _)| *chat == 1 && *action == ChatAction::UploadPhoto));
+        assert_eq!(actions[1].2 - actions[0].2, CHAT_ACTION_REFRESH);
+        time::sleep(time::Duration::from_secs(20)).await;
+        assert_eq!(sink.actions.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_timed_out_upload_stops_the_chat_action() {
+        let sink = MockSink::default();
+        let opts = SendOptions { chat_action_min_bytes: Some(1), ..SendOptions::default() };
+        let upload = with_chat_action(&sink, ChatId(1), ChatAction::UploadPhoto, 10, opts, std::future::pending::<()>());
+        assert!(time::timeout(time::Duration::from_secs(5), upload).await.is_err());
+        assert_eq!(sink.actions.lock().unwrap().len(), 2);
+        time::sleep(time::Duration::from_secs(60)).await;
+        assert_eq!(sink.actions.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_actions_are_opt_in_and_sized() {
+        let sink = MockSink::default();
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let _ = send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), SendOptions::default()).await;
+        let opts = SendOptions { chat_action_min_bytes: Some(u64::MAX), ..SendOptions::default() };
+        let _ = send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), opts).await;
+        assert!(sink.actions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_chat_action_does_not_affect_the_send() {
+        let sink = MockSink::default();
+        *sink.fail_actions.lock().unwrap() = true;
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let opts = SendOptions { chat_action_min_bytes: Some(1), ..SendOptions::default() };
+        assert!(send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), opts).await.is_ok());
+        assert_eq!(sink.calls().len(), 1);
+        assert_eq!(sink.calls()[0].kind, Kind::Photo);
+    }
+
+    /// A temp image with `contents`, removed on drop
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("corky-{}-{}.png", name, std::process::id()));
+            fs::write(&path, contents).unwrap();
+            TempImage(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_uploads_an_image_once() {
+        let image = TempImage::new("broadcast", b"broadcast image");
+        let sink = MockSink::default();
+        let state = state();
+        let mut cmd = zmq_message("look", None);
+        cmd.subscriber_list = Some("big".to_string());
+        cmd.image_path = Some(image.path().to_string());
+        process_zmq_message(&sink, &settings(), &state, cmd.clone()).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::PhotoById, Kind::PhotoById, Kind::PhotoById]);
+
+        // A later message with the same image skips the upload too
+        let sink = MockSink::default();
+        cmd.subscriber_list = None;
+        cmd.chat_id = Some(7);
+        process_zmq_message(&sink, &settings(), &state, cmd).await;
+        assert_eq!(sink.calls()[0].kind, Kind::PhotoById);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn image_is_deleted_after_the_last_chat() {
+        let image = TempImage::new("delete-all", b"spooled");
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("shot", None);
+        cmd.subscriber_list = Some("team".to_string());
+        cmd.image_path = Some(image.path().to_string());
+        cmd.delete_after_send = Some(true);
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        assert_eq!(sink.calls().len(), 3);
+        assert!(!image.0.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsupported_images_are_converted_or_sent_as_documents() {
+        let mut settings = settings();
+        settings.convert_unsupported_images = true;
+        // Both are named .png; their bytes say otherwise
+        let tiff = TempImage::new("convert-tiff", include_bytes!("../tests/fixtures/thermal-16bit.tiff"));
+        let corrupt = TempImage::new("convert-corrupt", include_bytes!("../tests/fixtures/corrupt.png"));
+        let sink = MockSink::default();
+        for image in [&tiff, &corrupt] {
+            let mut cmd = zmq_message("thermal", None);
+            cmd.chat_id = Some(5);
+            cmd.image_path = Some(image.path().to_string());
+            process_zmq_message(&sink, &settings, &state(), cmd).await;
+        }
+        let mut cmd = zmq_message("thermal", None);
+        cmd.chat_id = Some(5);
+        cmd.image_bytes = Some(ImageBytes(Arc::new(include_bytes!("../tests/fixtures/thermal.webp").to_vec())));
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::Document, Kind::Photo]);
+        // Only the converted copy is removed
+        assert!(tiff.0.exists() && corrupt.0.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn image_is_kept_when_a_chat_failed_or_deletion_is_off() {
+        let image = TempImage::new("delete-partial", b"spooled");
+        let sink = MockSink::default();
+        // By file ID, then the upload, then the text fallback
+        sink.fail_next_with(2, 3, ErrorCategory::Blocked);
+        let mut settings = settings();
+        settings.delete_after_send = true;
+        let mut cmd = zmq_message("shot", None);
+        cmd.subscriber_list = Some("team".to_string());
+        cmd.image_path = Some(image.path().to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd.clone()).await;
+        assert!(image.0.exists());
+
+        // The message's own flag wins over the configured default
+        cmd.subscriber_list = None;
+        cmd.chat_id = Some(5);
+        cmd.delete_after_send = Some(false);
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        assert!(image.0.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn images_outside_media_dir_are_refused() {
+        let media = std::env::temp_dir().join(format!("corky-sender-media-{}", std::process::id()));
+        fs::create_dir_all(&media).unwrap();
+        let inside = TempImage::new("media-inside", b"inside");
+        fs::rename(&inside.0, media.join("inside.png")).unwrap();
+        let outside = TempImage::new("media-outside", b"outside");
+        let mut settings = settings();
+        settings.media_dir = Some(media.clone());
+        settings.long_text_as_file_over = 1000;
+
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("chart", None);
+        cmd.chat_id = Some(5);
+        cmd.image_path = Some("inside.png".to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd.clone()).await;
+        assert_eq!(sink.calls()[0].kind, Kind::Photo);
+
+        let sink = MockSink::default();
+        cmd.image_path = Some(outside.path().to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd.clone()).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].chat == 99 && calls[0].text.starts_with("Security warning"));
+        assert_eq!((calls[1].kind, calls[1].chat), (Kind::Text, 5));
+        assert!(calls[1].text.contains("(Image attachment refused:"));
+
+        settings.media_dir_warn_owners = false;
+        let sink = MockSink::default();
+        cmd.image_path = Some("../".to_string() + outside.0.file_name().unwrap().to_str().unwrap());
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        assert_eq!(sink.calls().len(), 1);
+        assert_eq!(sink.calls()[0].kind, Kind::Text);
+        let _ = fs::remove_dir_all(&media);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_markup_is_sent_as_plain_text() {
+        let sink = MockSink::default();
+        *sink.reject_html.lock().unwrap() = true;
+        let opts = SendOptions { html: true, ..SendOptions::default() };
+        let sent = send_to_chat_with_retry(&sink, ChatId(1), "<b>disk</b> &amp; <i>cpu</i>", opts).await;
+        assert!(sent.is_ok());
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].html);
+        assert!(!calls[1].html);
+        assert_eq!(calls[1].text, "disk & cpu");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn downgraded_deliveries_are_reported_degraded() {
+        let sink = MockSink::default();
+        let file_ids = FileIds::new(0, time::Duration::ZERO);
+        let cmd = zmq_message("<b>disk</b> full", None);
+        let opts = SendOptions { html: true, ..SendOptions::default() };
+        let (sent, degraded) = deliver_to_chat(&sink, &file_ids, ChatId(1), &cmd, None, "", opts).await;
+        assert!(sent.is_ok() && !degraded);
+
+        *sink.reject_html.lock().unwrap() = true;
+        let (sent, degraded) = deliver_to_chat(&sink, &file_ids, ChatId(1), &cmd, None, "", opts).await;
+        assert!(sent.is_ok() && degraded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_bad_requests_are_not_downgraded() {
+        let sink = MockSink::default();
+        sink.fail_next_with(1, 1, ErrorCategory::BadRequest);
+        let opts = SendOptions { html: true, ..SendOptions::default() };
+        assert_eq!(send_to_chat_with_retry(&sink, ChatId(1), "<b>x</b>", opts).await, Err(ErrorCategory::BadRequest));
+        assert_eq!(sink.calls().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn outcomes_are_returned_per_chat() {
+        let sink = MockSink::default();
+        sink.fail_next_with(2, 1, ErrorCategory::Blocked);
+        let mut cmd = zmq_message("hello", None);
+        cmd.subscriber_list = Some("team".to_string());
+        let mut outcomes = process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        outcomes.sort_by_key(|(chat, _)| *chat);
+        let failed: Vec<bool> = outcomes.iter().map(|(_, outcome)| outcome.is_err()).collect();
+        assert_eq!(outcomes.iter().map(|(chat, _)| *chat).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(failed, vec![false, true, false]);
+
+        // Nothing was attempted for an unknown list
+        cmd.subscriber_list = Some("nobody".to_string());
+        assert!(process_zmq_message(&sink, &settings(), &state(), cmd).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_do_not_reorder_messages_to_a_chat() {
+        let sink = MockSink::default();
+        sink.fail_next(5, 2);
+        let state = state();
+        for (text, chat) in [("part 1", 5), ("part 2", 5), ("elsewhere", 6)] {
+            let mut cmd = zmq_message(text, None);
+            cmd.chat_id = Some(chat);
+            state.outbox.push(cmd);
+        }
+        state.outbox.close();
+        let worker = || run_outbox_worker(sink.clone(), settings().into(), state.clone(), Serve::All);
+        tokio::join!(worker(), worker(), worker());
+
+        let calls = sink.calls();
+        let to_five: Vec<&str> = calls.iter().filter(|c| c.chat == 5).map(|c| c.text.as_str()).collect();
+        assert_eq!(to_five, vec!["part 1", "part 1", "part 1", "part 2"]);
+        // The other chat did not wait for the retries
+        assert_eq!(calls[1].chat, 6);
+        assert!(state.chat_order.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duplicates_are_suppressed_and_summarised() {
+        let mut settings = settings();
+        settings.suppress_duplicates_secs = 60;
+        let state = Arc::new(BotState::in_memory(&settings));
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("disk full", None);
+        cmd.chat_id = Some(5);
+        for _ in 0..3 {
+            process_zmq_message(&sink, &settings, &state, cmd.clone()).await;
+        }
+        // A broadcast still reaches the chats that have not seen it
+        cmd.chat_id = None;
+        cmd.subscriber_list = Some("team".to_string());
+        process_zmq_message(&sink, &settings, &state, cmd.clone()).await;
+        let chats: Vec<i64> = sink.calls().iter().map(|c| c.chat).collect();
+        assert_eq!(chats, vec![5, 1, 2, 3]);
+
+        time::advance(time::Duration::from_secs(60)).await;
+        let sink = MockSink::default();
+        send_duplicate_summaries(&sink, &settings, &state).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].chat, 5);
+        assert_eq!(calls[0].text, "Previous message repeated 2 time(s): \"disk full\"");
+
+        // Without summaries the window closes quietly
+        settings.duplicate_summaries = false;
+        let sink = MockSink::default();
+        cmd.subscriber_list = None;
+        cmd.chat_id = Some(5);
+        process_zmq_message(&sink, &settings, &state, cmd.clone()).await;
+        process_zmq_message(&sink, &settings, &state, cmd).await;
+        time::advance(time::Duration::from_secs(60)).await;
+        send_duplicate_summaries(&sink, &settings, &state).await;
+        assert_eq!(sink.calls().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn image_bytes_are_uploaded_once_per_broadcast() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("chart", None);
+        cmd.subscriber_list = Some("team".to_string());
+        cmd.image_frame = true;
+        cmd.image_bytes = Some(ImageBytes(Arc::new(b"frame image".to_vec())));
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::PhotoById, Kind::PhotoById]);
+        assert!(sink.calls().iter().all(|c| c.text == "chart"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_file_id_is_dropped_and_uploaded_again() {
+        let image = TempImage::new("rejected", b"rejected image");
+        let sink = MockSink::default();
+        let file_ids = FileIds::new(8, std::time::Duration::from_secs(60));
+        let _ = send_to_chat_with_image_retry(&sink, &file_ids, ChatId(1), "a", Image::File(image.path()), SendOptions::default()).await;
+        *sink.reject_file_ids.lock().unwrap() = true;
+        assert!(send_to_chat_with_image_retry(&sink, &file_ids, ChatId(1), "b", Image::File(image.path()), SendOptions::default()).await.is_ok());
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::PhotoById, Kind::Photo]);
+        // The fresh upload's file ID replaced the rejected one
+        let key = file_ids::key_for(&image.0).unwrap();
+        assert_eq!(file_ids.get(key, time::Instant::now().into_std()).as_deref(), Some("file-3"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_image_upload_falls_back_to_text() {
+        let sink = MockSink::default();
+        sink.fail_next(1, 3);
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let _ = send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), SendOptions::default()).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::Photo, Kind::Photo, Kind::Text]);
+        assert!(sink.calls()[3].text.contains("(Image attachment failed:"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn content_flags_reach_every_send() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.subscriber_lists.get_mut("team").unwrap().protect_content = true;
+        let mut cmd = zmq_message("preview", None);
+        cmd.subscriber_list = Some("team".to_string());
+        cmd.image_path = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string());
+        cmd.spoiler = Some(true);
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls.iter().all(|c| matches!(c.kind, Kind::Photo | Kind::PhotoById) && c.protected && c.spoiler));
+
+        let mut direct = zmq_message(&"x".repeat(60), None);
+        direct.chat_id = Some(5);
+        process_zmq_message(&sink, &settings, &state(), direct).await;
+        let last = sink.calls().pop().unwrap();
+        assert_eq!(last.kind, Kind::Document);
+        assert!(!last.protected && !last.spoiler);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_chunk_skips_link_previews() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.long_text_as_file_over = 100_000;
+        let mut cmd = zmq_message(&"https://example.com ".repeat(300), None);
+        cmd.chat_id = Some(5);
+        cmd.disable_link_preview = Some(true);
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|c| c.kind == Kind::Text && c.no_preview));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn html_is_sanitized_before_sending() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("<b>cpu <div>high", None);
+        cmd.chat_id = Some(5);
+        cmd.parse_mode = Some(ParseMode::Html);
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls[0].text, "<b>cpu high</b>");
+        assert!(calls[0].html);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn valid_html_is_sent_as_written() {
+        let sink = MockSink::default();
+        let texts = ["<B>cpu</B>", "<a href='https://x.io'>x</a>", "<a href=https://x.io>x</a>", "<b >cpu</b >"];
+        for text in texts {
+            let mut cmd = zmq_message(text, None);
+            cmd.chat_id = Some(5);
+            cmd.parse_mode = Some(ParseMode::Html);
+            process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        }
+        let sent: Vec<_> = sink.calls().into_iter().map(|c| c.text).collect();
+        assert_eq!(sent, texts);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_html_mode_rejects_invalid_markup() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.html_mode = HtmlMode::Strict;
+        let mut cmd = zmq_message("<b>cpu", None);
+        cmd.chat_id = Some(5);
+        cmd.parse_mode = Some(ParseMode::Html);
+        process_zmq_message(&sink, &settings, &state(), cmd.clone()).await;
+        assert!(sink.calls().is_empty());
+        cmd.text = "<b>cpu</b>".to_string();
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        assert_eq!(sink.calls().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn split_html_parts_are_each_balanced() {
+        let sink = MockSink::default();
+        let text = format!("<b>{}</b>", "x".repeat(5000));
+        let _ = send_to_chat_with_retry(&sink, ChatId(1), &text, SendOptions { html: true, ..SendOptions::default() }).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|c| html::validate(&c.text).is_ok()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsplittable_html_is_sent_as_plain_text() {
+        let sink = MockSink::default();
+        let text = format!("<a href=\"https://x.io/{}\">{}</a>", "p".repeat(5000), "x".repeat(5000));
+        let _ = send_to_chat_with_retry(&sink, ChatId(1), &text, SendOptions { html: true, ..SendOptions::default() }).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|c| !c.html && c.text.chars().all(|ch| ch == 'x')));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_image_is_sent_as_text() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.max_photo_bytes = 10;
+        settings.long_text_as_file_over = 8000;
+        let mut cmd = zmq_message("graph", None);
+        cmd.chat_id = Some(5);
+        cmd.image_path = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].kind, Kind::Text);
+        assert!(calls[0].text.starts_with("graph (Image attachment too large: "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_document_is_split_instead() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.max_document_bytes = 10;
+        let mut cmd = zmq_message(&"x".repeat(60), None);
+        cmd.chat_id = Some(5);
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Text]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_list_fans_out_to_every_member() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("hi team", None);
+        cmd.subscriber_list = Some("team".to_string());
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_subscriber_list_warns_owner() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("nobody".to_string());
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].chat, 99);
+        assert!(calls[0].text.contains("unknown subscriber list 'nobody'"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_text_is_sent_as_document() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message(&"x".repeat(60), Some("dump"));
+        cmd.chat_id = Some(5);
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].kind, Kind::Document);
+        assert_eq!(calls[0].text, "dump");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_document_falls_back_to_text() {
+        let sink = MockSink::default();
+        sink.fail_next(5, 3);
+        let mut cmd = zmq_message(&"x".repeat(60), None);
+        cmd.chat_id = Some(5);
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Document, Kind::Document, Kind::Document, Kind::Text]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_recipient_does_not_delay_others() {
+        let sink = MockSink::default();
+        sink.delay(1, time::Duration::from_secs(30));
+        let start = time::Instant::now();
+        let mut cmd = zmq_message("hi team", None);
+        cmd.subscriber_list = Some("team".to_string());
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        for call in sink.calls().iter().filter(|c| c.chat != 1) {
+            assert!(call.at - start < time::Duration::from_secs(1), "chat {} waited", call.chat);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcast_concurrency_is_bounded() {
+        let sink = MockSink::default();
+        for chat in 1..=4 {
+            sink.delay(chat, time::Duration::from_secs(10));
+        }
+        let start = time::Instant::now();
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("big".to_string());
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let mut offsets: Vec<_> = sink.calls().iter().map(|c| (c.at - start).as_secs()).collect();
+        offsets.sort();
+        assert_eq!(offsets, vec![0, 0, 10, 10]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_error_is_not_retried() {
+        let sink = MockSink::default();
+        sink.fail_next_with(1, 3, ErrorCategory::Blocked);
+        assert_eq!(send_to_chat_with_retry(&sink, ChatId(1), "hello", SendOptions::default()).await, Err(ErrorCategory::Blocked));
+        assert_eq!(sink.calls().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_image_error_falls_back_immediately() {
+        let sink = MockSink::default();
+        sink.fail_next_with(1, 1, ErrorCategory::BadRequest);
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        assert!(send_to_chat_with_image_retry(&sink, &FileIds::disabled(), ChatId(1), "caption", Image::File(image), SendOptions::default()).await.is_ok());
+        let kinds: Vec<_> = sink.calls().iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![Kind::Photo, Kind::Text]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blocked_chat_is_quarantined_and_owner_notified_once() {
+        let sink = MockSink::default();
+        sink.fail_next_with(2, 10, ErrorCategory::Blocked);
+        let settings = settings();
+        let state = state();
+        for _ in 0..3 {
+            let mut cmd = zmq_message("hi team", None);
+            cmd.subscriber_list = Some("team".to_string());
+            process_zmq_message(&sink, &settings, &state, cmd).await;
+        }
+        assert!(state.quarantine.is_quarantined(2));
+        let calls = sink.calls();
+        // Two failed attempts before quarantine, then chat 2 is skipped
+        assert_eq!(calls.iter().filter(|c| c.chat == 2).count(), 2);
+        let notices: Vec<_> = calls.iter().filter(|c| c.chat == 99).collect();
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].text.contains("Chat 2 has been quarantined"));
+        assert!(notices[0].text.contains("Lists: big, team"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn control_action_releases_quarantine() {
+        let state = state();
+        state.quarantine.record(2, Err(ErrorCategory::Blocked));
+        state.quarantine.record(2, Err(ErrorCategory::Blocked));
+        assert!(state.quarantine.is_quarantined(2));
+        let shared = SharedSettings::new(settings());
+        let ack = process_control(&shared, &state, ControlAction::Unquarantine { chat_id: 2 });
+        assert!(!state.quarantine.is_quarantined(2));
+        assert!(ack.ok);
+        assert!(!process_control(&shared, &state, ControlAction::Unquarantine { chat_id: 2 }).ok);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn muted_chat_is_skipped_for_broadcasts_only() {
+        let sink = MockSink::default();
+        let settings = settings();
+        let state = state();
+        state.mutes.mute(2, None);
+        let mut cmd = zmq_message("hi team", None);
+        cmd.subscriber_list = Some("team".to_string());
+        process_zmq_message(&sink, &settings, &state, cmd).await;
+        let mut direct = zmq_message("direct", None);
+        direct.chat_id = Some(2);
+        process_zmq_message(&sink, &settings, &state, direct).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.text.clone())).collect();
+        chats.sort();
+        assert_eq!(
+            chats,
+            vec![(1, "hi team".to_string()), (2, "direct".to_string()), (3, "hi team".to_string())]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mutes_can_apply_to_direct_sends() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.mutes_apply_to_direct = true;
+        let state = state();
+        state.mutes.mute(2, None);
+        let mut direct = zmq_message("direct", None);
+        direct.chat_id = Some(2);
+        process_zmq_message(&sink, &settings, &state, direct).await;
+        assert!(sink.calls().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_ids_fan_out_once_per_chat() {
+        let sink = MockSink::default();
+        let settings = settings();
+        let state = state();
+        state.quarantine.record(7, Err(ErrorCategory::Blocked));
+        state.quarantine.record(7, Err(ErrorCategory::Blocked));
+        let mut cmd = zmq_message("deploy done", None);
+        cmd.chat_ids = vec![4, 5, 4, 7, 6];
+        process_zmq_message(&sink, &settings, &state, cmd).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![4, 5, 6]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn list_and_chat_id_are_combined() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("disk full", None);
+        cmd.subscriber_list = Some("team".to_string());
+        cmd.chat_id = Some(2);
+        cmd.chat_ids = vec![7, 3];
+        process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![1, 2, 3, 7]);
+
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.combine_targets = false;
+        cmd.chat_ids.clear();
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        assert_eq!(chats, vec![2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn owner_is_fallback_only_without_targets() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("nobody".to_string());
+        cmd.chat_id = Some(5);
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let calls: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.text.clone())).collect();
+        assert_eq!(
+            calls,
+            vec![(99, "Warning: unknown subscriber list 'nobody'".to_string()), (5, "hi".to_string())]
+        );
+
+        let sink = MockSink::default();
+        process_zmq_message(&sink, &settings(), &state(), zmq_message("hi", None)).await;
+        assert_eq!(sink.calls().iter().map(|c| c.chat).collect::<Vec<_>>(), vec![99]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn migrated_group_is_followed_and_remembered() {
+        let sink = MockSink::default();
+        sink.migrate(-4001, -1004001);
+        let mut cmd = zmq_message("hi", None);
+        cmd.chat_id = Some(-4001);
+        process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        let calls: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.text.clone())).collect();
+        assert_eq!(
+            calls,
+            vec![
+                (-4001, "hi".to_string()),
+                (-1004001, "hi".to_string()),
+                (99, migrations::notice(-4001, -1004001)),
+            ]
+        );
+
+        // Later messages go to the new ID straight away and the owner is not told again
+        let sink = MockSink { migrations: sink.migrations.clone(), ..MockSink::default() };
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        assert_eq!(sink.calls().iter().map(|c| c.chat).collect::<Vec<_>>(), vec![-1004001]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_lists_use_the_migrated_id() {
+        let sink = MockSink::default();
+        sink.migrate(-4002, -1004002);
+        assert!(send_to_chat_with_retry(&sink, ChatId(-4002), "hello", SendOptions { max_attempts: 1, ..SendOptions::default() }).await.is_ok());
+
+        let sink = MockSink { migrations: sink.migrations.clone(), ..MockSink::default() };
+        let mut settings = settings();
+        settings.subscriber_lists = toml::from_str("moved = [-4002, 5, -1004002]").unwrap();
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("moved".to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![-1004002, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn usernames_are_resolved_once_and_cached() {
+        let sink = MockSink::default();
+        sink.usernames.lock().unwrap().insert("@news_channel".to_string(), -100777);
+        let mut settings = settings();
+        settings.username_cache_secs = 60;
+        let state = Arc::new(BotState::in_memory(&settings));
+        let mut cmd = zmq_message("hi", None);
+        cmd.chat = Some(ChatRef::Username("@news_channel".to_string()));
+        process_zmq_message(&sink, &settings, &state, cmd.clone()).await;
+        process_zmq_message(&sink, &settings, &state, cmd.clone()).await;
+        assert_eq!(sink.calls().iter().map(|c| c.chat).collect::<Vec<_>>(), vec![-100777, -100777]);
+        assert_eq!(sink.lookups.lock().unwrap().len(), 1);
+
+        // Looked up again once the cache entry has expired
+        time::advance(time::Duration::from_secs(61)).await;
+        process_zmq_message(&sink, &settings, &state, cmd).await;
+        assert_eq!(sink.lookups.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unresolvable_username_warns_owner_without_fallback() {
+        let sink = MockSink::default();
+        let mut cmd = zmq_message("hi", None);
+        cmd.chat = Some(ChatRef::Username("@gone_channel".to_string()));
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        let calls: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.text.clone())).collect();
+        assert_eq!(calls, vec![(99, "Warning: could not resolve chat @gone_channel (chat-not-found)".to_string())]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_lists_may_name_usernames() {
+        let sink = MockSink::default();
+        sink.usernames.lock().unwrap().insert("@news_channel".to_string(), -100777);
+        let mut settings = settings();
+        settings.subscriber_lists = toml::from_str(r#"news = ["@news_channel", 5, "@gone_channel"]"#).unwrap();
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("news".to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let mut chats: Vec<_> = sink.calls().iter().filter(|c| c.text == "hi").map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![-100777, 5]);
+        assert!(sink.calls().iter().any(|c| c.chat == 99 && c.text.contains("@gone_channel")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_owner_gets_fallbacks_and_warnings() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.owner_chat_ids = vec![99, 98];
+        process_zmq_message(&sink, &settings, &state(), zmq_message("hi", None)).await;
+        let mut cmd = zmq_message("hi", None);
+        cmd.subscriber_list = Some("nobody".to_string());
+        process_zmq_message(&sink, &settings, &state(), cmd).await;
+        let calls: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.text.clone())).collect();
+        let warning = "Warning: unknown subscriber list 'nobody'".to_string();
+        assert_eq!(
+            calls,
+            vec![(99, "hi".to_string()), (98, "hi".to_string()), (99, warning.clone()), (98, warning)]
+        );
+    }
+
+    fn quiet_settings(mode: &str) -> TelegramSettings {
+        toml::from_str::<crate::config::AppConfig>(&format!(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 99\n\
+             [telegram.subscriber_lists]\nops = [1]\n\
+             [telegram.subscriber_lists.family]\nchats = [2, 3]\n\
+             quiet_hours = {{ start = \"23:00\", end = \"07:00\", tz = \"UTC\", mode = \"{}\" }}\n",
+            mode
+        ))
+        .unwrap()
+        .telegram
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn broadcast(list: &str, ttl: Option<u64>) -> ZmqMessage {
+        let mut cmd = zmq_message("night news", None);
+        cmd.subscriber_list = Some(list.to_string());
+        cmd.ttl = ttl;
+        cmd
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_hours_send_silently() {
+        let sink = MockSink::default();
+        let settings = quiet_settings("silent");
+        let state = state();
+        let night = utc("2026-01-10T23:30:00Z");
+        process_zmq_message_at(&sink, &settings, &state, broadcast("family", None), night).await;
+        process_zmq_message_at(&sink, &settings, &state, broadcast("ops", None), night).await;
+        process_zmq_message_at(&sink, &settings, &state, broadcast("family", None), utc("2026-01-10T12:00:00Z")).await;
+        let mut calls: Vec<_> = sink.calls().iter().map(|c| (c.chat, c.silent)).collect();
+        calls.sort();
+        assert_eq!(calls, vec![(1, false), (2, false), (2, true), (3, false), (3, true)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_hours_defer_until_window_ends() {
+        let sink = MockSink::default();
+        let settings = quiet_settings("defer");
+        let state = state();
+        process_zmq_message_at(&sink, &settings, &state, broadcast("family", None), utc("2026-01-10T23:30:00Z")).await;
+        assert!(sink.calls().is_empty());
+        release_deferred(&sink, &settings, &state, utc("2026-01-11T06:59:00Z")).await;
+        assert!(sink.calls().is_empty());
+        release_deferred(&sink, &settings, &state, utc("2026-01-11T07:00:30Z")).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|c| !c.silent && c.text == "night news"));
+        assert!(state.deferred.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deferred_message_past_ttl_is_dropped() {
+        let sink = MockSink::default();
+        let settings = quiet_settings("defer");
+        let state = state();
+        process_zmq_message_at(&sink, &settings, &state, broadcast("family", Some(3600)), utc("2026-01-10T23:30:00Z")).await;
+        release_deferred(&sink, &settings, &state, utc("2026-01-11T07:00:00Z")).await;
+        assert!(sink.calls().is_empty());
+        assert!(state.deferred.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn high_priority_gets_larger_retry_budget() {
+        let sink = MockSink::default();
+        sink.fail_next(1, 10);
+        let mut cmd = zmq_message("alert", None);
+        cmd.chat_id = Some(1);
+        process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        assert_eq!(sink.calls().len(), 3);
+        cmd.priority = crate::zmq_listener::Priority::High;
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        assert_eq!(sink.calls().len(), 3 + 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_token_spools_until_accepted() {
+        let sink = MockSink::default();
+        let state = state();
+        let message = |text| ZmqMessage { chat_id: Some(1), ..zmq_message(text, None) };
+        sink.fail_next_with(1, 1, ErrorCategory::Unauthorized);
+        let outcomes = process_zmq_message(&sink, &settings(), &state, message("first")).await;
+        assert_eq!(outcomes, vec![(1, Err(ErrorCategory::Unauthorized))]);
+        assert!(process_zmq_message(&sink, &settings(), &state, message("second")).await.is_empty());
+        assert_eq!((sink.calls().len(), state.spool.len()), (1, 2));
+
+        // Other failures say nothing about the token
+        token_checked(&sink, &settings(), &state, Err(ErrorCategory::Network)).await;
+        assert_eq!(sink.calls().len(), 1);
+        token_checked(&sink, &settings(), &state, Ok(())).await;
+        let texts: Vec<String> = sink.calls().into_iter().skip(1).map(|call| call.text).collect();
+        assert_eq!(texts, ["first", "second"]);
+        assert!(!state.spool.is_active());
+        assert_eq!(process_zmq_message(&sink, &settings(), &state, message("third")).await.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_stops_when_the_token_is_refused_again() {
+        let sink = MockSink::default();
+        let state = state();
+        let message = |text| ZmqMessage { chat_id: Some(1), ..zmq_message(text, None) };
+        token_checked(&sink, &settings(), &state, Err(ErrorCategory::Unauthorized)).await;
+        process_zmq_message(&sink, &settings(), &state, message("first")).await;
+        process_zmq_message(&sink, &settings(), &state, message("second")).await;
+        assert!(sink.calls().is_empty());
+
+        sink.fail_next_with(1, 1, ErrorCategory::Unauthorized);
+        token_checked(&sink, &settings(), &state, Ok(())).await;
+        assert_eq!(sink.calls().len(), 1);
+        assert!(state.spool.is_active());
+        assert_eq!(state.spool.take_next().unwrap().message.text, "first");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_lists_warn_the_owner_until_traffic_resumes() {
+        let sink = MockSink::default();
+        let mut settings = settings();
+        settings.subscriber_lists.get_mut("team").unwrap().expect_message_every = Some(chrono::Duration::hours(2));
+        let state = Arc::new(BotState::in_memory(&settings));
+        state.traffic.record(Some("team"), None, Utc::now() - chrono::Duration::hours(3));
+        check_traffic(&sink, &settings, &state).await;
+        check_traffic(&sink, &settings, &state).await;
+        let owner_texts = || sink.calls().iter().filter(|c| c.chat == 99).map(|c| c.text.clone()).collect::<Vec<_>>();
+        assert_eq!(owner_texts(), vec!["Warning: no traffic for list 'team' in 2h.".to_string()]);
+
+        let cmd = ZmqMessage { subscriber_list: Some("team".to_string()), ..zmq_message("back", None) };
+        process_zmq_message(&sink, &settings, &state, cmd).await;
+        check_traffic(&sink, &settings, &state).await;
+        assert!(owner_texts()[1].starts_with("Traffic for list 'team' resumed after 3h"));
+        // Only a list broadcast counts for the list; a direct send counts for its chat
+        process_zmq_message(&sink, &settings, &state, ZmqMessage { chat_id: Some(1), ..zmq_message("hi", None) }).await;
+        assert_eq!(state.traffic.chats().iter().map(|(chat, _)| *chat).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn broadcasts_leave_no_journal_entry_once_finished() {
+        let sink = MockSink::default();
+        let state = state();
+        sink.fail_next(2, 3);
+        let cmd = ZmqMessage { subscriber_list: Some("team".to_string()), ..zmq_message("hi", None) };
+        process_zmq_message(&sink, &settings(), &state, cmd).await;
+        assert!(state.broadcasts.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interrupted_broadcast_resumes_with_the_remaining_chats() {
+        let sink = MockSink::default();
+        let state = state();
+        // A run that crashed after chats 1 and 3 got the message
+        let cmd = ZmqMessage { subscriber_list: Some("big".to_string()), ..zmq_message("hi", None) };
+        let id = state.broadcasts.begin(1, "'big'", &cmd, SendOptions::default(), &[1, 2, 3, 4], Utc::now());
+        state.broadcasts.done(id, 1);
+        state.broadcasts.done(id, 3);
+
+        resume_broadcasts(&sink, &settings(), &state, Utc::now()).await;
+        let mut chats: Vec<_> = sink.calls().iter().map(|c| c.chat).collect();
+        chats.sort();
+        assert_eq!(chats, vec![2, 4]);
+        assert!(state.broadcasts.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_interrupted_broadcast_is_abandoned() {
+        let sink = MockSink::default();
+        let state = state();
+        let cmd = ZmqMessage { subscriber_list: Some("big".to_string()), ttl: Some(60), ..zmq_message("hi", None) };
+        let started = Utc::now() - chrono::Duration::seconds(61);
+        state.broadcasts.begin(1, "'big'", &cmd, SendOptions::default(), &[1, 2], started);
+
+        resume_broadcasts(&sink, &settings(), &state, Utc::now()).await;
+        let calls = sink.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].chat, 99);
+        assert!(calls[0].text.starts_with("Abandoned broadcast to 'big' of \"hi\""));
+        assert!(state.broadcasts.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_flood_breaker_holds_every_send() {
+        let sink = MockSink::default();
+        sink.breaker.configure(2, time::Duration::from_secs(30));
+        sink.fail_next_with(1, 1, ErrorCategory::FloodWait);
+        sink.fail_next_with(2, 1, ErrorCategory::FloodWait);
+        let (settings, state) = (settings(), state());
+        let message = |chat, text| ZmqMessage { chat_id: Some(chat), ..zmq_message(text, None) };
+        let spawn = |cmd: ZmqMessage| {
+            let (sink, settings, state) = (sink.clone(), settings.clone(), state.clone());
+            tokio::spawn(async move { process_zmq_message(&sink, &settings, &state, cmd).await })
+        };
+        // The second flood wait, 1s in, opens the breaker for 5s
+        let first = spawn(message(1, "first"));
+        time::sleep(time::Duration::from_secs(1)).await;
+        let second = spawn(message(2, "second"));
+        time::sleep(time::Duration::from_millis(4900)).await;
+        assert_eq!(sink.calls().len(), 2);
+        assert_eq!(first.await.unwrap()[0].1.as_ref().map(Vec::len), Ok(1));
+        assert!(second.await.unwrap()[0].1.is_ok());
+        assert_eq!(sink.calls().len(), 4);
+
+        announce_flood_changes(&sink, &settings).await;
+        let notices: Vec<String> = sink.calls().into_iter().skip(4).map(|call| call.text).collect();
+        assert!(notices[0].starts_with("Telegram flood limit hit 2 times; all sends paused for 5s."));
+        assert_eq!(notices[1], "Sending resumed after a 5s pause for Telegram's flood limit.");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flood_wait_parks_only_its_chat() {
+        let sink = MockSink::default();
+        sink.flood_wait(1, time::Duration::from_secs(30));
+        let state = state();
+        let workers: Vec<_> = (0..2)
+            .map(|_| tokio::spawn(run_outbox_worker(sink.clone(), settings().into(), state.clone(), Serve::All)))
+            .collect();
+        let message = |chat, text: &str| ZmqMessage { chat_id: Some(chat), ..zmq_message(text, None) };
+        state.outbox.push(message(1, "a1"));
+        time::sleep(time::Duration::from_millis(10)).await;
+        for text in ["a2", "a3"] {
+            state.outbox.push(message(1, text));
+        }
+        for text in ["b1", "b2", "b3"] {
+            state.outbox.push(message(2, text));
+        }
+
+        time::sleep(time::Duration::from_millis(10)).await;
+        let texts = |chat| sink.calls().into_iter().filter(|call| call.chat == chat).map(|call| call.text).collect::<Vec<_>>();
+        assert_eq!(texts(2), ["b1", "b2", "b3"]);
+        assert_eq!(texts(1), ["a1"]);
+        assert_eq!(state.outbox.held(), 2);
+        assert_eq!(sink.breaker.parked(time::Instant::now().into_std()).len(), 1);
+
+        time::sleep(time::Duration::from_secs(30)).await;
+        assert_eq!(texts(1), ["a1", "a1", "a2", "a3"]);
+        state.outbox.close();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_outbox_resumes_with_high_priority() {
+        let sink = MockSink::default();
+        sink.breaker.configure(1, time::Duration::from_secs(30));
+        sink.breaker.record(1, Some(time::Duration::from_secs(10)), time::Instant::now().into_std());
+        let state = state();
+        let worker = tokio::spawn(run_outbox_worker(sink.clone(), settings().into(), state.clone(), Serve::All));
+        state.outbox.push(ZmqMessage { chat_id: Some(1), ..zmq_message("normal", None) });
+        state.outbox.push(ZmqMessage { chat_id: Some(1), ttl: Some(5), ..zmq_message("stale", None) });
+        let mut alert = ZmqMessage { chat_id: Some(1), ..zmq_message("alert", None) };
+        alert.priority = crate::zmq_listener::Priority::High;
+        state.outbox.push(alert);
+
+        time::sleep(time::Duration::from_secs(9)).await;
+        assert!(sink.calls().is_empty());
+        time::sleep(time::Duration::from_secs(2)).await;
+        state.outbox.close();
+        worker.await.unwrap();
+        let texts: Vec<String> = sink.calls().into_iter().map(|call| call.text).collect();
+        assert_eq!(texts, ["alert", "normal"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn payload_overrides_the_retry_policy() {
+        let sink = MockSink::default();
+        sink.fail_next(1, 20);
+        let mut cmd = zmq_message("metrics tick", None);
+        cmd.chat_id = Some(1);
+        cmd.max_retries = Some(0);
+        process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        assert_eq!(sink.calls().len(), 1);
+
+        cmd.max_retries = Some(2);
+        cmd.retry_base_delay_ms = Some(100);
+        let started = time::Instant::now();
+        process_zmq_message(&sink, &settings(), &state(), cmd.clone()).await;
+        assert_eq!(sink.calls().len(), 1 + 3);
+        assert_eq!(started.elapsed(), time::Duration::from_millis(100 + 200));
+
+        // Clamped to 10 retries, each wait at most a minute
+        cmd.max_retries = Some(50);
+        cmd.retry_base_delay_ms = Some(3_600_000);
+        let started = time::Instant::now();
+        process_zmq_message(&sink, &settings(), &state(), cmd).await;
+        assert_eq!(sink.calls().len(), 4 + 11);
+        assert_eq!(started.elapsed(), time::Duration::from_secs(10 * 60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sent_messages_are_remembered_for_replies() {
+        let sink = MockSink::default();
+        let state = state();
+        let mut cmd = zmq_message("disk full", None);
+        cmd.id = Some("alert-42".to_string());
+        process_zmq_message(&sink, &settings(), &state, cmd).await;
+        // Sent to the owner as the first message the sink saw
+        let correlation = state.sent.get(99, 1).unwrap();
+        assert_eq!(correlation.id.as_deref(), Some("alert-42"));
+        assert!(state.sent.get(99, 2).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_without_a_trace_id_get_one() {
+        let sink = MockSink::default();
+        let state = state();
+        process_zmq_message(&sink, &settings(), &state, zmq_message("disk full", None)).await;
+        let mut cmd = zmq_message("disk fuller", None);
+        cmd.trace_id = Some("job-7".to_string());
+        process_zmq_message(&sink, &settings(), &state, cmd).await;
+        let generated = state.sent.get(99, 1).unwrap().trace_id.unwrap();
+        assert_eq!(generated.len(), 36);
+        assert_eq!(state.sent.get(99, 2).unwrap().trace_id.as_deref(), Some("job-7"));
+    }
+}
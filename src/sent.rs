@@ -0,0 +1,97 @@
+//! Bounded memory of recently sent messages, so replies to them can be
+//! traced back to the ZMQ message that caused them.
+
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Sent messages remembered before the oldest are forgotten
+pub const SENT_CACHE_CAPACITY: usize = 4096;
+
+/// Where a sent Telegram message came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correlation {
+    /// The producer's `id`, if the ZMQ message carried one
+    pub id: Option<String>,
+    pub subscriber_list: Option<String>,
+    pub sent_at: DateTime<Utc>,
+    /// The ZMQ message's `trace_id`
+    pub trace_id: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<(i64, i32), Correlation>,
+    order: VecDeque<(i64, i32)>,
+}
+
+/// Telegram (chat, message ID) to correlation data, oldest evicted first
+pub struct SentMessages {
+    inner: Mutex<Inner>,
+    capacity: usize,
+}
+
+impl Default for SentMessages {
+    fn default() -> Self {
+        Self::new(SENT_CACHE_CAPACITY)
+    }
+}
+
+impl SentMessages {
+    pub fn new(capacity: usize) -> Self {
+        SentMessages { inner: Mutex::new(Inner::default()), capacity: capacity.max(1) }
+    }
+
+    /// Remember that `message_id` in `chat` was sent for `correlation`
+    pub fn record(&self, chat: i64, message_id: i32, correlation: Correlation) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.insert((chat, message_id), correlation).is_none() {
+            inner.order.push_back((chat, message_id));
+        }
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, chat: i64, message_id: i32) -> Option<Correlation> {
+        self.inner.lock().unwrap().entries.get(&(chat, message_id)).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn correlation(id: &str) -> Correlation {
+        Correlation { id: Some(id.to_string()), subscriber_list: None, sent_at: Utc::now(), trace_id: None }
+    }
+
+    #[test]
+    fn lookups_are_per_chat() {
+        let sent = SentMessages::new(8);
+        sent.record(1, 10, correlation("a"));
+        assert_eq!(sent.get(1, 10).unwrap().id.as_deref(), Some("a"));
+        assert!(sent.get(2, 10).is_none());
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted() {
+        let sent = SentMessages::new(2);
+        sent.record(1, 1, correlation("a"));
+        sent.record(1, 2, correlation("b"));
+        sent.record(1, 3, correlation("c"));
+        assert_eq!(sent.len(), 2);
+        assert!(sent.get(1, 1).is_none());
+        assert!(sent.get(1, 3).is_some());
+    }
+}
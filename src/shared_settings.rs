@@ -0,0 +1,401 @@
+//! The settings every task reads, behind one handle that can swap them.
+//!
+//! Readers take a `snapshot`, the settings as they are at that moment, and
+//! use it for a whole unit of work such as one message or one command, so
+//! a change never shows up halfway through one. Changes go through
+//! `update` or `set_list_chats`, one at a time; the changed settings are
+//! validated before they replace the current ones. Subscriber lists changed
+//! at runtime, by `set_list_chats` or by the owners' `/listadd` and
+//! `/listdel` and the matching ZMQ actions, are persisted and applied over
+//! the config file on the next start, including lists created at runtime.
+//! A list emptied at runtime is kept. Mutes, quarantine and the rest of the
+//! runtime state stay in `BotState`, which keeps its own files.
+
+use crate::config::{ChatRef, SubscriberList, TelegramSettings};
+use crate::data_dir::DataDir;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Longest name of a list created at runtime
+const MAX_LIST_NAME_CHARS: usize = 64;
+
+/// On-disk representation: the chats of each list changed at runtime
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct ListsFile {
+    lists: BTreeMap<String, Vec<ChatRef>>,
+    /// Lists created at runtime rather than in the config file
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    created: BTreeSet<String>,
+}
+
+/// What adding or removing a chat did to a subscriber list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListChange {
+    /// The list did not exist and was created with the chat
+    Created,
+    Added { chats: usize },
+    AlreadyListed,
+    /// Removed, leaving `chats` in the list, which is kept even when empty
+    Removed { chats: usize },
+    NotListed,
+}
+
+impl ListChange {
+    /// Whether the list was changed
+    pub fn changed(self) -> bool {
+        !matches!(self, ListChange::AlreadyListed | ListChange::NotListed)
+    }
+
+    /// One line for the reply or acknowledgement
+    pub fn describe(self, list: &str, chat_id: i64) -> String {
+        match self {
+            ListChange::Created => format!("Created subscriber list '{}' with chat {}", list, chat_id),
+            ListChange::Added { chats } => format!("Added chat {} to list '{}' ({} chats)", chat_id, list, chats),
+            ListChange::AlreadyListed => format!("Chat {} is already on list '{}'", chat_id, list),
+            ListChange::Removed { chats } => format!("Removed chat {} from list '{}' ({} chats left)", chat_id, list, chats),
+            ListChange::NotListed => format!("Chat {} is not on list '{}'", chat_id, list),
+        }
+    }
+}
+
+/// Why a subscriber list was not changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListError {
+    /// There is no such list and creating it was not asked for
+    UnknownList,
+    /// The change would leave the settings invalid
+    Invalid(Vec<String>),
+}
+
+impl ListError {
+    /// One line for the reply or acknowledgement
+    pub fn describe(&self, list: &str) -> String {
+        match self {
+            ListError::UnknownList => format!("Unknown subscriber list '{}'", list),
+            ListError::Invalid(problems) => format!("List '{}' not changed: {}", list, problems.join("; ")),
+        }
+    }
+}
+
+struct Inner {
+    current: RwLock<Arc<TelegramSettings>>,
+    /// Held while a change is made, so changes apply one after another
+    overrides: Mutex<ListsFile>,
+    path: Option<PathBuf>,
+}
+
+/// Handle to the current settings; clones share them
+#[derive(Clone)]
+pub struct SharedSettings {
+    inner: Arc<Inner>,
+}
+
+impl From<TelegramSettings> for SharedSettings {
+    fn from(settings: TelegramSettings) -> Self {
+        SharedSettings::new(settings)
+    }
+}
+
+impl SharedSettings {
+    /// `settings`, with changes kept only in memory
+    pub fn new(settings: TelegramSettings) -> Self {
+        SharedSettings::with(settings, ListsFile::default(), None)
+    }
+
+    /// `settings` with the list changes persisted in the data directory,
+    /// falling back to memory when they cannot be kept there
+    pub fn load(settings: TelegramSettings) -> Self {
+        match DataDir::for_settings(&settings).file("lists.json", "Subscriber list changes") {
+            Some(path) => SharedSettings::load_from(settings, path),
+            None => SharedSettings::new(settings),
+        }
+    }
+
+    /// `settings` with the list changes persisted at `path` applied over them
+    pub fn load_from(mut settings: TelegramSettings, path: PathBuf) -> Self {
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<ListsFile>(&contents).unwrap_or_else(|err| {
+                error!("Ignoring unreadable lists file {}: {}", path.display(), err);
+                ListsFile::default()
+            }),
+            Err(_) => ListsFile::default(),
+        };
+        for (name, chats) in &file.lists {
+            match settings.subscriber_lists.get_mut(name) {
+                Some(list) => {
+                    info!("Using the {} chat(s) of list '{}' changed at runtime", chats.len(), name);
+                    list.chats = chats.clone();
+                }
+                None if file.created.contains(name) => {
+                    info!("Using list '{}' created at runtime, with {} chat(s)", name, chats.len());
+                    let list = SubscriberList { chats: chats.clone(), ..Default::default() };
+                    settings.subscriber_lists.insert(name.clone(), list);
+                }
+                None => warn!("Ignoring runtime changes to list '{}', which is no longer configured", name),
+            }
+        }
+        SharedSettings::with(settings, file, Some(path))
+    }
+
+    fn with(settings: TelegramSettings, file: ListsFile, path: Option<PathBuf>) -> Self {
+        let inner = Inner { current: RwLock::new(Arc::new(settings)), overrides: Mutex::new(file), path };
+        SharedSettings { inner: Arc::new(inner) }
+    }
+
+    /// The current settings; later changes do not affect the snapshot
+    pub fn snapshot(&self) -> Arc<TelegramSettings> {
+        self.inner.current.read().unwrap().clone()
+    }
+
+    /// Change the settings with `change` for this run, if the result is
+    /// valid. Returns the new settings, or the problems with them.
+    pub fn update(&self, change: impl FnOnce(&mut TelegramSettings)) -> Result<Arc<TelegramSettings>, Vec<String>> {
+        let _writer = self.inner.overrides.lock().unwrap();
+        self.replace(change)
+    }
+
+    /// Make `chats` the chats of subscriber list `list` and persist that
+    pub fn set_list_chats(&self, list: &str, chats: Vec<ChatRef>) -> Result<Arc<TelegramSettings>, Vec<String>> {
+        let mut overrides = self.inner.overrides.lock().unwrap();
+        if !self.snapshot().subscriber_lists.contains_key(list) {
+            return Err(vec![format!("Unknown subscriber list '{}'", list)]);
+        }
+        let updated = self.replace(|settings| {
+            if let Some(configured) = settings.subscriber_lists.get_mut(list) {
+                configured.chats = chats.clone();
+            }
+        })?;
+        overrides.lists.insert(list.to_string(), chats);
+        self.save(&overrides);
+        Ok(updated)
+    }
+
+    /// Add `chat_id` to subscriber list `list` and persist that. A list
+    /// that does not exist is created only when `create` is set.
+    pub fn add_list_chat(&self, list: &str, chat_id: i64, create: bool) -> Result<ListChange, ListError> {
+        self.change_list(list, create, |chats| {
+            if chats.contains(&ChatRef::Id(chat_id)) {
+                return ListChange::AlreadyListed;
+            }
+            chats.push(ChatRef::Id(chat_id));
+            ListChange::Added { chats: chats.len() }
+        })
+    }
+
+    /// Remove `chat_id` from subscriber list `list` and persist that. The
+    /// list is kept when its last chat is removed.
+    pub fn remove_list_chat(&self, list: &str, chat_id: i64) -> Result<ListChange, ListError> {
+        self.change_list(list, false, |chats| {
+            let before = chats.len();
+            chats.retain(|chat| *chat != ChatRef::Id(chat_id));
+            match chats.len() {
+                after if after == before => ListChange::NotListed,
+                after => ListChange::Removed { chats: after },
+            }
+        })
+    }
+
+    /// Apply `change` to the chats of `list`, creating it first if it is
+    /// missing and `create` is set, then swap in and persist the result
+    fn change_list(
+        &self,
+        list: &str,
+        create: bool,
+        change: impl FnOnce(&mut Vec<ChatRef>) -> ListChange,
+    ) -> Result<ListChange, ListError> {
+        let mut overrides = self.inner.overrides.lock().unwrap();
+        let current = self.snapshot();
+        let existed = current.subscriber_lists.contains_key(list);
+        let mut chats = match current.subscriber_lists.get(list) {
+            Some(configured) => configured.chats.clone(),
+            None if create => {
+                let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+                if list.is_empty() || list.chars().count() > MAX_LIST_NAME_CHARS || !list.chars().all(valid) {
+                    return Err(ListError::Invalid(vec![format!(
+                        "a new list name must be 1-{} characters of A-Z, a-z, 0-9, _ and -",
+                        MAX_LIST_NAME_CHARS
+                    )]));
+                }
+                Vec::new()
+            }
+            None => return Err(ListError::UnknownList),
+        };
+        let outcome = match change(&mut chats) {
+            outcome if !outcome.changed() => return Ok(outcome),
+            ListChange::Added { .. } if !existed => ListChange::Created,
+            outcome => outcome,
+        };
+        self.replace(|settings| {
+            settings.subscriber_lists.entry(list.to_string()).or_default().chats = chats.clone();
+        })
+        .map_err(ListError::Invalid)?;
+        overrides.lists.insert(list.to_string(), chats);
+        if !existed {
+            overrides.created.insert(list.to_string());
+        }
+        self.save(&overrides);
+        info!("Subscriber list '{}' changed at runtime: {:?}", list, outcome);
+        Ok(outcome)
+    }
+
+    /// Apply `change` to a copy of the current settings and swap it in if
+    /// valid; the caller holds the writer lock
+    fn replace(&self, change: impl FnOnce(&mut TelegramSettings)) -> Result<Arc<TelegramSettings>, Vec<String>> {
+        let mut settings = TelegramSettings::clone(&self.snapshot());
+        change(&mut settings);
+        let problems = settings.validate();
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        let settings = Arc::new(settings);
+        *self.inner.current.write().unwrap() = settings.clone();
+        Ok(settings)
+    }
+
+    fn save(&self, file: &ListsFile) {
+        let Some(path) = &self.inner.path else { return };
+        let result = serde_json::to_string_pretty(file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist subscriber lists to {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn ids(chats: &[i64]) -> Vec<ChatRef> {
+        chats.iter().copied().map(ChatRef::Id).collect()
+    }
+
+    fn settings() -> TelegramSettings {
+        toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nops = [2]\nteam = [2]\n",
+        )
+        .unwrap()
+        .telegram
+    }
+
+    #[test]
+    fn snapshots_keep_the_settings_they_were_taken_with() {
+        let shared = SharedSettings::new(settings());
+        let before = shared.snapshot();
+        shared.set_list_chats("ops", ids(&[3, 4])).unwrap();
+        assert_eq!(before.subscriber_lists["ops"].chats, ids(&[2]));
+        assert_eq!(shared.snapshot().subscriber_lists["ops"].chats, ids(&[3, 4]));
+    }
+
+    #[test]
+    fn invalid_changes_are_refused_whole() {
+        let shared = SharedSettings::new(settings());
+        assert!(shared.set_list_chats("nope", ids(&[3])).is_err());
+        let problems = shared
+            .update(|settings| {
+                settings.subscriber_lists.get_mut("ops").unwrap().chats = ids(&[9]);
+                settings.owner_chat_ids.clear();
+            })
+            .unwrap_err();
+        assert!(!problems.is_empty());
+        assert_eq!(shared.snapshot().subscriber_lists["ops"].chats, ids(&[2]));
+        assert_eq!(shared.snapshot().owner_chat_ids, vec![1]);
+    }
+
+    #[test]
+    fn list_changes_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("corky-lists-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lists.json");
+        let shared = SharedSettings::load_from(settings(), path.clone());
+        shared.set_list_chats("team", ids(&[5, 6])).unwrap();
+        // Changes made with `update` last only for the run
+        shared.update(|settings| settings.subscriber_lists.get_mut("ops").unwrap().chats = ids(&[7])).unwrap();
+        let reloaded = SharedSettings::load_from(settings(), path).snapshot();
+        assert_eq!(reloaded.subscriber_lists["team"].chats, ids(&[5, 6]));
+        assert_eq!(reloaded.subscriber_lists["ops"].chats, ids(&[2]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn chats_are_added_and_removed_one_at_a_time() {
+        let shared = SharedSettings::new(settings());
+        assert_eq!(shared.add_list_chat("ops", 3, false), Ok(ListChange::Added { chats: 2 }));
+        assert_eq!(shared.add_list_chat("ops", 3, false), Ok(ListChange::AlreadyListed));
+        assert_eq!(shared.remove_list_chat("ops", 2), Ok(ListChange::Removed { chats: 1 }));
+        assert_eq!(shared.remove_list_chat("ops", 2), Ok(ListChange::NotListed));
+        assert_eq!(shared.remove_list_chat("ops", 3), Ok(ListChange::Removed { chats: 0 }));
+        let emptied = shared.snapshot();
+        assert!(emptied.subscriber_lists["ops"].chats.is_empty(), "an emptied list is kept");
+        assert!(emptied.validate().is_empty());
+        assert_eq!(shared.remove_list_chat("nope", 2), Err(ListError::UnknownList));
+    }
+
+    #[test]
+    fn lists_are_created_only_when_asked() {
+        let shared = SharedSettings::new(settings());
+        assert_eq!(shared.add_list_chat("new", 5, false), Err(ListError::UnknownList));
+        assert!(!shared.snapshot().subscriber_lists.contains_key("new"));
+        assert!(matches!(shared.add_list_chat("bad name", 5, true), Err(ListError::Invalid(_))));
+        assert_eq!(shared.add_list_chat("new", 5, true), Ok(ListChange::Created));
+        assert_eq!(shared.add_list_chat("new", 6, true), Ok(ListChange::Added { chats: 2 }));
+        assert_eq!(shared.snapshot().subscriber_lists["new"].chats, ids(&[5, 6]));
+    }
+
+    #[test]
+    fn created_and_emptied_lists_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("corky-lists-created-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lists.json");
+        let shared = SharedSettings::load_from(settings(), path.clone());
+        shared.add_list_chat("new", 5, true).unwrap();
+        shared.remove_list_chat("team", 2).unwrap();
+        let reloaded = SharedSettings::load_from(settings(), path).snapshot();
+        assert_eq!(reloaded.subscriber_lists["new"].chats, ids(&[5]));
+        assert!(reloaded.subscriber_lists["team"].chats.is_empty());
+        assert!(reloaded.validate().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn readers_never_see_half_a_change() {
+        let shared = SharedSettings::new(settings());
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for round in 0..500i64 {
+                    shared
+                        .update(|settings| {
+                            for list in settings.subscriber_lists.values_mut() {
+                                list.chats = ids(&[round + 1, round + 2]);
+                            }
+                        })
+                        .unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..2000 {
+                        let snapshot = shared.snapshot();
+                        assert_eq!(snapshot.subscriber_lists["ops"].chats, snapshot.subscriber_lists["team"].chats);
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(shared.snapshot().subscriber_lists["team"].chats, ids(&[500, 501]));
+    }
+}
@@ -0,0 +1,190 @@
+//! HMAC signatures on ZMQ commands, for when `zmq_hmac_secret` is set.
+//!
+//! A producer adds `"ts"` (unix seconds) to the command object, signs the
+//! object's canonical form with HMAC-SHA256 and adds the hex digest as
+//! `"sig"`; see `sign_command`. The bot rejects commands without a valid
+//! signature, with a `ts` too far from its own clock, or whose signature
+//! it has already seen within that window.
+//!
+//! Signed commands may only hold strings, integers, booleans and nulls:
+//! Rust and Python write fractional numbers differently (`1e20` against
+//! `1e+20`), so a float would make the two sides sign different bytes.
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Why a command was not accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// No `sig` or no integer `ts`
+    Unsigned,
+    BadSignature,
+    /// `ts` is more than the window away from the bot's clock
+    Stale { ts: i64, now: i64 },
+    /// The same signature was accepted before
+    Replayed,
+    /// The command holds a fractional number, which has no canonical form
+    Fractional,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Unsigned => write!(f, "missing \"sig\" or \"ts\""),
+            Rejection::BadSignature => write!(f, "bad signature"),
+            Rejection::Stale { ts, now } => write!(f, "timestamp {} is {}s away from now", ts, (now - ts).abs()),
+            Rejection::Replayed => write!(f, "replayed signature"),
+            Rejection::Fractional => write!(f, "fractional number in a signed command"),
+        }
+    }
+}
+
+/// The bytes that are signed: `command` without its `sig` field, as
+/// compact JSON with object keys in sorted order at every level. This is
+/// what Python's `json.dumps(command, sort_keys=True, separators=(",", ":"),
+/// ensure_ascii=False)` produces, as long as `command` holds no fractional
+/// numbers; those are refused.
+pub fn canonical(command: &Value) -> Result<String, Rejection> {
+    let mut command = command.clone();
+    if let Value::Object(fields) = &mut command {
+        fields.remove("sig");
+    }
+    if has_fraction(&command) {
+        return Err(Rejection::Fractional);
+    }
+    // serde_json's maps are sorted by key
+    Ok(command.to_string())
+}
+
+/// The `sig` a producer must put into `command`, which must already hold
+/// its `ts`. In Python:
+///
+/// ```text
+/// command["ts"] = int(time.time())
+/// body = json.dumps(command, sort_keys=True, separators=(",", ":"), ensure_ascii=False)
+/// command["sig"] = hmac.new(secret, body.encode(), hashlib.sha256).hexdigest()
+/// ```
+pub fn sign_command(secret: &[u8], command: &Value) -> Result<String, Rejection> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(canonical(command)?.as_bytes());
+    Ok(hex(&mac.finalize().into_bytes()))
+}
+
+/// Checks signatures and remembers the ones seen within the window
+pub struct Verifier {
+    secret: Vec<u8>,
+    window_secs: i64,
+    /// Accepted signatures and their `ts`, dropped once outside the window
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl Verifier {
+    pub fn new(secret: &str, window_secs: u64) -> Self {
+        Verifier { secret: secret.as_bytes().to_vec(), window_secs: window_secs as i64, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Accept `command` if it is signed, fresh as of `now` (unix seconds)
+    /// and not seen before
+    pub fn check(&self, command: &Value, now: i64) -> Result<(), Rejection> {
+        let sig = command.get("sig").and_then(Value::as_str).ok_or(Rejection::Unsigned)?;
+        let ts = command.get("ts").and_then(Value::as_i64).ok_or(Rejection::Unsigned)?;
+        if !constant_time_eq(sig.to_ascii_lowercase().as_bytes(), sign_command(&self.secret, command)?.as_bytes()) {
+            return Err(Rejection::BadSignature);
+        }
+        if (now - ts).abs() > self.window_secs {
+            return Err(Rejection::Stale { ts, now });
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, &mut seen_ts| (now - seen_ts).abs() <= self.window_secs);
+        if seen.insert(sig.to_ascii_lowercase(), ts).is_some() {
+            return Err(Rejection::Replayed);
+        }
+        Ok(())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `value` holds a number that is not an integer anywhere inside
+fn has_fraction(value: &Value) -> bool {
+    match value {
+        Value::Number(number) => number.is_f64(),
+        Value::Array(items) => items.iter().any(has_fraction),
+        Value::Object(fields) => fields.values().any(has_fraction),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn canonical_form_sorts_keys_and_drops_sig() {
+        let command = json!({"text": "héllo", "chat_id": 5, "ts": 1700000000, "sig": "x", "extra": {"b": 1, "a": [2, 1]}});
+        assert_eq!(
+            canonical(&command),
+            Ok(r#"{"chat_id":5,"extra":{"a":[2,1],"b":1},"text":"héllo","ts":1700000000}"#.to_string())
+        );
+    }
+
+    #[test]
+    fn fractional_numbers_are_refused() {
+        assert_eq!(canonical(&json!({"text": "hi", "ts": 1000, "extra": {"ratio": 1e20}})), Err(Rejection::Fractional));
+        assert_eq!(canonical(&json!({"text": "hi", "ts": 1000, "extra": [1.0]})), Err(Rejection::Fractional));
+        let verifier = Verifier::new("s3cret", 30);
+        let command = json!({"text": "hi", "ts": 1000, "ratio": 0.5, "sig": "00"});
+        assert_eq!(verifier.check(&command, 1000), Err(Rejection::Fractional));
+    }
+
+    #[test]
+    fn producer_signature_is_stable() {
+        // Computed with the Python snippet in `sign_command`'s docs
+        let command = json!({"chat_id": 5, "text": "héllo", "ts": 1700000000});
+        assert_eq!(
+            sign_command(b"s3cret", &command).as_deref(),
+            Ok("4295fc94c9ac1b7f0228dd756585b155ad5bcc16c363af8b6fb6319123ad6d2e")
+        );
+    }
+
+    fn signed(secret: &str, mut command: Value) -> Value {
+        let sig = sign_command(secret.as_bytes(), &command).unwrap();
+        command["sig"] = Value::String(sig);
+        command
+    }
+
+    #[test]
+    fn verifier_accepts_fresh_signed_commands_once() {
+        let verifier = Verifier::new("s3cret", 30);
+        let command = signed("s3cret", json!({"text": "hi", "ts": 1000}));
+        assert_eq!(verifier.check(&command, 1010), Ok(()));
+        assert_eq!(verifier.check(&command, 1011), Err(Rejection::Replayed));
+        let other = signed("s3cret", json!({"text": "hi again", "ts": 1000}));
+        assert_eq!(verifier.check(&other, 1011), Ok(()));
+    }
+
+    #[test]
+    fn verifier_rejects_tampering_and_stale_timestamps() {
+        let verifier = Verifier::new("s3cret", 30);
+        assert_eq!(verifier.check(&json!({"text": "hi", "ts": 1000}), 1000), Err(Rejection::Unsigned));
+        assert_eq!(verifier.check(&json!({"text": "hi", "sig": "00"}), 1000), Err(Rejection::Unsigned));
+        let mut command = signed("s3cret", json!({"text": "hi", "ts": 1000}));
+        assert_eq!(verifier.check(&signed("wrong", json!({"text": "hi", "ts": 1000})), 1000), Err(Rejection::BadSignature));
+        command["text"] = json!("bye");
+        assert_eq!(verifier.check(&command, 1000), Err(Rejection::BadSignature));
+        let old = signed("s3cret", json!({"text": "hi", "ts": 1000}));
+        assert_eq!(verifier.check(&old, 1031), Err(Rejection::Stale { ts: 1000, now: 1031 }));
+        assert_eq!(verifier.check(&old, 969), Err(Rejection::Stale { ts: 1000, now: 969 }));
+    }
+}
@@ -0,0 +1,277 @@
+//! Seam between the delivery logic and the Telegram Bot API.
+
+use crate::activity::Activity;
+use crate::config::TelegramSettings;
+use crate::errors::SendError;
+use crate::flood::FloodBreaker;
+use crate::heartbeat::Heartbeat;
+use crate::migrations::Migrations;
+use crate::state::BotState;
+use crate::stats::Stats;
+use crate::zmq_listener::{ImageBytes, Priority};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use teloxide::{prelude::*, types::{ChatAction, InputFile, LinkPreviewOptions, MessageId, ParseMode, Recipient}, RequestError};
+
+/// Most retries a message may ask for
+pub const MAX_RETRIES: u8 = 10;
+/// Longest wait between two attempts
+pub const MAX_RETRY_DELAY_MS: u64 = 60_000;
+
+/// Per-message delivery settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendOptions {
+    /// Deliver without a notification sound
+    pub disable_notification: bool,
+    /// Attempts per send before giving up; handled by the retry loop, not Telegram
+    pub max_attempts: u8,
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_base_delay_ms: u64,
+    /// Forbid forwarding and saving the message
+    pub protect_content: bool,
+    /// Blur photos behind a spoiler
+    pub spoiler: bool,
+    /// Send links in texts without preview cards
+    pub disable_link_preview: bool,
+    /// Texts and captions are already-sanitized HTML
+    pub html: bool,
+    /// Show an upload indicator for files at least this big; `None` never does
+    pub chat_action_min_bytes: Option<u64>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            disable_notification: false,
+            max_attempts: 3,
+            retry_base_delay_ms: 500,
+            protect_content: false,
+            spoiler: false,
+            disable_link_preview: false,
+            html: false,
+            chat_action_min_bytes: None,
+        }
+    }
+}
+
+impl SendOptions {
+    /// Defaults for a message of the given priority
+    pub fn for_priority(priority: Priority) -> Self {
+        match priority {
+            Priority::Normal => SendOptions::default(),
+            Priority::High => SendOptions { max_attempts: 6, ..SendOptions::default() },
+        }
+    }
+
+    /// How long to wait after failed attempt `attempt` (counted from 0),
+    /// never more than `MAX_RETRY_DELAY_MS`
+    pub fn retry_delay_ms(&self, attempt: u8) -> u64 {
+        self.retry_base_delay_ms.saturating_mul(2_u64.saturating_pow(attempt as u32)).min(MAX_RETRY_DELAY_MS)
+    }
+}
+
+/// A photo accepted by Telegram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentPhoto {
+    pub id: MessageId,
+    /// File ID of the uploaded image, for sending it again without uploading
+    pub file_id: Option<String>,
+}
+
+/// A `Bot` for `settings`, pointed at `api_url` when one is configured.
+/// An invalid `api_url` is ignored here; `validate` reports it.
+pub fn bot_for(settings: &TelegramSettings) -> Bot {
+    let bot = Bot::new(&settings.bot_token);
+    match settings.api_url() {
+        Ok(Some(url)) => bot.set_api_url(url),
+        _ => bot,
+    }
+}
+
+/// A `Bot` together with the state its sends share with the `BotState`,
+/// built by `BotState::sink`. Derefs to the `Bot` for everything else.
+#[derive(Clone)]
+pub struct TelegramSink {
+    bot: Bot,
+    breaker: Arc<FloodBreaker>,
+    migrations: Arc<Migrations>,
+    heartbeat: Arc<Heartbeat>,
+    stats: Arc<Stats>,
+    activity: Arc<Activity>,
+}
+
+impl TelegramSink {
+    pub fn new(bot: Bot, state: &BotState) -> Self {
+        TelegramSink {
+            bot,
+            breaker: state.flood.clone(),
+            migrations: state.migrations.clone(),
+            heartbeat: state.heartbeat.clone(),
+            stats: state.stats.clone(),
+            activity: state.activity.clone(),
+        }
+    }
+
+    pub fn bot(&self) -> &Bot {
+        &self.bot
+    }
+}
+
+impl Deref for TelegramSink {
+    type Target = Bot;
+
+    fn deref(&self) -> &Bot {
+        &self.bot
+    }
+}
+
+/// Something that can deliver messages to Telegram chats.
+///
+/// Implemented by `TelegramSink`; tests provide a recording mock so retry
+/// and routing logic can run without network calls.
+pub trait MessageSink: Clone + Send + Sync + 'static {
+    type Error: SendError;
+
+    /// Send a plain text message, returning its message ID
+    fn send_text(&self, chat: ChatId, text: &str, opts: SendOptions)
+        -> impl Future<Output = Result<MessageId, Self::Error>> + Send;
+
+    /// Upload an image file with a caption
+    fn send_photo(&self, chat: ChatId, path: &Path, caption: &str, opts: SendOptions)
+        -> impl Future<Output = Result<SentPhoto, Self::Error>> + Send;
+
+    /// Upload an image received as bytes with a caption
+    fn send_photo_bytes(&self, chat: ChatId, image: &ImageBytes, caption: &str, opts: SendOptions)
+        -> impl Future<Output = Result<SentPhoto, Self::Error>> + Send;
+
+    /// Send an image uploaded before, by its file ID
+    fn send_photo_by_id(&self, chat: ChatId, file_id: &str, caption: &str, opts: SendOptions)
+        -> impl Future<Output = Result<MessageId, Self::Error>> + Send;
+
+    /// Send a file as a document with a caption
+    fn send_document(&self, chat: ChatId, path: &Path, caption: &str, opts: SendOptions)
+        -> impl Future<Output = Result<MessageId, Self::Error>> + Send;
+
+    /// Show `action` ("uploading photo…" etc.) in the chat for a few seconds
+    fn send_chat_action(&self, chat: ChatId, action: ChatAction)
+        -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// The ID of the public chat `@username`
+    fn resolve_username(&self, username: &str)
+        -> impl Future<Output = Result<ChatId, Self::Error>> + Send;
+
+    /// The flood breaker of the bot account behind this sink
+    fn flood_breaker(&self) -> &FloodBreaker;
+
+    /// Groups this sink's sends found upgraded to supergroups
+    fn migrations(&self) -> &Migrations;
+
+    /// Counters of the bot account behind this sink
+    fn stats(&self) -> &Stats;
+
+    /// Recent deliveries and failures of the bot account behind this sink
+    fn activity(&self) -> &Activity;
+
+    /// The heartbeat refreshed by each successful send, if any
+    fn heartbeat(&self) -> Option<&Heartbeat> {
+        None
+    }
+}
+
+impl MessageSink for TelegramSink {
+    type Error = RequestError;
+
+    async fn send_text(&self, chat: ChatId, text: &str, opts: SendOptions) -> Result<MessageId, RequestError> {
+        let mut request = self
+            .bot
+            .send_message(chat, text)
+            .disable_notification(opts.disable_notification)
+            .protect_content(opts.protect_content);
+        if opts.html {
+            request = request.parse_mode(ParseMode::Html);
+        }
+        if opts.disable_link_preview {
+            request = request.link_preview_options(LinkPreviewOptions {
+                is_disabled: true,
+                url: None,
+                prefer_small_media: false,
+                prefer_large_media: false,
+                show_above_text: false,
+            });
+        }
+        request.await.map(|message| message.id)
+    }
+
+    async fn send_photo(&self, chat: ChatId, path: &Path, caption: &str, opts: SendOptions) -> Result<SentPhoto, RequestError> {
+        send_input_photo(&self.bot, chat, InputFile::file(path.to_path_buf()), caption, opts).await.map(sent_photo)
+    }
+
+    async fn send_photo_bytes(&self, chat: ChatId, image: &ImageBytes, caption: &str, opts: SendOptions) -> Result<SentPhoto, RequestError> {
+        let file = InputFile::memory(image.0.as_ref().clone());
+        send_input_photo(&self.bot, chat, file, caption, opts).await.map(sent_photo)
+    }
+
+    async fn send_photo_by_id(&self, chat: ChatId, file_id: &str, caption: &str, opts: SendOptions) -> Result<MessageId, RequestError> {
+        send_input_photo(&self.bot, chat, InputFile::file_id(file_id), caption, opts).await.map(|message| message.id)
+    }
+
+    async fn send_document(&self, chat: ChatId, path: &Path, caption: &str, opts: SendOptions) -> Result<MessageId, RequestError> {
+        let mut request = Requester::send_document(&self.bot, chat, InputFile::file(path.to_path_buf()))
+            .caption(caption)
+            .disable_notification(opts.disable_notification)
+            .protect_content(opts.protect_content);
+        if opts.html {
+            request = request.parse_mode(ParseMode::Html);
+        }
+        request.await.map(|message| message.id)
+    }
+
+    async fn send_chat_action(&self, chat: ChatId, action: ChatAction) -> Result<(), RequestError> {
+        Requester::send_chat_action(&self.bot, chat, action).await.map(|_| ())
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<ChatId, RequestError> {
+        self.bot.get_chat(Recipient::ChannelUsername(username.to_string())).await.map(|chat| chat.id)
+    }
+
+    fn flood_breaker(&self) -> &FloodBreaker {
+        &self.breaker
+    }
+
+    fn migrations(&self) -> &Migrations {
+        &self.migrations
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    fn heartbeat(&self) -> Option<&Heartbeat> {
+        Some(&self.heartbeat)
+    }
+}
+
+/// Telegram returns several sizes; any of their IDs resends the photo
+fn sent_photo(message: Message) -> SentPhoto {
+    let file_id = message.photo().and_then(|sizes| sizes.last()).map(|size| size.file.id.clone());
+    SentPhoto { id: message.id, file_id }
+}
+
+async fn send_input_photo(bot: &Bot, chat: ChatId, photo: InputFile, caption: &str, opts: SendOptions) -> Result<Message, RequestError> {
+    let mut request = Requester::send_photo(bot, chat, photo)
+        .caption(caption)
+        .has_spoiler(opts.spoiler)
+        .disable_notification(opts.disable_notification)
+        .protect_content(opts.protect_content);
+    if opts.html {
+        request = request.parse_mode(ParseMode::Html);
+    }
+    request.await
+}
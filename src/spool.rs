@@ -0,0 +1,131 @@
+//! Messages kept on disk while Telegram refuses the bot token.
+//!
+//! A mistyped or revoked token would otherwise turn every message into a
+//! 401 and lose it. Once the token is refused the spool is active: new
+//! messages are appended to `spool.json` in the data directory instead of
+//! being sent, in the same format as broadcasts held by quiet hours. When the
+//! token is accepted again they are replayed in the order they arrived.
+
+use crate::deferred::{Deferred, HeldMessage};
+use crate::zmq_listener::ZmqMessage;
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::sync::Mutex;
+
+/// Messages kept at most; beyond this the oldest is dropped
+pub const MAX_SPOOLED: usize = 10_000;
+
+pub struct Spool {
+    held: Deferred,
+    /// Whether new messages are spooled. Checked and changed under this lock
+    /// together with `held`, so no message is spooled after the last replay.
+    active: Mutex<bool>,
+}
+
+impl Spool {
+    /// Spool kept in `held`; active if messages are left over from a
+    /// previous run, so new ones queue behind them
+    pub fn new(held: Deferred) -> Self {
+        let active = !held.is_empty();
+        Spool { held, active: Mutex::new(active) }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.active.lock().unwrap()
+    }
+
+    /// Spool `message` if the spool is active, or hand it back to be sent
+    pub fn hold_if_active(&self, message: ZmqMessage, now: DateTime<Utc>) -> Option<ZmqMessage> {
+        let active = self.active.lock().unwrap();
+        if !*active {
+            return Some(message);
+        }
+        self.hold(message, now);
+        None
+    }
+
+    /// Start spooling, with `message` as the first one if given. Returns
+    /// whether the spool was inactive before.
+    pub fn refused(&self, message: Option<ZmqMessage>, now: DateTime<Utc>) -> bool {
+        let mut active = self.active.lock().unwrap();
+        if let Some(message) = message {
+            self.hold(message, now);
+        }
+        !std::mem::replace(&mut *active, true)
+    }
+
+    /// The oldest spooled message; once none are left the spool stops
+    pub fn take_next(&self) -> Option<HeldMessage> {
+        let mut active = self.active.lock().unwrap();
+        let next = self.held.take_oldest();
+        if next.is_none() {
+            *active = false;
+        }
+        next
+    }
+
+    /// Spool `held` again ahead of the rest, after the token was refused
+    /// during a replay
+    pub fn put_back(&self, held: HeldMessage) {
+        let mut active = self.active.lock().unwrap();
+        self.held.put_back(held);
+        *active = true;
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn hold(&self, message: ZmqMessage, now: DateTime<Utc>) {
+        while self.held.len() >= MAX_SPOOLED {
+            if let Some(dropped) = self.held.take_oldest() {
+                warn!("Spool is full; dropping the message received at {}: {:?}", dropped.received_at, dropped.message.id);
+            }
+        }
+        self.held.hold(message, now, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ZmqMessage {
+        serde_json::from_value(serde_json::json!({ "chat_id": 1, "text": text })).unwrap()
+    }
+
+    #[test]
+    fn messages_are_spooled_only_while_active() {
+        let spool = Spool::new(Deferred::new());
+        let now = Utc::now();
+        assert!(spool.hold_if_active(message("sent"), now).is_some());
+        assert!(spool.refused(Some(message("a")), now));
+        assert!(!spool.refused(None, now));
+        assert!(spool.hold_if_active(message("b"), now).is_none());
+
+        let first = spool.take_next().unwrap();
+        assert_eq!(first.message.text, "a");
+        spool.put_back(first);
+        let texts: Vec<String> = std::iter::from_fn(|| spool.take_next()).map(|held| held.message.text).collect();
+        assert_eq!(texts, ["a", "b"]);
+        assert!(!spool.is_active());
+        assert!(spool.hold_if_active(message("c"), now).is_some());
+    }
+
+    #[test]
+    fn leftovers_keep_the_spool_active() {
+        let path = std::env::temp_dir().join(format!("corky-spool-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Spool::new(Deferred::load(path.clone())).refused(Some(message("overnight")), Utc::now());
+        let spool = Spool::new(Deferred::load(path.clone()));
+        assert!(spool.is_active());
+        assert_eq!(spool.take_next().unwrap().message.text, "overnight");
+        assert!(spool.take_next().is_none());
+        assert!(!Spool::new(Deferred::load(path.clone())).is_active());
+        let _ = std::fs::remove_file(&path);
+    }
+}
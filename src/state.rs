@@ -0,0 +1,188 @@
+//! Runtime state shared by the event loop, send tasks, and command handlers.
+
+use crate::activity::Activity;
+use crate::chat_order::ChatOrder;
+use crate::config::TelegramSettings;
+use crate::data_dir::DataDir;
+use crate::dedupe::Duplicates;
+use crate::deferred::Deferred;
+use crate::digest::{Digest, Digests};
+use crate::events::Events;
+use crate::file_ids::FileIds;
+use crate::flood::FloodBreaker;
+use crate::health::Health;
+use crate::heartbeat::Heartbeat;
+use crate::history::History;
+use crate::journal::Journal;
+use crate::migrations::Migrations;
+use crate::mutes::Mutes;
+use crate::outbox::Outbox;
+use crate::quarantine::Quarantine;
+use crate::reports::Reports;
+use crate::sent::SentMessages;
+use crate::signing::Verifier;
+use crate::sink::TelegramSink;
+use crate::spool::Spool;
+use crate::stats::Stats;
+use crate::traffic::Traffic;
+use crate::unknown_commands::UnknownReplies;
+use crate::usernames::Usernames;
+use chrono::Utc;
+use log::error;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::Bot;
+
+/// Mutable bot state that lives alongside the (immutable) settings
+pub struct BotState {
+    pub quarantine: Quarantine,
+    pub mutes: Mutes,
+    pub deferred: Deferred,
+    /// Messages kept while Telegram refuses the token
+    pub spool: Spool,
+    pub digests: Digests,
+    /// Broadcasts in progress, resumed after a restart
+    pub broadcasts: Journal,
+    pub outbox: Outbox,
+    /// Keeps sends to each chat in the order the messages were processed
+    pub chat_order: ChatOrder,
+    pub sent: SentMessages,
+    pub history: History,
+    pub usernames: Usernames,
+    pub file_ids: FileIds,
+    pub duplicates: Duplicates,
+    pub health: Health,
+    /// Last delivery per list and per directly targeted chat
+    pub traffic: Traffic,
+    /// Counts for the next delivery report
+    pub reports: Reports,
+    /// Checks ZMQ signatures when `zmq_hmac_secret` is set
+    pub signatures: Option<Verifier>,
+    /// Chats recently told a command was not understood
+    pub unknown_replies: UnknownReplies,
+    /// Pauses sends while Telegram rate-limits the bot account, shared
+    /// with the sinks
+    pub flood: Arc<FloodBreaker>,
+    /// Groups found upgraded to supergroups, shared with the sinks
+    pub migrations: Arc<Migrations>,
+    /// Written after each send through a sink from `sink`, and by keepalives
+    pub heartbeat: Arc<Heartbeat>,
+    /// Counters for `/status`, shared with the sinks
+    pub stats: Arc<Stats>,
+    /// Recent events for `/tail`, shared with the sinks
+    pub activity: Arc<Activity>,
+    /// Bot events for `zmq_events_endpoint`, published once the bot runs
+    pub events: Events,
+}
+
+impl BotState {
+    /// State persisted in the data directory. Each part whose file cannot
+    /// be kept there is held in memory instead.
+    pub fn load(settings: &TelegramSettings) -> Self {
+        let dir = DataDir::for_settings(settings);
+        let migrations = Migrations::new();
+        if let Some(path) = dir.file("migrations.json", "Group migrations") {
+            migrations.load(path);
+        }
+        let heartbeat = Heartbeat::new();
+        heartbeat.configure(settings.heartbeat_file.clone());
+        let quarantine_after = settings.quarantine_after;
+        BotState {
+            quarantine: dir
+                .file("quarantine.json", "Quarantine")
+                .map_or_else(|| Quarantine::new(quarantine_after), |path| Quarantine::load(path, quarantine_after)),
+            mutes: dir.file("mutes.json", "Mutes").map_or_else(Mutes::new, Mutes::load),
+            deferred: dir.file("deferred.json", "Deferred broadcasts").map_or_else(Deferred::new, Deferred::load),
+            spool: Spool::new(dir.file("spool.json", "The token spool").map_or_else(Deferred::new, Deferred::load)),
+            broadcasts: dir.file("broadcasts.json", "The broadcast journal").map_or_else(Journal::new, Journal::load),
+            traffic: dir
+                .file("traffic.json", "Traffic timestamps")
+                .map_or_else(|| Traffic::new(Utc::now()), |path| Traffic::load(path, Utc::now())),
+            reports: dir
+                .file("reports.json", "Report counts")
+                .map_or_else(|| Reports::new(Utc::now()), |path| Reports::load(path, Utc::now())),
+            history: open_history(settings),
+            migrations: Arc::new(migrations),
+            heartbeat: Arc::new(heartbeat),
+            ..Self::in_memory(settings)
+        }
+    }
+
+    /// State that is never written to disk
+    pub fn in_memory(settings: &TelegramSettings) -> Self {
+        let stats = Arc::new(Stats::new());
+        BotState {
+            quarantine: Quarantine::new(settings.quarantine_after),
+            mutes: Mutes::new(),
+            deferred: Deferred::new(),
+            spool: Spool::new(Deferred::new()),
+            digests: Digests::new(),
+            broadcasts: Journal::new(),
+            outbox: Outbox::new(),
+            chat_order: ChatOrder::new(),
+            sent: SentMessages::default(),
+            history: History::disabled(),
+            usernames: usernames(settings),
+            file_ids: file_ids(settings),
+            duplicates: duplicates(settings),
+            health: Health::new(Utc::now()),
+            traffic: Traffic::new(Utc::now()),
+            reports: Reports::new(Utc::now()),
+            signatures: signatures(settings),
+            unknown_replies: UnknownReplies::default(),
+            flood: Arc::new(flood_breaker(settings, stats.clone())),
+            migrations: Arc::default(),
+            heartbeat: Arc::default(),
+            activity: Arc::default(),
+            events: Events::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// A sink sending with `bot` that shares this state's flood breaker,
+    /// migrations, heartbeat, counters and activity
+    pub fn sink(&self, bot: Bot) -> TelegramSink {
+        TelegramSink::new(bot, self)
+    }
+
+    /// Queue a finished digest: the summary, then its attachments
+    pub fn push_digest(&self, digest: Digest) {
+        for message in digest.into_messages() {
+            self.outbox.push(message);
+        }
+    }
+}
+
+fn usernames(settings: &TelegramSettings) -> Usernames {
+    Usernames::new(Duration::from_secs(settings.username_cache_secs))
+}
+
+fn flood_breaker(settings: &TelegramSettings, stats: Arc<Stats>) -> FloodBreaker {
+    let breaker = FloodBreaker::new(stats);
+    breaker.configure(settings.flood_breaker_threshold, Duration::from_secs(settings.flood_breaker_window_secs));
+    breaker
+}
+
+fn file_ids(settings: &TelegramSettings) -> FileIds {
+    FileIds::new(settings.file_id_cache_size, Duration::from_secs(settings.file_id_cache_ttl_secs))
+}
+
+fn duplicates(settings: &TelegramSettings) -> Duplicates {
+    Duplicates::new(Duration::from_secs(settings.suppress_duplicates_secs))
+}
+
+fn signatures(settings: &TelegramSettings) -> Option<Verifier> {
+    let secret = settings.zmq_hmac_secret.as_ref()?;
+    Some(Verifier::new(secret, settings.zmq_hmac_window_secs))
+}
+
+/// Delivery history, if `history_db` is set and can be opened
+fn open_history(settings: &TelegramSettings) -> History {
+    let Some(path) = &settings.history_db else {
+        return History::disabled();
+    };
+    History::open(path, settings.history_keep_days).unwrap_or_else(|err| {
+        error!("{}; delivery history is disabled", err);
+        History::disabled()
+    })
+}
@@ -0,0 +1,171 @@
+//! Counters for queue and delivery health.
+//!
+//! Each `BotState` keeps its own, shared with the sinks, the event queue and
+//! the flood breaker that update them, so two bots in one process do not
+//! mix their `/status`.
+
+use crate::errors::ErrorCategory;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const CATEGORIES: usize = ErrorCategory::ALL.len();
+
+/// Counters updated from the ZMQ thread and the send tasks
+pub struct Stats {
+    delivered: AtomicU64,
+    dropped_events: AtomicU64,
+    failures: [AtomicU64; CATEGORIES],
+    file_id_hits: AtomicU64,
+    upload_bytes_saved: AtomicU64,
+    rejected_signatures: AtomicU64,
+    invalid_messages: AtomicU64,
+    dropped_publications: AtomicU64,
+    duplicates_suppressed: AtomicU64,
+    dispatcher_restarts: AtomicU64,
+    flood_pauses: AtomicU64,
+}
+
+/// Point-in-time copy of the counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Telegram messages sent successfully
+    pub delivered: u64,
+    pub dropped_events: u64,
+    /// Failed send attempts per category, omitting categories with no failures
+    pub failures: Vec<(ErrorCategory, u64)>,
+    /// Images sent by cached file ID instead of being uploaded
+    pub file_id_hits: u64,
+    pub upload_bytes_saved: u64,
+    /// ZMQ payloads dropped for a missing, bad, stale or replayed signature
+    pub rejected_signatures: u64,
+    /// ZMQ messages rejected by validation before any send
+    pub invalid_messages: u64,
+    /// Bot events not published because the events socket was full
+    pub dropped_publications: u64,
+    /// Messages not sent because they repeated one sent to the same chat
+    pub duplicates_suppressed: u64,
+    /// Times the Telegram dispatcher ended unexpectedly
+    pub dispatcher_restarts: u64,
+    /// Times all sends were paused for Telegram's flood limits
+    pub flood_pauses: u64,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            delivered: AtomicU64::new(0),
+            dropped_events: AtomicU64::new(0),
+            failures: [const { AtomicU64::new(0) }; CATEGORIES],
+            file_id_hits: AtomicU64::new(0),
+            upload_bytes_saved: AtomicU64::new(0),
+            rejected_signatures: AtomicU64::new(0),
+            invalid_messages: AtomicU64::new(0),
+            dropped_publications: AtomicU64::new(0),
+            duplicates_suppressed: AtomicU64::new(0),
+            dispatcher_restarts: AtomicU64::new(0),
+            flood_pauses: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a message accepted by Telegram
+    pub fn record_delivered(&self) {
+        self.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event discarded because the event queue was full
+    pub fn record_dropped_event(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a failed send attempt
+    pub fn record_failure(&self, category: ErrorCategory) {
+        self.failures[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an image sent by file ID instead of uploading its `bytes`
+    pub fn record_file_id_hit(&self, bytes: u64) {
+        self.file_id_hits.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes_saved.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a ZMQ payload rejected by signature checking
+    pub fn record_rejected_signature(&self) {
+        self.rejected_signatures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a ZMQ message that failed validation
+    pub fn record_invalid_message(&self) {
+        self.invalid_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a bot event dropped instead of published
+    pub fn record_dropped_publication(&self) {
+        self.dropped_publications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a message dropped as a duplicate
+    pub fn record_duplicate_suppressed(&self) {
+        self.duplicates_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an unexpected end of the Telegram dispatcher
+    pub fn record_dispatcher_restart(&self) {
+        self.dispatcher_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an opening of the flood breaker
+    pub fn record_flood_pause(&self) {
+        self.flood_pauses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            failures: ErrorCategory::ALL
+                .iter()
+                .map(|&c| (c, self.failures[c as usize].load(Ordering::Relaxed)))
+                .filter(|&(_, n)| n > 0)
+                .collect(),
+            file_id_hits: self.file_id_hits.load(Ordering::Relaxed),
+            upload_bytes_saved: self.upload_bytes_saved.load(Ordering::Relaxed),
+            rejected_signatures: self.rejected_signatures.load(Ordering::Relaxed),
+            invalid_messages: self.invalid_messages.load(Ordering::Relaxed),
+            dropped_publications: self.dropped_publications.load(Ordering::Relaxed),
+            duplicates_suppressed: self.duplicates_suppressed.load(Ordering::Relaxed),
+            dispatcher_restarts: self.dispatcher_restarts.load(Ordering::Relaxed),
+            flood_pauses: self.flood_pauses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_counted_per_category() {
+        let stats = Stats::new();
+        stats.record_failure(ErrorCategory::Blocked);
+        stats.record_failure(ErrorCategory::Timeout);
+        stats.record_failure(ErrorCategory::Blocked);
+        assert_eq!(
+            stats.snapshot().failures,
+            vec![(ErrorCategory::Timeout, 1), (ErrorCategory::Blocked, 2)]
+        );
+    }
+
+    #[test]
+    fn file_id_hits_add_up_saved_bytes() {
+        let stats = Stats::new();
+        stats.record_file_id_hit(1000);
+        stats.record_file_id_hit(500);
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.file_id_hits, snapshot.upload_bytes_saved), (2, 1500));
+    }
+}
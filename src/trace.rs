@@ -0,0 +1,116 @@
+//! Trace IDs tying a ZMQ message to the log lines, events and history rows
+//! it caused.
+//!
+//! A message keeps the `trace_id` its producer gave it, cleaned up, or gets
+//! a random UUID. While it is processed the ID is held in a task-local, so
+//! the logger can append it to every line without each call site passing
+//! it along. Texts split into several Telegram messages log the index of
+//! each part as well.
+
+use std::future::Future;
+use std::sync::Arc;
+
+/// Longest trace ID kept; longer ones are cut
+pub const MAX_TRACE_ID_CHARS: usize = 64;
+
+#[derive(Clone)]
+struct Current {
+    id: Arc<str>,
+    /// 1-based index of the Telegram message being sent for a split text
+    part: Option<usize>,
+}
+
+tokio::task_local! {
+    static CURRENT: Current;
+}
+
+/// `raw` reduced to ASCII letters, digits and `-_.:` and cut to
+/// `MAX_TRACE_ID_CHARS`, so it cannot forge or break log lines. `None` if
+/// nothing is left.
+pub fn sanitize(raw: &str) -> Option<String> {
+    let id: String = raw
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        .take(MAX_TRACE_ID_CHARS)
+        .collect();
+    (!id.is_empty()).then_some(id)
+}
+
+/// A new random trace ID
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// The sanitized `given` ID, or a new one
+pub fn ensure(given: Option<&str>) -> String {
+    given.and_then(sanitize).unwrap_or_else(generate)
+}
+
+/// Run `fut` with `id` as the current trace
+pub async fn scope<F: Future>(id: &str, fut: F) -> F::Output {
+    CURRENT.scope(Current { id: Arc::from(id), part: None }, fut).await
+}
+
+/// Run `fut` as part `part` of the current trace, if there is one
+pub async fn part<F: Future>(part: usize, fut: F) -> F::Output {
+    match CURRENT.try_with(Current::clone) {
+        Ok(current) => CURRENT.scope(Current { part: Some(part), ..current }, fut).await,
+        Err(_) => fut.await,
+    }
+}
+
+/// `fut` carrying the current trace along, for spawning it as its own task
+pub fn inherit<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let current = CURRENT.try_with(Current::clone).ok();
+    async move {
+        match current {
+            Some(current) => CURRENT.scope(current, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// The current trace ID, if any
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|current| current.id.to_string()).ok()
+}
+
+/// The current trace as a log field, e.g. `trace_id=4f1c…` or
+/// `trace_id=4f1c…#2` for the second part of a split text
+pub fn log_field() -> Option<String> {
+    CURRENT
+        .try_with(|current| match current.part {
+            Some(part) => format!("trace_id={}#{}", current.id, part),
+            None => format!("trace_id={}", current.id),
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_cleaned_and_cut() {
+        assert_eq!(sanitize("job-42:run.7_b").as_deref(), Some("job-42:run.7_b"));
+        assert_eq!(sanitize("abc\n[ERROR] forged line").as_deref(), Some("abcERRORforgedline"));
+        assert_eq!(sanitize(&"x".repeat(100)).map(|id| id.len()), Some(MAX_TRACE_ID_CHARS));
+        assert_eq!(sanitize(" \n\u{1b}[31m"), Some("31m".to_string()));
+        assert_eq!(sanitize("\n\t "), None);
+        assert_eq!(ensure(Some("é")).len(), 36);
+        assert_eq!(ensure(Some("given")), "given");
+    }
+
+    #[tokio::test]
+    async fn the_trace_follows_parts_and_spawned_tasks() {
+        assert_eq!(log_field(), None);
+        scope("t1", async {
+            assert_eq!(log_field().as_deref(), Some("trace_id=t1"));
+            assert_eq!(part(2, async { log_field() }).await.as_deref(), Some("trace_id=t1#2"));
+            let spawned = tokio::spawn(inherit(async { current() })).await.unwrap();
+            assert_eq!(spawned.as_deref(), Some("t1"));
+            assert_eq!(tokio::spawn(async { current() }).await.unwrap(), None);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,232 @@
+//! Last successful delivery per subscriber list and per directly targeted
+//! chat, and alerts for lists that went quiet.
+//!
+//! Deliveries are recorded in memory and written out by `save`, which the
+//! periodic tick and shutdown call, so a restart keeps the timestamps and a
+//! quiet list is not reported just because the bot was restarted.
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// On-disk representation; times are unix seconds
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct TrafficFile {
+    lists: BTreeMap<String, i64>,
+    chats: BTreeMap<i64, i64>,
+    /// Lists reported quiet, with their last delivery when that happened
+    #[serde(default)]
+    quiet: BTreeMap<String, i64>,
+}
+
+/// A list going quiet or resuming
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    Quiet { list: String, expected: Duration },
+    Resumed { list: String, quiet_for: Duration },
+}
+
+impl Alert {
+    /// Message for the owners
+    pub fn notice(&self) -> String {
+        match self {
+            Alert::Quiet { list, expected } => {
+                format!("Warning: no traffic for list '{}' in {}.", list, format_age(*expected))
+            }
+            Alert::Resumed { list, quiet_for } => {
+                format!("Traffic for list '{}' resumed after {} without deliveries.", list, format_age(*quiet_for))
+            }
+        }
+    }
+}
+
+struct Inner {
+    file: TrafficFile,
+    dirty: bool,
+}
+
+/// Last-delivery times, and which lists were reported quiet
+pub struct Traffic {
+    path: Option<PathBuf>,
+    /// Lists never delivered to count as quiet from here
+    started: DateTime<Utc>,
+    inner: Mutex<Inner>,
+}
+
+impl Traffic {
+    /// Timestamps kept only in memory
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Traffic { path: None, started: now, inner: Mutex::new(Inner { file: TrafficFile::default(), dirty: false }) }
+    }
+
+    /// Timestamps persisted at `path`, loading any previous state
+    pub fn load(path: PathBuf, now: DateTime<Utc>) -> Self {
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str::<TrafficFile>(&contents).unwrap_or_else(|err| {
+                error!("Ignoring unreadable traffic file {}: {}", path.display(), err);
+                TrafficFile::default()
+            }),
+            Err(_) => TrafficFile::default(),
+        };
+        Traffic { path: Some(path), started: now, inner: Mutex::new(Inner { file, dirty: false }) }
+    }
+
+    /// A message reached a chat, through `list` and/or addressed `directly`
+    pub fn record(&self, list: Option<&str>, chat: Option<i64>, now: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(list) = list {
+            inner.file.lists.insert(list.to_string(), now.timestamp());
+        }
+        if let Some(chat) = chat {
+            inner.file.chats.insert(chat, now.timestamp());
+        }
+        inner.dirty = true;
+    }
+
+    /// Last delivery per list, by list name
+    pub fn lists(&self) -> Vec<(String, DateTime<Utc>)> {
+        let inner = self.inner.lock().unwrap();
+        inner.file.lists.iter().filter_map(|(list, &at)| Some((list.clone(), DateTime::from_timestamp(at, 0)?))).collect()
+    }
+
+    /// Last direct delivery per chat, most recent first
+    pub fn chats(&self) -> Vec<(i64, DateTime<Utc>)> {
+        let inner = self.inner.lock().unwrap();
+        let mut chats: Vec<_> =
+            inner.file.chats.iter().filter_map(|(&chat, &at)| Some((chat, DateTime::from_timestamp(at, 0)?))).collect();
+        chats.sort_by_key(|&(_, at)| std::cmp::Reverse(at));
+        chats
+    }
+
+    /// Whether `list` is currently reported quiet
+    pub fn is_quiet(&self, list: &str) -> bool {
+        self.inner.lock().unwrap().file.quiet.contains_key(list)
+    }
+
+    /// Compare each list's last delivery with how often it `expected`
+    /// traffic, returning each list that went quiet or resumed since the
+    /// last check
+    pub fn check(&self, expected: &[(String, Duration)], now: DateTime<Utc>) -> Vec<Alert> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut alerts = Vec::new();
+        for (list, every) in expected {
+            let last = inner.file.lists.get(list).copied();
+            match inner.file.quiet.get(list).copied() {
+                Some(quiet_since) if last.is_some_and(|last| last > quiet_since) => {
+                    inner.file.quiet.remove(list);
+                    let quiet_for = Duration::seconds(last.unwrap_or(quiet_since) - quiet_since);
+                    alerts.push(Alert::Resumed { list: list.clone(), quiet_for });
+                }
+                Some(_) => {}
+                None => {
+                    let since = last.unwrap_or(self.started.timestamp());
+                    if now.timestamp() - since >= every.num_seconds() {
+                        inner.file.quiet.insert(list.clone(), since);
+                        alerts.push(Alert::Quiet { list: list.clone(), expected: *every });
+                    }
+                }
+            }
+        }
+        // Lists no longer configured are not reported again
+        inner.file.quiet.retain(|list, _| expected.iter().any(|(name, _)| name == list));
+        inner.dirty |= !alerts.is_empty();
+        alerts
+    }
+
+    /// Write the timestamps out if anything changed since the last save
+    pub fn save(&self) {
+        let Some(path) = &self.path else { return };
+        let file = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            inner.file.clone()
+        };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+        if let Err(err) = result {
+            warn!("Failed to persist traffic timestamps to {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// `age` rounded down to its largest unit, e.g. `4m` or `2h`
+pub fn format_age(age: Duration) -> String {
+    match age.num_seconds().max(0) {
+        secs if secs < 60 => format!("{}s", secs),
+        secs if secs < 3600 => format!("{}m", secs / 60),
+        secs if secs < 86_400 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86_400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn hourly() -> Vec<(String, Duration)> {
+        vec![("ops".to_string(), Duration::hours(1))]
+    }
+
+    #[test]
+    fn quiet_list_alerts_once_and_resumes() {
+        let traffic = Traffic::new(at(0));
+        traffic.record(Some("ops"), None, at(100));
+        assert!(traffic.check(&hourly(), at(3699)).is_empty());
+        assert_eq!(
+            traffic.check(&hourly(), at(3700)),
+            vec![Alert::Quiet { list: "ops".to_string(), expected: Duration::hours(1) }]
+        );
+        assert!(traffic.is_quiet("ops"));
+        assert!(traffic.check(&hourly(), at(9000)).is_empty());
+
+        traffic.record(Some("ops"), Some(5), at(7300));
+        let alerts = traffic.check(&hourly(), at(7310));
+        assert_eq!(alerts, vec![Alert::Resumed { list: "ops".to_string(), quiet_for: Duration::hours(2) }]);
+        assert_eq!(alerts[0].notice(), "Traffic for list 'ops' resumed after 2h without deliveries.");
+        assert!(!traffic.is_quiet("ops"));
+        assert_eq!(traffic.chats(), vec![(5, at(7300))]);
+    }
+
+    #[test]
+    fn never_delivered_list_counts_from_start() {
+        let traffic = Traffic::new(at(1000));
+        assert!(traffic.check(&hourly(), at(4599)).is_empty());
+        assert_eq!(traffic.check(&hourly(), at(4600)).len(), 1);
+    }
+
+    #[test]
+    fn timestamps_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("corky-traffic-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let traffic = Traffic::load(path.clone(), at(0));
+        traffic.record(Some("ops"), Some(7), at(5000));
+        traffic.save();
+
+        // Restarted long after the last delivery but within the expected interval
+        let restarted = Traffic::load(path.clone(), at(8000));
+        assert_eq!(restarted.lists(), vec![("ops".to_string(), at(5000))]);
+        assert!(restarted.check(&hourly(), at(8599)).is_empty());
+        assert_eq!(restarted.check(&hourly(), at(8600)).len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ages_use_the_largest_unit() {
+        assert_eq!(format_age(Duration::seconds(59)), "59s");
+        assert_eq!(format_age(Duration::minutes(4)), "4m");
+        assert_eq!(format_age(Duration::minutes(150)), "2h");
+        assert_eq!(format_age(Duration::days(3)), "3d");
+    }
+}
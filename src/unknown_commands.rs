@@ -0,0 +1,173 @@
+//! Replies to messages that look like commands but are not understood.
+//!
+//! A typo such as `/hepl` would otherwise get no answer at all. Commands
+//! that are neither built in nor configured, and built-ins whose arguments
+//! do not parse, are logged and answered with a pointer to /help. Only
+//! chats that may use the bot are answered, at most once per chat every
+//! `REPLY_INTERVAL`, so two bots cannot keep answering each other. Messages
+//! from bots and replies to messages, which may be relayed, are left alone.
+
+use crate::commands::Command;
+use crate::config::TelegramSettings;
+use crate::shared_settings::SharedSettings;
+use crate::state::BotState;
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::Me;
+use teloxide::utils::command::BotCommands;
+
+/// Shortest time between two replies to the same chat
+pub const REPLY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Chats remembered before those answered longest ago are forgotten
+const MAX_CHATS: usize = 1024;
+
+/// Characters of an unknown command kept in the log
+const LOG_CHARS: usize = 100;
+
+/// A command the other handlers did not take
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unknown {
+    /// The command as typed, e.g. `/hepl`, without any `@bot`
+    pub command: String,
+    /// A built-in command whose arguments did not parse
+    pub malformed: bool,
+}
+
+/// Parse `text` as a command to this bot. `None` for text that is not a
+/// command, or a command addressed to another bot.
+pub fn parse_unknown(text: &str, bot_username: &str) -> Option<Unknown> {
+    let rest = text.strip_prefix('/')?;
+    let head = rest.split(char::is_whitespace).next().unwrap_or_default();
+    let name = match head.split_once('@') {
+        Some((name, bot)) if bot.eq_ignore_ascii_case(bot_username) => name,
+        Some(_) => return None,
+        None => head,
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let builtin = format!("/{}", name.to_lowercase());
+    let malformed = Command::bot_commands().iter().any(|command| command.command == builtin);
+    Some(Unknown { command: format!("/{}", name), malformed })
+}
+
+/// dptree filter: the unknown command in `msg`, if any
+pub fn unknown(msg: Message, me: Me) -> Option<Unknown> {
+    if msg.reply_to_message().is_some() || msg.from.as_ref().is_some_and(|user| user.is_bot) {
+        return None;
+    }
+    parse_unknown(msg.text()?, me.username())
+}
+
+/// Chats answered recently
+#[derive(Default)]
+pub struct UnknownReplies {
+    last_reply: Mutex<HashMap<i64, Instant>>,
+}
+
+impl UnknownReplies {
+    /// Whether `chat` may be answered at `now`; if so, the reply is counted
+    pub fn allow(&self, chat: i64, now: Instant) -> bool {
+        let mut last_reply = self.last_reply.lock().unwrap();
+        if last_reply.get(&chat).is_some_and(|last| now.saturating_duration_since(*last) < REPLY_INTERVAL) {
+            return false;
+        }
+        if last_reply.len() >= MAX_CHATS {
+            last_reply.retain(|_, last| now.saturating_duration_since(*last) < REPLY_INTERVAL);
+            if last_reply.len() >= MAX_CHATS {
+                return false;
+            }
+        }
+        last_reply.insert(chat, now);
+        true
+    }
+}
+
+/// The reply to `unknown` in `chat`, or `None` to stay silent
+fn reply(settings: &TelegramSettings, state: &BotState, chat: i64, unknown: &Unknown, now: Instant) -> Option<&'static str> {
+    if !settings.knows_chat(chat) || !state.unknown_replies.allow(chat, now) {
+        return None;
+    }
+    Some(if unknown.malformed { "bad_command_args" } else { "unknown_command" })
+}
+
+/// Log `unknown` and, if the chat may be answered, point it to /help
+pub async fn handle(
+    bot: Bot,
+    msg: Message,
+    unknown: Unknown,
+    shared: SharedSettings,
+    state: Arc<BotState>,
+) -> ResponseResult<()> {
+    let settings = shared.snapshot();
+    let text = crate::sender::preview(msg.text().unwrap_or_default(), LOG_CHARS);
+    let user = msg.from.as_ref().map(|user| user.id.to_string()).unwrap_or_default();
+    let Some(key) = reply(&settings, &state, msg.chat.id.0, &unknown, Instant::now()) else {
+        info!("Unrecognised command from chat {} (user {}), not answered: {:?}", msg.chat.id, user, text);
+        return Ok(());
+    };
+    info!("Unrecognised command from chat {} (user {}): {:?}", msg.chat.id, user, text);
+    let language = settings.language_for(msg.chat.id.0, msg.from.as_ref().and_then(|user| user.language_code.as_deref()));
+    let texts = settings.translations.texts(&language);
+    bot.send_message(msg.chat.id, texts.text(key, &[("command", &unknown.command)])).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    fn settings() -> TelegramSettings {
+        toml::from_str::<AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 100\n\
+             [telegram.subscriber_lists]\nops = [200]\n\
+             [telegram.commands.lights_off]\ndescription = \"Lights off\"\ndestination = \"home\"\nallowed_chats = [300]\n",
+        )
+        .unwrap()
+        .telegram
+    }
+
+    #[test]
+    fn commands_are_recognised_as_unknown_or_malformed() {
+        let unknown = |text| parse_unknown(text, "corky_bot");
+        assert_eq!(unknown("/hepl me"), Some(Unknown { command: "/hepl".to_string(), malformed: false }));
+        assert_eq!(unknown("/Hepl@Corky_Bot"), Some(Unknown { command: "/Hepl".to_string(), malformed: false }));
+        assert_eq!(unknown("/unquarantine abc"), Some(Unknown { command: "/unquarantine".to_string(), malformed: true }));
+        assert_eq!(unknown("/hepl@other_bot"), None);
+        assert_eq!(unknown("hello"), None);
+        assert_eq!(unknown("/"), None);
+        assert_eq!(unknown("/usr/bin/env"), None);
+    }
+
+    #[test]
+    fn only_known_chats_are_answered_and_not_too_often() {
+        let settings = settings();
+        let state = BotState::in_memory(&settings);
+        let typo = Unknown { command: "/hepl".to_string(), malformed: false };
+        let now = Instant::now();
+        assert_eq!(reply(&settings, &state, 100, &typo, now), Some("unknown_command"));
+        assert_eq!(reply(&settings, &state, 100, &typo, now + Duration::from_secs(1)), None);
+        assert_eq!(reply(&settings, &state, 100, &typo, now + REPLY_INTERVAL), Some("unknown_command"));
+        let malformed = Unknown { command: "/mute".to_string(), malformed: true };
+        assert_eq!(reply(&settings, &state, 200, &malformed, now), Some("bad_command_args"));
+        assert_eq!(reply(&settings, &state, 300, &typo, now), Some("unknown_command"));
+        assert_eq!(reply(&settings, &state, 999, &typo, now), None);
+    }
+
+    #[test]
+    fn the_chats_remembered_are_bounded() {
+        let replies = UnknownReplies::default();
+        let now = Instant::now();
+        for chat in 0..MAX_CHATS as i64 {
+            assert!(replies.allow(chat, now));
+        }
+        assert!(!replies.allow(-1, now));
+        assert!(replies.allow(-1, now + REPLY_INTERVAL));
+        assert_eq!(replies.last_reply.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,54 @@
+//! Cache of `@username` to chat ID lookups.
+//!
+//! Resolving a username costs a `get_chat` call, so each answer is kept for
+//! `username_cache_secs` and then looked up again in case the chat was renamed.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Resolved usernames with the time they were looked up
+pub struct Usernames {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (i64, Instant)>>,
+}
+
+impl Usernames {
+    pub fn new(ttl: Duration) -> Self {
+        Usernames { ttl, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// The cached ID for `username`, unless it is older than the TTL
+    pub fn get(&self, username: &str, now: Instant) -> Option<i64> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = username.to_ascii_lowercase();
+        match entries.get(&key) {
+            Some(&(id, at)) if now.saturating_duration_since(at) < self.ttl => Some(id),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, username: &str, id: i64, now: Instant) {
+        self.entries.lock().unwrap().insert(username.to_ascii_lowercase(), (id, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = Usernames::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(cache.get("@news", start), None);
+        cache.insert("@News", -100123, start);
+        assert_eq!(cache.get("@news", start + Duration::from_secs(59)), Some(-100123));
+        assert_eq!(cache.get("@NEWS", start + Duration::from_secs(60)), None);
+        assert_eq!(cache.get("@news", start), None);
+    }
+}
@@ -0,0 +1,1301 @@
+//! ZMQ listener (DEALER or ROUTER) and payload parsing.
+
+use crate::aggregate::Aggregator;
+use crate::compression::{self, Format, InflateError};
+use crate::config::{ChatRef, Envelope, SocketType, TelegramSettings};
+use crate::error_replies::ErrorReplies;
+use crate::queue::EventQueue;
+use crate::routes;
+use crate::sender;
+use crate::shared_settings::SharedSettings;
+use crate::state::BotState;
+use crate::trace;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "async-zmq")]
+mod task;
+#[cfg(not(feature = "async-zmq"))]
+mod blocking;
+
+/// Command carried in the JSON payload, possibly inside an array envelope
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ZmqMessage {
+    /// Several chats at once; takes precedence over `chat_id` and `subscriber_list`
+    #[serde(default)]
+    pub chat_ids: Vec<i64>,
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    /// A chat given as `"@username"` (or an ID), resolved before sending and
+    /// then used like `chat_id`
+    #[serde(default)]
+    pub chat: Option<ChatRef>,
+    #[serde(default)]
+    pub subscriber_list: Option<String>,
+    pub text: String,
+    #[serde(default)]
+    pub image_path: Option<String>,
+    /// The frame after the payload holds the raw bytes of an image to send
+    #[serde(default)]
+    pub image_frame: bool,
+    /// Those bytes, attached by the listener. Not persisted, so a deferred
+    /// message loses them across a restart.
+    #[serde(skip)]
+    pub image_bytes: Option<ImageBytes>,
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Seconds after receipt after which a message held by quiet hours is dropped
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Producer-chosen identifier, echoed back with replies to this message
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Forbid forwarding and saving; overrides the list's default when set
+    #[serde(default)]
+    pub protect_content: Option<bool>,
+    /// Blur the image behind a spoiler; overrides the list's default when set
+    #[serde(default)]
+    pub spoiler: Option<bool>,
+    /// Send links without preview cards; overrides the configured default when set
+    #[serde(default)]
+    pub disable_link_preview: Option<bool>,
+    /// How Telegram should interpret `text`; plain text when unset
+    #[serde(default)]
+    pub parse_mode: Option<ParseMode>,
+    /// Delete `image_path` once every chat got the message; overrides the
+    /// configured `delete_after_send` when set
+    #[serde(default)]
+    pub delete_after_send: Option<bool>,
+    /// Retries after a failed send; overrides the priority's default when set
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    /// Wait before the first retry, doubled for each one after it;
+    /// overrides the default 500ms when set
+    #[serde(default)]
+    pub retry_base_delay_ms: Option<u64>,
+    /// Identity of the client that sent this message, when known; set by the
+    /// listener rather than the producer
+    #[serde(skip)]
+    pub peer: Option<String>,
+    /// Free-form labels that `[[telegram.routes]]` rules can match on
+    #[serde(default)]
+    pub tags: Option<serde_json::Value>,
+    /// Correlation ID for logs, events and history; the listener cleans it
+    /// up, or generates one when the producer leaves it out
+    #[serde(default)]
+    pub trace_id: Option<String>,
+}
+
+impl ZmqMessage {
+    /// `chat_ids` without duplicates, in their original order
+    pub fn recipients(&self) -> Vec<i64> {
+        let mut seen = std::collections::HashSet::new();
+        self.chat_ids.iter().copied().filter(|id| seen.insert(*id)).collect()
+    }
+
+    /// Chats addressed by ID. With `combine` these are `chat_ids` and
+    /// `chat_id` together; otherwise `chat_ids` if set, else `chat_id`.
+    pub fn explicit_chats(&self, combine: bool) -> Vec<i64> {
+        let mut chats = self.recipients();
+        if let Some(chat_id) = self.chat_id.filter(|id| !chats.contains(id)) {
+            if combine || chats.is_empty() {
+                chats.push(chat_id);
+            }
+        }
+        chats
+    }
+
+    /// The list addressed. With `combine` this is `subscriber_list`;
+    /// otherwise only if no chat IDs take precedence.
+    pub fn target_list(&self, combine: bool) -> Option<&str> {
+        match combine {
+            true => self.subscriber_list.as_deref(),
+            false => self.broadcast_list(),
+        }
+    }
+
+    /// The list this message is broadcast to alone: `subscriber_list`, when
+    /// neither `chat_ids` nor `chat_id` is set. List options such as quiet
+    /// hours and digests only apply to these broadcasts.
+    pub fn broadcast_list(&self) -> Option<&str> {
+        match (self.chat_ids.is_empty(), self.chat_id.is_none() && self.chat.is_none()) {
+            (true, true) => self.subscriber_list.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// How many of `chat_ids`, `chat_id`, `chat` and `subscriber_list` are set
+    pub fn target_count(&self) -> usize {
+        [!self.chat_ids.is_empty(), self.chat_id.is_some(), self.chat.is_some(), self.subscriber_list.is_some()]
+            .into_iter()
+            .filter(|&set| set)
+            .count()
+    }
+
+    /// Whether an image is attached, by path or as bytes
+    pub fn has_image(&self) -> bool {
+        self.image_path.is_some() || self.image_bytes.is_some()
+    }
+
+    /// Chats addressed directly, by ID or username. Lists are not counted:
+    /// their size is up to the config, not the producer.
+    pub fn direct_target_count(&self, combine: bool) -> usize {
+        let chats = self.explicit_chats(combine).len();
+        match self.chat.is_some() && (combine || chats == 0) {
+            true => chats + 1,
+            false => chats,
+        }
+    }
+
+    /// Check the message against `limits` before anything is sent
+    pub fn validate(&self, limits: &MessageLimits) -> Result<(), ValidationError> {
+        if self.text.len() > limits.max_text_bytes {
+            return Err(ValidationError::TextTooLong { bytes: self.text.len(), max: limits.max_text_bytes });
+        }
+        let targets = self.direct_target_count(limits.combine_targets);
+        if targets > limits.max_targets {
+            return Err(ValidationError::TooManyTargets { count: targets, max: limits.max_targets });
+        }
+        if let Some(chars) = self.image_path.as_ref().map(|path| path.chars().count()) {
+            if chars > limits.max_path_chars {
+                return Err(ValidationError::PathTooLong { chars, max: limits.max_path_chars });
+            }
+        }
+        if self.chat.is_some() && self.chat_id.is_some() {
+            return Err(ValidationError::Conflict("chat", "chat_id"));
+        }
+        if self.image_frame && self.delete_after_send == Some(true) {
+            return Err(ValidationError::Conflict("image_frame", "delete_after_send"));
+        }
+        Ok(())
+    }
+}
+
+/// Limits a received message must stay within
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub max_text_bytes: usize,
+    pub max_targets: usize,
+    pub max_path_chars: usize,
+    /// Whether `chat_ids`, `chat_id` and `chat` all count, or only the first set
+    pub combine_targets: bool,
+}
+
+impl MessageLimits {
+    pub fn from_settings(settings: &TelegramSettings) -> Self {
+        MessageLimits {
+            max_text_bytes: settings.max_text_bytes,
+            max_targets: settings.max_targets_per_message,
+            max_path_chars: settings.max_image_path_chars,
+            combine_targets: settings.combine_targets,
+        }
+    }
+}
+
+/// Reasons a parsed message is refused before any Telegram call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    TextTooLong { bytes: usize, max: usize },
+    TooManyTargets { count: usize, max: usize },
+    PathTooLong { chars: usize, max: usize },
+    /// Two fields that cannot be used together
+    Conflict(&'static str, &'static str),
+}
+
+impl ValidationError {
+    /// Stable code reported back to the producer in error replies
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ValidationError::TextTooLong { .. } => "TEXT_TOO_LONG",
+            ValidationError::TooManyTargets { .. } => "TOO_MANY_TARGETS",
+            ValidationError::PathTooLong { .. } => "PATH_TOO_LONG",
+            ValidationError::Conflict(..) => "CONFLICTING_FIELDS",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::TextTooLong { bytes, max } => {
+                write!(f, "Text is {} bytes, over max_text_bytes ({})", bytes, max)
+            }
+            ValidationError::TooManyTargets { count, max } => {
+                write!(f, "Message addresses {} chats, over max_targets_per_message ({})", count, max)
+            }
+            ValidationError::PathTooLong { chars, max } => {
+                write!(f, "image_path is {} characters, over max_image_path_chars ({})", chars, max)
+            }
+            ValidationError::Conflict(a, b) => write!(f, "{} and {} cannot be used together", a, b),
+        }
+    }
+}
+
+/// Image received as a frame, shared between the chats it is sent to
+#[derive(Clone, PartialEq, Eq)]
+pub struct ImageBytes(pub Arc<Vec<u8>>);
+
+impl ImageBytes {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Only the size, so logging a message does not dump the image
+impl fmt::Debug for ImageBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ImageBytes({} bytes)", self.len())
+    }
+}
+
+impl fmt::Display for ImageBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "image frame ({} bytes)", self.len())
+    }
+}
+
+/// Markup understood in a message's text
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Telegram's HTML subset, cleaned up according to `html_mode`
+    #[serde(rename = "html", alias = "HTML")]
+    Html,
+}
+
+/// Delivery priority of a message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Sent ahead of queued normal traffic with a larger retry budget
+    High,
+}
+
+/// Control request sent instead of a message, selected by an `"action"` key
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ControlAction {
+    /// Resume deliveries to a quarantined chat
+    Unquarantine { chat_id: i64 },
+    /// Add a chat to a subscriber list; a missing list is created only
+    /// with `"create": true`
+    ListAdd {
+        list: String,
+        chat_id: i64,
+        #[serde(default)]
+        create: bool,
+    },
+    /// Remove a chat from a subscriber list, keeping the list when empty
+    ListDel { list: String, chat_id: i64 },
+}
+
+impl ControlAction {
+    /// The `"action"` value, as echoed in acknowledgements
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlAction::Unquarantine { .. } => "unquarantine",
+            ControlAction::ListAdd { .. } => "list_add",
+            ControlAction::ListDel { .. } => "list_del",
+        }
+    }
+}
+
+/// Anything a producer can ask the bot to do
+#[derive(Debug)]
+pub enum ZmqCommand {
+    Send(Box<ZmqMessage>),
+    Control(ControlAction),
+}
+
+/// Where the command lives inside a multipart message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeLayout {
+    /// In ROUTER mode every message starts with the client identity, which
+    /// `payload_frame` does not count
+    pub socket_type: SocketType,
+    pub payload_frame: usize,
+    pub envelope: Envelope,
+    pub envelope_index: usize,
+    /// gzip and zstd payloads are inflated up to this many bytes; `None` rejects
+    /// compressed payloads
+    pub max_inflated_bytes: Option<usize>,
+}
+
+impl Default for EnvelopeLayout {
+    /// frame[1] holds `[status, action, data]` and `data` is the command
+    fn default() -> Self {
+        EnvelopeLayout {
+            socket_type: SocketType::Dealer,
+            payload_frame: 1,
+            envelope: Envelope::Array,
+            envelope_index: 2,
+            max_inflated_bytes: None,
+        }
+    }
+}
+
+impl EnvelopeLayout {
+    /// Layout configured in the `zmq_socket_type` / `zmq_payload_frame` /
+    /// `zmq_envelope*` settings
+    pub fn from_settings(settings: &TelegramSettings) -> Self {
+        EnvelopeLayout {
+            socket_type: settings.zmq_socket_type,
+            payload_frame: settings.payload_frame(),
+            envelope: settings.zmq_envelope,
+            envelope_index: settings.zmq_envelope_index,
+            max_inflated_bytes: settings.zmq_accept_compressed.then_some(settings.zmq_max_inflated_bytes as usize),
+        }
+    }
+
+    /// Index of the payload among all received frames
+    pub fn payload_index(&self) -> usize {
+        match self.socket_type {
+            SocketType::Dealer => self.payload_frame,
+            SocketType::Router => self.payload_frame + 1,
+        }
+    }
+
+    /// The frame identifying the sender: the client identity our ROUTER
+    /// prepends, or the frame a broker puts before the payload
+    pub fn peer<'a>(&self, frames: &'a [Vec<u8>]) -> Option<&'a Vec<u8>> {
+        match self.socket_type {
+            SocketType::Router => frames.first(),
+            SocketType::Dealer if self.payload_frame > 0 => frames.first(),
+            SocketType::Dealer => None,
+        }
+    }
+}
+
+/// Reasons a multipart ZMQ message could not be turned into a `ZmqMessage`
+#[derive(Debug)]
+pub enum ParseError {
+    MissingFrame { index: usize, frame_count: usize },
+    /// Neither UTF-8 nor a known compression; `head` is the first bytes in hex
+    NonUtf8 { head: String },
+    /// A compressed payload while `zmq_accept_compressed` is off
+    Compressed(Format),
+    Inflate(InflateError),
+    InvalidJson(serde_json::Error),
+    NotAnArray,
+    ArrayTooShort { len: usize, needed: usize },
+    /// The command lacks a required field such as `text`
+    MissingField(serde_json::Error),
+    InvalidCommand(serde_json::Error),
+    /// `image_frame` is set but the frame after the payload is empty
+    EmptyImage,
+    /// The image frame is over `max_image_frame_bytes`
+    ImageTooLarge { size: usize, max: u64 },
+    Invalid(ValidationError),
+}
+
+impl ParseError {
+    /// Stable code reported back to the producer in error replies
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::MissingFrame { .. } | ParseError::ArrayTooShort { .. } => "SHORT_ENVELOPE",
+            ParseError::NonUtf8 { .. } => "NOT_UTF8",
+            ParseError::Compressed(_) => "COMPRESSED",
+            ParseError::Inflate(InflateError::TooLarge { .. }) => "INFLATED_TOO_LARGE",
+            ParseError::Inflate(InflateError::Corrupt(_)) => "BAD_COMPRESSION",
+            ParseError::InvalidJson(_) => "BAD_JSON",
+            ParseError::NotAnArray => "BAD_ENVELOPE",
+            ParseError::MissingField(_) => "MISSING_FIELD",
+            ParseError::InvalidCommand(_) => "INVALID_COMMAND",
+            ParseError::EmptyImage => "EMPTY_IMAGE",
+            ParseError::ImageTooLarge { .. } => "IMAGE_TOO_LARGE",
+            ParseError::Invalid(err) => err.reason(),
+        }
+    }
+
+    /// Sort a deserialization failure of the command object
+    fn from_command(err: serde_json::Error) -> Self {
+        if err.to_string().starts_with("missing field") {
+            ParseError::MissingField(err)
+        } else {
+            ParseError::InvalidCommand(err)
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingFrame { index, frame_count } => write!(
+                f,
+                "Payload frame {} missing: message has {} frame(s)",
+                index, frame_count
+            ),
+            ParseError::NonUtf8 { head } => write!(f, "Non-UTF8 payload in message (starts with {})", head),
+            ParseError::Compressed(format) => {
+                write!(f, "Payload is {}-compressed; set zmq_accept_compressed = true to accept it", format)
+            }
+            ParseError::Inflate(err) => write!(f, "{}", err),
+            ParseError::InvalidJson(err) => write!(f, "Failed to parse JSON: {:?}", err),
+            ParseError::NotAnArray => write!(f, "JSON payload is not an array"),
+            ParseError::ArrayTooShort { needed, .. } => {
+                write!(f, "JSON array too short (needs {}+ elements)", needed)
+            }
+            ParseError::MissingField(err) => write!(f, "Invalid command structure: {}", err),
+            ParseError::InvalidCommand(err) => write!(f, "Invalid command structure: {:?}", err),
+            ParseError::EmptyImage => write!(f, "Image frame is empty"),
+            ParseError::ImageTooLarge { size, max } => {
+                write!(f, "Image frame is {} bytes, over max_image_frame_bytes ({})", size, max)
+            }
+            ParseError::Invalid(err) => write!(f, "Invalid message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Extract a message to send from raw frames according to `layout`
+pub fn parse_frames(frames: &[Vec<u8>], layout: &EnvelopeLayout) -> Result<ZmqMessage, ParseError> {
+    let command = extract_command(frames, layout)?;
+    let msg = serde_json::from_value::<ZmqMessage>(command).map_err(ParseError::from_command)?;
+    attach_frames(msg, frames, layout)
+}
+
+/// Extract a message or control action from raw frames. Objects with an
+/// `"action"` key are control actions; everything else is a message.
+pub fn parse_command(frames: &[Vec<u8>], layout: &EnvelopeLayout) -> Result<ZmqCommand, ParseError> {
+    command_from_value(extract_command(frames, layout)?, frames, layout)
+}
+
+/// The message or control action in an extracted command object
+fn command_from_value(command: serde_json::Value, frames: &[Vec<u8>], layout: &EnvelopeLayout) -> Result<ZmqCommand, ParseError> {
+    if command.get("action").is_some() {
+        serde_json::from_value::<ControlAction>(command)
+            .map(ZmqCommand::Control)
+            .map_err(ParseError::from_command)
+    } else {
+        let msg = serde_json::from_value::<ZmqMessage>(command).map_err(ParseError::from_command)?;
+        Ok(ZmqCommand::Send(Box::new(attach_frames(msg, frames, layout)?)))
+    }
+}
+
+/// Bytes of a rejected payload shown in its error
+const HEX_HEAD_BYTES: usize = 32;
+
+/// The first `HEX_HEAD_BYTES` of `frame` in hex, for producers debugging
+/// what they sent
+fn hex_head(frame: &[u8]) -> String {
+    let head: String = frame.iter().take(HEX_HEAD_BYTES).map(|b| format!("{:02x}", b)).collect();
+    match frame.len() > HEX_HEAD_BYTES {
+        true => format!("{}…", head),
+        false => head,
+    }
+}
+
+/// Fill in what `msg` takes from other frames: the peer, and the image
+/// frame right after the payload when `image_frame` is set
+fn attach_frames(mut msg: ZmqMessage, frames: &[Vec<u8>], layout: &EnvelopeLayout) -> Result<ZmqMessage, ParseError> {
+    msg.peer = layout.peer(frames).map(|peer| String::from_utf8_lossy(peer).into_owned());
+    msg.trace_id = Some(trace::ensure(msg.trace_id.as_deref()));
+    if msg.image_frame {
+        let index = layout.payload_index() + 1;
+        let frame = frames.get(index).ok_or(ParseError::MissingFrame { index, frame_count: frames.len() })?;
+        if frame.is_empty() {
+            return Err(ParseError::EmptyImage);
+        }
+        msg.image_bytes = Some(ImageBytes(Arc::new(frame.clone())));
+    }
+    Ok(msg)
+}
+
+/// Reject image frames over `max` bytes
+fn check_image_size(command: ZmqCommand, max: u64) -> Result<ZmqCommand, ParseError> {
+    match &command {
+        ZmqCommand::Send(msg) => match &msg.image_bytes {
+            Some(image) if image.len() as u64 > max => Err(ParseError::ImageTooLarge { size: image.len(), max }),
+            _ => Ok(command),
+        },
+        ZmqCommand::Control(_) => Ok(command),
+    }
+}
+
+/// Reject messages outside `limits`
+fn check_limits(command: ZmqCommand, limits: &MessageLimits) -> Result<ZmqCommand, ParseError> {
+    match &command {
+        ZmqCommand::Send(msg) => msg.validate(limits).map(|()| command).map_err(ParseError::Invalid),
+        ZmqCommand::Control(_) => Ok(command),
+    }
+}
+
+/// Locate the JSON command object within the frames
+fn extract_command(frames: &[Vec<u8>], layout: &EnvelopeLayout) -> Result<serde_json::Value, ParseError> {
+    let frame = frames.get(layout.payload_index()).ok_or(ParseError::MissingFrame {
+        index: layout.payload_index(),
+        frame_count: frames.len(),
+    })?;
+    let inflated;
+    let frame = match (compression::detect(frame), layout.max_inflated_bytes) {
+        (None, _) => frame.as_slice(),
+        (Some(format), None) => return Err(ParseError::Compressed(format)),
+        (Some(format), max) => {
+            inflated = compression::inflate(frame, format, max.unwrap_or(0)).map_err(ParseError::Inflate)?;
+            debug!("ZMQ: Inflated {} byte {} payload to {} bytes", frame.len(), format, inflated.len());
+            &inflated
+        }
+    };
+    let payload = std::str::from_utf8(frame).map_err(|_| ParseError::NonUtf8 { head: hex_head(frame) })?;
+    let val = serde_json::from_str::<serde_json::Value>(payload).map_err(ParseError::InvalidJson)?;
+    let command = match layout.envelope {
+        Envelope::None => val,
+        Envelope::Array => {
+            let mut arr = match val {
+                serde_json::Value::Array(arr) => arr,
+                _ => return Err(ParseError::NotAnArray),
+            };
+            if arr.len() <= layout.envelope_index {
+                return Err(ParseError::ArrayTooShort {
+                    len: arr.len(),
+                    needed: layout.envelope_index + 1,
+                });
+            }
+            arr.swap_remove(layout.envelope_index)
+        }
+    };
+    Ok(command)
+}
+
+/// Parse and handle raw ZMQ frames. Messages pass through `aggregator` on
+/// their way to the outbox; payloads that fail to parse are answered
+/// through `replies`.
+pub fn handle_zmq_frames(
+    shared: &SharedSettings,
+    state: &BotState,
+    aggregator: &mut Aggregator,
+    replies: &mut ErrorReplies,
+    frames: Vec<Vec<u8>>,
+) {
+    let settings = &*shared.snapshot();
+    info!("ZMQ: Received message with {} frames", frames.len());
+    let layout = EnvelopeLayout::from_settings(settings);
+
+    // Log each frame concisely
+    for (i, frame) in frames.iter().enumerate() {
+        if i <= layout.payload_index() { // Only log frames up to the payload
+            match std::str::from_utf8(frame) {
+                Ok(txt) => info!("ZMQ: Frame {}: {}", i, txt),
+                Err(_) => {
+                    let hex_repr = frame.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<_>>()
+                        .join("");
+                    info!("ZMQ: Frame {} (hex): {}", i, hex_repr);
+                }
+            }
+        }
+    }
+
+    match accept_frames(settings, state, &frames, &layout) {
+        Ok(None) => {}
+        Ok(Some(ZmqCommand::Send(cmd))) => {
+            let cmd = *cmd;
+            info!("ZMQ: Successfully extracted command: {:?}", cmd);
+            let Some(cmd) = routes::apply(&settings.routes, cmd) else {
+                return;
+            };
+            if let Some((list, interval)) = settings.digest_for(&cmd) {
+                let list = list.to_string();
+                info!(
+                    "ZMQ: Buffering message for digest to '{}' trace_id={}",
+                    list,
+                    cmd.trace_id.as_deref().unwrap_or("-")
+                );
+                if let Some(digest) = state.digests.add(&list, interval, cmd, Utc::now()) {
+                    state.push_digest(digest);
+                }
+                return;
+            }
+            let window = settings.aggregate_window(&cmd);
+            for message in aggregator.offer(cmd, window, Instant::now()) {
+                state.outbox.push(message);
+            }
+        }
+        Ok(Some(ZmqCommand::Control(action))) => {
+            let ack = sender::process_control(shared, state, action);
+            replies.acknowledge(&frames, &layout, &ack);
+        }
+        Err(err) => {
+            error!("{}", err);
+            state.activity.record_parse_error(&err.to_string());
+            if let ParseError::Invalid(_) = err {
+                state.stats.record_invalid_message();
+            }
+            replies.report(&frames, &layout, &err, Instant::now());
+        }
+    }
+}
+
+/// Parse, authenticate and check the command in `frames`. `None` means it
+/// was dropped for its signature, which gets no error reply.
+pub fn accept_frames(
+    settings: &TelegramSettings,
+    state: &BotState,
+    frames: &[Vec<u8>],
+    layout: &EnvelopeLayout,
+) -> Result<Option<ZmqCommand>, ParseError> {
+    let command = extract_command(frames, layout)?;
+    if let Some(verifier) = &state.signatures {
+        if let Err(rejection) = verifier.check(&command, Utc::now().timestamp()) {
+            let peer = layout.peer(frames).map(|peer| String::from_utf8_lossy(peer).into_owned());
+            warn!("ZMQ: Rejected command from {}: {}", peer.as_deref().unwrap_or("(unknown peer)"), rejection);
+            state.stats.record_rejected_signature();
+            return Ok(None);
+        }
+    }
+    command_from_value(command, frames, layout)
+        .and_then(|command| check_image_size(command, settings.max_image_frame_bytes))
+        .and_then(|command| check_limits(command, &MessageLimits::from_settings(settings)))
+        .map(Some)
+}
+
+/// Health of the ZMQ link as seen by the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// Nothing received (or no connection) for `silent_for`
+    Down { silent_for: Duration },
+    /// Traffic resumed after being down for `down_for`
+    Up { down_for: Duration },
+}
+
+impl LinkState {
+    /// Message for the owner chat
+    pub fn notice(&self, endpoint: &str) -> String {
+        match self {
+            LinkState::Down { silent_for } => format!(
+                "Warning: nothing received from ZMQ endpoint {} for {} seconds. The producer or router may be down.",
+                endpoint,
+                silent_for.as_secs()
+            ),
+            LinkState::Up { down_for } => format!(
+                "ZMQ endpoint {} is receiving again after {} seconds of silence.",
+                endpoint,
+                down_for.as_secs()
+            ),
+        }
+    }
+}
+
+/// Minimum time between two "link down" alerts, so a flapping link does not spam the owner
+const LINK_ALERT_COOLDOWN: Duration = Duration::from_secs(30 * 60);
+
+/// Turns receive activity into link-state transitions
+pub struct LinkMonitor {
+    down_after: Option<Duration>,
+    last_activity: Instant,
+    down_since: Option<Instant>,
+    last_alert: Option<Instant>,
+}
+
+impl LinkMonitor {
+    /// `down_after` of `None` disables alerts
+    pub fn new(down_after: Option<Duration>, now: Instant) -> Self {
+        LinkMonitor { down_after, last_activity: now, down_since: None, last_alert: None }
+    }
+
+    /// Something was received. Returns `Up` if a down alert was sent earlier.
+    pub fn on_activity(&mut self, now: Instant) -> Option<LinkState> {
+        self.last_activity = now;
+        let since = self.down_since.take()?;
+        Some(LinkState::Up { down_for: now.saturating_duration_since(since) })
+    }
+
+    /// Periodic check. Returns `Down` once per outage, unless an alert went
+    /// out within the cooldown.
+    pub fn check(&mut self, now: Instant) -> Option<LinkState> {
+        let down_after = self.down_after?;
+        let silent_for = now.saturating_duration_since(self.last_activity);
+        if self.down_since.is_some() || silent_for < down_after {
+            return None;
+        }
+        if self.last_alert.is_some_and(|t| now.saturating_duration_since(t) < LINK_ALERT_COOLDOWN) {
+            return None;
+        }
+        self.down_since = Some(self.last_activity);
+        self.last_alert = Some(now);
+        Some(LinkState::Down { silent_for })
+    }
+}
+
+/// Exponential reconnect delay with jitter, so several bots reconnecting
+/// to the same router do not do so in lockstep
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+    seed: u64,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+            ^ ((std::process::id() as u64) << 32);
+        Backoff { min, max: max.max(min), attempt: 0, seed: seed | 1 }
+    }
+
+    /// Delay before the next attempt: half the exponential step plus a
+    /// random share of the other half
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .min
+            .saturating_mul(1u32.checked_shl(self.attempt).unwrap_or(u32::MAX))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = step / 2;
+        let jitter_ms = match half.as_millis() as u64 {
+            0 => 0,
+            ms => self.next_random() % (ms + 1),
+        };
+        half + Duration::from_millis(jitter_ms)
+    }
+
+    /// Start again from the minimum delay
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// xorshift64; good enough to spread reconnects apart
+    fn next_random(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+}
+
+/// Settings the listener needs, copied out of `TelegramSettings`
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    pub endpoint: String,
+    pub socket_type: SocketType,
+    pub down_alert_after: Option<Duration>,
+    pub reconnect_min: Duration,
+    pub reconnect_max: Duration,
+    pub max_consecutive_errors: u32,
+    pub poll_timeout: Duration,
+}
+
+impl ListenerOptions {
+    pub fn from_settings(settings: &TelegramSettings) -> Self {
+        ListenerOptions {
+            endpoint: settings.zmq_endpoint.clone(),
+            socket_type: settings.zmq_socket_type,
+            down_alert_after: match settings.zmq_down_alert_after_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            reconnect_min: Duration::from_millis(settings.zmq_reconnect_min_ms),
+            reconnect_max: Duration::from_millis(settings.zmq_reconnect_max_ms),
+            max_consecutive_errors: settings.zmq_max_consecutive_errors,
+            poll_timeout: Duration::from_millis(settings.zmq_poll_timeout_ms),
+        }
+    }
+}
+
+/// Create the configured socket and attach it to `endpoint`: a DEALER with
+/// the bot's identity connects to the broker, a ROUTER binds for producers
+fn open_socket(context: &zmq::Context, options: &ListenerOptions) -> Result<zmq::Socket, String> {
+    let endpoint = options.endpoint.as_str();
+    let socket = match options.socket_type {
+        SocketType::Dealer => {
+            let socket = context.socket(zmq::DEALER).map_err(|e| format!("create socket: {:?}", e))?;
+            // Same identity as the Python producer expects
+            socket.set_identity(b"telegram").map_err(|e| format!("set identity: {:?}", e))?;
+            socket
+        }
+        SocketType::Router => context.socket(zmq::ROUTER).map_err(|e| format!("create socket: {:?}", e))?,
+    };
+    if let Err(e) = socket.set_linger(0) {
+        warn!("Failed to set ZMQ linger option: {:?}", e);
+    }
+    match options.socket_type {
+        SocketType::Dealer => {
+            if let Err(e) = socket.set_reconnect_ivl(1000) {
+                warn!("Failed to set ZMQ reconnect interval: {:?}", e);
+            }
+            if let Err(e) = socket.set_reconnect_ivl_max(30000) {
+                warn!("Failed to set ZMQ max reconnect interval: {:?}", e);
+            }
+            info!("ZMQ: DEALER socket connecting to {}", endpoint);
+            socket.connect(endpoint).map_err(|e| format!("connect: {:?}", e))?;
+        }
+        SocketType::Router => {
+            info!("ZMQ: ROUTER socket binding {}", endpoint);
+            socket.bind(endpoint).map_err(|e| format!("bind: {:?}", e))?;
+        }
+    }
+    Ok(socket)
+}
+
+/// Handle to the running listener
+pub struct Listener {
+    outbound: mpsc::Sender<Vec<Vec<u8>>>,
+    #[cfg(feature = "async-zmq")]
+    handle: tokio::task::JoinHandle<()>,
+    #[cfg(not(feature = "async-zmq"))]
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl Listener {
+    /// Sender for multipart messages to write back on the socket; in ROUTER
+    /// mode the first frame selects the client
+    pub fn outbound(&self) -> mpsc::Sender<Vec<Vec<u8>>> {
+        self.outbound.clone()
+    }
+
+    /// Wait for the listener to exit after `shutdown` was cancelled
+    pub async fn join(self) {
+        #[cfg(feature = "async-zmq")]
+        let result = self.handle.await.map_err(|e| format!("{:?}", e));
+        #[cfg(not(feature = "async-zmq"))]
+        let result = match tokio::task::spawn_blocking(move || self.handle.join()).await {
+            Ok(joined) => joined.map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+        if let Err(err) = result {
+            error!("ZMQ listener panicked: {}", err);
+        }
+    }
+}
+
+/// Start the ZMQ listener. Received multipart messages are pushed onto
+/// `queue` until `shutdown` is cancelled or the queue closes. With the
+/// `async-zmq` feature it runs as a tokio task, otherwise on its own thread.
+pub fn spawn(options: ListenerOptions, queue: Arc<EventQueue>, shutdown: CancellationToken) -> Listener {
+    let (outbound, mut outbound_rx) = mpsc::channel(OUTBOUND_CAPACITY);
+    #[cfg(feature = "async-zmq")]
+    let handle = tokio::spawn(async move { task::run(&options, &queue, &shutdown, &mut outbound_rx).await });
+    #[cfg(not(feature = "async-zmq"))]
+    let handle = std::thread::spawn(move || blocking::run(&options, &queue, &shutdown, &mut outbound_rx));
+    Listener { outbound, handle }
+}
+
+/// Outbound messages buffered while the socket is busy or reconnecting
+const OUTBOUND_CAPACITY: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_monitor_alerts_once_per_outage() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut monitor = LinkMonitor::new(Some(Duration::from_secs(60)), t0);
+        assert_eq!(monitor.check(secs(59)), None);
+        assert_eq!(monitor.check(secs(60)), Some(LinkState::Down { silent_for: Duration::from_secs(60) }));
+        assert_eq!(monitor.check(secs(120)), None);
+        assert_eq!(monitor.on_activity(secs(130)), Some(LinkState::Up { down_for: Duration::from_secs(130) }));
+        assert_eq!(monitor.on_activity(secs(131)), None);
+    }
+
+    #[test]
+    fn link_monitor_rate_limits_flapping() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut monitor = LinkMonitor::new(Some(Duration::from_secs(60)), t0);
+        assert!(monitor.check(secs(60)).is_some());
+        assert!(monitor.on_activity(secs(70)).is_some());
+        // Second outage within the cooldown: no alert, and so no recovery notice
+        assert_eq!(monitor.check(secs(200)), None);
+        assert_eq!(monitor.on_activity(secs(210)), None);
+        // Once the cooldown has passed, alerts resume
+        assert!(monitor.check(secs(60 + LINK_ALERT_COOLDOWN.as_secs())).is_some());
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let bounds = [(50, 100), (100, 200), (200, 400), (400, 800), (500, 1000), (500, 1000)];
+        for (low, high) in bounds {
+            let delay = backoff.next_delay().as_millis();
+            assert!((low..=high).contains(&delay), "{} not in {}..={}", delay, low, high);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backoff_jitter_varies() {
+        let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(10));
+        let delays: std::collections::HashSet<_> = (0..20).map(|_| backoff.next_delay()).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn link_monitor_disabled() {
+        let t0 = Instant::now();
+        let mut monitor = LinkMonitor::new(None, t0);
+        assert_eq!(monitor.check(t0 + Duration::from_secs(86_400)), None);
+    }
+
+    fn frames(payload: &[u8]) -> Vec<Vec<u8>> {
+        vec![b"sender".to_vec(), payload.to_vec()]
+    }
+
+    fn parse_frames_default(frames: &[Vec<u8>]) -> Result<ZmqMessage, ParseError> {
+        parse_frames(frames, &EnvelopeLayout::default())
+    }
+
+    #[test]
+    fn parses_chat_id_message() {
+        let cmd = parse_frames_default(&frames(br#"["ok", "send_message", {"chat_id": 42, "text": "hi"}]"#)).unwrap();
+        assert_eq!(cmd.chat_id, Some(42));
+        assert_eq!(cmd.text, "hi");
+        assert!(cmd.subscriber_list.is_none());
+        assert!(cmd.image_path.is_none());
+    }
+
+    #[test]
+    fn parses_subscriber_list_with_image() {
+        let cmd = parse_frames_default(&frames(
+            br#"["ok", "send_message", {"subscriber_list": "team", "text": "x", "image_path": "/tmp/a.png"}]"#,
+        ))
+        .unwrap();
+        assert_eq!(cmd.subscriber_list.as_deref(), Some("team"));
+        assert_eq!(cmd.image_path.as_deref(), Some("/tmp/a.png"));
+    }
+
+    #[test]
+    fn chat_ids_take_precedence_and_are_deduplicated() {
+        let cmd = parse_frames_default(&frames(
+            br#"["ok", "send_message", {"chat_ids": [3, 1, 3, 2, 1], "chat_id": 9, "subscriber_list": "team", "text": "x"}]"#,
+        ))
+        .unwrap();
+        assert_eq!(cmd.recipients(), vec![3, 1, 2]);
+        assert_eq!(cmd.target_count(), 3);
+        assert_eq!(cmd.broadcast_list(), None);
+        assert_eq!(cmd.explicit_chats(true), vec![3, 1, 2, 9]);
+        assert_eq!(cmd.explicit_chats(false), vec![3, 1, 2]);
+        assert_eq!(cmd.target_list(true), Some("team"));
+        assert_eq!(cmd.target_list(false), None);
+        let list = parse_frames_default(&frames(br#"["ok", "send_message", {"subscriber_list": "team", "text": "x"}]"#)).unwrap();
+        assert_eq!(list.broadcast_list(), Some("team"));
+    }
+
+    #[test]
+    fn ignores_extra_frames_and_elements() {
+        let mut f = frames(br#"["ok", "send_message", {"text": "hi"}, "extra"]"#);
+        f.push(b"trailing".to_vec());
+        assert_eq!(parse_frames_default(&f).unwrap().text, "hi");
+    }
+
+    #[test]
+    fn image_frame_follows_the_payload() {
+        let mut f = frames(br#"["ok", "send_message", {"chat_id": 1, "text": "chart", "image_frame": true}]"#);
+        assert!(matches!(parse_frames_default(&f), Err(ParseError::MissingFrame { index: 2, frame_count: 2 })));
+        f.push(Vec::new());
+        assert!(matches!(parse_frames_default(&f), Err(ParseError::EmptyImage)));
+        f[2] = vec![0x89, b'P', b'N', b'G'];
+        let cmd = parse_frames_default(&f).unwrap();
+        assert_eq!(cmd.image_bytes.as_ref().map(|image| image.0.as_slice()), Some(&[0x89, b'P', b'N', b'G'][..]));
+        assert!(cmd.has_image() && cmd.image_path.is_none());
+        assert!(format!("{:?}", cmd).contains("ImageBytes(4 bytes)"));
+
+        // Without the flag a trailing frame is ignored as before
+        let plain = frames(br#"["ok", "send_message", {"text": "hi"}]"#);
+        assert!(parse_frames_default(&[plain, vec![b"png".to_vec()]].concat()).unwrap().image_bytes.is_none());
+    }
+
+    #[test]
+    fn image_frames_over_the_cap_are_rejected() {
+        let mut f = frames(br#"["ok", "send_message", {"text": "chart", "image_frame": true}]"#);
+        f.push(vec![0; 10]);
+        let command = || parse_command(&f, &EnvelopeLayout::default()).unwrap();
+        assert!(check_image_size(command(), 10).is_ok());
+        let err = check_image_size(command(), 9).unwrap_err();
+        assert_eq!(err.reason(), "IMAGE_TOO_LARGE");
+        assert_eq!(err.to_string(), "Image frame is 10 bytes, over max_image_frame_bytes (9)");
+    }
+
+    fn limits() -> MessageLimits {
+        MessageLimits { max_text_bytes: 5, max_targets: 2, max_path_chars: 4, combine_targets: true }
+    }
+
+    fn message(command: serde_json::Value) -> ZmqMessage {
+        serde_json::from_value(command).unwrap()
+    }
+
+    #[test]
+    fn validation_limits_are_inclusive() {
+        let reason = |command| message(command).validate(&limits()).map_err(|err| err.reason());
+        assert_eq!(reason(serde_json::json!({"text": "héllo"[..5]})), Ok(()));
+        assert_eq!(reason(serde_json::json!({"text": "héllo"})), Err("TEXT_TOO_LONG"));
+        assert_eq!(reason(serde_json::json!({"text": "", "chat_ids": [1, 2]})), Ok(()));
+        assert_eq!(reason(serde_json::json!({"text": "", "chat_ids": [1, 2, 3]})), Err("TOO_MANY_TARGETS"));
+        // Counted in characters, not bytes
+        assert_eq!(reason(serde_json::json!({"text": "", "image_path": "/é.p"})), Ok(()));
+        assert_eq!(reason(serde_json::json!({"text": "", "image_path": "/a.png"})), Err("PATH_TOO_LONG"));
+
+        let err = message(serde_json::json!({"text": "too long"})).validate(&limits()).unwrap_err();
+        assert_eq!(err.to_string(), "Text is 8 bytes, over max_text_bytes (5)");
+    }
+
+    #[test]
+    fn validation_counts_targets_as_they_would_be_sent() {
+        let count = |command, combine| message(command).direct_target_count(combine);
+        // Duplicates and a chat_id already among chat_ids are sent once
+        assert_eq!(count(serde_json::json!({"text": "", "chat_ids": [1, 1, 2], "chat_id": 2}), true), 2);
+        assert_eq!(count(serde_json::json!({"text": "", "chat_ids": [1, 2], "chat_id": 3}), true), 3);
+        // Without combine_targets only the first target set counts
+        assert_eq!(count(serde_json::json!({"text": "", "chat_ids": [1, 2], "chat_id": 3}), false), 2);
+        assert_eq!(count(serde_json::json!({"text": "", "chat_ids": [1], "chat": "@news"}), false), 1);
+        assert_eq!(count(serde_json::json!({"text": "", "chat_ids": [1], "chat": "@news"}), true), 2);
+        // A list's members are not the producer's to count
+        assert_eq!(count(serde_json::json!({"text": "", "chat_id": 1, "subscriber_list": "all"}), true), 1);
+
+        let combined = message(serde_json::json!({"text": "", "chat_ids": [1, 2], "chat_id": 3, "subscriber_list": "all"}));
+        assert!(combined.validate(&limits()).is_err());
+        assert!(combined.validate(&MessageLimits { combine_targets: false, ..limits() }).is_ok());
+    }
+
+    #[test]
+    fn validation_rejects_conflicting_fields() {
+        let err = message(serde_json::json!({"text": "", "chat": "@news", "chat_id": 1})).validate(&limits()).unwrap_err();
+        assert_eq!(err, ValidationError::Conflict("chat", "chat_id"));
+        assert_eq!(err.to_string(), "chat and chat_id cannot be used together");
+        let frame_delete = message(serde_json::json!({"text": "", "image_frame": true, "delete_after_send": true}));
+        assert_eq!(frame_delete.validate(&limits()).unwrap_err().reason(), "CONFLICTING_FIELDS");
+        let frame_keep = message(serde_json::json!({"text": "", "image_frame": true, "delete_after_send": false}));
+        assert!(frame_keep.validate(&limits()).is_ok());
+    }
+
+    #[test]
+    fn invalid_messages_are_never_queued() {
+        let mut settings = toml::from_str::<crate::config::AppConfig>("[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n")
+            .unwrap()
+            .telegram;
+        settings.max_targets_per_message = 2;
+        let state = BotState::in_memory(&settings);
+        let payload = br#"["ok", "send_message", {"chat_ids": [1, 2, 3], "text": "hi"}]"#;
+        handle_zmq_frames(&settings.clone().into(), &state, &mut Aggregator::default(), &mut ErrorReplies::disabled(), frames(payload));
+        assert!(state.outbox.try_next(crate::outbox::Serve::All).is_none());
+        assert_eq!(state.stats.snapshot().invalid_messages, 1);
+    }
+
+    #[test]
+    fn untargeted_messages_are_routed_before_queueing() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\n\
+             [telegram.subscriber_lists]\nstorage = [2]\n\
+             [[telegram.routes]]\ntag = \"/team\"\nequals = \"storage\"\nlist = \"storage\"\n\
+             [[telegram.routes]]\nstarts_with = \"[DEBUG]\"\ndrop = true\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        let send = |payload: &[u8]| {
+            handle_zmq_frames(&settings.clone().into(), &state, &mut Aggregator::default(), &mut ErrorReplies::disabled(), frames(payload));
+            state.outbox.try_next(crate::outbox::Serve::All)
+        };
+        let routed = send(br#"["ok", "send_message", {"text": "disk full", "tags": {"team": "storage"}}]"#).unwrap();
+        assert_eq!(routed.subscriber_list.as_deref(), Some("storage"));
+        assert!(send(br#"["ok", "send_message", {"text": "[DEBUG] noise"}]"#).is_none());
+        let direct = send(br#"["ok", "send_message", {"chat_id": 5, "text": "[DEBUG] kept"}]"#).unwrap();
+        assert_eq!(direct.chat_id, Some(5));
+    }
+
+    #[test]
+    fn only_signed_commands_are_queued_when_a_secret_is_set() {
+        let settings = toml::from_str::<crate::config::AppConfig>(
+            "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 1\nzmq_hmac_secret = \"0123456789abcdef\"\n",
+        )
+        .unwrap()
+        .telegram;
+        let state = BotState::in_memory(&settings);
+        let handle = |command: &serde_json::Value| {
+            let payload = serde_json::json!(["ok", "send_message", command]).to_string();
+            handle_zmq_frames(&settings.clone().into(), &state, &mut Aggregator::default(), &mut ErrorReplies::disabled(), frames(payload.as_bytes()));
+            state.outbox.try_next(crate::outbox::Serve::All).map(|cmd| cmd.text)
+        };
+        let mut command = serde_json::json!({"chat_id": 5, "text": "signed", "ts": Utc::now().timestamp()});
+        assert_eq!(handle(&command), None);
+        command["sig"] = serde_json::Value::String(crate::signing::sign_command(b"0123456789abcdef", &command).unwrap());
+        assert_eq!(handle(&command).as_deref(), Some("signed"));
+        // The same payload again is a replay
+        assert_eq!(handle(&command), None);
+    }
+
+    #[test]
+    fn rejects_too_few_frames() {
+        let err = parse_frames_default(&[b"only".to_vec()]).unwrap_err();
+        assert!(matches!(err, ParseError::MissingFrame { index: 1, frame_count: 1 }));
+        assert_eq!(err.to_string(), "Payload frame 1 missing: message has 1 frame(s)");
+    }
+
+    #[test]
+    fn rejects_non_utf8_payload() {
+        assert!(matches!(parse_frames_default(&frames(&[0xff, 0xfe])), Err(ParseError::NonUtf8 { .. })));
+        let err = parse_frames_default(&frames(&[0xff; 40])).unwrap_err();
+        assert_eq!(err.to_string(), format!("Non-UTF8 payload in message (starts with {}…)", "ff".repeat(32)));
+    }
+
+    #[test]
+    fn compressed_payloads_are_inflated_when_accepted() {
+        let json = br#"["ok", "send_message", {"chat_id": 42, "text": "hi"}]"#;
+        let f = frames(&crate::compression::gzip(json));
+        assert_eq!(parse_frames_default(&f).unwrap_err().reason(), "COMPRESSED");
+        let layout = EnvelopeLayout { max_inflated_bytes: Some(1024), ..EnvelopeLayout::default() };
+        assert_eq!(parse_frames(&f, &layout).unwrap().text, "hi");
+
+        let small = EnvelopeLayout { max_inflated_bytes: Some(16), ..EnvelopeLayout::default() };
+        assert_eq!(parse_frames(&f, &small).unwrap_err().reason(), "INFLATED_TOO_LARGE");
+        let truncated = frames(&crate::compression::gzip(json)[..30]);
+        assert_eq!(parse_frames(&truncated, &layout).unwrap_err().reason(), "BAD_COMPRESSION");
+        let zstd = frames(&crate::compression::zstd(json));
+        assert_eq!(parse_frames_default(&zstd).unwrap_err().reason(), "COMPRESSED");
+        assert_eq!(parse_frames(&zstd, &layout).unwrap().text, "hi");
+        assert_eq!(parse_frames(&zstd, &small).unwrap_err().reason(), "INFLATED_TOO_LARGE");
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        assert!(matches!(parse_frames_default(&frames(b"{not json")), Err(ParseError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn rejects_non_array_payload() {
+        assert!(matches!(parse_frames_default(&frames(br#"{"text": "hi"}"#)), Err(ParseError::NotAnArray)));
+    }
+
+    #[test]
+    fn rejects_short_array() {
+        assert!(matches!(parse_frames_default(&frames(br#"["ok", "send_message"]"#)), Err(ParseError::ArrayTooShort { len: 2, needed: 3 })));
+    }
+
+    #[test]
+    fn rejects_missing_text() {
+        assert!(matches!(
+            parse_frames_default(&frames(br#"["ok", "send_message", {"chat_id": 1}]"#)),
+            Err(ParseError::MissingField(_))
+        ));
+    }
+
+    #[test]
+    fn single_frame_bare_object() {
+        let layout = EnvelopeLayout { payload_frame: 0, envelope: Envelope::None, ..EnvelopeLayout::default() };
+        let cmd = parse_frames(&[br#"{"chat_id": 5, "text": "solo"}"#.to_vec()], &layout).unwrap();
+        assert_eq!(cmd.chat_id, Some(5));
+        assert_eq!(cmd.text, "solo");
+    }
+
+    #[test]
+    fn sender_is_captured_as_peer() {
+        let cmd = parse_frames_default(&frames(br#"["ok", "send_message", {"text": "hi"}]"#)).unwrap();
+        assert_eq!(cmd.peer.as_deref(), Some("sender"));
+        let layout = EnvelopeLayout { payload_frame: 0, envelope: Envelope::None, ..EnvelopeLayout::default() };
+        let cmd = parse_frames(&[br#"{"text": "solo"}"#.to_vec()], &layout).unwrap();
+        assert_eq!(cmd.peer, None);
+    }
+
+    #[test]
+    fn trace_ids_are_cleaned_or_generated() {
+        let cmd = parse_frames_default(&frames(br#"["ok", "send_message", {"text": "hi", "trace_id": "job 7\n"}]"#)).unwrap();
+        assert_eq!(cmd.trace_id.as_deref(), Some("job7"));
+        let cmd = parse_frames_default(&frames(br#"["ok", "send_message", {"text": "hi"}]"#)).unwrap();
+        assert!(cmd.trace_id.is_some_and(|id| id.len() == 36));
+    }
+
+    #[test]
+    fn router_mode_skips_the_client_identity() {
+        let layout = EnvelopeLayout { socket_type: SocketType::Router, payload_frame: 0, ..EnvelopeLayout::default() };
+        let received = vec![b"script-a".to_vec(), br#"["ok", "send_message", {"text": "direct"}]"#.to_vec()];
+        let cmd = parse_frames(&received, &layout).unwrap();
+        assert_eq!(cmd.text, "direct");
+        assert_eq!(cmd.peer.as_deref(), Some("script-a"));
+
+        // A client still sending the broker-style [sender, payload] frames
+        let layout = EnvelopeLayout { payload_frame: 1, ..layout };
+        let received = vec![b"script-b".to_vec(), b"producer".to_vec(), br#"["ok", "x", {"text": "legacy"}]"#.to_vec()];
+        let cmd = parse_frames(&received, &layout).unwrap();
+        assert_eq!(cmd.text, "legacy");
+        assert_eq!(cmd.peer.as_deref(), Some("script-b"));
+    }
+
+    #[test]
+    fn router_mode_reports_missing_payload_by_received_index() {
+        let layout = EnvelopeLayout { socket_type: SocketType::Router, payload_frame: 0, ..EnvelopeLayout::default() };
+        let err = parse_frames(&[b"script-a".to_vec()], &layout).unwrap_err();
+        assert!(matches!(err, ParseError::MissingFrame { index: 1, frame_count: 1 }));
+        // The identity alone is enough to answer the client
+        assert_eq!(layout.peer(&[b"script-a".to_vec()]).map(Vec::as_slice), Some(&b"script-a"[..]));
+    }
+
+    #[test]
+    fn custom_envelope_index() {
+        let layout = EnvelopeLayout { envelope_index: 1, ..EnvelopeLayout::default() };
+        let cmd = parse_frames(&frames(br#"["send_message", {"text": "at one"}]"#), &layout).unwrap();
+        assert_eq!(cmd.text, "at one");
+    }
+
+    #[test]
+    fn envelope_index_out_of_range() {
+        let layout = EnvelopeLayout { envelope_index: 4, ..EnvelopeLayout::default() };
+        let err = parse_frames(&frames(br#"["a", "b", {"text": "x"}]"#), &layout).unwrap_err();
+        assert_eq!(err.to_string(), "JSON array too short (needs 5+ elements)");
+    }
+
+    #[test]
+    fn payload_frame_beyond_received_frames() {
+        let layout = EnvelopeLayout { payload_frame: 3, ..EnvelopeLayout::default() };
+        let err = parse_frames(&frames(br#"["a", "b", {"text": "x"}]"#), &layout).unwrap_err();
+        assert_eq!(err.to_string(), "Payload frame 3 missing: message has 2 frame(s)");
+    }
+
+    #[test]
+    fn parses_unquarantine_action() {
+        let cmd = parse_command(
+            &frames(br#"["ok", "control", {"action": "unquarantine", "chat_id": 12}]"#),
+            &EnvelopeLayout::default(),
+        )
+        .unwrap();
+        assert!(matches!(cmd, ZmqCommand::Control(ControlAction::Unquarantine { chat_id: 12 })));
+    }
+
+    #[test]
+    fn parses_list_actions() {
+        let parse = |payload: &[u8]| match parse_command(&frames(payload), &EnvelopeLayout::default()) {
+            Ok(ZmqCommand::Control(action)) => action,
+            other => panic!("not a control action: {:?}", other),
+        };
+        let add = parse(br#"["ok", "control", {"action": "list_add", "list": "ops", "chat_id": -100123}]"#);
+        assert_eq!(add, ControlAction::ListAdd { list: "ops".into(), chat_id: -100123, create: false });
+        assert_eq!(add.name(), "list_add");
+        let create = parse(br#"["ok", "control", {"action": "list_add", "list": "new", "chat_id": 5, "create": true}]"#);
+        assert_eq!(create, ControlAction::ListAdd { list: "new".into(), chat_id: 5, create: true });
+        let del = parse(br#"["ok", "control", {"action": "list_del", "list": "ops", "chat_id": 5}]"#);
+        assert_eq!(del, ControlAction::ListDel { list: "ops".into(), chat_id: 5 });
+        let err = parse_command(&frames(br#"["ok", "control", {"action": "list_del", "list": "ops"}]"#), &EnvelopeLayout::default());
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn messages_without_action_are_sends() {
+        let cmd = parse_command(&frames(br#"["ok", "send_message", {"text": "hi"}]"#), &EnvelopeLayout::default()).unwrap();
+        assert!(matches!(cmd, ZmqCommand::Send(m) if m.text == "hi"));
+    }
+
+    #[test]
+    fn rejects_unknown_action() {
+        let err = parse_command(&frames(br#"["ok", "control", {"action": "reboot"}]"#), &EnvelopeLayout::default());
+        assert!(matches!(err, Err(ParseError::InvalidCommand(_))));
+    }
+}
@@ -0,0 +1,139 @@
+//! Listener running on a dedicated OS thread with blocking `zmq::poll`.
+//!
+//! Fallback for builds without the `async-zmq` feature.
+
+use super::{open_socket, Backoff, LinkMonitor, LinkState, ListenerOptions};
+use crate::queue::{Event, EventQueue};
+use log::{error, info, trace, warn};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Report a link-state change to the event loop. Returns false once the
+/// queue has closed.
+fn report(change: Option<LinkState>, queue: &EventQueue, shutdown: &CancellationToken) -> bool {
+    match change {
+        Some(state) => {
+            warn!("ZMQ: Link state changed: {:?}", state);
+            queue.push(Event::ZmqStateChanged(state), shutdown).is_ok()
+        }
+        None => true,
+    }
+}
+
+/// Sleep for the next backoff delay, waking early on shutdown
+fn wait_before_reconnect(backoff: &mut Backoff, shutdown: &CancellationToken) {
+    let delay = backoff.next_delay();
+    info!("ZMQ: Reconnecting in {}ms", delay.as_millis());
+    let deadline = Instant::now() + delay;
+    while !shutdown.is_cancelled() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(Duration::from_millis(100)));
+    }
+}
+
+/// Blocking listener loop: connect a DEALER (or bind a ROUTER) socket at the endpoint, push
+/// every received multipart message onto `queue`, and reconnect after
+/// repeated errors. Returns once `shutdown` is set or the queue closes.
+pub(super) fn run(
+    options: &ListenerOptions,
+    queue: &EventQueue,
+    shutdown: &CancellationToken,
+    outbound: &mut mpsc::Receiver<Vec<Vec<u8>>>,
+) {
+    info!("ZMQ: Starting listener thread");
+    let endpoint = options.endpoint.as_str();
+    let context = zmq::Context::new();
+    let mut monitor = LinkMonitor::new(options.down_alert_after, Instant::now());
+    let mut backoff = Backoff::new(options.reconnect_min, options.reconnect_max);
+
+    // Outer reconnection loop
+    while !shutdown.is_cancelled() {
+        if !report(monitor.check(Instant::now()), queue, shutdown) {
+            return;
+        }
+
+        let socket = match open_socket(&context, options) {
+            Ok(socket) => {
+                info!("ZMQ: Listening on {}", endpoint);
+                socket
+            }
+            Err(e) => {
+                error!("Failed to set up ZMQ socket: {}", e);
+                wait_before_reconnect(&mut backoff, shutdown);
+                continue;
+            }
+        };
+
+        // Create items for polling, similar to Python implementation
+        let mut items = [socket.as_poll_item(zmq::POLLIN)];
+        info!("ZMQ: Entering polling loop");
+
+        // Connection health check tracker
+        let mut consecutive_errors = 0;
+        let max_consecutive_errors = options.max_consecutive_errors.max(1);
+
+        // Inner polling loop - runs until max consecutive errors or shutdown
+        while consecutive_errors < max_consecutive_errors && !shutdown.is_cancelled() {
+            // Outbound messages go out between polls, so they may wait up to one poll timeout
+            while let Ok(frames) = outbound.try_recv() {
+                if let Err(err) = socket.send_multipart(frames, 0) {
+                    error!("ZMQ send error: {:?}", err);
+                }
+            }
+
+            // Poll with timeout, which also paces the link health checks
+            match zmq::poll(&mut items, options.poll_timeout.as_millis() as i64) {
+                Ok(0) => {
+                    // No events, just a timeout
+                    trace!("ZMQ: Poll timeout, connection still alive");
+                    if !report(monitor.check(Instant::now()), queue, shutdown) {
+                        return;
+                    }
+                },
+                Ok(_) => {
+                    // Check if our socket has data
+                    if items[0].get_revents().contains(zmq::POLLIN) {
+                        match socket.recv_multipart(0) {
+                            Ok(frames) => {
+                                info!("ZMQ: Received message with {} frames", frames.len());
+                                if !report(monitor.on_activity(Instant::now()), queue, shutdown) {
+                                    return;
+                                }
+                                backoff.reset();
+                                if queue.push(Event::Zmq(frames), shutdown).is_err() {
+                                    info!("ZMQ: Event queue closed, shutting down");
+                                    return;
+                                }
+                                consecutive_errors = 0;
+                            }
+                            Err(err) => {
+                                error!("ZMQ recv error: {:?}", err);
+                                consecutive_errors += 1;
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("ZMQ poll error: {:?}", err);
+                    consecutive_errors += 1;
+                }
+            }
+        }
+
+        if shutdown.is_cancelled() {
+            break;
+        }
+
+        // If we reached max consecutive errors, close socket and reconnect
+        error!("ZMQ: Too many consecutive errors ({}), reconnecting...", max_consecutive_errors);
+        drop(socket);
+        wait_before_reconnect(&mut backoff, shutdown);
+    }
+
+    info!("ZMQ: Listener thread exiting");
+}
@@ -0,0 +1,176 @@
+//! Listener running as a tokio task.
+//!
+//! The socket is registered with the runtime through its signalling
+//! fd (`ZMQ_FD`), so receives, sends, reconnect sleeps and shutdown can all
+//! be awaited in one `select!`.
+
+use super::{open_socket, Backoff, LinkMonitor, LinkState, ListenerOptions};
+use crate::queue::{Event, EventQueue};
+use log::{error, info, trace, warn};
+use std::time::Instant;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// DEALER or ROUTER socket driven by the tokio reactor
+struct AsyncSocket {
+    fd: AsyncFd<zmq::Socket>,
+}
+
+impl AsyncSocket {
+    /// Create the configured socket and connect or bind it
+    fn open(context: &zmq::Context, options: &ListenerOptions) -> Result<Self, String> {
+        let socket = open_socket(context, options)?;
+        let fd = AsyncFd::with_interest(socket, tokio::io::Interest::READABLE)
+            .map_err(|e| format!("register fd: {}", e))?;
+        Ok(AsyncSocket { fd })
+    }
+
+    /// Receive one multipart message. ZMQ_FD is edge-triggered and only
+    /// says "events may have changed", so always try a non-blocking receive
+    /// before waiting on it.
+    async fn recv(&mut self) -> Result<Vec<Vec<u8>>, zmq::Error> {
+        loop {
+            match self.fd.get_ref().recv_multipart(zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => {}
+                result => return result,
+            }
+            match self.fd.readable_mut().await {
+                Ok(mut guard) => guard.clear_ready(),
+                Err(e) => {
+                    error!("ZMQ fd error: {}", e);
+                    return Err(zmq::Error::EFAULT);
+                }
+            }
+        }
+    }
+
+    /// Send one multipart message, waiting while the socket cannot accept it
+    async fn send(&mut self, frames: Vec<Vec<u8>>) -> Result<(), zmq::Error> {
+        loop {
+            match self.fd.get_ref().send_multipart(&frames, zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => {}
+                result => return result,
+            }
+            match self.fd.readable_mut().await {
+                Ok(mut guard) => guard.clear_ready(),
+                Err(e) => {
+                    error!("ZMQ fd error: {}", e);
+                    return Err(zmq::Error::EFAULT);
+                }
+            }
+        }
+    }
+}
+
+/// Report a link-state change to the event loop. Returns false once the
+/// queue has closed.
+async fn report(change: Option<LinkState>, queue: &EventQueue, shutdown: &CancellationToken) -> bool {
+    match change {
+        Some(state) => {
+            warn!("ZMQ: Link state changed: {:?}", state);
+            queue.push_async(Event::ZmqStateChanged(state), shutdown).await.is_ok()
+        }
+        None => true,
+    }
+}
+
+/// Sleep for the next backoff delay, waking early on shutdown
+async fn wait_before_reconnect(backoff: &mut Backoff, shutdown: &CancellationToken) {
+    let delay = backoff.next_delay();
+    info!("ZMQ: Reconnecting in {}ms", delay.as_millis());
+    tokio::select! {
+        _ = time::sleep(delay) => {}
+        _ = shutdown.cancelled() => {}
+    }
+}
+
+/// Listener loop: connect a DEALER (or bind a ROUTER) socket at the endpoint, push every
+/// received multipart message onto `queue`, write `outbound` messages back,
+/// and reconnect after repeated errors. Returns once `shutdown` is cancelled
+/// or the queue closes.
+pub(super) async fn run(
+    options: &ListenerOptions,
+    queue: &EventQueue,
+    shutdown: &CancellationToken,
+    outbound: &mut mpsc::Receiver<Vec<Vec<u8>>>,
+) {
+    info!("ZMQ: Starting listener task");
+    let endpoint = options.endpoint.as_str();
+    let context = zmq::Context::new();
+    let mut monitor = LinkMonitor::new(options.down_alert_after, Instant::now());
+    let mut backoff = Backoff::new(options.reconnect_min, options.reconnect_max);
+
+    // Outer reconnection loop
+    while !shutdown.is_cancelled() {
+        if !report(monitor.check(Instant::now()), queue, shutdown).await {
+            return;
+        }
+
+        let mut socket = match AsyncSocket::open(&context, options) {
+            Ok(socket) => {
+                info!("ZMQ: Listening on {}", endpoint);
+                socket
+            }
+            Err(e) => {
+                error!("Failed to set up ZMQ socket: {}", e);
+                wait_before_reconnect(&mut backoff, shutdown).await;
+                continue;
+            }
+        };
+
+        // Periodic tick for link health checks while the socket is idle
+        let mut health = time::interval(options.poll_timeout);
+        health.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut consecutive_errors = 0;
+        let max_consecutive_errors = options.max_consecutive_errors.max(1);
+
+        while consecutive_errors < max_consecutive_errors {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = socket.recv() => match received {
+                    Ok(frames) => {
+                        info!("ZMQ: Received message with {} frames", frames.len());
+                        if !report(monitor.on_activity(Instant::now()), queue, shutdown).await {
+                            return;
+                        }
+                        if queue.push_async(Event::Zmq(frames), shutdown).await.is_err() {
+                            info!("ZMQ: Event queue closed, shutting down");
+                            return;
+                        }
+                        backoff.reset();
+                        consecutive_errors = 0;
+                    }
+                    Err(err) => {
+                        error!("ZMQ recv error: {:?}", err);
+                        consecutive_errors += 1;
+                    }
+                },
+                Some(frames) = outbound.recv() => {
+                    if let Err(err) = socket.send(frames).await {
+                        error!("ZMQ send error: {:?}", err);
+                        consecutive_errors += 1;
+                    }
+                }
+                _ = health.tick() => {
+                    trace!("ZMQ: Idle, checking link health");
+                    if !report(monitor.check(Instant::now()), queue, shutdown).await {
+                        return;
+                    }
+                }
+            }
+        }
+
+        if shutdown.is_cancelled() {
+            break;
+        }
+
+        // Too many consecutive errors: recreate the socket
+        error!("ZMQ: Too many consecutive errors ({}), reconnecting...", consecutive_errors);
+        drop(socket);
+        wait_before_reconnect(&mut backoff, shutdown).await;
+    }
+
+    info!("ZMQ: Listener task exiting");
+}
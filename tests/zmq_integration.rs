@@ -0,0 +1,279 @@
+//! End-to-end test of the ZMQ wire path: a ROUTER sends envelopes to the
+//! listener's DEALER, frames arrive on the event channel, are parsed into the
+//! outbox, and are routed through a recording sink so no Telegram traffic occurs.
+//! A second test runs the listener as a ROUTER that DEALER clients connect to.
+//!
+//! Binds a local TCP port, so it is ignored by default:
+//! `cargo test -- --ignored`
+
+use corky_telegram::activity::Activity;
+use corky_telegram::aggregate::Aggregator;
+use corky_telegram::config::{AppConfig, TelegramSettings};
+use corky_telegram::sink::{MessageSink, SendOptions, SentPhoto};
+use corky_telegram::config::OverflowPolicy;
+use corky_telegram::error_replies::ErrorReplies;
+use corky_telegram::errors::ErrorCategory;
+use corky_telegram::flood::FloodBreaker;
+use corky_telegram::migrations::Migrations;
+use corky_telegram::outbox::Serve;
+use corky_telegram::queue::{Event, EventQueue};
+use corky_telegram::sender;
+use corky_telegram::state::BotState;
+use corky_telegram::stats::Stats;
+use corky_telegram::zmq_listener::{self, EnvelopeLayout, ImageBytes, ParseError};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use teloxide::types::{ChatAction, ChatId, MessageId};
+use tokio_util::sync::CancellationToken;
+
+/// Records (chat, text) for every send and always succeeds
+#[derive(Clone, Default)]
+struct RecordingSink {
+    calls: Arc<Mutex<Vec<(i64, String)>>>,
+    breaker: Arc<FloodBreaker>,
+    migrations: Arc<Migrations>,
+    stats: Arc<Stats>,
+    activity: Arc<Activity>,
+}
+
+impl RecordingSink {
+    fn take(&self) -> Vec<(i64, String)> {
+        std::mem::take(&mut *self.calls.lock().unwrap())
+    }
+
+    fn record(&self, chat: ChatId, text: &str) -> Result<MessageId, ErrorCategory> {
+        let mut calls = self.calls.lock().unwrap();
+        calls.push((chat.0, text.to_string()));
+        Ok(MessageId(calls.len() as i32))
+    }
+}
+
+impl MessageSink for RecordingSink {
+    type Error = ErrorCategory;
+
+    async fn send_text(&self, chat: ChatId, text: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+        self.record(chat, text)
+    }
+
+    async fn send_photo(&self, chat: ChatId, _path: &Path, caption: &str, _opts: SendOptions) -> Result<SentPhoto, ErrorCategory> {
+        self.record(chat, caption).map(|id| SentPhoto { id, file_id: None })
+    }
+
+    async fn send_photo_bytes(&self, chat: ChatId, _image: &ImageBytes, caption: &str, _opts: SendOptions) -> Result<SentPhoto, ErrorCategory> {
+        self.record(chat, caption).map(|id| SentPhoto { id, file_id: None })
+    }
+
+    async fn send_photo_by_id(&self, chat: ChatId, _file_id: &str, caption: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+        self.record(chat, caption)
+    }
+
+    async fn send_document(&self, chat: ChatId, _path: &Path, caption: &str, _opts: SendOptions) -> Result<MessageId, ErrorCategory> {
+        self.record(chat, caption)
+    }
+
+    async fn send_chat_action(&self, _chat: ChatId, _action: ChatAction) -> Result<(), ErrorCategory> {
+        Ok(())
+    }
+
+    async fn resolve_username(&self, _username: &str) -> Result<ChatId, ErrorCategory> {
+        Err(ErrorCategory::ChatNotFound)
+    }
+
+    fn flood_breaker(&self) -> &FloodBreaker {
+        &self.breaker
+    }
+
+    fn migrations(&self) -> &Migrations {
+        &self.migrations
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn activity(&self) -> &Activity {
+        &self.activity
+    }
+}
+
+fn test_settings(endpoint: &str) -> TelegramSettings {
+    settings_with(endpoint, "")
+}
+
+fn settings_with(endpoint: &str, extra: &str) -> TelegramSettings {
+    let toml_str = format!(
+        "[telegram]\nbot_token = \"1:x\"\nowner_chat_id = 99\nzmq_endpoint = \"{}\"\n{}\
+         [telegram.subscriber_lists]\nteam = [1, 2]\n",
+        endpoint, extra
+    );
+    toml::from_str::<AppConfig>(&toml_str).unwrap().telegram
+}
+
+/// Send to the DEALER identity, retrying until the connection is routable
+fn route(router: &zmq::Socket, payload: &[u8]) {
+    let frames: [&[u8]; 3] = [b"telegram", b"producer", payload];
+    for _ in 0..100 {
+        match router.send_multipart(frames, 0) {
+            Ok(()) => return,
+            Err(zmq::Error::EHOSTUNREACH) => std::thread::sleep(Duration::from_millis(20)),
+            Err(err) => panic!("ROUTER send failed: {:?}", err),
+        }
+    }
+    panic!("DEALER never became routable");
+}
+
+/// Parse frames into the outbox and send whatever was queued
+async fn handle(
+    sink: &RecordingSink,
+    settings: &TelegramSettings,
+    state: &Arc<BotState>,
+    replies: &mut ErrorReplies,
+    frames: Vec<Vec<u8>>,
+) {
+    zmq_listener::handle_zmq_frames(&settings.clone().into(), state, &mut Aggregator::default(), replies, frames);
+    while let Some(cmd) = state.outbox.try_next(Serve::All) {
+        sender::process_zmq_message(sink, settings, state, cmd).await;
+    }
+}
+
+async fn next_frames(queue: &EventQueue) -> Vec<Vec<u8>> {
+    match tokio::time::timeout(Duration::from_secs(5), queue.recv()).await {
+        Ok(Some(Event::Zmq(frames))) => frames,
+        other => panic!("expected ZMQ event, got {:?}", other.map(|e| e.is_some())),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "binds a local TCP port; run with `cargo test -- --ignored`"]
+async fn router_to_dealer_round_trip() {
+    let context = zmq::Context::new();
+    let router = context.socket(zmq::ROUTER).unwrap();
+    router.set_router_mandatory(true).unwrap();
+    router.set_linger(0).unwrap();
+    router.bind("tcp://127.0.0.1:*").unwrap();
+    let endpoint = router.get_last_endpoint().unwrap().unwrap();
+    let settings = test_settings(&endpoint);
+    let layout = EnvelopeLayout::from_settings(&settings);
+    let state = Arc::new(BotState::in_memory(&settings));
+
+    let queue = Arc::new(EventQueue::new(16, OverflowPolicy::Block, Arc::default()));
+    let shutdown = CancellationToken::new();
+    let listener = zmq_listener::spawn(
+        zmq_listener::ListenerOptions::from_settings(&settings),
+        queue.clone(),
+        shutdown.clone(),
+    );
+    let sink = RecordingSink::default();
+    let mut replies = ErrorReplies::new(listener.outbound(), Duration::from_secs(10));
+
+    // Valid array envelope addressed to a subscriber list
+    route(&router, br#"["ok", "send_message", {"subscriber_list": "team", "text": "hello"}]"#);
+    // Bare object instead of an envelope array
+    route(&router, br#"{"text": "not wrapped"}"#);
+    // Envelope array missing the data element
+    route(&router, br#"["ok", "send_message"]"#);
+    // Non-UTF8 bytes
+    route(&router, &[0xff, 0xfe, 0xfd]);
+    // Huge payload, well past the long-text threshold
+    let huge = "y".repeat(200_000);
+    let huge_payload = format!(r#"["ok", "send_message", {{"chat_id": 7, "text": "{}"}}]"#, huge);
+    route(&router, huge_payload.as_bytes());
+
+    let frames = next_frames(&queue).await;
+    assert_eq!(frames[0], b"producer");
+    let cmd = zmq_listener::parse_frames(&frames, &layout).unwrap();
+    assert_eq!(cmd.subscriber_list.as_deref(), Some("team"));
+    handle(&sink, &settings, &state, &mut replies, frames).await;
+    let mut calls = sink.take();
+    calls.sort();
+    assert_eq!(calls, vec![(1, "hello".to_string()), (2, "hello".to_string())]);
+
+    let frames = next_frames(&queue).await;
+    assert!(matches!(zmq_listener::parse_frames(&frames, &layout), Err(ParseError::NotAnArray)));
+    handle(&sink, &settings, &state, &mut replies, frames).await;
+    assert!(sink.take().is_empty());
+    // The producer is told why, through the ROUTER
+    // The blocking listener only sends between polls, so allow more than one poll timeout
+    router.set_rcvtimeo(15_000).unwrap();
+    let reply = router.recv_multipart(0).unwrap();
+    assert_eq!(reply[1], b"producer");
+    let event: serde_json::Value = serde_json::from_slice(&reply[2]).unwrap();
+    assert_eq!(event["type"], "error");
+    assert_eq!(event["reason"], "BAD_ENVELOPE");
+    assert_eq!(event["echo"], r#"{"text": "not wrapped"}"#);
+
+    let frames = next_frames(&queue).await;
+    assert!(matches!(zmq_listener::parse_frames(&frames, &layout), Err(ParseError::ArrayTooShort { len: 2, .. })));
+
+    let frames = next_frames(&queue).await;
+    assert_eq!(frames[1], vec![0xff, 0xfe, 0xfd]);
+    assert!(matches!(zmq_listener::parse_frames(&frames, &layout), Err(ParseError::NonUtf8 { .. })));
+
+    let frames = next_frames(&queue).await;
+    let cmd = zmq_listener::parse_frames(&frames, &layout).unwrap();
+    assert_eq!(cmd.text.len(), huge.len());
+    handle(&sink, &settings, &state, &mut replies, frames).await;
+    let calls = sink.take();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].0, 7);
+
+    shutdown.cancel();
+    listener.join().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "binds a local TCP port; run with `cargo test -- --ignored`"]
+async fn router_mode_accepts_dealer_clients() {
+    // Find a free port for the bot to bind
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let endpoint = format!("tcp://127.0.0.1:{}", port);
+    let settings = settings_with(&endpoint, "zmq_socket_type = \"router\"\n");
+    let state = Arc::new(BotState::in_memory(&settings));
+    let queue = Arc::new(EventQueue::new(16, OverflowPolicy::Block, Arc::default()));
+    let shutdown = CancellationToken::new();
+    let listener = zmq_listener::spawn(
+        zmq_listener::ListenerOptions::from_settings(&settings),
+        queue.clone(),
+        shutdown.clone(),
+    );
+    let sink = RecordingSink::default();
+    let mut replies = ErrorReplies::new(listener.outbound(), Duration::from_secs(10));
+
+    let context = zmq::Context::new();
+    let clients: Vec<zmq::Socket> = [&b"script-a"[..], b"script-b"]
+        .into_iter()
+        .map(|identity| {
+            let client = context.socket(zmq::DEALER).unwrap();
+            client.set_identity(identity).unwrap();
+            client.set_linger(0).unwrap();
+            client.set_rcvtimeo(15_000).unwrap();
+            client.connect(&endpoint).unwrap();
+            client
+        })
+        .collect();
+
+    // Each client sends just the payload frame
+    clients[0].send(&br#"["ok", "send_message", {"chat_id": 5, "text": "from a"}]"#[..], 0).unwrap();
+    let frames = next_frames(&queue).await;
+    assert_eq!(frames[0], b"script-a");
+    let cmd = zmq_listener::parse_frames(&frames, &EnvelopeLayout::from_settings(&settings)).unwrap();
+    assert_eq!(cmd.peer.as_deref(), Some("script-a"));
+    handle(&sink, &settings, &state, &mut replies, frames).await;
+    assert_eq!(sink.take(), vec![(5, "from a".to_string())]);
+
+    // A malformed payload is answered to the client that sent it, and only to it
+    clients[1].send(&b"{not json"[..], 0).unwrap();
+    let frames = next_frames(&queue).await;
+    assert_eq!(frames[0], b"script-b");
+    handle(&sink, &settings, &state, &mut replies, frames).await;
+    let reply = clients[1].recv_bytes(0).unwrap();
+    let event: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+    assert_eq!(event["reason"], "BAD_JSON");
+    assert_eq!(event["echo"], "{not json");
+    clients[0].set_rcvtimeo(200).unwrap();
+    assert!(clients[0].recv_bytes(0).is_err());
+
+    shutdown.cancel();
+    listener.join().await;
+}